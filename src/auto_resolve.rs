@@ -0,0 +1,249 @@
+use log::{error, info, warn};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::DaemonContext;
+use crate::config::{ILConfig, Spec};
+use crate::consumers::mqtt::topic_filter_matches;
+use crate::db::ILDatabase;
+use crate::json_util::get_nested_value;
+use crate::models::alert_state::AlertStateItem;
+use crate::models::event_db::EventQueueItem;
+use crate::queue::{self, QueueError};
+use ilert::ilert_builders::ILertEventType;
+
+const AUTO_RESOLVE_BATCH_SIZE: i32 = 50;
+
+/**
+    Auto resolve timeout for events from matching topics, e.g. `topic=plant/+/alarm,timeout=300`.
+    The topic supports MQTT wildcards, Kafka topics are matched as they are.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoResolveRule {
+    pub topic: String,
+    pub timeout: Duration,
+}
+
+impl AutoResolveRule {
+    pub fn parse(definition: &str) -> Result<AutoResolveRule, String> {
+        let mut spec = Spec::parse(definition)?;
+        let topic = spec
+            .take("topic")
+            .filter(|t| !t.is_empty())
+            .ok_or("'topic' is required")?;
+        let timeout = spec.take_u64("timeout")?.ok_or("'timeout' is required")?;
+        if timeout == 0 {
+            return Err("'timeout' must be greater than 0".to_string());
+        }
+        spec.finish()?;
+        Ok(AutoResolveRule {
+            topic,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+pub fn is_enabled(config: &ILConfig) -> bool {
+    config.auto_resolve.is_some()
+        || !config.auto_resolve_rules.is_empty()
+        || config.map_key_auto_resolve.is_some()
+}
+
+/// Timeout from the payload field, the first matching rule or the default, in this order.
+pub fn resolve_timeout(config: &ILConfig, payload: &str, topic: &str) -> Option<Duration> {
+    if let Some(ref path) = config.map_key_auto_resolve
+        && let Ok(json) = serde_json::from_str::<Value>(payload)
+        && let Some(val) = get_nested_value(&json, path)
+    {
+        let secs = val
+            .as_u64()
+            .or_else(|| val.as_str().and_then(|s| s.trim().parse::<u64>().ok()));
+        match secs {
+            Some(secs) if secs > 0 => return Some(Duration::from_secs(secs)),
+            _ => warn!(
+                "Ignoring auto resolve field '{}' with value {}, expected seconds",
+                path, val
+            ),
+        }
+    }
+
+    config
+        .auto_resolve_rules
+        .iter()
+        .find(|rule| topic_filter_matches(&rule.topic, topic))
+        .map(|rule| rule.timeout)
+        .or(config.auto_resolve)
+}
+
+/// Opens, refreshes or clears the tracked state of the event's alert.
+pub fn track_event(
+    config: &ILConfig,
+    db: &ILDatabase,
+    event: &EventQueueItem,
+    payload: &str,
+    topic: &str,
+) -> Result<(), rusqlite::Error> {
+    let Some(alert_key) = event.alert_key.as_deref().filter(|k| !k.is_empty()) else {
+        return Ok(());
+    };
+
+    if event.event_type == ILertEventType::ALERT.as_str() {
+        if let Some(timeout) = resolve_timeout(config, payload, topic) {
+            db.upsert_alert_state(
+                &event.integration_key,
+                alert_key,
+                &event.summary,
+                event.event_api_path.as_deref(),
+                timeout.as_secs(),
+            )?;
+        }
+    } else if event.event_type == ILertEventType::RESOLVE.as_str() {
+        db.delete_alert_state(&event.integration_key, alert_key)?;
+    }
+    Ok(())
+}
+
+pub fn build_resolve_event(state: &AlertStateItem) -> EventQueueItem {
+    let mut event = EventQueueItem::new_with_required(
+        &state.integration_key,
+        ILertEventType::RESOLVE.as_str(),
+        &format!("Resolved after silence: {}", state.summary),
+        Some(state.alert_key.clone()),
+    );
+    event.event_api_path = state.event_api_path.clone();
+    event.custom_details = Some(
+        json!({
+            "autoResolved": true,
+            "lastAlertAt": state.last_alert_at,
+        })
+        .to_string(),
+    );
+    event
+}
+
+/// Queues a RESOLVE for every expired alert state, returns the number of resolved alerts.
+pub fn resolve_expired(config: &ILConfig, db: &ILDatabase) -> Result<usize, rusqlite::Error> {
+    let states = db.get_expired_alert_states(AUTO_RESOLVE_BATCH_SIZE)?;
+    let mut resolved = 0;
+    for state in states.iter() {
+        match queue::create_event(config, db, &build_resolve_event(state)) {
+            Ok(_) | Err(QueueError::Suppressed(_)) => {
+                info!(
+                    "Alert {} was silent since {}, queued RESOLVE",
+                    state.alert_key,
+                    state.last_alert_at.as_deref().unwrap_or_default()
+                );
+                resolved += 1;
+            }
+            Err(QueueError::Db(e)) => return Err(e),
+            // keep the state and try again on the next run
+            Err(QueueError::Full) => continue,
+        }
+        db.delete_alert_state(&state.integration_key, &state.alert_key)?;
+    }
+    Ok(resolved)
+}
+
+pub async fn run_auto_resolve_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    while daemon_ctx.running.load(Ordering::Relaxed) {
+        {
+            let db = daemon_ctx.db.lock().await;
+            if let Err(e) = resolve_expired(&daemon_ctx.config, &db) {
+                error!("Failed to auto resolve silent alerts {}", e);
+            }
+        }
+
+        // short sleeps so shutdown is not delayed
+        for _ in 0..4 {
+            if !daemon_ctx.running.load(Ordering::Relaxed) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn event(event_type: &str) -> EventQueueItem {
+        EventQueueItem::new_with_required("k1", event_type, "too hot", Some("a1".to_string()))
+    }
+
+    #[test]
+    fn parse_rule() {
+        let rule = AutoResolveRule::parse("topic=plant/+/alarm,timeout=300").unwrap();
+        assert_eq!(rule.topic, "plant/+/alarm");
+        assert_eq!(rule.timeout, Duration::from_secs(300));
+        assert!(AutoResolveRule::parse("timeout=300").is_err());
+        assert!(AutoResolveRule::parse("topic=a").is_err());
+        assert!(AutoResolveRule::parse("topic=a,timeout=0").is_err());
+    }
+
+    #[test]
+    fn timeout_prefers_payload_then_rule_then_default() {
+        let mut config = ILConfig::new();
+        assert_eq!(resolve_timeout(&config, "{}", "plant/1/alarm"), None);
+
+        config.auto_resolve = Some(Duration::from_secs(600));
+        config.auto_resolve_rules =
+            vec![AutoResolveRule::parse("topic=plant/+/alarm,timeout=60").unwrap()];
+        config.map_key_auto_resolve = Some("meta.resolveAfter".to_string());
+
+        assert_eq!(
+            resolve_timeout(
+                &config,
+                r#"{"meta":{"resolveAfter":"15"}}"#,
+                "plant/1/alarm"
+            ),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            resolve_timeout(&config, "{}", "plant/1/alarm"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            resolve_timeout(&config, "{}", "office/alarm"),
+            Some(Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn silent_alert_is_resolved_once() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.auto_resolve = Some(Duration::from_secs(60));
+
+        track_event(&config, &db, &event("ALERT"), "{}", "t").unwrap();
+        assert_eq!(resolve_expired(&config, &db).unwrap(), 0);
+
+        // simulate the timeout passing
+        db.upsert_alert_state("k1", "a1", "too hot", None, 0)
+            .unwrap();
+        assert_eq!(resolve_expired(&config, &db).unwrap(), 1);
+        let queued = db.get_il_events(10).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].event_type, "RESOLVE");
+        assert_eq!(queued[0].alert_key.as_deref(), Some("a1"));
+
+        assert_eq!(resolve_expired(&config, &db).unwrap(), 0);
+    }
+
+    #[test]
+    fn explicit_resolve_clears_state() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.auto_resolve = Some(Duration::from_secs(60));
+
+        track_event(&config, &db, &event("ALERT"), "{}", "t").unwrap();
+        track_event(&config, &db, &event("RESOLVE"), "{}", "t").unwrap();
+        db.upsert_alert_state("k2", "a2", "other", None, 0).unwrap();
+        db.delete_alert_state("k2", "a2").unwrap();
+        assert!(db.get_expired_alert_states(10).unwrap().is_empty());
+        assert_eq!(resolve_expired(&config, &db).unwrap(), 0);
+    }
+}
@@ -0,0 +1,526 @@
+use log::{error, info, warn};
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use crate::config::{ILConfig, Spec};
+use crate::db::ILDatabase;
+use crate::models::event_db::EventQueueItem;
+use crate::queue::QueueError;
+use crate::{CALLER_AGENT, DaemonContext, hbt, queue};
+use ilert::ilert_builders::ILertEventType;
+
+const DEFAULT_CHECK_INTERVAL_SEC: u64 = 60;
+const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 10;
+const CHECK_OUTPUT_MAX_LEN: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckKind {
+    Command(String),
+    Tcp(String),
+    Http(String),
+    File { path: String, max_age: Duration },
+}
+
+impl CheckKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckKind::Command(_) => "command",
+            CheckKind::Tcp(_) => "tcp",
+            CheckKind::Http(_) => "http",
+            CheckKind::File { .. } => "file",
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        match self {
+            CheckKind::Command(cmd) => cmd,
+            CheckKind::Tcp(addr) => addr,
+            CheckKind::Http(url) => url,
+            CheckKind::File { path, .. } => path,
+        }
+    }
+}
+
+/**
+    A local check that runs on a schedule, e.g.
+    `name=backup,file=/var/backup/last.stamp,max_age=90000,interval=300,integration_key=il1api...`
+    Success pings the heartbeat key (if any), failure and recovery create ALERT/RESOLVE events
+    for the integration key (if any) with the stable alert key `ilagent-check-{name}`.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckDefinition {
+    pub name: String,
+    pub kind: CheckKind,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub heartbeat_key: Option<String>,
+    pub integration_key: Option<String>,
+}
+
+impl CheckDefinition {
+    pub fn parse(definition: &str) -> Result<CheckDefinition, String> {
+        let mut spec = Spec::parse(definition)?;
+
+        let name = spec
+            .take("name")
+            .filter(|n| !n.is_empty())
+            .ok_or("'name' is required")?;
+
+        let mut kinds = Vec::new();
+        if let Some(cmd) = spec.take("command") {
+            kinds.push(CheckKind::Command(cmd));
+        }
+        if let Some(addr) = spec.take("tcp") {
+            kinds.push(CheckKind::Tcp(addr));
+        }
+        if let Some(url) = spec.take("http") {
+            kinds.push(CheckKind::Http(url));
+        }
+        let max_age = spec.take_u64("max_age")?;
+        if let Some(path) = spec.take("file") {
+            let max_age = max_age.ok_or("'file' checks require 'max_age' (seconds)")?;
+            kinds.push(CheckKind::File {
+                path,
+                max_age: Duration::from_secs(max_age),
+            });
+        } else if max_age.is_some() {
+            return Err("'max_age' is only supported for 'file' checks".to_string());
+        }
+        if kinds.len() != 1 {
+            return Err(
+                "exactly one of 'command', 'tcp', 'http' or 'file' is required".to_string(),
+            );
+        }
+        let kind = kinds.remove(0);
+
+        let interval = spec
+            .take_u64("interval")?
+            .unwrap_or(DEFAULT_CHECK_INTERVAL_SEC);
+        let timeout = spec
+            .take_u64("timeout")?
+            .unwrap_or(DEFAULT_CHECK_TIMEOUT_SEC);
+        if interval == 0 || timeout == 0 {
+            return Err("'interval' and 'timeout' must be greater than 0".to_string());
+        }
+
+        let heartbeat_key = spec.take("heartbeat");
+        let integration_key = spec.take("integration_key");
+        if heartbeat_key.is_none() && integration_key.is_none() {
+            return Err("at least one of 'heartbeat' or 'integration_key' is required".to_string());
+        }
+
+        spec.finish()?;
+
+        Ok(CheckDefinition {
+            name,
+            kind,
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+            heartbeat_key,
+            integration_key,
+        })
+    }
+
+    pub fn alert_key(&self) -> String {
+        format!("ilagent-check-{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckOutcome {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl CheckOutcome {
+    fn ok(message: String) -> CheckOutcome {
+        CheckOutcome { ok: true, message }
+    }
+
+    fn failed(message: String) -> CheckOutcome {
+        CheckOutcome { ok: false, message }
+    }
+}
+
+pub async fn run_check_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    let mut handles = Vec::new();
+    for check in daemon_ctx.config.checks.iter() {
+        info!(
+            "Running {} check '{}' every {}s",
+            check.kind.as_str(),
+            check.name,
+            check.interval.as_secs()
+        );
+        let cloned_ctx = daemon_ctx.clone();
+        let check = check.clone();
+        handles.push(tokio::spawn(async move {
+            run_check_loop(cloned_ctx, check).await;
+        }));
+    }
+
+    for handle in handles {
+        if let Err(e) = handle.await {
+            error!("Check task failed {:?}", e);
+        }
+    }
+}
+
+async fn run_check_loop(daemon_ctx: Arc<DaemonContext>, check: CheckDefinition) {
+    // None until the first run, so a healthy start does not produce a RESOLVE
+    let mut failing: Option<bool> = None;
+    let mut last_run: Option<Instant> = None;
+    while daemon_ctx.running.load(Ordering::Relaxed) {
+        if let Some(last) = last_run
+            && last.elapsed() < check.interval
+        {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            continue;
+        }
+        last_run = Some(Instant::now());
+
+        let outcome = execute_check(&check).await;
+        if outcome.ok {
+            if let Some(ref heartbeat_key) = check.heartbeat_key {
+                hbt::ping_heartbeat(&daemon_ctx.ilert_client, heartbeat_key).await;
+            }
+        } else {
+            warn!("Check '{}' failed: {}", check.name, outcome.message);
+        }
+
+        let db = daemon_ctx.db.lock().await;
+        failing = record_check_outcome(&daemon_ctx.config, &db, &check, failing, &outcome);
+    }
+}
+
+/// Queues the ALERT or RESOLVE of a state change and returns the new failing state,
+/// which stays unchanged if the event could not be queued, so the next run retries it.
+fn record_check_outcome(
+    config: &ILConfig,
+    db: &ILDatabase,
+    check: &CheckDefinition,
+    failing: Option<bool>,
+    outcome: &CheckOutcome,
+) -> Option<bool> {
+    let transition = match (failing, outcome.ok) {
+        (Some(false) | None, false) => Some(ILertEventType::ALERT),
+        (Some(true), true) => Some(ILertEventType::RESOLVE),
+        _ => None,
+    };
+
+    if let (Some(event_type), Some(integration_key)) = (transition, check.integration_key.as_ref())
+    {
+        let event = build_check_event(check, integration_key, event_type, outcome);
+        match queue::create_event(config, db, &event) {
+            Ok(Some(val)) => info!(
+                "Check '{}' event {} added to queue",
+                check.name,
+                val.id.unwrap_or_default()
+            ),
+            Ok(None) => {
+                error!("Failed to queue check event, result is empty");
+                return failing;
+            }
+            Err(QueueError::Suppressed(reason)) => {
+                info!("Check '{}' event not queued, {}", check.name, reason)
+            }
+            Err(e) => {
+                error!("Failed to queue check event {:?}", e);
+                return failing;
+            }
+        }
+    }
+    Some(!outcome.ok)
+}
+
+pub fn build_check_event(
+    check: &CheckDefinition,
+    integration_key: &str,
+    event_type: ILertEventType,
+    outcome: &CheckOutcome,
+) -> EventQueueItem {
+    let summary = match event_type {
+        ILertEventType::RESOLVE => format!("Check '{}' recovered", check.name),
+        _ => format!("Check '{}' failed: {}", check.name, outcome.message),
+    };
+    let mut event = EventQueueItem::new_with_required(
+        integration_key,
+        event_type.as_str(),
+        summary.as_str(),
+        Some(check.alert_key()),
+    );
+    event.details = Some(outcome.message.clone());
+    event.custom_details = Some(
+        json!({
+            "check": check.name,
+            "type": check.kind.as_str(),
+            "target": check.kind.target(),
+        })
+        .to_string(),
+    );
+    event
+}
+
+pub async fn execute_check(check: &CheckDefinition) -> CheckOutcome {
+    let result = tokio::time::timeout(check.timeout, async {
+        match &check.kind {
+            CheckKind::Command(cmd) => run_command_check(cmd).await,
+            CheckKind::Tcp(addr) => run_tcp_check(addr).await,
+            CheckKind::Http(url) => run_http_check(url, check.timeout).await,
+            CheckKind::File { path, max_age } => run_file_check(path, *max_age),
+        }
+    })
+    .await;
+
+    match result {
+        Ok(outcome) => outcome,
+        Err(_) => CheckOutcome::failed(format!("timed out after {}s", check.timeout.as_secs())),
+    }
+}
+
+async fn run_command_check(cmd: &str) -> CheckOutcome {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .kill_on_drop(true)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if text.is_empty() {
+                text = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            }
+            let text = truncate_output(text);
+            if output.status.success() {
+                CheckOutcome::ok(text)
+            } else if text.is_empty() {
+                CheckOutcome::failed(format!("command exited with {}", output.status))
+            } else {
+                CheckOutcome::failed(text)
+            }
+        }
+        Err(e) => CheckOutcome::failed(format!("failed to run command: {}", e)),
+    }
+}
+
+async fn run_tcp_check(addr: &str) -> CheckOutcome {
+    match TcpStream::connect(addr).await {
+        Ok(_) => CheckOutcome::ok(format!("connected to {}", addr)),
+        Err(e) => CheckOutcome::failed(format!("failed to connect to {}: {}", addr, e)),
+    }
+}
+
+async fn run_http_check(url: &str, timeout: Duration) -> CheckOutcome {
+    let client = match reqwest::Client::builder()
+        .user_agent(CALLER_AGENT)
+        .timeout(timeout)
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => return CheckOutcome::failed(format!("failed to create http client: {}", e)),
+    };
+
+    match client.get(url).send().await {
+        Ok(resp) if resp.status().is_success() => {
+            CheckOutcome::ok(format!("{} returned {}", url, resp.status().as_u16()))
+        }
+        Ok(resp) => CheckOutcome::failed(format!("{} returned {}", url, resp.status().as_u16())),
+        Err(e) => CheckOutcome::failed(format!("request to {} failed: {}", url, e)),
+    }
+}
+
+fn run_file_check(path: &str, max_age: Duration) -> CheckOutcome {
+    let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(m) => m,
+        Err(e) => return CheckOutcome::failed(format!("cannot read {}: {}", path, e)),
+    };
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    if age > max_age {
+        CheckOutcome::failed(format!(
+            "{} is {}s old (max {}s)",
+            path,
+            age.as_secs(),
+            max_age.as_secs()
+        ))
+    } else {
+        CheckOutcome::ok(format!("{} is {}s old", path, age.as_secs()))
+    }
+}
+
+fn truncate_output(text: String) -> String {
+    if text.len() <= CHECK_OUTPUT_MAX_LEN {
+        return text;
+    }
+    let mut end = CHECK_OUTPUT_MAX_LEN;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn check(kind: CheckKind) -> CheckDefinition {
+        CheckDefinition {
+            name: "test".to_string(),
+            kind,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+            heartbeat_key: None,
+            integration_key: Some("il1api123".to_string()),
+        }
+    }
+
+    // --- parse ---
+
+    #[test]
+    fn parse_command_check_with_defaults() {
+        let def = CheckDefinition::parse("name=disk,command=/usr/bin/check_disk,heartbeat=il1hbt1")
+            .unwrap();
+        assert_eq!(def.name, "disk");
+        assert_eq!(
+            def.kind,
+            CheckKind::Command("/usr/bin/check_disk".to_string())
+        );
+        assert_eq!(def.interval, Duration::from_secs(60));
+        assert_eq!(def.timeout, Duration::from_secs(10));
+        assert_eq!(def.heartbeat_key.unwrap(), "il1hbt1");
+        assert!(def.integration_key.is_none());
+    }
+
+    #[test]
+    fn parse_file_check() {
+        let def = CheckDefinition::parse(
+            "name=backup,file=/var/backup.stamp,max_age=3600,interval=300,integration_key=k1",
+        )
+        .unwrap();
+        assert_eq!(
+            def.kind,
+            CheckKind::File {
+                path: "/var/backup.stamp".to_string(),
+                max_age: Duration::from_secs(3600)
+            }
+        );
+        assert_eq!(def.interval, Duration::from_secs(300));
+        assert_eq!(def.alert_key(), "ilagent-check-backup");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_definitions() {
+        // missing name
+        assert!(CheckDefinition::parse("tcp=localhost:5432,heartbeat=h").is_err());
+        // no kind
+        assert!(CheckDefinition::parse("name=a,heartbeat=h").is_err());
+        // multiple kinds
+        assert!(CheckDefinition::parse("name=a,tcp=x:1,http=http://x,heartbeat=h").is_err());
+        // file without max_age
+        assert!(CheckDefinition::parse("name=a,file=/tmp/x,heartbeat=h").is_err());
+        // no heartbeat or integration key
+        assert!(CheckDefinition::parse("name=a,tcp=localhost:5432").is_err());
+        // unknown key
+        assert!(CheckDefinition::parse("name=a,tcp=x:1,heartbeat=h,foo=bar").is_err());
+    }
+
+    // --- execute ---
+
+    #[tokio::test]
+    async fn command_check_exit_codes() {
+        let outcome = execute_check(&check(CheckKind::Command("echo fine".to_string()))).await;
+        assert!(outcome.ok);
+        assert_eq!(outcome.message, "fine");
+
+        let outcome = execute_check(&check(CheckKind::Command(
+            "echo broken >&2; exit 2".to_string(),
+        )))
+        .await;
+        assert!(!outcome.ok);
+        assert_eq!(outcome.message, "broken");
+    }
+
+    #[tokio::test]
+    async fn command_check_times_out() {
+        let mut def = check(CheckKind::Command("sleep 5".to_string()));
+        def.timeout = Duration::from_millis(100);
+        let outcome = execute_check(&def).await;
+        assert!(!outcome.ok);
+        assert!(outcome.message.contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn tcp_check_against_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(execute_check(&check(CheckKind::Tcp(addr.clone()))).await.ok);
+
+        drop(listener);
+        assert!(!execute_check(&check(CheckKind::Tcp(addr))).await.ok);
+    }
+
+    #[tokio::test]
+    async fn file_check_age() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+        let fresh = check(CheckKind::File {
+            path: path.clone(),
+            max_age: Duration::from_secs(60),
+        });
+        assert!(execute_check(&fresh).await.ok);
+
+        let missing = check(CheckKind::File {
+            path: "/nonexistent/ilagent.stamp".to_string(),
+            max_age: Duration::from_secs(60),
+        });
+        assert!(!execute_check(&missing).await.ok);
+    }
+
+    #[test]
+    fn state_is_kept_until_transition_is_queued() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(1);
+        config.event_queue_limit.overflow = crate::queue::QueueOverflow::RejectNew;
+        let check = check(CheckKind::Tcp("127.0.0.1:1".to_string()));
+        let failed = CheckOutcome::failed("connection refused".to_string());
+
+        let other = EventQueueItem::new_with_required("k1", "ALERT", "other", None);
+        let queued = db.create_il_event(&other).unwrap().unwrap();
+        assert_eq!(
+            record_check_outcome(&config, &db, &check, Some(false), &failed),
+            Some(false)
+        );
+
+        db.delete_il_events(&[queued.id.unwrap()]).unwrap();
+        assert_eq!(
+            record_check_outcome(&config, &db, &check, Some(false), &failed),
+            Some(true)
+        );
+        assert_eq!(db.get_il_events(10).unwrap()[0].event_type, "ALERT");
+    }
+
+    #[test]
+    fn check_event_uses_stable_alert_key() {
+        let def = check(CheckKind::Tcp("db:5432".to_string()));
+        let outcome = CheckOutcome::failed("connection refused".to_string());
+        let alert = build_check_event(&def, "k1", ILertEventType::ALERT, &outcome);
+        assert_eq!(alert.event_type, "ALERT");
+        assert_eq!(alert.alert_key.as_deref(), Some("ilagent-check-test"));
+        assert_eq!(alert.summary, "Check 'test' failed: connection refused");
+
+        let resolve = build_check_event(&def, "k1", ILertEventType::RESOLVE, &outcome);
+        assert_eq!(resolve.event_type, "RESOLVE");
+        assert_eq!(resolve.alert_key, alert.alert_key);
+        let cd: serde_json::Value =
+            serde_json::from_str(resolve.custom_details.as_ref().unwrap()).unwrap();
+        assert_eq!(cd["target"], "db:5432");
+    }
+}
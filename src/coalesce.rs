@@ -0,0 +1,253 @@
+use log::info;
+
+use crate::db::ILDatabase;
+use crate::models::event_db::EventQueueItem;
+use ilert::ilert_builders::ILertEventType;
+
+const AUDIT_RETENTION_DAYS: u32 = 7;
+
+/// How a backlog of events for the same alert is collapsed before delivery.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoalesceMode {
+    /// keep only what is needed to reach the net state, e.g. ALERT, RESOLVE -> RESOLVE
+    KeepLast,
+    /// like keep_last, but a backlog that starts with ALERT and ends with RESOLVE is dropped entirely
+    DropResolved,
+}
+
+impl CoalesceMode {
+    pub fn parse(val: &str) -> Result<CoalesceMode, String> {
+        match val {
+            "keep_last" => Ok(CoalesceMode::KeepLast),
+            "drop_resolved" => Ok(CoalesceMode::DropResolved),
+            _ => Err(format!(
+                "unsupported coalesce mode '{}', expected 'keep_last' or 'drop_resolved'",
+                val
+            )),
+        }
+    }
+}
+
+/// Indices of the events to keep from the backlog of a single alert, oldest first.
+pub fn plan_alert_backlog(events: &[EventQueueItem], mode: CoalesceMode) -> Vec<usize> {
+    let Some(last) = events.len().checked_sub(1) else {
+        return Vec::new();
+    };
+    let is_type =
+        |idx: usize, event_type: ILertEventType| events[idx].event_type == event_type.as_str();
+
+    if is_type(last, ILertEventType::RESOLVE) {
+        if mode == CoalesceMode::DropResolved && is_type(0, ILertEventType::ALERT) {
+            return Vec::new();
+        }
+        return vec![last];
+    }
+
+    if is_type(last, ILertEventType::ACCEPT) {
+        // an ACCEPT needs the alert to exist, so keep the newest ALERT in front of it
+        let alert = (0..last)
+            .rev()
+            .find(|idx| is_type(*idx, ILertEventType::ALERT));
+        return alert.into_iter().chain([last]).collect();
+    }
+
+    vec![last]
+}
+
+/**
+    Collapses every alert backlog in the queue in one transaction and records what was dropped,
+    returns the number of dropped events. Comments are kept, unless the whole backlog is dropped,
+    then the comments queued before its last event are dropped with it. Comments queued before
+    a kept ALERT are moved behind it, as the ALERT that preceded them may have been dropped.
+*/
+pub fn coalesce_queue(db: &ILDatabase, mode: CoalesceMode) -> Result<usize, rusqlite::Error> {
+    db.in_transaction(|| coalesce_backlogs(db, mode))
+}
+
+fn coalesce_backlogs(db: &ILDatabase, mode: CoalesceMode) -> Result<usize, rusqlite::Error> {
+    let events = db.get_il_events_to_coalesce()?;
+    let mut dropped_total = 0;
+
+    for group in
+        events.chunk_by(|a, b| a.integration_key == b.integration_key && a.alert_key == b.alert_key)
+    {
+        let is_comment =
+            |event: &EventQueueItem| event.event_type == ILertEventType::COMMENT.as_str();
+        let backlog: Vec<EventQueueItem> =
+            group.iter().filter(|e| !is_comment(e)).cloned().collect();
+        let keep = plan_alert_backlog(&backlog, mode);
+        if keep.len() == backlog.len() {
+            continue;
+        }
+
+        let mut kept_ids = Vec::new();
+        let mut dropped = Vec::new();
+        let mut drop_event = |event: &EventQueueItem| -> Result<(), rusqlite::Error> {
+            let id = event.id.clone().unwrap_or_default();
+            db.delete_il_event(&id)?;
+            dropped.push(format!("{}:{}", event.event_type, id));
+            Ok(())
+        };
+        for (idx, event) in backlog.iter().enumerate() {
+            if keep.contains(&idx) {
+                kept_ids.push(event.id.clone().unwrap_or_default());
+            } else {
+                drop_event(event)?;
+            }
+        }
+        if keep.is_empty()
+            && let Some(last) = group.iter().rposition(|e| !is_comment(e))
+        {
+            for comment in group[..last].iter().filter(|e| is_comment(e)) {
+                drop_event(comment)?;
+            }
+        }
+        if let Some(first_kept) = keep.first().map(|idx| &backlog[*idx])
+            && first_kept.event_type == ILertEventType::ALERT.as_str()
+            && let Some(position) = group.iter().position(|e| e.id == first_kept.id)
+        {
+            for comment in group[..position].iter().filter(|e| is_comment(e)) {
+                db.requeue_il_event(comment.id.as_deref().unwrap_or_default())?;
+            }
+        }
+
+        let alert_key = backlog[0].alert_key.clone().unwrap_or_default();
+        db.create_il_coalesce_audit(
+            &backlog[0].integration_key,
+            &alert_key,
+            &kept_ids.join(","),
+            &dropped.join(","),
+        )?;
+        info!(
+            "Coalesced {} queued events for alert {} into {}",
+            group.len(),
+            alert_key,
+            keep.len()
+        );
+        dropped_total += dropped.len();
+    }
+
+    if dropped_total > 0 {
+        db.delete_expired_il_coalesce_audit(AUDIT_RETENTION_DAYS)?;
+    }
+    Ok(dropped_total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn backlog(types: &[&str]) -> Vec<EventQueueItem> {
+        types
+            .iter()
+            .map(|t| EventQueueItem::new_with_required("k1", t, "s", Some("a1".to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!(
+            CoalesceMode::parse("keep_last").unwrap(),
+            CoalesceMode::KeepLast
+        );
+        assert_eq!(
+            CoalesceMode::parse("drop_resolved").unwrap(),
+            CoalesceMode::DropResolved
+        );
+        assert!(CoalesceMode::parse("merge").is_err());
+    }
+
+    #[test]
+    fn keep_last_reduces_to_net_state() {
+        let mode = CoalesceMode::KeepLast;
+        assert_eq!(
+            plan_alert_backlog(&backlog(&["ALERT", "RESOLVE"]), mode),
+            vec![1]
+        );
+        assert_eq!(
+            plan_alert_backlog(&backlog(&["RESOLVE", "ALERT", "ALERT"]), mode),
+            vec![2]
+        );
+        assert_eq!(
+            plan_alert_backlog(&backlog(&["ALERT", "RESOLVE", "ALERT", "ACCEPT"]), mode),
+            vec![2, 3]
+        );
+        assert_eq!(
+            plan_alert_backlog(&backlog(&["RESOLVE", "ACCEPT"]), mode),
+            vec![1]
+        );
+    }
+
+    fn queue(db: &ILDatabase, event_type: &str, alert_key: &str, summary: &str) {
+        let event = EventQueueItem::new_with_required(
+            "k1",
+            event_type,
+            summary,
+            Some(alert_key.to_string()),
+        );
+        db.create_il_event(&event).unwrap();
+    }
+
+    /// Queued events in delivery order.
+    fn queued(db: &ILDatabase) -> Vec<String> {
+        db.get_il_events(100)
+            .unwrap()
+            .into_iter()
+            .map(|e| format!("{}:{}", e.event_type, e.summary))
+            .collect()
+    }
+
+    #[test]
+    fn drop_resolved_drops_comments_of_the_pair() {
+        let (db, _f) = temp_db();
+        queue(&db, "ALERT", "a1", "open");
+        queue(&db, "COMMENT", "a1", "note");
+        queue(&db, "COMMENT", "a2", "other alert");
+        queue(&db, "RESOLVE", "a1", "close");
+        queue(&db, "ALERT", "a3", "single");
+        queue(&db, "COMMENT", "a3", "single note");
+
+        assert_eq!(coalesce_queue(&db, CoalesceMode::DropResolved).unwrap(), 3);
+        assert_eq!(
+            queued(&db),
+            vec!["COMMENT:other alert", "ALERT:single", "COMMENT:single note"]
+        );
+        let audit = db.get_il_coalesce_audit(10).unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].dropped_events.matches("COMMENT").count(), 1);
+    }
+
+    #[test]
+    fn keep_last_keeps_comments() {
+        let (db, _f) = temp_db();
+        queue(&db, "ALERT", "a1", "open");
+        queue(&db, "COMMENT", "a1", "note");
+        queue(&db, "ALERT", "a1", "again");
+        queue(&db, "COMMENT", "a1", "later note");
+        queue(&db, "ALERT", "a2", "other");
+
+        // the first comment is delivered after the kept ALERT it refers to
+        assert_eq!(coalesce_queue(&db, CoalesceMode::KeepLast).unwrap(), 1);
+        assert_eq!(
+            queued(&db),
+            vec![
+                "ALERT:again",
+                "COMMENT:later note",
+                "ALERT:other",
+                "COMMENT:note"
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_resolved_drops_alerts_that_opened_and_closed() {
+        let mode = CoalesceMode::DropResolved;
+        assert!(plan_alert_backlog(&backlog(&["ALERT", "ACCEPT", "RESOLVE"]), mode).is_empty());
+        // the alert may have been opened before the backlog, so the RESOLVE is kept
+        assert_eq!(
+            plan_alert_backlog(&backlog(&["RESOLVE", "ALERT", "RESOLVE"]), mode),
+            vec![2]
+        );
+    }
+}
@@ -1,3 +1,19 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::auto_resolve::AutoResolveRule;
+use crate::checks::CheckDefinition;
+use crate::coalesce::CoalesceMode;
+use crate::consumers::decoder::PayloadDecoder;
+use crate::consumers::heartbeat::HeartbeatMapping;
+use crate::consumers::routing::Route;
+use crate::consumers::threshold::ThresholdRule;
+use crate::consumers::topic_vars::{TopicPattern, TopicVarKey};
+use crate::device_watch::DeviceWatch;
+use crate::hbt::HeartbeatDefinition;
+use crate::queue::QueueLimit;
+use crate::watchdog::WatchdogDefinition;
+
 #[derive(Clone)]
 pub struct ILConfig {
     pub http_host: String,
@@ -5,7 +21,7 @@ pub struct ILConfig {
     pub start_http: bool,
     pub http_worker_count: i8,
     pub db_file: String,
-    pub heartbeat_key: Option<String>,
+    pub heartbeats: Vec<HeartbeatDefinition>,
 
     pub mqtt_host: Option<String>,
     pub mqtt_port: Option<u16>,
@@ -16,18 +32,48 @@ pub struct ILConfig {
     pub mqtt_qos: u8,
     pub mqtt_buffer: bool,
     pub mqtt_shared_group: Option<String>,
+    /// MQTT v5 instead of v3.1.1
+    pub mqtt_v5: bool,
+    /// v5 session expiry interval in seconds, None requests a session that never expires (u32::MAX)
+    pub mqtt_session_expiry: Option<u32>,
+    /// Sparkplug B topic filter `spBv1.0/{group}/#`, set in Sparkplug mode
+    pub sparkplug_topic: Option<String>,
     pub mqtt_ca_path: Option<String>,
     pub mqtt_client_cert_path: Option<String>,
     pub mqtt_client_key_path: Option<String>,
+    pub topic_pattern: Option<TopicPattern>,
+    pub topic_var_keys: Vec<TopicVarKey>,
+    pub alert_key_template: Option<String>,
+    pub summary_template: Option<String>,
+    pub filter_vars: Vec<(String, String)>,
 
     pub kafka_brokers: Option<String>,
     pub kafka_group_id: Option<String>,
+    pub kafka_security_protocol: Option<String>,
+    pub kafka_sasl_mechanism: Option<String>,
+    pub kafka_sasl_username: Option<String>,
+    pub kafka_sasl_password: Option<String>,
+    pub kafka_ca_path: Option<String>,
+    pub kafka_client_cert_path: Option<String>,
+    pub kafka_client_key_path: Option<String>,
+    /// librdkafka properties passed through as they are, applied last
+    pub kafka_properties: Vec<(String, String)>,
+    pub kafka_retry_topic: Option<String>,
+    pub kafka_retry_attempts: u32,
+    pub kafka_dlq_topic: Option<String>,
+    /// how often offsets of processed messages are committed
+    pub kafka_commit_interval: Duration,
+    /// queue events in SQLite and commit once written instead of delivering inline
+    pub kafka_buffer: bool,
+    /// consumer lag in messages above which the agent reports itself not ready
+    pub kafka_max_lag: Option<u64>,
 
-    pub event_topic: Option<String>,
-    pub heartbeat_topic: Option<String>,
+    pub event_topics: Vec<String>,
+    pub heartbeat_topics: Vec<String>,
 
     pub event_key: Option<String>,
     pub map_key_etype: Option<String>,
+    pub map_key_integration_key: Option<String>,
     pub map_key_alert_key: Option<String>,
     pub map_key_summary: Option<String>,
 
@@ -40,12 +86,28 @@ pub struct ILConfig {
     pub forward_message_payload: bool,
 
     pub api_key: Option<String>,
-    pub policy_topic: Option<String>,
+    pub policy_topics: Vec<String>,
     pub policy_routing_keys: Option<String>,
     pub map_key_email: Option<String>,
     pub map_key_shift: Option<String>,
     pub shift_offset: i64,
     pub max_retries: u32,
+    pub checks: Vec<CheckDefinition>,
+
+    pub event_queue_limit: QueueLimit,
+    pub mqtt_queue_limit: QueueLimit,
+    pub queue_watchdog: Option<WatchdogDefinition>,
+    pub dedup_window: Option<Duration>,
+    pub resolve_hold: Option<Duration>,
+    pub coalesce_events: Option<CoalesceMode>,
+    pub auto_resolve: Option<Duration>,
+    pub auto_resolve_rules: Vec<AutoResolveRule>,
+    pub map_key_auto_resolve: Option<String>,
+    pub thresholds: Vec<ThresholdRule>,
+    pub routes: Vec<Route>,
+    pub heartbeat_mappings: Vec<HeartbeatMapping>,
+    pub device_watches: Vec<DeviceWatch>,
+    pub decoders: Vec<PayloadDecoder>,
 }
 
 impl ILConfig {
@@ -56,7 +118,7 @@ impl ILConfig {
             start_http: false,
             http_worker_count: 1,
             db_file: "./ilagent.db3".to_string(),
-            heartbeat_key: None,
+            heartbeats: Vec::new(),
             mqtt_host: None,
             mqtt_port: None,
             mqtt_name: None,
@@ -66,15 +128,38 @@ impl ILConfig {
             mqtt_qos: 0,
             mqtt_buffer: false,
             mqtt_shared_group: None,
+            mqtt_v5: false,
+            mqtt_session_expiry: None,
+            sparkplug_topic: None,
             mqtt_ca_path: None,
             mqtt_client_cert_path: None,
             mqtt_client_key_path: None,
+            topic_pattern: None,
+            topic_var_keys: Vec::new(),
+            alert_key_template: None,
+            summary_template: None,
+            filter_vars: Vec::new(),
             kafka_brokers: None,
             kafka_group_id: None,
-            event_topic: None,
-            heartbeat_topic: None,
+            kafka_security_protocol: None,
+            kafka_sasl_mechanism: None,
+            kafka_sasl_username: None,
+            kafka_sasl_password: None,
+            kafka_ca_path: None,
+            kafka_client_cert_path: None,
+            kafka_client_key_path: None,
+            kafka_properties: Vec::new(),
+            kafka_retry_topic: None,
+            kafka_retry_attempts: 5,
+            kafka_dlq_topic: None,
+            kafka_commit_interval: Duration::from_secs(1),
+            kafka_buffer: false,
+            kafka_max_lag: None,
+            event_topics: Vec::new(),
+            heartbeat_topics: Vec::new(),
             event_key: None,
             map_key_etype: None,
+            map_key_integration_key: None,
             map_key_alert_key: None,
             map_key_summary: None,
             map_val_etype_alert: None,
@@ -84,12 +169,27 @@ impl ILConfig {
             filter_val: None,
             forward_message_payload: false,
             api_key: None,
-            policy_topic: None,
+            policy_topics: Vec::new(),
             policy_routing_keys: None,
             map_key_email: None,
             map_key_shift: None,
             shift_offset: 0,
             max_retries: 100,
+            checks: Vec::new(),
+            event_queue_limit: QueueLimit::new(),
+            mqtt_queue_limit: QueueLimit::new(),
+            queue_watchdog: None,
+            dedup_window: None,
+            resolve_hold: None,
+            coalesce_events: None,
+            auto_resolve: None,
+            auto_resolve_rules: Vec::new(),
+            map_key_auto_resolve: None,
+            thresholds: Vec::new(),
+            routes: Vec::new(),
+            heartbeat_mappings: Vec::new(),
+            device_watches: Vec::new(),
+            decoders: Vec::new(),
         }
     }
 
@@ -112,3 +212,124 @@ impl ILConfig {
         ()
     }
 }
+
+/**
+    Key/value definition as used by repeatable CLI flags, e.g. `--check 'name=disk,command=df -h'`.
+    Pairs are separated by ',' (use '\,' for a literal comma inside a value).
+    Values are consumed with take_* and finish() rejects any key that was not consumed.
+*/
+#[derive(Debug, Clone)]
+pub struct Spec {
+    values: HashMap<String, String>,
+}
+
+impl Spec {
+    pub fn parse(spec: &str) -> Result<Spec, String> {
+        let mut values = HashMap::new();
+        for pair in split_spec_pairs(spec) {
+            let pair = pair.trim();
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, val) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected 'key=value', got '{}'", pair))?;
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(format!("empty key in '{}'", pair));
+            }
+            if values
+                .insert(key.to_string(), val.trim().to_string())
+                .is_some()
+            {
+                return Err(format!("duplicate key '{}'", key));
+            }
+        }
+        Ok(Spec { values })
+    }
+
+    pub fn take(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    pub fn take_u64(&mut self, key: &str) -> Result<Option<u64>, String> {
+        match self.values.remove(key) {
+            Some(val) => val
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| format!("'{}' must be a positive integer, got '{}'", key, val)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if self.values.is_empty() {
+            return Ok(());
+        }
+        let mut keys: Vec<&String> = self.values.keys().collect();
+        keys.sort();
+        Err(format!(
+            "unknown key(s): {}",
+            keys.iter()
+                .map(|k| k.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+}
+
+fn split_spec_pairs(spec: &str) -> Vec<String> {
+    let mut pairs = Vec::new();
+    let mut current = String::new();
+    let mut chars = spec.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                current.push(',');
+                chars.next();
+            }
+            ',' => pairs.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    pairs.push(current);
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_parses_pairs() {
+        let mut spec = Spec::parse("name=disk, interval=30").unwrap();
+        assert_eq!(spec.take("name").unwrap(), "disk");
+        assert_eq!(spec.take_u64("interval").unwrap(), Some(30));
+        assert!(spec.finish().is_ok());
+    }
+
+    #[test]
+    fn spec_value_may_contain_equals_and_escaped_commas() {
+        let mut spec = Spec::parse(r"command=test a=b\, c").unwrap();
+        assert_eq!(spec.take("command").unwrap(), "test a=b, c");
+    }
+
+    #[test]
+    fn spec_rejects_unknown_keys() {
+        let mut spec = Spec::parse("name=a,foo=1,bar=2").unwrap();
+        spec.take("name");
+        assert_eq!(spec.finish().unwrap_err(), "unknown key(s): bar, foo");
+    }
+
+    #[test]
+    fn spec_rejects_missing_value_and_duplicates() {
+        assert!(Spec::parse("name").is_err());
+        assert!(Spec::parse("name=a,name=b").is_err());
+        assert!(
+            Spec::parse("interval=x")
+                .unwrap()
+                .take_u64("interval")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+
+/// Nesting of records, arrays, maps and unions a message may have, recursive schemas stop here.
+const MAX_DEPTH: usize = 64;
+/// Array and map items a message may have beyond one per byte, for items like null that take no bytes.
+const MAX_EMPTY_ITEMS: u64 = 1024;
+
+/**
+    Avro schema for decoding the binary encoding into JSON. Logical types are decoded as
+    their underlying type, union values are unwrapped and bytes are kept as ISO-8859-1
+    strings like in the Avro JSON encoding.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvroSchema {
+    root: AvroType,
+    /// named types by full name, for references to already defined (or recursive) types
+    named: HashMap<String, AvroType>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AvroType {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, AvroType)>),
+    Enum(Vec<String>),
+    Array(Box<AvroType>),
+    Map(Box<AvroType>),
+    Union(Vec<AvroType>),
+    Fixed(usize),
+    Ref(String),
+}
+
+impl AvroSchema {
+    pub fn parse_str(schema: &str) -> Result<AvroSchema, String> {
+        let json: Value =
+            serde_json::from_str(schema).map_err(|e| format!("invalid schema json: {}", e))?;
+        let mut named = HashMap::new();
+        let root = parse_type(&json, None, &mut named)?;
+        Ok(AvroSchema { root, named })
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Value, String> {
+        let mut reader = Reader {
+            data,
+            pos: 0,
+            items: 0,
+        };
+        let value = self.decode_type(&self.root, &mut reader, 0)?;
+        if reader.pos != data.len() {
+            return Err(format!(
+                "{} trailing bytes after the record",
+                data.len() - reader.pos
+            ));
+        }
+        Ok(value)
+    }
+
+    fn decode_type(
+        &self,
+        avro_type: &AvroType,
+        reader: &mut Reader,
+        depth: usize,
+    ) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("data is nested deeper than {} levels", MAX_DEPTH));
+        }
+        let depth = depth + 1;
+        Ok(match avro_type {
+            AvroType::Null => Value::Null,
+            AvroType::Boolean => Value::Bool(reader.byte()? != 0),
+            AvroType::Int | AvroType::Long => Value::from(reader.long()?),
+            AvroType::Float => {
+                let bytes = reader.take(4)?;
+                float_value(f32::from_le_bytes(bytes.try_into().unwrap_or_default()) as f64)
+            }
+            AvroType::Double => {
+                let bytes = reader.take(8)?;
+                float_value(f64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+            }
+            AvroType::Bytes => {
+                let len = reader.length()?;
+                Value::String(reader.take(len)?.iter().map(|b| *b as char).collect())
+            }
+            AvroType::String => {
+                let len = reader.length()?;
+                let bytes = reader.take(len)?;
+                Value::String(
+                    String::from_utf8(bytes.to_vec())
+                        .map_err(|e| format!("invalid UTF-8 string: {}", e))?,
+                )
+            }
+            AvroType::Record(fields) => {
+                let mut object = Map::new();
+                for (name, field_type) in fields.iter() {
+                    object.insert(name.clone(), self.decode_type(field_type, reader, depth)?);
+                }
+                Value::Object(object)
+            }
+            AvroType::Enum(symbols) => {
+                let index = reader.long()?;
+                let symbol = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| symbols.get(i))
+                    .ok_or_else(|| format!("enum index {} out of range", index))?;
+                Value::String(symbol.clone())
+            }
+            AvroType::Array(items) => {
+                let mut values = Vec::new();
+                while let Some(count) = reader.block_count()? {
+                    for _ in 0..count {
+                        values.push(self.decode_type(items, reader, depth)?);
+                    }
+                }
+                Value::Array(values)
+            }
+            AvroType::Map(values) => {
+                let mut object = Map::new();
+                while let Some(count) = reader.block_count()? {
+                    for _ in 0..count {
+                        let len = reader.length()?;
+                        let key = String::from_utf8(reader.take(len)?.to_vec())
+                            .map_err(|e| format!("invalid UTF-8 map key: {}", e))?;
+                        object.insert(key, self.decode_type(values, reader, depth)?);
+                    }
+                }
+                Value::Object(object)
+            }
+            AvroType::Union(variants) => {
+                let index = reader.long()?;
+                let variant = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| variants.get(i))
+                    .ok_or_else(|| format!("union index {} out of range", index))?;
+                self.decode_type(variant, reader, depth)?
+            }
+            AvroType::Fixed(size) => {
+                Value::String(reader.take(*size)?.iter().map(|b| *b as char).collect())
+            }
+            AvroType::Ref(name) => {
+                let named = self
+                    .named
+                    .get(name)
+                    .ok_or_else(|| format!("unknown type '{}'", name))?;
+                self.decode_type(named, reader, depth)?
+            }
+        })
+    }
+}
+
+fn float_value(value: f64) -> Value {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn full_name(name: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(ns) if !name.contains('.') && !ns.is_empty() => format!("{}.{}", ns, name),
+        _ => name.to_string(),
+    }
+}
+
+fn parse_type(
+    json: &Value,
+    namespace: Option<&str>,
+    named: &mut HashMap<String, AvroType>,
+) -> Result<AvroType, String> {
+    match json {
+        Value::String(name) => parse_type_name(name, namespace, named),
+        Value::Array(variants) => Ok(AvroType::Union(
+            variants
+                .iter()
+                .map(|v| parse_type(v, namespace, named))
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::Object(object) => {
+            let type_value = object.get("type").ok_or("missing 'type'")?;
+            let Some(type_name) = type_value.as_str() else {
+                return parse_type(type_value, namespace, named);
+            };
+            match type_name {
+                "record" | "error" | "enum" | "fixed" => {
+                    let name = object
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .ok_or_else(|| format!("{} without 'name'", type_name))?;
+                    let namespace = object
+                        .get("namespace")
+                        .and_then(|n| n.as_str())
+                        .or(namespace);
+                    let full = full_name(name, namespace);
+                    // the namespace of a full name applies to nested types
+                    let namespace = full.rsplit_once('.').map(|(ns, _)| ns.to_string());
+                    let parsed =
+                        parse_named_type(type_name, object, namespace.as_deref(), &full, named)?;
+                    named.insert(full, parsed.clone());
+                    Ok(parsed)
+                }
+                "array" => Ok(AvroType::Array(Box::new(parse_type(
+                    object.get("items").ok_or("array without 'items'")?,
+                    namespace,
+                    named,
+                )?))),
+                "map" => Ok(AvroType::Map(Box::new(parse_type(
+                    object.get("values").ok_or("map without 'values'")?,
+                    namespace,
+                    named,
+                )?))),
+                // primitives, also with a logicalType
+                _ => parse_type_name(type_name, namespace, named),
+            }
+        }
+        _ => Err(format!("invalid type {}", json)),
+    }
+}
+
+fn parse_named_type(
+    type_name: &str,
+    object: &Map<String, Value>,
+    namespace: Option<&str>,
+    full: &str,
+    named: &mut HashMap<String, AvroType>,
+) -> Result<AvroType, String> {
+    match type_name {
+        "enum" => {
+            let symbols = object
+                .get("symbols")
+                .and_then(|s| s.as_array())
+                .ok_or_else(|| format!("enum '{}' without 'symbols'", full))?;
+            Ok(AvroType::Enum(
+                symbols
+                    .iter()
+                    .map(|s| s.as_str().map(|s| s.to_string()))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| format!("enum '{}' has non-string symbols", full))?,
+            ))
+        }
+        "fixed" => {
+            let size = object
+                .get("size")
+                .and_then(|s| s.as_u64())
+                .ok_or_else(|| format!("fixed '{}' without 'size'", full))?;
+            Ok(AvroType::Fixed(size as usize))
+        }
+        _ => {
+            // registered before the fields so they can refer to the record itself
+            named.insert(full.to_string(), AvroType::Ref(full.to_string()));
+            let fields = object
+                .get("fields")
+                .and_then(|f| f.as_array())
+                .ok_or_else(|| format!("record '{}' without 'fields'", full))?;
+            let mut parsed = Vec::new();
+            for field in fields.iter() {
+                let name = field
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .ok_or_else(|| format!("field without 'name' in '{}'", full))?;
+                let field_type = field
+                    .get("type")
+                    .ok_or_else(|| format!("field '{}' without 'type'", name))?;
+                parsed.push((name.to_string(), parse_type(field_type, namespace, named)?));
+            }
+            Ok(AvroType::Record(parsed))
+        }
+    }
+}
+
+fn parse_type_name(
+    name: &str,
+    namespace: Option<&str>,
+    named: &HashMap<String, AvroType>,
+) -> Result<AvroType, String> {
+    Ok(match name {
+        "null" => AvroType::Null,
+        "boolean" => AvroType::Boolean,
+        "int" => AvroType::Int,
+        "long" => AvroType::Long,
+        "float" => AvroType::Float,
+        "double" => AvroType::Double,
+        "bytes" => AvroType::Bytes,
+        "string" => AvroType::String,
+        _ => {
+            let full = full_name(name, namespace);
+            if named.contains_key(&full) {
+                AvroType::Ref(full)
+            } else if named.contains_key(name) {
+                AvroType::Ref(name.to_string())
+            } else {
+                return Err(format!("unknown type '{}'", name));
+            }
+        }
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// array and map items announced so far
+    items: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("unexpected end of data")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Zigzag encoded variable length integer, used for int and long.
+    fn long(&mut self) -> Result<i64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err("integer is too long".to_string())
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let len = self.long()?;
+        usize::try_from(len).map_err(|_| format!("negative length {}", len))
+    }
+
+    /**
+        Item count of the next array or map block, None after the last block. Items like null take
+        no bytes, so the items of a message are limited by its size instead of the remaining data.
+    */
+    fn block_count(&mut self) -> Result<Option<u64>, String> {
+        let count = self.long()?;
+        if count == 0 {
+            return Ok(None);
+        }
+        if count < 0 {
+            // followed by the block size in bytes
+            self.long()?;
+        }
+        let count = count.unsigned_abs();
+        self.items = self.items.saturating_add(count);
+        if self.items > self.data.len() as u64 + MAX_EMPTY_ITEMS {
+            return Err(format!("block of {} items exceeds the data", count));
+        }
+        Ok(Some(count))
+    }
+}
+
+/// Schema id and Avro data of a Confluent framed message, magic byte 0 followed by the big endian id.
+pub fn split_confluent_frame(payload: &[u8]) -> Result<(u32, &[u8]), String> {
+    match payload {
+        [0, a, b, c, d, data @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), data)),
+        _ => Err("missing Confluent framing (magic byte 0 and schema id)".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn zigzag(value: i64) -> Vec<u8> {
+        let mut n = ((value << 1) ^ (value >> 63)) as u64;
+        let mut bytes = Vec::new();
+        loop {
+            if n & !0x7f == 0 {
+                bytes.push(n as u8);
+                return bytes;
+            }
+            bytes.push((n & 0x7f | 0x80) as u8);
+            n >>= 7;
+        }
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = zigzag(value.len() as i64);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    const READING_SCHEMA: &str = r#"{
+        "type": "record", "name": "Reading", "namespace": "acme",
+        "fields": [
+            {"name": "device", "type": "string"},
+            {"name": "temperature", "type": "double"},
+            {"name": "level", "type": {"type": "enum", "name": "Level", "symbols": ["OK", "WARN", "CRIT"]}},
+            {"name": "site", "type": ["null", "string"]},
+            {"name": "tags", "type": {"type": "array", "items": "string"}},
+            {"name": "labels", "type": {"type": "map", "values": "long"}},
+            {"name": "at", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+            {"name": "previous", "type": ["null", "Level"]}
+        ]
+    }"#;
+
+    #[test]
+    fn decodes_records_with_nested_types() {
+        let schema = AvroSchema::parse_str(READING_SCHEMA).unwrap();
+        let mut data = string("t1");
+        data.extend_from_slice(&81.5f64.to_le_bytes());
+        data.extend(zigzag(2));
+        data.extend(zigzag(1));
+        data.extend(string("berlin"));
+        // one array block given with its byte size
+        data.extend(zigzag(-2));
+        data.extend(zigzag(6));
+        data.extend(string("a"));
+        data.extend(string("bc"));
+        data.extend(zigzag(0));
+        data.extend(zigzag(1));
+        data.extend(string("line"));
+        data.extend(zigzag(-3));
+        data.extend(zigzag(0));
+        data.extend(zigzag(1_700_000_000_000));
+        data.extend(zigzag(1));
+        data.extend(zigzag(1));
+
+        assert_eq!(
+            schema.decode(&data).unwrap(),
+            json!({
+                "device": "t1",
+                "temperature": 81.5,
+                "level": "CRIT",
+                "site": "berlin",
+                "tags": ["a", "bc"],
+                "labels": {"line": -3},
+                "at": 1_700_000_000_000i64,
+                "previous": "WARN",
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_recursive_records() {
+        let schema = AvroSchema::parse_str(
+            r#"{"type": "record", "name": "Node", "fields": [
+                {"name": "value", "type": "int"},
+                {"name": "next", "type": ["null", "Node"]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut data = zigzag(1);
+        data.extend(zigzag(1));
+        data.extend(zigzag(2));
+        data.extend(zigzag(0));
+        assert_eq!(
+            schema.decode(&data).unwrap(),
+            json!({"value": 1, "next": {"value": 2, "next": null}})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schemas_and_data() {
+        assert!(AvroSchema::parse_str("not json").is_err());
+        assert!(AvroSchema::parse_str(r#"{"type": "record", "name": "R"}"#).is_err());
+        assert!(AvroSchema::parse_str(r#""Unknown""#).is_err());
+
+        let schema = AvroSchema::parse_str(READING_SCHEMA).unwrap();
+        assert!(schema.decode(&string("t1")).is_err());
+        let schema = AvroSchema::parse_str(r#""int""#).unwrap();
+        assert!(schema.decode(&[0x02, 0x02]).is_err());
+    }
+
+    #[test]
+    fn rejects_block_counts_beyond_the_data() {
+        let schema = AvroSchema::parse_str(r#"{"type": "array", "items": "null"}"#).unwrap();
+        let mut data = zigzag(2);
+        data.extend(zigzag(0));
+        assert_eq!(schema.decode(&data).unwrap(), json!([null, null]));
+        let mut data = zigzag(i64::MAX);
+        data.extend(zigzag(0));
+        assert!(schema.decode(&data).is_err());
+
+        // blocks that add up to more items than the data can hold
+        let mut data = Vec::new();
+        for _ in 0..20 {
+            data.extend(zigzag(100));
+        }
+        data.extend(zigzag(0));
+        assert!(schema.decode(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_data_nested_too_deep() {
+        let schema = AvroSchema::parse_str(
+            r#"{"type": "record", "name": "Node", "fields": [
+                {"name": "next", "type": ["null", "Node"]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut data = vec![0x02; 10];
+        data.push(0);
+        assert!(schema.decode(&data).is_ok());
+        let mut data = vec![0x02; 100_000];
+        data.push(0);
+        let err = schema.decode(&data).unwrap_err();
+        assert!(err.contains("nested deeper"), "{}", err);
+    }
+
+    #[test]
+    fn splits_confluent_frames() {
+        assert_eq!(
+            split_confluent_frame(&[0, 0, 0, 1, 7, 0x02]).unwrap(),
+            (263, &[0x02][..])
+        );
+        assert!(split_confluent_frame(&[1, 0, 0, 0, 1]).is_err());
+        assert!(split_confluent_frame(&[0, 0]).is_err());
+    }
+}
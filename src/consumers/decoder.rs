@@ -0,0 +1,482 @@
+use log::info;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::CALLER_AGENT;
+use crate::config::{ILConfig, Spec};
+use crate::consumers::avro::{AvroSchema, split_confluent_frame};
+use crate::consumers::routing::RouteTopic;
+use crate::consumers::text_payload;
+
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+    Decodes non-JSON message payloads of matching topics into JSON before they are mapped, e.g.
+    `topic=orders,format=avro,schema=/etc/ilagent/order.avsc`,
+    `topic=orders,format=avro,registry=http://localhost:8081`,
+    `topic=sensors/+/data,format=protobuf,descriptor=/etc/ilagent/sensors.desc,message=acme.Reading`,
+    `topic=plant/+/raw,format=regex,pattern=^(?P<eventType>\w+) (?P<alertKey>\w+)`,
+    `topic=plant/+/csv,format=csv,columns=eventType|alertKey|value`, `format=logfmt` or `format=msgpack`.
+    Avro payloads are expected in the Confluent wire format (magic byte and schema id).
+*/
+#[derive(Debug, Clone)]
+pub struct PayloadDecoder {
+    pub topic: RouteTopic,
+    pub format: PayloadFormat,
+}
+
+#[derive(Debug, Clone)]
+pub enum PayloadFormat {
+    Avro(AvroSchemaSource),
+    Protobuf(MessageDescriptor),
+    /// named capture groups become fields
+    Regex(Regex),
+    /// fields of the first record, named by the columns or a header line
+    Csv {
+        columns: Option<Vec<String>>,
+        delimiter: u8,
+    },
+    Logfmt,
+    MessagePack,
+}
+
+#[derive(Debug, Clone)]
+pub enum AvroSchemaSource {
+    /// local schema file, the schema id of the message is ignored
+    File(Arc<AvroSchema>),
+    /// schema registry base url, fetched schemas are cached by id
+    Registry {
+        url: String,
+        schemas: Arc<Mutex<HashMap<u32, Arc<AvroSchema>>>>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// the payload does not match its schema, retrying will not help
+    Invalid(String),
+    /// the schema registry could not be reached
+    Unavailable(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Invalid(e) => write!(f, "invalid payload: {}", e),
+            DecodeError::Unavailable(e) => write!(f, "schema unavailable: {}", e),
+        }
+    }
+}
+
+impl PayloadDecoder {
+    pub fn parse(definition: &str) -> Result<PayloadDecoder, String> {
+        let mut spec = Spec::parse(definition)?;
+        let topic = RouteTopic::take(&mut spec)?.ok_or("'topic' or 'topic_regex' is required")?;
+        let format = match spec.take("format").as_deref() {
+            Some("avro") => match (spec.take("schema"), spec.take("registry")) {
+                (Some(path), None) => {
+                    let schema = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("cannot read schema '{}': {}", path, e))?;
+                    let schema = AvroSchema::parse_str(&schema)
+                        .map_err(|e| format!("invalid schema '{}': {}", path, e))?;
+                    PayloadFormat::Avro(AvroSchemaSource::File(Arc::new(schema)))
+                }
+                (None, Some(url)) if !url.is_empty() => {
+                    PayloadFormat::Avro(AvroSchemaSource::Registry {
+                        url: url.trim_end_matches('/').to_string(),
+                        schemas: Arc::new(Mutex::new(HashMap::new())),
+                    })
+                }
+                _ => return Err("avro needs either 'schema' or 'registry'".to_string()),
+            },
+            Some("protobuf") => {
+                let path = spec
+                    .take("descriptor")
+                    .ok_or("protobuf needs 'descriptor'")?;
+                let name = spec.take("message").ok_or("protobuf needs 'message'")?;
+                let bytes = std::fs::read(&path)
+                    .map_err(|e| format!("cannot read descriptor '{}': {}", path, e))?;
+                let pool = DescriptorPool::decode(bytes.as_slice())
+                    .map_err(|e| format!("invalid descriptor set '{}': {}", path, e))?;
+                let message = pool
+                    .get_message_by_name(&name)
+                    .ok_or_else(|| format!("message '{}' not found in '{}'", name, path))?;
+                PayloadFormat::Protobuf(message)
+            }
+            Some("regex") => {
+                let pattern = spec.take("pattern").ok_or("regex needs 'pattern'")?;
+                let regex =
+                    Regex::new(&pattern).map_err(|e| format!("invalid 'pattern': {}", e))?;
+                if regex.capture_names().flatten().next().is_none() {
+                    return Err("'pattern' needs named groups like (?P<summary>.*)".to_string());
+                }
+                PayloadFormat::Regex(regex)
+            }
+            Some("csv") => {
+                let columns = spec
+                    .take("columns")
+                    .map(|c| c.split('|').map(|c| c.trim().to_string()).collect());
+                let delimiter = match spec.take("delimiter").as_deref() {
+                    None => b',',
+                    Some("tab") => b'\t',
+                    Some(d) if d.len() == 1 && d.is_ascii() => d.as_bytes()[0],
+                    Some(d) => return Err(format!("invalid 'delimiter' '{}'", d)),
+                };
+                PayloadFormat::Csv { columns, delimiter }
+            }
+            Some("logfmt") => PayloadFormat::Logfmt,
+            Some("msgpack") => PayloadFormat::MessagePack,
+            Some(other) => return Err(format!("unknown format '{}'", other)),
+            None => return Err("'format' is required".to_string()),
+        };
+        spec.finish()?;
+        Ok(PayloadDecoder { topic, format })
+    }
+
+    pub async fn decode(&self, payload: &[u8]) -> Result<Value, DecodeError> {
+        match self.format {
+            PayloadFormat::Avro(ref source) => {
+                let (id, data) = split_confluent_frame(payload).map_err(DecodeError::Invalid)?;
+                let schema = source.schema(id).await?;
+                schema.decode(data).map_err(DecodeError::Invalid)
+            }
+            PayloadFormat::Protobuf(ref descriptor) => {
+                let message = DynamicMessage::decode(descriptor.clone(), payload)
+                    .map_err(|e| DecodeError::Invalid(e.to_string()))?;
+                let options = SerializeOptions::new()
+                    .use_proto_field_name(true)
+                    .stringify_64_bit_integers(false);
+                message
+                    .serialize_with_options(serde_json::value::Serializer, &options)
+                    .map_err(|e| DecodeError::Invalid(e.to_string()))
+            }
+            PayloadFormat::Regex(ref regex) => {
+                text_payload::parse_regex(regex, utf8(payload)?).map_err(DecodeError::Invalid)
+            }
+            PayloadFormat::Csv {
+                ref columns,
+                delimiter,
+            } => text_payload::parse_csv(columns.as_deref(), delimiter, utf8(payload)?)
+                .map_err(DecodeError::Invalid),
+            PayloadFormat::Logfmt => {
+                text_payload::parse_logfmt(utf8(payload)?).map_err(DecodeError::Invalid)
+            }
+            PayloadFormat::MessagePack => rmp_serde::from_slice::<Value>(payload)
+                .map_err(|e| DecodeError::Invalid(e.to_string())),
+        }
+    }
+}
+
+fn utf8(payload: &[u8]) -> Result<&str, DecodeError> {
+    std::str::from_utf8(payload).map_err(|e| DecodeError::Invalid(e.to_string()))
+}
+
+impl AvroSchemaSource {
+    async fn schema(&self, id: u32) -> Result<Arc<AvroSchema>, DecodeError> {
+        let (url, schemas) = match self {
+            AvroSchemaSource::File(schema) => return Ok(schema.clone()),
+            AvroSchemaSource::Registry { url, schemas } => (url, schemas),
+        };
+        // the cache only holds complete schemas, so it stays usable after a panic while locked
+        let cached = schemas
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .cloned();
+        if let Some(schema) = cached {
+            return Ok(schema);
+        }
+
+        let schema = Arc::new(fetch_registry_schema(url, id).await?);
+        info!("Fetched avro schema {} from {}", id, url);
+        schemas
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, schema.clone());
+        Ok(schema)
+    }
+}
+
+async fn fetch_registry_schema(url: &str, id: u32) -> Result<AvroSchema, DecodeError> {
+    let client = reqwest::Client::builder()
+        .user_agent(CALLER_AGENT)
+        .timeout(REGISTRY_TIMEOUT)
+        .build()
+        .map_err(|e| DecodeError::Unavailable(format!("failed to create http client: {}", e)))?;
+    let schema_url = format!("{}/schemas/ids/{}", url, id);
+    let response = client.get(&schema_url).send().await.map_err(|e| {
+        DecodeError::Unavailable(format!("request to {} failed: {}", schema_url, e))
+    })?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        // the message refers to a schema that does not exist
+        return Err(DecodeError::Invalid(format!("schema {} not found", id)));
+    }
+    if !status.is_success() {
+        return Err(DecodeError::Unavailable(format!(
+            "{} returned {}",
+            schema_url,
+            status.as_u16()
+        )));
+    }
+    let body = response
+        .text()
+        .await
+        .map_err(|e| DecodeError::Unavailable(format!("failed to read {}: {}", schema_url, e)))?;
+    let schema = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|json| {
+            json.get("schema")
+                .and_then(|s| s.as_str())
+                .map(String::from)
+        })
+        .ok_or_else(|| DecodeError::Invalid(format!("no schema in response of {}", schema_url)))?;
+    AvroSchema::parse_str(&schema)
+        .map_err(|e| DecodeError::Invalid(format!("invalid schema {}: {}", id, e)))
+}
+
+/// Decoder of the first matching topic, None if payloads of the topic are JSON.
+pub fn find_decoder<'a>(
+    config: &'a ILConfig,
+    consumer_type: &str,
+    topic: &str,
+) -> Option<&'a PayloadDecoder> {
+    config
+        .decoders
+        .iter()
+        .find(|decoder| decoder.topic.matches(consumer_type, topic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_reflect::prost::Message;
+    use prost_reflect::prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        field_descriptor_proto::Type,
+    };
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const SCHEMA: &str = r#"{"type": "record", "name": "Alarm", "fields": [
+        {"name": "summary", "type": "string"},
+        {"name": "count", "type": "long"}
+    ]}"#;
+
+    // Confluent framed Alarm {summary: "hot", count: 2} with schema id 7
+    const FRAMED_ALARM: &[u8] = &[0, 0, 0, 0, 7, 6, b'h', b'o', b't', 4];
+
+    fn temp_file(name: &str, content: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("ilagent_{}_{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn field(name: &str, number: i32, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_decoders() {
+        let schema = temp_file("parse.avsc", SCHEMA.as_bytes());
+        assert!(
+            PayloadDecoder::parse(&format!("topic=orders,format=avro,schema={}", schema)).is_ok()
+        );
+        assert!(
+            PayloadDecoder::parse(
+                "topic_regex=^orders-,format=avro,registry=http://localhost:8081"
+            )
+            .is_ok()
+        );
+        assert!(PayloadDecoder::parse("format=avro,registry=http://localhost:8081").is_err());
+        assert!(PayloadDecoder::parse("topic=orders,format=avro").is_err());
+        assert!(PayloadDecoder::parse("topic=orders,format=xml").is_err());
+        assert!(PayloadDecoder::parse("topic=orders,format=avro,schema=/missing.avsc").is_err());
+        assert!(
+            PayloadDecoder::parse("topic=orders,format=protobuf,descriptor=/missing.desc").is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_avro_with_schema_file() {
+        let schema = temp_file("alarm.avsc", SCHEMA.as_bytes());
+        let decoder =
+            PayloadDecoder::parse(&format!("topic=orders,format=avro,schema={}", schema)).unwrap();
+        assert_eq!(
+            decoder.decode(FRAMED_ALARM).await.unwrap(),
+            json!({"summary": "hot", "count": 2})
+        );
+        assert!(matches!(
+            decoder.decode(b"{\"summary\": \"hot\"}").await,
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetches_and_caches_avro_schemas_from_registry() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/schemas/ids/7"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"schema": SCHEMA})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/schemas/ids/8"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let decoder = PayloadDecoder::parse(&format!(
+            "topic=orders,format=avro,registry={}/",
+            server.uri()
+        ))
+        .unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                decoder.decode(FRAMED_ALARM).await.unwrap(),
+                json!({"summary": "hot", "count": 2})
+            );
+        }
+        assert!(matches!(
+            decoder.decode(&[0, 0, 0, 0, 8, 0]).await,
+            Err(DecodeError::Invalid(_))
+        ));
+
+        let unreachable =
+            PayloadDecoder::parse("topic=orders,format=avro,registry=http://127.0.0.1:1").unwrap();
+        assert!(matches!(
+            unreachable.decode(FRAMED_ALARM).await,
+            Err(DecodeError::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn decodes_protobuf_messages() {
+        let descriptor_set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("reading.proto".to_string()),
+                package: Some("acme".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Reading".to_string()),
+                    field: vec![
+                        field("device_id", 1, Type::String),
+                        field("temperature", 2, Type::Double),
+                        field("sequence", 3, Type::Int64),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let descriptor = temp_file("reading.desc", &descriptor_set.encode_to_vec());
+        let decoder = PayloadDecoder::parse(&format!(
+            "topic=sensors/+/data,format=protobuf,descriptor={},message=acme.Reading",
+            descriptor
+        ))
+        .unwrap();
+        assert!(
+            PayloadDecoder::parse(&format!(
+                "topic=sensors/+/data,format=protobuf,descriptor={},message=acme.Missing",
+                descriptor
+            ))
+            .is_err()
+        );
+
+        let mut payload = vec![0x0a, 2, b's', b'1', 0x11];
+        payload.extend_from_slice(&21.5f64.to_le_bytes());
+        payload.extend_from_slice(&[0x18, 42]);
+        assert_eq!(
+            decoder.decode(&payload).await.unwrap(),
+            json!({"device_id": "s1", "temperature": 21.5, "sequence": 42})
+        );
+        assert!(matches!(
+            decoder.decode(&[0x0a, 10]).await,
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn decodes_text_payloads() {
+        let regex = PayloadDecoder::parse(
+            r"topic=plant/+/raw,format=regex,pattern=^(?P<eventType>\w+) (?P<alertKey>\w+) (?P<value>[\d.]+)$",
+        )
+        .unwrap();
+        assert_eq!(
+            regex.decode(b"ALARM high_temp 87.2").await.unwrap(),
+            json!({"eventType": "ALARM", "alertKey": "high_temp", "value": "87.2"})
+        );
+        assert!(matches!(
+            regex.decode(&[0xff, 0xfe]).await,
+            Err(DecodeError::Invalid(_))
+        ));
+
+        let csv = PayloadDecoder::parse(
+            "topic=plant/+/csv,format=csv,delimiter=;,columns=eventType|alertKey|value",
+        )
+        .unwrap();
+        assert_eq!(
+            csv.decode(b"ALERT;pump_1;12").await.unwrap(),
+            json!({"eventType": "ALERT", "alertKey": "pump_1", "value": "12"})
+        );
+
+        let logfmt = PayloadDecoder::parse("topic=logs,format=logfmt").unwrap();
+        assert_eq!(
+            logfmt
+                .decode(b"level=error summary=\"disk full\"")
+                .await
+                .unwrap(),
+            json!({"level": "error", "summary": "disk full"})
+        );
+
+        assert!(PayloadDecoder::parse("topic=raw,format=regex,pattern=^\\w+$").is_err());
+        assert!(PayloadDecoder::parse("topic=raw,format=csv,delimiter=;;").is_err());
+    }
+
+    #[tokio::test]
+    async fn decodes_message_pack() {
+        let decoder = PayloadDecoder::parse("topic=sensors/#,format=msgpack").unwrap();
+        let payload = rmp_serde::to_vec_named(&json!({
+            "eventType": "ALERT",
+            "summary": "pressure",
+            "details": {"bar": 7.5, "sensors": [1, 2]},
+        }))
+        .unwrap();
+        assert_eq!(
+            decoder.decode(&payload).await.unwrap(),
+            json!({
+                "eventType": "ALERT",
+                "summary": "pressure",
+                "details": {"bar": 7.5, "sensors": [1, 2]},
+            })
+        );
+        assert!(matches!(
+            decoder.decode(&[0xc1]).await,
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn finds_decoders_by_topic() {
+        let mut config = ILConfig::new();
+        config.decoders = vec![
+            PayloadDecoder::parse("topic=sensors/+/data,format=avro,registry=http://r").unwrap(),
+        ];
+        assert!(find_decoder(&config, "mqtt", "sensors/s1/data").is_some());
+        assert!(find_decoder(&config, "mqtt", "sensors/s1/status").is_none());
+        assert!(find_decoder(&config, "kafka", "sensors/s1/data").is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::config::{ILConfig, Spec};
+use crate::consumers::mqtt::topic_filter_matches;
+use crate::consumers::routing::value_matches;
+use crate::json_util::get_nested_value;
+use crate::models::heartbeat::HeartbeatJson;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeartbeatKeySource {
+    Fixed(String),
+    Level(usize),
+    Path(String),
+}
+
+/**
+    Maps heartbeat messages without an `integrationKey` to a heartbeat key, e.g.
+    `topic=devices/+/alive,key=il1hbt...`, `topic=hb/+,key_level=1` or
+    `key_path=device.heartbeatKey,field=status,value=ok`. The topic supports MQTT wildcards,
+    without a topic the mapping applies to all heartbeat topics.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartbeatMapping {
+    pub topic: Option<String>,
+    pub key: HeartbeatKeySource,
+    pub filter: Option<(String, String)>,
+}
+
+impl HeartbeatMapping {
+    pub fn parse(definition: &str) -> Result<HeartbeatMapping, String> {
+        let mut spec = Spec::parse(definition)?;
+        let topic = spec.take("topic").filter(|t| !t.is_empty());
+        let key = match (
+            spec.take("key").filter(|k| !k.is_empty()),
+            spec.take_u64("key_level")?,
+            spec.take("key_path").filter(|p| !p.is_empty()),
+        ) {
+            (Some(key), None, None) => HeartbeatKeySource::Fixed(key),
+            (None, Some(level), None) => HeartbeatKeySource::Level(level as usize),
+            (None, None, Some(path)) => HeartbeatKeySource::Path(path),
+            _ => {
+                return Err(
+                    "exactly one of 'key', 'key_level' or 'key_path' is required".to_string(),
+                );
+            }
+        };
+        let filter = match (spec.take("field"), spec.take("value")) {
+            (Some(field), Some(value)) if !field.is_empty() => Some((field, value)),
+            (None, None) => None,
+            _ => return Err("'field' and 'value' have to be set together".to_string()),
+        };
+        spec.finish()?;
+        Ok(HeartbeatMapping { topic, key, filter })
+    }
+
+    pub fn matches_topic(&self, topic: &str) -> bool {
+        self.topic
+            .as_ref()
+            .is_none_or(|filter| topic_filter_matches(filter, topic))
+    }
+
+    fn needs_payload(&self) -> bool {
+        self.filter.is_some() || matches!(self.key, HeartbeatKeySource::Path(_))
+    }
+
+    /// Heartbeat key for the message, None if the filter does not match or the key is missing.
+    pub fn resolve_key(&self, json: Option<&Value>, topic: &str) -> Option<String> {
+        if let Some((ref path, ref expected)) = self.filter
+            && !json
+                .and_then(|json| get_nested_value(json, path))
+                .is_some_and(|val| value_matches(val, expected))
+        {
+            debug!("Heartbeat message on '{}' does not match the filter", topic);
+            return None;
+        }
+        let key = match self.key {
+            HeartbeatKeySource::Fixed(ref key) => Some(key.clone()),
+            HeartbeatKeySource::Level(level) => topic.split('/').nth(level).map(|l| l.to_string()),
+            HeartbeatKeySource::Path(ref path) => json
+                .and_then(|json| get_nested_value(json, path))
+                .and_then(|val| val.as_str())
+                .map(|key| key.to_string()),
+        };
+        key.filter(|k| !k.is_empty())
+    }
+}
+
+/// Heartbeat key from the first mapping for the topic, the payload's `integrationKey` otherwise.
+pub fn resolve_heartbeat_key(config: &ILConfig, payload: &str, topic: &str) -> Option<String> {
+    let Some(mapping) = config
+        .heartbeat_mappings
+        .iter()
+        .find(|mapping| mapping.matches_topic(topic))
+    else {
+        return HeartbeatJson::parse_heartbeat_json(payload).map(|hbt| hbt.integrationKey);
+    };
+
+    let json = if mapping.needs_payload() {
+        serde_json::from_str::<Value>(payload).ok()
+    } else {
+        None
+    };
+    let key = mapping.resolve_key(json.as_ref(), topic);
+    if key.is_none() && mapping.filter.is_none() {
+        warn!("Could not find a heartbeat key for message on '{}'", topic);
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(mappings: &[&str]) -> ILConfig {
+        let mut config = ILConfig::new();
+        config.heartbeat_mappings = mappings
+            .iter()
+            .map(|m| HeartbeatMapping::parse(m).unwrap())
+            .collect();
+        config
+    }
+
+    #[test]
+    fn parse_mappings() {
+        let mapping = HeartbeatMapping::parse("topic=devices/+/alive,key_level=1").unwrap();
+        assert_eq!(mapping.topic.as_deref(), Some("devices/+/alive"));
+        assert_eq!(mapping.key, HeartbeatKeySource::Level(1));
+        assert!(HeartbeatMapping::parse("key=il1hbt1,field=status,value=ok").is_ok());
+        assert!(HeartbeatMapping::parse("topic=a").is_err());
+        assert!(HeartbeatMapping::parse("key=k,key_path=p").is_err());
+        assert!(HeartbeatMapping::parse("key=k,field=status").is_err());
+    }
+
+    #[test]
+    fn bare_messages_use_fixed_or_topic_key() {
+        let config = config_with(&[
+            "topic=devices/+/alive,key_level=1",
+            "topic=gw/alive,key=il1hbt1",
+        ]);
+        assert_eq!(
+            resolve_heartbeat_key(&config, "alive", "devices/il1hbt9/alive").as_deref(),
+            Some("il1hbt9")
+        );
+        assert_eq!(
+            resolve_heartbeat_key(&config, "alive", "gw/alive").as_deref(),
+            Some("il1hbt1")
+        );
+        // unmapped topics still expect the key in the payload
+        assert_eq!(
+            resolve_heartbeat_key(&config, r#"{"apiKey":"il1hbt2"}"#, "other").as_deref(),
+            Some("il1hbt2")
+        );
+        assert_eq!(resolve_heartbeat_key(&config, "alive", "other"), None);
+    }
+
+    #[test]
+    fn payload_key_and_filter() {
+        let config = config_with(&["key_path=device.hbt,field=status,value=ok"]);
+        let payload = r#"{"device": {"hbt": "il1hbt3"}, "status": "ok"}"#;
+        assert_eq!(
+            resolve_heartbeat_key(&config, payload, "hb").as_deref(),
+            Some("il1hbt3")
+        );
+        let payload = r#"{"device": {"hbt": "il1hbt3"}, "status": "degraded"}"#;
+        assert_eq!(resolve_heartbeat_key(&config, payload, "hb"), None);
+        assert_eq!(resolve_heartbeat_key(&config, "alive", "hb"), None);
+    }
+}
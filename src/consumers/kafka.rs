@@ -1,30 +1,134 @@
 use log::{debug, error, info, warn};
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::Ordering;
-use std::time::Duration;
+use std::sync::{Arc, Condvar, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
-use crate::{DaemonContext, hbt, poll};
+use crate::config::ILConfig;
+use crate::consumers::decoder::{DecodeError, find_decoder};
+use crate::consumers::{heartbeat, routing, threshold};
+use crate::db::ILDatabase;
+use crate::json_util::get_nested_value;
+use crate::models::event::{EventQueueItemJson, EventQueueTransitionItemJson};
+use crate::models::event_db::EventQueueItem;
+use crate::poll::DeliveryOutcome;
+use crate::queue::{self, QueueError};
+use crate::{DaemonContext, auto_resolve, device_watch, hbt, poll};
 use rdkafka::client::ClientContext;
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
-use rdkafka::message::Message;
-use rdkafka::topic_partition_list::TopicPartitionList;
-use rdkafka::util::get_rdkafka_version;
-use serde_json::json;
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::statistics::Statistics;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+use rdkafka::util::{Timeout, get_rdkafka_version};
+use regex::Regex;
+use serde_json::{Value, json};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
 
-struct CustomContext;
+/// Drains in-flight messages of revoked partitions and commits their offsets before a rebalance completes,
+/// reports the assignment and consumer lag to the readiness probe.
+struct CustomContext {
+    daemon_ctx: Arc<DaemonContext>,
+    tracker: Arc<PartitionTracker>,
+}
 
-impl ClientContext for CustomContext {}
+impl ClientContext for CustomContext {
+    fn stats(&self, statistics: Statistics) {
+        let Some(ref probe) = self.daemon_ctx.kafka_probe else {
+            return;
+        };
+        for (name, topic) in statistics.topics.iter() {
+            for (id, partition) in topic.partitions.iter() {
+                // -1 is librdkafka's internal unassigned partition
+                if *id < 0 {
+                    continue;
+                }
+                probe.update_partition(
+                    name,
+                    *id,
+                    known_offset(partition.committed_offset),
+                    known_offset(partition.consumer_lag),
+                );
+            }
+        }
+
+        if let Some(max_lag) = self.daemon_ctx.config.kafka_max_lag {
+            let lag = probe.max_lag().unwrap_or(0);
+            let lagging = lag > max_lag as i64;
+            if probe.lagging.swap(lagging, Ordering::Relaxed) != lagging {
+                if lagging {
+                    let msg = format!(
+                        "Kafka consumer lag of {} messages exceeds --kafka_max_lag {}",
+                        lag, max_lag
+                    );
+                    warn!("{}", msg);
+                    probe.record_error(msg);
+                } else {
+                    info!("Kafka consumer lag is back to {} messages", lag);
+                }
+            }
+        }
+    }
+}
+
+/// librdkafka reports unknown offsets and lag as negative values.
+fn known_offset(value: i64) -> Option<i64> {
+    (value >= 0).then_some(value)
+}
 
 impl ConsumerContext for CustomContext {
-    fn pre_rebalance(&self, _consumer: &BaseConsumer<CustomContext>, rebalance: &Rebalance) {
+    fn pre_rebalance(&self, consumer: &BaseConsumer<CustomContext>, rebalance: &Rebalance) {
         debug!("Pre rebalance {:?}", rebalance);
+        let Rebalance::Revoke(list) = rebalance else {
+            return;
+        };
+        let revoked = partitions_of(list);
+        // called from within the consumer's recv, the partition workers keep running on other threads
+        let drained =
+            block_in_place_if_possible(|| self.tracker.drain(&revoked, REBALANCE_DRAIN_TIMEOUT));
+        if !drained {
+            warn!(
+                "In-flight Kafka messages of revoked partitions did not finish within {}s, they will be consumed again",
+                REBALANCE_DRAIN_TIMEOUT.as_secs()
+            );
+        }
+        let offsets = self.tracker.take_commit_offsets(Some(&revoked));
+        if offsets.count() > 0
+            && let Err(e) = consumer.commit(&offsets, CommitMode::Sync)
+        {
+            warn!(
+                "Failed to commit offsets of revoked Kafka partitions: {}",
+                e
+            );
+        }
+        self.tracker.revoke(revoked);
     }
 
     fn post_rebalance(&self, _consumer: &BaseConsumer<CustomContext>, rebalance: &Rebalance) {
-        debug!("Post rebalance {:?}", rebalance);
+        match rebalance {
+            Rebalance::Assign(list) => {
+                let assigned = partitions_of(list);
+                info!("Kafka partitions assigned: {:?}", assigned);
+                if let Some(ref probe) = self.daemon_ctx.kafka_probe {
+                    probe.assign_partitions(&assigned);
+                }
+            }
+            Rebalance::Revoke(list) => {
+                let revoked = partitions_of(list);
+                info!("Kafka partitions revoked: {:?}", revoked);
+                if let Some(ref probe) = self.daemon_ctx.kafka_probe {
+                    probe.revoke_partitions(&revoked);
+                }
+            }
+            Rebalance::Error(e) => error!("Kafka rebalance failed: {}", e),
+        }
     }
 
     fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
@@ -32,36 +136,274 @@ impl ConsumerContext for CustomContext {
     }
 }
 
+/// Blocks in place on a multi-threaded runtime so other tasks can move to another worker thread.
+fn block_in_place_if_possible<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
 type LoggingConsumer = StreamConsumer<CustomContext>;
 
-pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
-    let (version_n, version_s) = get_rdkafka_version();
-    info!("rd_kafka_version: 0x{:08x}, {}", version_n, version_s);
+/// Subscribed topic names, topics starting with `^` are regex subscriptions as in librdkafka.
+struct KafkaTopics {
+    names: Vec<String>,
+    patterns: Vec<Regex>,
+}
 
-    let event_topic = if let Some(topic) = daemon_ctx.config.clone().event_topic {
-        topic.clone()
-    } else {
-        "".to_string()
-    };
+impl KafkaTopics {
+    fn new(topics: &[String]) -> KafkaTopics {
+        let mut names = Vec::new();
+        let mut patterns = Vec::new();
+        for topic in topics.iter() {
+            if topic.starts_with('^') {
+                match Regex::new(topic) {
+                    Ok(regex) => patterns.push(regex),
+                    Err(e) => error!("Invalid Kafka topic regex '{}': {}", topic, e),
+                }
+            } else {
+                names.push(topic.clone());
+            }
+        }
+        KafkaTopics { names, patterns }
+    }
 
-    let heartbeat_topic = if let Some(topic) = daemon_ctx.config.clone().heartbeat_topic {
-        topic.clone()
-    } else {
-        "".to_string()
-    };
+    fn matches(&self, topic: &str) -> bool {
+        self.names.iter().any(|name| name == topic)
+            || self.patterns.iter().any(|regex| regex.is_match(topic))
+    }
+}
 
-    let policy_topic = daemon_ctx.config.clone().policy_topic.unwrap_or_default();
+/// how often librdkafka reports the assignment, committed offsets and lag
+const STATISTICS_INTERVAL_MS: &str = "5000";
 
-    let mut topics = Vec::new();
-    if !event_topic.is_empty() {
-        topics.push(event_topic.as_str());
+/// Client config with the fixed consumer settings, security options and passthrough properties, in this order.
+pub fn build_client_config(config: &ILConfig, brokers: String, group_id: String) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("group.id", group_id)
+        .set("bootstrap.servers", brokers)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", "6000")
+        .set("enable.auto.commit", "false")
+        .set("statistics.interval.ms", STATISTICS_INTERVAL_MS)
+        .set_log_level(RDKafkaLogLevel::Debug);
+    apply_client_options(config, &mut client_config);
+    client_config
+}
+
+/// Producer config for forwarding messages, shares the security options and properties of the consumer.
+pub fn build_producer_config(config: &ILConfig, brokers: String) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", brokers)
+        .set("message.timeout.ms", "10000");
+    apply_client_options(config, &mut client_config);
+    client_config
+}
+
+fn apply_client_options(config: &ILConfig, client_config: &mut ClientConfig) {
+    let options = [
+        ("security.protocol", &config.kafka_security_protocol),
+        ("sasl.mechanism", &config.kafka_sasl_mechanism),
+        ("sasl.username", &config.kafka_sasl_username),
+        ("sasl.password", &config.kafka_sasl_password),
+        ("ssl.ca.location", &config.kafka_ca_path),
+        ("ssl.certificate.location", &config.kafka_client_cert_path),
+        ("ssl.key.location", &config.kafka_client_key_path),
+    ];
+    for (key, value) in options.iter() {
+        if let Some(value) = value {
+            client_config.set(*key, value);
+        }
     }
-    if !heartbeat_topic.is_empty() {
-        topics.push(heartbeat_topic.as_str());
+    for (key, value) in config.kafka_properties.iter() {
+        client_config.set(key, value);
+    }
+}
+
+/// Parses librdkafka properties in `key=value` lines, blank lines and `#` comments are skipped.
+pub fn parse_kafka_properties(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut properties = Vec::new();
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                properties.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            _ => return Err(format!("expected 'key=value', got '{}'", line)),
+        }
+    }
+    Ok(properties)
+}
+
+pub fn validate_kafka_security(config: &ILConfig) -> Result<(), String> {
+    let sasl = matches!(
+        config.kafka_security_protocol.as_deref(),
+        Some("sasl_plaintext") | Some("sasl_ssl")
+    );
+    let tls = matches!(
+        config.kafka_security_protocol.as_deref(),
+        Some("ssl") | Some("sasl_ssl")
+    );
+    if !sasl && (config.kafka_sasl_mechanism.is_some() || config.kafka_sasl_username.is_some()) {
+        return Err(
+            "SASL options require --kafka_security_protocol sasl_plaintext or sasl_ssl".to_string(),
+        );
+    }
+    if !tls
+        && (config.kafka_ca_path.is_some()
+            || config.kafka_client_cert_path.is_some()
+            || config.kafka_client_key_path.is_some())
+    {
+        return Err(
+            "certificate options require --kafka_security_protocol ssl or sasl_ssl".to_string(),
+        );
+    }
+    if config.kafka_sasl_password.is_some() && config.kafka_sasl_username.is_none() {
+        return Err("--kafka_sasl_password requires --kafka_sasl_username".to_string());
+    }
+    if config.kafka_client_cert_path.is_some() != config.kafka_client_key_path.is_some() {
+        return Err(
+            "--kafka_client_cert and --kafka_client_key have to be set together".to_string(),
+        );
+    }
+    if matches!(
+        config.kafka_sasl_mechanism.as_deref(),
+        Some("PLAIN") | Some("SCRAM-SHA-256") | Some("SCRAM-SHA-512")
+    ) && (config.kafka_sasl_username.is_none() || config.kafka_sasl_password.is_none())
+    {
+        return Err(format!(
+            "SASL mechanism {} requires --kafka_sasl_username and --kafka_sasl_password",
+            config.kafka_sasl_mechanism.as_deref().unwrap_or_default()
+        ));
     }
-    if !policy_topic.is_empty() {
-        topics.push(policy_topic.as_str());
+    Ok(())
+}
+
+const RETRY_BACKOFF_INITIAL_MS: u64 = 1000;
+const RETRY_BACKOFF_MAX_MS: u64 = 60000;
+/// messages queued per partition worker before the partition is paused
+const PARTITION_QUEUE_SIZE: usize = 100;
+const WORKER_TICK_MS: u64 = 100;
+const REBALANCE_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delivery attempts of the offset that is currently retried, per partition.
+#[derive(Default)]
+struct RetryTracker {
+    attempts: HashMap<(String, i32), (i64, u32)>,
+}
+
+impl RetryTracker {
+    /// Records a failed attempt and returns the number of attempts for this offset.
+    fn failed(&mut self, topic: &str, partition: i32, offset: i64) -> u32 {
+        let entry = self
+            .attempts
+            .entry((topic.to_string(), partition))
+            .or_insert((offset, 0));
+        if entry.0 != offset {
+            *entry = (offset, 0);
+        }
+        entry.1 += 1;
+        entry.1
+    }
+
+    fn clear(&mut self, topic: &str, partition: i32) {
+        self.attempts.remove(&(topic.to_string(), partition));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.attempts.is_empty()
+    }
+}
+
+fn retry_backoff(attempts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_millis(
+        RETRY_BACKOFF_INITIAL_MS
+            .saturating_mul(factor)
+            .min(RETRY_BACKOFF_MAX_MS),
+    )
+}
+
+/// Produces the original message with its key and headers plus `ilagent-*` headers to another topic.
+async fn forward_message(
+    producer: &FutureProducer,
+    target_topic: &str,
+    m: &OwnedMessage,
+    extra_headers: &[(&str, String)],
+) -> Result<(), String> {
+    let mut headers = OwnedHeaders::new();
+    if let Some(source_headers) = m.headers() {
+        for header in source_headers.iter() {
+            headers = headers.insert(header);
+        }
+    }
+    let partition = m.partition().to_string();
+    let offset = m.offset().to_string();
+    let source_headers = [
+        ("ilagent-source-topic", m.topic()),
+        ("ilagent-source-partition", partition.as_str()),
+        ("ilagent-source-offset", offset.as_str()),
+    ];
+    for (key, value) in source_headers.iter() {
+        headers = headers.insert(Header {
+            key,
+            value: Some(*value),
+        });
+    }
+    for (key, value) in extra_headers.iter() {
+        headers = headers.insert(Header {
+            key,
+            value: Some(value.as_str()),
+        });
+    }
+
+    let mut record = FutureRecord::<[u8], [u8]>::to(target_topic).headers(headers);
+    if let Some(payload) = m.payload() {
+        record = record.payload(payload);
+    }
+    if let Some(key) = m.key() {
+        record = record.key(key);
+    }
+    producer
+        .send(record, Timeout::After(Duration::from_secs(10)))
+        .await
+        .map(|_| ())
+        .map_err(|(e, _)| e.to_string())
+}
+
+pub fn validate_kafka_topics(config: &ILConfig) -> Result<(), String> {
+    for topic in config
+        .event_topics
+        .iter()
+        .chain(config.heartbeat_topics.iter())
+        .chain(config.policy_topics.iter())
+    {
+        if topic.starts_with('^') {
+            Regex::new(topic).map_err(|e| format!("'{}': {}", topic, e))?;
+        }
     }
+    Ok(())
+}
+
+pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    let (version_n, version_s) = get_rdkafka_version();
+    info!("rd_kafka_version: 0x{:08x}, {}", version_n, version_s);
+
+    let topics: Vec<&str> = daemon_ctx
+        .config
+        .event_topics
+        .iter()
+        .chain(daemon_ctx.config.heartbeat_topics.iter())
+        .chain(daemon_ctx.config.policy_topics.iter())
+        .map(|t| t.as_str())
+        .collect();
 
     let brokers = daemon_ctx.config.clone().kafka_brokers.expect("no broker");
     let group_id = daemon_ctx
@@ -70,27 +412,26 @@ pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
         .kafka_group_id
         .expect("no group id");
 
-    let context = CustomContext;
-    let consumer: LoggingConsumer = match ClientConfig::new()
-        .set("group.id", group_id)
-        .set("bootstrap.servers", brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
-    {
-        Ok(c) => c,
-        Err(e) => {
-            let msg = format!("Consumer creation failed: {}", e);
-            error!("{}", msg);
-            if let Some(ref probe) = daemon_ctx.kafka_probe {
-                probe.record_error(msg);
-                probe.worker_exited.store(true, Ordering::Relaxed);
-            }
-            return;
-        }
+    let tracker = Arc::new(PartitionTracker::default());
+    let context = CustomContext {
+        daemon_ctx: daemon_ctx.clone(),
+        tracker: tracker.clone(),
     };
+    let consumer: LoggingConsumer =
+        match build_client_config(&daemon_ctx.config, brokers.clone(), group_id)
+            .create_with_context(context)
+        {
+            Ok(c) => c,
+            Err(e) => {
+                let msg = format!("Consumer creation failed: {}", e);
+                error!("{}", msg);
+                if let Some(ref probe) = daemon_ctx.kafka_probe {
+                    probe.record_error(msg);
+                    probe.worker_exited.store(true, Ordering::Relaxed);
+                }
+                return;
+            }
+        };
 
     if let Some(ref probe) = daemon_ctx.kafka_probe {
         probe.consumer_started.store(true, Ordering::Relaxed);
@@ -110,13 +451,46 @@ pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
         probe.subscribed.store(true, Ordering::Relaxed);
     }
 
+    let producer: Option<FutureProducer> = if daemon_ctx.config.kafka_retry_topic.is_some()
+        || daemon_ctx.config.kafka_dlq_topic.is_some()
+    {
+        // without it rejected messages could neither be forwarded nor committed
+        match build_producer_config(&daemon_ctx.config, brokers.clone()).create() {
+            Ok(producer) => Some(producer),
+            Err(e) => {
+                let msg = format!("Producer creation failed: {}", e);
+                error!("{}", msg);
+                if let Some(ref probe) = daemon_ctx.kafka_probe {
+                    probe.record_error(msg);
+                    probe.worker_exited.store(true, Ordering::Relaxed);
+                }
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let processor = Arc::new(MessageProcessor {
+        daemon_ctx: daemon_ctx.clone(),
+        event_topics: KafkaTopics::new(&daemon_ctx.config.event_topics),
+        heartbeat_topics: KafkaTopics::new(&daemon_ctx.config.heartbeat_topics),
+        policy_topics: KafkaTopics::new(&daemon_ctx.config.policy_topics),
+        producer,
+        retry_tracker: std::sync::Mutex::new(RetryTracker::default()),
+    });
+
+    let mut workers: HashMap<(String, i32), PartitionWorker> = HashMap::new();
+    let mut last_commit = Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_millis(WORKER_TICK_MS));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
     loop {
         if !daemon_ctx.running.load(Ordering::Relaxed) {
             info!("Kafka consumer shutting down");
             break;
         }
 
-        let m = tokio::select! {
+        tokio::select! {
             result = consumer.recv() => {
                 match result {
                     Err(e) => {
@@ -124,24 +498,437 @@ pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
                         if let Some(ref probe) = daemon_ctx.kafka_probe {
                             probe.record_error(format!("{}", e));
                         }
-                        continue;
                     }
-                    Ok(m) => m,
+                    Ok(m) => {
+                        let m = m.detach();
+                        stop_revoked_workers(&tracker, &processor, &mut workers);
+                        let worker = workers
+                            .entry((m.topic().to_string(), m.partition()))
+                            .or_insert_with_key(|(topic, partition)| {
+                                PartitionWorker::spawn(topic, *partition, processor.clone(), tracker.clone())
+                            });
+                        worker.hand_over(&consumer, &tracker, m);
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                stop_revoked_workers(&tracker, &processor, &mut workers);
+                for worker in workers.values_mut() {
+                    worker.hand_over_backlog(&consumer, &tracker);
+                }
+                if last_commit.elapsed() >= daemon_ctx.config.kafka_commit_interval {
+                    commit_processed(&consumer, &tracker, CommitMode::Async);
+                    last_commit = Instant::now();
                 }
             }
             _ = shutdown_signal(&daemon_ctx) => {
                 info!("Kafka consumer received shutdown signal");
                 break;
             }
-        };
+        }
+    }
 
-        let payload = match m.payload_view::<str>() {
-            None => "",
-            Some(Ok(s)) => s,
-            Some(Err(e)) => {
-                warn!("Error while deserializing message payload: {:?}", e);
-                ""
+    // workers stop on the shutdown signal, messages they did not finish stay uncommitted
+    for (_, worker) in workers.drain() {
+        drop(worker.sender);
+        if let Err(e) = worker.handle.await {
+            error!("Kafka partition worker failed: {}", e);
+        }
+    }
+    commit_processed(&consumer, &tracker, CommitMode::Sync);
+
+    if let Some(ref probe) = daemon_ctx.kafka_probe {
+        probe.worker_exited.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Processing state of an assigned partition.
+#[derive(Default)]
+struct PartitionProgress {
+    /// messages handed to the worker that are not processed yet
+    in_flight: usize,
+    /// offset after the last processed message
+    processed: Option<i64>,
+    committed: Option<i64>,
+}
+
+/// Progress of all partitions, shared by the consumer loop, the partition workers and the rebalance callbacks.
+#[derive(Default)]
+struct PartitionTracker {
+    partitions: std::sync::Mutex<HashMap<(String, i32), PartitionProgress>>,
+    finished: Condvar,
+    /// partitions revoked by a rebalance whose workers still have to be stopped
+    revoked: std::sync::Mutex<Vec<(String, i32)>>,
+}
+
+impl PartitionTracker {
+    fn lock_partitions(&self) -> MutexGuard<'_, HashMap<(String, i32), PartitionProgress>> {
+        self.partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn queued(&self, topic: &str, partition: i32) {
+        let mut partitions = self.lock_partitions();
+        let progress = partitions
+            .entry((topic.to_string(), partition))
+            .or_default();
+        progress.in_flight += 1;
+    }
+
+    /// Reverts `queued` for a message the worker did not accept.
+    fn not_queued(&self, topic: &str, partition: i32) {
+        let mut partitions = self.lock_partitions();
+        if let Some(progress) = partitions.get_mut(&(topic.to_string(), partition)) {
+            progress.in_flight = progress.in_flight.saturating_sub(1);
+        }
+        self.finished.notify_all();
+    }
+
+    fn processed(&self, topic: &str, partition: i32, offset: i64) {
+        let mut partitions = self.lock_partitions();
+        if let Some(progress) = partitions.get_mut(&(topic.to_string(), partition)) {
+            progress.in_flight = progress.in_flight.saturating_sub(1);
+            progress.processed = Some(offset + 1);
+        }
+        self.finished.notify_all();
+    }
+
+    /// Offsets processed since the last commit, of the given partitions or all if None.
+    fn take_commit_offsets(&self, only: Option<&[(String, i32)]>) -> TopicPartitionList {
+        let mut offsets = TopicPartitionList::new();
+        let mut partitions = self.lock_partitions();
+        for ((topic, partition), progress) in partitions.iter_mut() {
+            if only.is_some_and(|only| !only.contains(&(topic.clone(), *partition))) {
+                continue;
+            }
+            let Some(processed) = progress.processed else {
+                continue;
+            };
+            if progress.committed == Some(processed) {
+                continue;
+            }
+            match offsets.add_partition_offset(topic, *partition, Offset::Offset(processed)) {
+                Ok(_) => progress.committed = Some(processed),
+                Err(e) => warn!(
+                    "Failed to add Kafka offset {}/{}@{}: {}",
+                    topic, partition, processed, e
+                ),
+            }
+        }
+        offsets
+    }
+
+    /// Waits until the partitions have no in-flight messages, returns false on timeout.
+    fn drain(&self, only: &[(String, i32)], timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut partitions = self.lock_partitions();
+        loop {
+            let busy = only.iter().any(|key| {
+                partitions
+                    .get(key)
+                    .is_some_and(|progress| progress.in_flight > 0)
+            });
+            if !busy {
+                return true;
             }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            partitions = self
+                .finished
+                .wait_timeout(partitions, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    fn revoke(&self, revoked: Vec<(String, i32)>) {
+        let mut partitions = self.lock_partitions();
+        for key in revoked.iter() {
+            partitions.remove(key);
+        }
+        self.revoked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(revoked);
+    }
+
+    fn take_revoked(&self) -> Vec<(String, i32)> {
+        std::mem::take(&mut *self.revoked.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Processes the messages of one partition in order.
+struct PartitionWorker {
+    topic: String,
+    partition: i32,
+    sender: mpsc::Sender<OwnedMessage>,
+    /// messages received while the worker queue was full, the partition is paused until they are handed over
+    backlog: VecDeque<OwnedMessage>,
+    handle: JoinHandle<()>,
+}
+
+impl PartitionWorker {
+    fn spawn(
+        topic: &str,
+        partition: i32,
+        processor: Arc<MessageProcessor>,
+        tracker: Arc<PartitionTracker>,
+    ) -> PartitionWorker {
+        let (sender, mut receiver) = mpsc::channel::<OwnedMessage>(PARTITION_QUEUE_SIZE);
+        let handle = tokio::spawn(async move {
+            while let Some(m) = receiver.recv().await {
+                if !process_until_handled(&processor, &m).await {
+                    break;
+                }
+                tracker.processed(m.topic(), m.partition(), m.offset());
+            }
+        });
+        PartitionWorker {
+            topic: topic.to_string(),
+            partition,
+            sender,
+            backlog: VecDeque::new(),
+            handle,
+        }
+    }
+
+    fn hand_over(
+        &mut self,
+        consumer: &LoggingConsumer,
+        tracker: &PartitionTracker,
+        m: OwnedMessage,
+    ) {
+        if !self.backlog.is_empty() {
+            self.backlog.push_back(m);
+            return;
+        }
+        tracker.queued(m.topic(), m.partition());
+        match self.sender.try_send(m) {
+            Ok(_) => {}
+            Err(TrySendError::Full(m)) => {
+                tracker.not_queued(m.topic(), m.partition());
+                debug!(
+                    "Kafka partition {}/{} is busy, pausing it",
+                    self.topic, self.partition
+                );
+                if let Err(e) = consumer.pause(&single_partition(&self.topic, self.partition)) {
+                    warn!(
+                        "Failed to pause Kafka partition {}/{}: {}",
+                        self.topic, self.partition, e
+                    );
+                }
+                self.backlog.push_back(m);
+            }
+            Err(TrySendError::Closed(m)) => {
+                tracker.not_queued(m.topic(), m.partition());
+                error!(
+                    "Kafka partition worker for {}/{} is gone, message {} stays uncommitted",
+                    m.topic(),
+                    m.partition(),
+                    m.offset()
+                );
+            }
+        }
+    }
+
+    /// Moves backlog messages to the worker queue and resumes the partition once the backlog is empty.
+    fn hand_over_backlog(&mut self, consumer: &LoggingConsumer, tracker: &PartitionTracker) {
+        if self.backlog.is_empty() {
+            return;
+        }
+        while let Some(m) = self.backlog.pop_front() {
+            tracker.queued(m.topic(), m.partition());
+            if let Err(e) = self.sender.try_send(m) {
+                let m = match e {
+                    TrySendError::Full(m) | TrySendError::Closed(m) => m,
+                };
+                tracker.not_queued(m.topic(), m.partition());
+                self.backlog.push_front(m);
+                return;
+            }
+        }
+        debug!(
+            "Kafka partition {}/{} caught up, resuming it",
+            self.topic, self.partition
+        );
+        if let Err(e) = consumer.resume(&single_partition(&self.topic, self.partition)) {
+            warn!(
+                "Failed to resume Kafka partition {}/{}: {}",
+                self.topic, self.partition, e
+            );
+        }
+    }
+}
+
+fn single_partition(topic: &str, partition: i32) -> TopicPartitionList {
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+    partitions
+}
+
+fn partitions_of(list: &TopicPartitionList) -> Vec<(String, i32)> {
+    list.elements()
+        .iter()
+        .map(|elem| (elem.topic().to_string(), elem.partition()))
+        .collect()
+}
+
+/// Aborts the workers of revoked partitions, their backlog is consumed again by the next owner.
+fn stop_revoked_workers(
+    tracker: &PartitionTracker,
+    processor: &MessageProcessor,
+    workers: &mut HashMap<(String, i32), PartitionWorker>,
+) {
+    for key in tracker.take_revoked() {
+        if let Some(worker) = workers.remove(&key) {
+            worker.handle.abort();
+        }
+        processor.delivery_recovered(&key.0, key.1);
+    }
+}
+
+fn commit_processed(consumer: &LoggingConsumer, tracker: &PartitionTracker, mode: CommitMode) {
+    let offsets = tracker.take_commit_offsets(None);
+    if offsets.count() == 0 {
+        return;
+    }
+    if let Err(e) = consumer.commit(&offsets, mode) {
+        warn!("Failed to commit Kafka offsets: {}", e);
+    }
+}
+
+/// Processes the message until it is handled, returns false if the daemon shut down before.
+async fn process_until_handled(processor: &MessageProcessor, m: &OwnedMessage) -> bool {
+    let daemon_ctx = &processor.daemon_ctx;
+    loop {
+        if !processor.process(m).await {
+            processor.delivery_recovered(m.topic(), m.partition());
+            return true;
+        }
+
+        let attempts = processor
+            .lock_retry_tracker()
+            .failed(m.topic(), m.partition(), m.offset());
+        if attempts >= daemon_ctx.config.kafka_retry_attempts
+            && processor.move_to_retry_topic(m, attempts).await
+        {
+            processor.delivery_recovered(m.topic(), m.partition());
+            return true;
+        }
+
+        let backoff = retry_backoff(attempts);
+        let msg = format!(
+            "Failed to deliver Kafka message {}/{}@{} (attempt {}), retrying in {}s",
+            m.topic(),
+            m.partition(),
+            m.offset(),
+            attempts,
+            backoff.as_secs()
+        );
+        error!("{}", msg);
+        if let Some(ref probe) = daemon_ctx.kafka_probe {
+            probe.delivery_failing.store(true, Ordering::Relaxed);
+            probe.record_error(msg);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown_signal(daemon_ctx) => return false,
+        }
+    }
+}
+
+enum MessageOutcome {
+    Done,
+    Retry,
+    /// not deliverable, forwarded to the dead-letter topic if configured
+    Rejected {
+        reason: &'static str,
+        status: Option<u16>,
+    },
+}
+
+impl From<bool> for MessageOutcome {
+    fn from(should_retry: bool) -> Self {
+        if should_retry {
+            MessageOutcome::Retry
+        } else {
+            MessageOutcome::Done
+        }
+    }
+}
+
+/// Dispatches consumed messages by topic and forwards undeliverable ones to the retry and dead-letter topics.
+struct MessageProcessor {
+    daemon_ctx: Arc<DaemonContext>,
+    event_topics: KafkaTopics,
+    heartbeat_topics: KafkaTopics,
+    policy_topics: KafkaTopics,
+    producer: Option<FutureProducer>,
+    retry_tracker: std::sync::Mutex<RetryTracker>,
+}
+
+impl MessageProcessor {
+    fn lock_retry_tracker(&self) -> MutexGuard<'_, RetryTracker> {
+        self.retry_tracker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Clears the retry state of the partition, ready again once no partition is retrying.
+    fn delivery_recovered(&self, topic: &str, partition: i32) {
+        let mut retry_tracker = self.lock_retry_tracker();
+        retry_tracker.clear(topic, partition);
+        if retry_tracker.is_empty()
+            && let Some(ref probe) = self.daemon_ctx.kafka_probe
+        {
+            probe.delivery_failing.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Handles the message, returns true if delivery failed and it should be retried.
+    async fn process(&self, m: &OwnedMessage) -> bool {
+        match self.dispatch(m).await {
+            MessageOutcome::Done => false,
+            MessageOutcome::Retry => true,
+            MessageOutcome::Rejected { reason, status } => {
+                !self.move_to_dlq_topic(m, reason, status).await
+            }
+        }
+    }
+
+    async fn dispatch(&self, m: &OwnedMessage) -> MessageOutcome {
+        let daemon_ctx = &self.daemon_ctx;
+        let decoded;
+        let payload = match find_decoder(&daemon_ctx.config, "kafka", m.topic()) {
+            Some(decoder) => match decoder.decode(m.payload().unwrap_or_default()).await {
+                Ok(json) => {
+                    decoded = json.to_string();
+                    decoded.as_str()
+                }
+                Err(e @ DecodeError::Invalid(_)) => {
+                    warn!("Failed to decode message payload of {}: {}", m.topic(), e);
+                    return MessageOutcome::Rejected {
+                        reason: "decode_failed",
+                        status: None,
+                    };
+                }
+                Err(e) => {
+                    error!("Failed to decode message payload of {}: {}", m.topic(), e);
+                    return MessageOutcome::Retry;
+                }
+            },
+            None => match m.payload_view::<str>() {
+                None => "",
+                Some(Ok(s)) => s,
+                Some(Err(e)) => {
+                    warn!("Error while deserializing message payload: {:?}", e);
+                    ""
+                }
+            },
         };
 
         let message_key = match m.key_view::<str>() {
@@ -163,36 +950,167 @@ pub async fn run_kafka_job(daemon_ctx: Arc<DaemonContext>) -> () {
             m.timestamp()
         );
 
-        let should_retry: bool = if m.topic().eq(event_topic.as_str()) {
-            handle_event_message(daemon_ctx.clone(), message_key, payload, m.topic()).await
-        } else if m.topic().eq(heartbeat_topic.as_str()) {
-            handle_heartbeat_message(daemon_ctx.clone(), message_key, payload).await
-        } else if !policy_topic.is_empty() && m.topic().eq(policy_topic.as_str()) {
-            handle_policy_message(daemon_ctx.clone(), payload).await
+        if !daemon_ctx.config.device_watches.is_empty() {
+            let db = daemon_ctx.db.lock().await;
+            if let Err(e) =
+                device_watch::observe(&daemon_ctx.config, &db, "kafka", payload, m.topic())
+            {
+                error!("Failed to track device last seen {}", e);
+            }
+        }
+
+        if self.event_topics.matches(m.topic()) {
+            let metadata = message_metadata(m);
+            let payload = super::with_metadata(payload, METADATA_FIELD, &metadata);
+            handle_event_message(
+                daemon_ctx.clone(),
+                message_key,
+                &metadata,
+                &payload,
+                m.topic(),
+            )
+            .await
+        } else if self.heartbeat_topics.matches(m.topic()) {
+            let payload = super::with_metadata(payload, METADATA_FIELD, &message_metadata(m));
+            handle_heartbeat_message(daemon_ctx.clone(), message_key, &payload, m.topic())
+                .await
+                .into()
+        } else if self.policy_topics.matches(m.topic()) {
+            handle_policy_message(daemon_ctx.clone(), payload)
+                .await
+                .into()
         } else {
             warn!(
                 "Received Kafka message from unsubscribed topic: {}",
                 m.topic()
             );
-            false
+            MessageOutcome::Done
+        }
+    }
+
+    /// Returns true if the message was produced to the retry topic.
+    async fn move_to_retry_topic(&self, m: &OwnedMessage, attempts: u32) -> bool {
+        let (Some(producer), Some(retry_topic)) = (
+            self.producer.as_ref(),
+            self.daemon_ctx.config.kafka_retry_topic.as_deref(),
+        ) else {
+            return false;
         };
+        let headers = [
+            ("ilagent-attempts", attempts.to_string()),
+            ("ilagent-failure-reason", "delivery_failed".to_string()),
+        ];
+        match forward_message(producer, retry_topic, m, &headers).await {
+            Ok(_) => {
+                warn!(
+                    "Kafka message {}/{}@{} failed {} times, moved to retry topic {}",
+                    m.topic(),
+                    m.partition(),
+                    m.offset(),
+                    attempts,
+                    retry_topic
+                );
+                true
+            }
+            Err(e) => {
+                error!("Failed to produce to retry topic {}: {}", retry_topic, e);
+                false
+            }
+        }
+    }
 
-        if !should_retry {
-            consumer
-                .commit_message(&m, CommitMode::Async)
-                .expect("failed to commit event message");
-        } else {
+    /// Returns false if the message should have been dead-lettered but producing failed.
+    async fn move_to_dlq_topic(&self, m: &OwnedMessage, reason: &str, status: Option<u16>) -> bool {
+        let Some(dlq_topic) = self.daemon_ctx.config.kafka_dlq_topic.as_deref() else {
+            warn!(
+                "Kafka message {}/{}@{} was rejected ({}), dropping it",
+                m.topic(),
+                m.partition(),
+                m.offset(),
+                reason
+            );
+            return true;
+        };
+        let Some(ref producer) = self.producer else {
             error!(
-                "failed to produce event, did not commit Kafka message offset, will exit in 5 seconds..."
+                "Kafka message {}/{}@{} was rejected ({}), no producer for dead-letter topic {}, dropping it",
+                m.topic(),
+                m.partition(),
+                m.offset(),
+                reason,
+                dlq_topic
             );
-            tokio::time::sleep(Duration::from_millis(5000)).await;
-            panic!("failed to produce event, did not commit Kafka message offset");
+            return true;
+        };
+        let mut headers = vec![("ilagent-failure-reason", reason.to_string())];
+        if let Some(status) = status {
+            headers.push(("ilagent-http-status", status.to_string()));
+        }
+        match forward_message(producer, dlq_topic, m, &headers).await {
+            Ok(_) => {
+                warn!(
+                    "Kafka message {}/{}@{} was rejected ({}), moved to dead-letter topic {}",
+                    m.topic(),
+                    m.partition(),
+                    m.offset(),
+                    reason,
+                    dlq_topic
+                );
+                true
+            }
+            Err(e) => {
+                error!(
+                    "Failed to produce to dead-letter topic {}: {}",
+                    dlq_topic, e
+                );
+                false
+            }
         }
     }
+}
 
-    if let Some(ref probe) = daemon_ctx.kafka_probe {
-        probe.worker_exited.store(true, Ordering::Relaxed);
+/// Reserved payload field with the message metadata, e.g. `_kafka.headers.tenant` or `_kafka.key`.
+const METADATA_FIELD: &str = "_kafka";
+
+fn message_metadata(m: &OwnedMessage) -> Value {
+    let mut headers = serde_json::Map::new();
+    if let Some(message_headers) = m.headers() {
+        for header in message_headers.iter() {
+            let value = header
+                .value
+                .map(|v| Value::String(String::from_utf8_lossy(v).into_owned()))
+                .unwrap_or(Value::Null);
+            headers.insert(header.key.to_string(), value);
+        }
+    }
+    json!({
+        "key": m.key().map(|k| String::from_utf8_lossy(k).into_owned()),
+        "topic": m.topic(),
+        "partition": m.partition(),
+        "offset": m.offset(),
+        "timestamp": m.timestamp().to_millis(),
+        "headers": headers,
+    })
+}
+
+/// Why a payload that did not result in an event is rejected, None if it was filtered on purpose.
+fn rejection_reason(config: &ILConfig, payload: &str) -> Option<&'static str> {
+    let Ok(json) = serde_json::from_str::<Value>(payload) else {
+        return Some("invalid_payload");
+    };
+    if serde_json::from_str::<EventQueueTransitionItemJson>(payload).is_err() {
+        return Some("invalid_event");
+    }
+    if let Some(ref filter_key) = config.filter_key
+        && config.filter_val.is_some()
+        && json
+            .get(filter_key)
+            .or_else(|| get_nested_value(&json, filter_key))
+            .is_some_and(|val| !val.is_string())
+    {
+        return Some("invalid_filter_value");
     }
+    None
 }
 
 async fn shutdown_signal(daemon_ctx: &Arc<DaemonContext>) {
@@ -217,42 +1135,431 @@ async fn handle_heartbeat_message(
     daemon_context: Arc<DaemonContext>,
     _key: &str,
     payload: &str,
+    topic: &str,
 ) -> bool {
-    let parsed = crate::models::heartbeat::HeartbeatJson::parse_heartbeat_json(payload);
-    if let Some(heartbeat) = parsed {
-        if hbt::ping_heartbeat(
-            &daemon_context.ilert_client,
-            heartbeat.integrationKey.as_str(),
-        )
-        .await
-        {
-            info!(
-                "Heartbeat {} pinged, triggered by kafka message",
-                heartbeat.integrationKey.as_str()
-            );
-        }
+    if let Some(key) = heartbeat::resolve_heartbeat_key(&daemon_context.config, payload, topic)
+        && hbt::ping_heartbeat(&daemon_context.ilert_client, key.as_str()).await
+    {
+        info!(
+            "Heartbeat {} pinged, triggered by kafka message",
+            key.as_str()
+        );
     }
 
     false
 }
 
+/// Telemetry is evaluated against the threshold rules and resulting events are queued.
+async fn enqueue_threshold_events(
+    daemon_context: &Arc<DaemonContext>,
+    payload: &str,
+    topic: &str,
+) -> bool {
+    let db = daemon_context.db.lock().await;
+    let events = match threshold::evaluate(&daemon_context.config, &db, payload, topic) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to evaluate thresholds {}", e);
+            return true;
+        }
+    };
+    for mut transition in events {
+        let event = &mut transition.event;
+        event.event_api_path = Some(super::build_event_api_path("kafka", &event.integration_key));
+        match queue::create_event(&daemon_context.config, &db, event) {
+            Ok(_) | Err(QueueError::Suppressed(_)) => {}
+            Err(QueueError::Full) => {
+                // the rule state stays unchanged, the next message retries the event
+                warn!(
+                    "{} not queued ({}), queue is full",
+                    event.summary, event.event_type
+                );
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to queue threshold event {}", e);
+                return true;
+            }
+        }
+        if let Err(e) = transition.record(&db) {
+            error!("Failed to record threshold state {}", e);
+            return true;
+        }
+    }
+    false
+}
+
 async fn handle_event_message(
     daemon_context: Arc<DaemonContext>,
     key: &str,
+    metadata: &Value,
     payload: &str,
     topic: &str,
-) -> bool {
-    let default_details = json!({"messageKey": key, "topic": topic});
+) -> MessageOutcome {
+    if threshold::has_rules_for(&daemon_context.config, topic) {
+        return enqueue_threshold_events(&daemon_context, payload, topic)
+            .await
+            .into();
+    }
+
+    let default_details = json!({
+        "messageKey": key,
+        "topic": topic,
+        "partition": metadata["partition"],
+        "offset": metadata["offset"],
+        "timestamp": metadata["timestamp"],
+        "headers": metadata["headers"],
+    });
     let parsed =
         super::prepare_consumer_event(&daemon_context.config, payload, topic, default_details);
-    if let Some(event) = parsed {
-        let event_api_path = super::build_event_api_path("kafka", &event.integrationKey);
-        let db_event_format =
-            crate::models::event::EventQueueItemJson::to_db(event, Some(event_api_path));
-        let should_retry =
-            poll::send_queued_event(&daemon_context.ilert_client, &db_event_format).await;
-        should_retry
-    } else {
-        false
+    let Some(mut event) = parsed else {
+        return match rejection_reason(&daemon_context.config, payload) {
+            Some(reason) => MessageOutcome::Rejected {
+                reason,
+                status: None,
+            },
+            None => MessageOutcome::Done,
+        };
+    };
+
+    // the forwarded payload stays as it was produced
+    if let Some(Value::Object(ref mut details)) = event.customDetails {
+        details.remove(METADATA_FIELD);
+    }
+    routing::apply_routes(&daemon_context.config, "kafka", &mut event, payload, topic);
+    let event_api_path = super::build_event_api_path("kafka", &event.integrationKey);
+    let db_event_format = EventQueueItemJson::to_db(event, Some(event_api_path));
+    if daemon_context.config.kafka_buffer {
+        let db = daemon_context.db.lock().await;
+        return enqueue_event(
+            &daemon_context.config,
+            &db,
+            &db_event_format,
+            payload,
+            topic,
+        );
+    }
+    match poll::deliver_queued_event(&daemon_context.ilert_client, &db_event_format).await {
+        DeliveryOutcome::Delivered => {
+            if auto_resolve::is_enabled(&daemon_context.config) {
+                let db = daemon_context.db.lock().await;
+                track_auto_resolve(
+                    &daemon_context.config,
+                    &db,
+                    &db_event_format,
+                    payload,
+                    topic,
+                );
+            }
+            MessageOutcome::Done
+        }
+        DeliveryOutcome::Retry => MessageOutcome::Retry,
+        // without a status the event could not be converted, e.g. an unknown event type
+        DeliveryOutcome::Rejected(None) => MessageOutcome::Rejected {
+            reason: "invalid_event",
+            status: None,
+        },
+        DeliveryOutcome::Rejected(status) => MessageOutcome::Rejected {
+            reason: "rejected_by_ilert",
+            status,
+        },
+    }
+}
+
+/// Queues the event for the poll job, the offset can be committed once it is written.
+fn enqueue_event(
+    config: &ILConfig,
+    db: &ILDatabase,
+    event: &EventQueueItem,
+    payload: &str,
+    topic: &str,
+) -> MessageOutcome {
+    match queue::create_event(config, db, event) {
+        Ok(created) => {
+            if let Some(id) = created.and_then(|e| e.id) {
+                info!("Event {} from topic {} added to queue", id, topic);
+            }
+            track_auto_resolve(config, db, event, payload, topic);
+            MessageOutcome::Done
+        }
+        Err(QueueError::Suppressed(reason)) => {
+            info!("Event from topic {} not queued, {}", topic, reason);
+            // a duplicate ALERT still shows the alert is not silent
+            track_auto_resolve(config, db, event, payload, topic);
+            MessageOutcome::Done
+        }
+        Err(QueueError::Full) => MessageOutcome::Rejected {
+            reason: "queue_full",
+            status: None,
+        },
+        Err(QueueError::Db(e)) => {
+            error!("Failed to queue event from topic {}: {}", topic, e);
+            MessageOutcome::Retry
+        }
+    }
+}
+
+fn track_auto_resolve(
+    config: &ILConfig,
+    db: &ILDatabase,
+    event: &EventQueueItem,
+    payload: &str,
+    topic: &str,
+) {
+    if auto_resolve::is_enabled(config)
+        && let Err(e) = auto_resolve::track_event(config, db, event, payload, topic)
+    {
+        error!("Failed to track alert state for auto resolve {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn topics(topics: &[&str]) -> Vec<String> {
+        topics.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn kafka_topics_match_names_and_regex_subscriptions() {
+        let matcher = KafkaTopics::new(&topics(&["ilert-events", "^alerts-.*"]));
+        assert!(matcher.matches("ilert-events"));
+        assert!(matcher.matches("alerts-db"));
+        assert!(!matcher.matches("ilert-events-2"));
+        assert!(!matcher.matches("my-alerts-db"));
+        assert!(!KafkaTopics::new(&[]).matches("ilert-events"));
+    }
+
+    #[test]
+    fn rejection_reason_separates_invalid_payloads_from_filtered_ones() {
+        let mut config = ILConfig::new();
+        assert_eq!(
+            rejection_reason(&config, "not json"),
+            Some("invalid_payload")
+        );
+        assert_eq!(
+            rejection_reason(&config, r#"{"summary": 42}"#),
+            Some("invalid_event")
+        );
+
+        config.filter_key = Some("type".to_string());
+        config.filter_val = Some("alarm".to_string());
+        let payload = r#"{"apiKey": "il1api1", "summary": "too hot", "type": 1}"#;
+        assert_eq!(
+            rejection_reason(&config, payload),
+            Some("invalid_filter_value")
+        );
+        let payload = r#"{"apiKey": "il1api1", "summary": "too hot", "type": "info"}"#;
+        assert_eq!(rejection_reason(&config, payload), None);
+    }
+
+    #[test]
+    fn client_config_applies_security_options_and_properties() {
+        let mut config = ILConfig::new();
+        config.kafka_security_protocol = Some("sasl_ssl".to_string());
+        config.kafka_sasl_mechanism = Some("SCRAM-SHA-512".to_string());
+        config.kafka_sasl_username = Some("user".to_string());
+        config.kafka_sasl_password = Some("secret".to_string());
+        config.kafka_ca_path = Some("/certs/ca.pem".to_string());
+        config.kafka_properties = parse_kafka_properties(
+            "# tuning\nsession.timeout.ms = 10000\n\nfetch.min.bytes=1024\n",
+        )
+        .unwrap();
+
+        let client_config =
+            build_client_config(&config, "broker:9093".to_string(), "group".to_string());
+        assert_eq!(client_config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(client_config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(client_config.get("sasl.username"), Some("user"));
+        assert_eq!(client_config.get("ssl.ca.location"), Some("/certs/ca.pem"));
+        assert_eq!(client_config.get("ssl.key.location"), None);
+        // passthrough properties override the fixed settings
+        assert_eq!(client_config.get("session.timeout.ms"), Some("10000"));
+        assert_eq!(client_config.get("fetch.min.bytes"), Some("1024"));
+        assert!(parse_kafka_properties("no value").is_err());
+    }
+
+    #[test]
+    fn librdkafka_supports_sasl_ssl() {
+        let mut config = ILConfig::new();
+        config.kafka_security_protocol = Some("sasl_ssl".to_string());
+        config.kafka_sasl_mechanism = Some("SCRAM-SHA-512".to_string());
+        config.kafka_sasl_username = Some("user".to_string());
+        config.kafka_sasl_password = Some("secret".to_string());
+
+        // fails if librdkafka is built without TLS or SASL support
+        let consumer: KafkaResult<StreamConsumer> =
+            build_client_config(&config, "broker:9093".to_string(), "group".to_string()).create();
+        if let Err(e) = consumer {
+            panic!("sasl_ssl consumer could not be created: {}", e);
+        }
+    }
+
+    #[test]
+    fn validate_kafka_security_options() {
+        let mut config = ILConfig::new();
+        assert!(validate_kafka_security(&config).is_ok());
+
+        config.kafka_sasl_mechanism = Some("PLAIN".to_string());
+        assert!(validate_kafka_security(&config).is_err());
+        config.kafka_security_protocol = Some("sasl_plaintext".to_string());
+        assert!(validate_kafka_security(&config).is_err());
+        config.kafka_sasl_username = Some("user".to_string());
+        config.kafka_sasl_password = Some("secret".to_string());
+        assert!(validate_kafka_security(&config).is_ok());
+
+        config.kafka_ca_path = Some("/certs/ca.pem".to_string());
+        assert!(validate_kafka_security(&config).is_err());
+        config.kafka_security_protocol = Some("sasl_ssl".to_string());
+        assert!(validate_kafka_security(&config).is_ok());
+
+        config.kafka_client_cert_path = Some("/certs/client.pem".to_string());
+        assert!(validate_kafka_security(&config).is_err());
+
+        let mut config = ILConfig::new();
+        config.kafka_security_protocol = Some("sasl_ssl".to_string());
+        config.kafka_sasl_mechanism = Some("OAUTHBEARER".to_string());
+        assert!(validate_kafka_security(&config).is_ok());
+    }
+
+    #[test]
+    fn retry_tracker_counts_attempts_per_offset() {
+        let mut tracker = RetryTracker::default();
+        assert_eq!(tracker.failed("events", 0, 10), 1);
+        assert_eq!(tracker.failed("events", 0, 10), 2);
+        assert_eq!(tracker.failed("events", 1, 10), 1);
+        // a different offset on the same partition starts over
+        assert_eq!(tracker.failed("events", 0, 11), 1);
+        tracker.clear("events", 0);
+        assert!(!tracker.is_empty());
+        tracker.clear("events", 1);
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn partition_tracker_commits_processed_offsets_once() {
+        let tracker = PartitionTracker::default();
+        tracker.queued("events", 0);
+        tracker.queued("events", 0);
+        tracker.queued("events", 1);
+        assert_eq!(tracker.take_commit_offsets(None).count(), 0);
+
+        tracker.processed("events", 0, 41);
+        tracker.processed("events", 1, 7);
+        let offsets = tracker.take_commit_offsets(Some(&[("events".to_string(), 0)]));
+        assert_eq!(offsets.count(), 1);
+        assert_eq!(
+            offsets.find_partition("events", 0).unwrap().offset(),
+            Offset::Offset(42)
+        );
+
+        let offsets = tracker.take_commit_offsets(None);
+        assert_eq!(offsets.count(), 1);
+        assert!(offsets.find_partition("events", 1).is_some());
+        assert_eq!(tracker.take_commit_offsets(None).count(), 0);
+    }
+
+    #[test]
+    fn partition_tracker_drains_and_revokes_partitions() {
+        let tracker = Arc::new(PartitionTracker::default());
+        let revoked = vec![("events".to_string(), 0)];
+        tracker.queued("events", 0);
+        assert!(!tracker.drain(&revoked, Duration::from_millis(10)));
+
+        let worker = {
+            let tracker = tracker.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                tracker.processed("events", 0, 3);
+            })
+        };
+        assert!(tracker.drain(&revoked, Duration::from_secs(5)));
+        worker.join().unwrap();
+
+        tracker.revoke(revoked.clone());
+        assert_eq!(tracker.take_revoked(), revoked);
+        assert!(tracker.take_revoked().is_empty());
+        assert_eq!(tracker.take_commit_offsets(None).count(), 0);
+    }
+
+    #[test]
+    fn buffered_events_are_queued_until_the_limit() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(1);
+        config.event_queue_limit.overflow = crate::queue::QueueOverflow::RejectNew;
+
+        let event = EventQueueItem::new_with_required("k1", "ALERT", "too hot", None);
+        assert!(matches!(
+            enqueue_event(&config, &db, &event, "{}", "events"),
+            MessageOutcome::Done
+        ));
+        assert_eq!(db.get_il_events(10).unwrap().len(), 1);
+        assert!(matches!(
+            enqueue_event(&config, &db, &event, "{}", "events"),
+            MessageOutcome::Rejected {
+                reason: "queue_full",
+                status: None
+            }
+        ));
+    }
+
+    #[test]
+    fn metadata_is_available_to_mappings() {
+        let headers = OwnedHeaders::new().insert(Header {
+            key: "integrationKey",
+            value: Some("il1api2"),
+        });
+        let m = OwnedMessage::new(
+            Some(br#"{"summary": "too hot"}"#.to_vec()),
+            Some(b"sensor-7".to_vec()),
+            "events".to_string(),
+            rdkafka::message::Timestamp::CreateTime(1700000000000),
+            2,
+            42,
+            Some(headers),
+        );
+        let metadata = message_metadata(&m);
+        assert_eq!(metadata["partition"], 2);
+        assert_eq!(metadata["offset"], 42);
+        assert_eq!(metadata["timestamp"], 1700000000000i64);
+
+        let payload =
+            crate::consumers::with_metadata(r#"{"summary": "too hot"}"#, METADATA_FIELD, &metadata);
+        let mut config = ILConfig::new();
+        config.map_key_alert_key = Some("_kafka.key".to_string());
+        config.map_key_integration_key = Some("_kafka.headers.integrationKey".to_string());
+        let event = EventQueueItemJson::parse_event_json(&config, &payload, "events").unwrap();
+        assert_eq!(event.integrationKey, "il1api2");
+        assert_eq!(event.alertKey.unwrap(), "sensor-7");
+
+        assert_eq!(
+            crate::consumers::with_metadata("not json", METADATA_FIELD, &metadata),
+            "not json"
+        );
+        assert_eq!(
+            crate::consumers::with_metadata("[1]", METADATA_FIELD, &metadata),
+            "[1]"
+        );
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_max() {
+        assert_eq!(retry_backoff(1), Duration::from_secs(1));
+        assert_eq!(retry_backoff(2), Duration::from_secs(2));
+        assert_eq!(retry_backoff(4), Duration::from_secs(8));
+        assert_eq!(retry_backoff(7), Duration::from_secs(60));
+        assert_eq!(retry_backoff(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn validate_kafka_topics_rejects_invalid_regex() {
+        let mut config = ILConfig::new();
+        config.event_topics = topics(&["events", "^alerts-.*"]);
+        assert!(validate_kafka_topics(&config).is_ok());
+        config.policy_topics = topics(&["^(policies"]);
+        assert!(validate_kafka_topics(&config).is_err());
     }
 }
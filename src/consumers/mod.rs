@@ -1,6 +1,15 @@
+pub mod avro;
+pub mod decoder;
+pub mod heartbeat;
 pub mod kafka;
 pub mod mqtt;
+pub mod mqtt_client;
 pub mod policy;
+pub mod routing;
+pub mod sparkplug;
+pub mod text_payload;
+pub mod threshold;
+pub mod topic_vars;
 
 use crate::config::ILConfig;
 use crate::models::event::EventQueueItemJson;
@@ -24,6 +33,17 @@ pub fn prepare_consumer_event(
     Some(event)
 }
 
+/// Adds the message metadata under `field` to JSON object payloads, other payloads stay as they are.
+pub(crate) fn with_metadata(payload: &str, field: &str, metadata: &serde_json::Value) -> String {
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut object)) => {
+            object.insert(field.to_string(), metadata.clone());
+            serde_json::Value::Object(object).to_string()
+        }
+        _ => payload.to_string(),
+    }
+}
+
 pub fn build_event_api_path(consumer_type: &str, integration_key: &str) -> String {
     format!("/v1/events/{}/{}", consumer_type, integration_key)
 }
@@ -1,15 +1,21 @@
 use crate::config::ILConfig;
+use crate::consumers::decoder::{DecodeError, find_decoder};
+use crate::consumers::mqtt_client::{
+    self, MqttClient, MqttEvent, MqttPublish, RecvError, SubAckReason,
+};
+use crate::consumers::sparkplug::{self, SparkplugMessage, SparkplugState};
+use crate::consumers::{heartbeat, routing, threshold, topic_vars};
 use crate::db::ILDatabase;
 use crate::models::event::EventQueueItemJson;
-use crate::{DaemonContext, hbt};
-use log::{error, info, warn};
-use rumqttc::{
-    Client, Event, Incoming, MqttOptions, Publish, QoS, RecvTimeoutError, SubscribeReasonCode,
-    TlsConfiguration, Transport,
-};
+use crate::models::event_db::EventQueueItem;
+use crate::queue::{self, QueueError};
+use crate::{DaemonContext, auto_resolve, dedup, device_watch, hbt};
+use log::{debug, error, info, warn};
+use rumqttc::{QoS, TlsConfiguration, Transport};
 use rustls::RootCertStore;
-use serde_json::json;
+use serde_json::{Value, json};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
@@ -24,7 +30,7 @@ pub enum MessageType {
     Ignored,
 }
 
-fn topic_filter_matches(filter: &str, topic: &str) -> bool {
+pub(crate) fn topic_filter_matches(filter: &str, topic: &str) -> bool {
     let filter_parts: Vec<&str> = filter.split('/').collect();
     let topic_parts: Vec<&str> = topic.split('/').collect();
 
@@ -42,29 +48,30 @@ fn topic_filter_matches(filter: &str, topic: &str) -> bool {
     filter_parts.len() == topic_parts.len()
 }
 
+/// Heartbeat topics win over policy topics, policy topics over event topics.
 pub(crate) fn classify_configured_message(
     message_topic: &str,
-    event_topic: Option<&str>,
-    heartbeat_topic: Option<&str>,
-    policy_topic: Option<&str>,
+    event_topics: &[String],
+    heartbeat_topics: &[String],
+    policy_topics: &[String],
 ) -> MessageType {
-    if let Some(ht) = heartbeat_topic {
-        if message_topic == ht {
-            return MessageType::Heartbeat;
-        }
+    if heartbeat_topics
+        .iter()
+        .any(|ht| topic_filter_matches(ht, message_topic))
+    {
+        return MessageType::Heartbeat;
     }
-    if let Some(pt) = policy_topic {
-        if topic_filter_matches(pt, message_topic) {
-            return MessageType::Policy;
-        }
+    if policy_topics
+        .iter()
+        .any(|pt| topic_filter_matches(pt, message_topic))
+    {
+        return MessageType::Policy;
     }
-    if let Some(et) = event_topic {
-        if message_topic == et {
-            return MessageType::Event;
-        }
-        if et.contains('#') || et.contains('+') {
-            return MessageType::Event;
-        }
+    if event_topics
+        .iter()
+        .any(|et| topic_filter_matches(et, message_topic))
+    {
+        return MessageType::Event;
     }
     MessageType::Ignored
 }
@@ -77,24 +84,23 @@ pub fn classify_message(
 ) -> MessageType {
     classify_configured_message(
         message_topic,
-        Some(event_topic),
-        Some(heartbeat_topic),
-        policy_topic,
+        &[event_topic.to_string()],
+        &[heartbeat_topic.to_string()],
+        &policy_topic
+            .map(|t| vec![t.to_string()])
+            .unwrap_or_default(),
     )
 }
 
 fn configured_mqtt_topics(config: &ILConfig) -> Vec<&str> {
-    let mut topics = Vec::new();
-    if let Some(topic) = config.event_topic.as_deref() {
-        topics.push(topic);
-    }
-    if let Some(topic) = config.heartbeat_topic.as_deref() {
-        topics.push(topic);
-    }
-    if let Some(topic) = config.policy_topic.as_deref() {
-        topics.push(topic);
-    }
-    topics
+    config
+        .event_topics
+        .iter()
+        .chain(config.heartbeat_topics.iter())
+        .chain(config.policy_topics.iter())
+        .chain(config.sparkplug_topic.iter())
+        .map(|t| t.as_str())
+        .collect()
 }
 
 fn describe_mqtt_topics(config: &ILConfig) -> String {
@@ -106,15 +112,13 @@ pub fn configured_topic_count(config: &ILConfig) -> u32 {
 }
 
 fn has_configured_mqtt_topics(config: &ILConfig) -> bool {
-    config.event_topic.is_some()
-        || config.heartbeat_topic.is_some()
-        || config.policy_topic.is_some()
+    !configured_mqtt_topics(config).is_empty()
 }
 
 pub fn validate_mqtt_topics(config: &ILConfig) {
     if config.mqtt_host.is_some() && !has_configured_mqtt_topics(config) {
         panic!(
-            "At least one MQTT topic must be configured: --event_topic, --heartbeat_topic, or --policy_topic"
+            "At least one MQTT topic must be configured: --event_topic, --heartbeat_topic, --policy_topic or --sparkplug"
         );
     }
 }
@@ -133,7 +137,48 @@ pub fn prepare_mqtt_event(
     payload: &str,
     topic: &str,
 ) -> Option<EventQueueItemJson> {
-    super::prepare_consumer_event(config, payload, topic, json!({"topic": topic}))
+    let vars = topic_vars::capture_topic_vars(config, topic);
+    if !topic_vars::matches_filter_vars(config, &vars) {
+        debug!(
+            "Dropping event because topic variables of {} are not matching",
+            topic
+        );
+        return None;
+    }
+
+    let mut default_details = if vars.is_empty() {
+        json!({"topic": topic})
+    } else {
+        json!({"topic": topic, "topicVars": vars})
+    };
+    let properties = if config.mqtt_v5 {
+        serde_json::from_str::<Value>(payload)
+            .ok()
+            .and_then(|mut json| json.get_mut(mqtt_client::METADATA_FIELD).map(Value::take))
+    } else {
+        None
+    };
+    if let Some(ref properties) = properties {
+        default_details["contentType"] = properties["contentType"].clone();
+        default_details["userProperties"] = properties["userProperties"].clone();
+    }
+    let mut event = super::prepare_consumer_event(config, payload, topic, default_details)?;
+    if let Some(Value::Object(ref mut details)) = event.customDetails {
+        // the forwarded payload stays as it was published, only the correlation data is added
+        details.remove(mqtt_client::METADATA_FIELD);
+        if let Some(correlation_data) = properties
+            .as_ref()
+            .map(|p| &p["correlationData"])
+            .filter(|c| !c.is_null())
+        {
+            details
+                .entry("correlationData")
+                .or_insert_with(|| correlation_data.clone());
+        }
+    }
+    routing::apply_routes(config, "mqtt", &mut event, payload, topic);
+    topic_vars::apply_topic_vars(config, &mut event, &vars, topic);
+    Some(event)
 }
 
 pub fn build_event_api_path(integration_key: &str) -> String {
@@ -250,9 +295,14 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
         .expect("Missing mqtt name");
 
     validate_mqtt_config(&daemon_ctx.config);
-    let event_topic = daemon_ctx.config.event_topic.clone();
-    let heartbeat_topic = daemon_ctx.config.heartbeat_topic.clone();
-    let policy_topic = daemon_ctx.config.policy_topic.clone();
+    let event_topics = daemon_ctx.config.event_topics.clone();
+    let heartbeat_topics = daemon_ctx.config.heartbeat_topics.clone();
+    let policy_topics = daemon_ctx.config.policy_topics.clone();
+    let mut sparkplug = daemon_ctx
+        .config
+        .sparkplug_topic
+        .as_ref()
+        .map(|_| SparkplugState::default());
 
     let qos = match daemon_ctx.config.mqtt_qos {
         1 => QoS::AtLeastOnce,
@@ -298,27 +348,8 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
             probe.reset();
         }
 
-        let mut mqtt_options = MqttOptions::new(mqtt_name.as_str(), mqtt_host.as_str(), mqtt_port);
-
-        mqtt_options
-            .set_keep_alive(Duration::from_secs(5))
-            .set_pending_throttle(Duration::from_secs(1))
-            .set_clean_session(false)
-            .set_manual_acks(true);
-
-        if let Some(mqtt_username) = daemon_ctx.config.mqtt_username.clone() {
-            mqtt_options.set_credentials(
-                mqtt_username.as_str(),
-                daemon_ctx
-                    .config
-                    .mqtt_password
-                    .clone()
-                    .expect("mqtt_username is set, expecting mqtt_password to be set as well")
-                    .as_str(),
-            );
-        }
-
         let mut attempt_tls_fp: Option<u64> = None;
+        let mut mqtt_transport = None;
 
         if daemon_ctx.config.mqtt_tls {
             let transport = if let Some((transport, fp)) = staged_transport.take() {
@@ -356,33 +387,37 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
             } else {
                 Transport::tls_with_default_config()
             };
-            mqtt_options.set_transport(transport);
+            mqtt_transport = Some(transport);
             info!("MQTT TLS enabled");
         }
 
-        let (client, mut connection) = Client::new(mqtt_options, 10);
-
-        if let Some(ref topic) = event_topic {
-            client
-                .subscribe(sub_topic(topic).as_str(), qos)
-                .expect("Failed to subscribe to mqtt event topic");
-        }
-
-        if let Some(ref topic) = heartbeat_topic {
-            client
-                .subscribe(sub_topic(topic).as_str(), qos)
-                .expect("Failed to subscribe to mqtt heartbeat topic");
-        }
+        let (client, mut connection) = MqttClient::new(
+            &daemon_ctx.config,
+            mqtt_name.as_str(),
+            mqtt_host.as_str(),
+            mqtt_port,
+            mqtt_transport,
+        );
 
-        if let Some(ref topic) = policy_topic {
+        // topics by the packet id of their subscription, to name rejected subscriptions
+        let mut pending_subscriptions = VecDeque::new();
+        let mut subscriptions = HashMap::new();
+        for topic in configured_mqtt_topics(&daemon_ctx.config) {
+            let topic = sub_topic(topic);
             client
-                .subscribe(sub_topic(topic).as_str(), qos)
-                .expect("Failed to subscribe to mqtt policy topic");
+                .subscribe(topic.as_str(), qos)
+                .expect("Failed to subscribe to mqtt topic");
+            pending_subscriptions.push_back(topic);
         }
 
         info!(
-            "Subscribing to mqtt topics {} (QoS {:?}{})",
+            "Subscribing to mqtt topics {} (MQTT {}, QoS {:?}{})",
             describe_mqtt_topics(&daemon_ctx.config),
+            if daemon_ctx.config.mqtt_v5 {
+                "5"
+            } else {
+                "3.1.1"
+            },
             qos,
             shared_prefix
                 .as_ref()
@@ -435,25 +470,21 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
                 }
             }
 
-            let invoke = match connection.recv_timeout(Duration::from_millis(250)) {
-                Ok(invoke) => invoke,
-                Err(RecvTimeoutError::Timeout) => continue,
-                Err(RecvTimeoutError::Disconnected) => break,
-            };
-
-            let event = match invoke {
+            let event = match connection.recv_timeout(Duration::from_millis(250)) {
                 Ok(event) => event,
-                Err(e) => {
-                    error!("mqtt error {:?}", e);
+                Err(RecvError::Timeout) => continue,
+                Err(RecvError::Disconnected) => break,
+                Err(RecvError::Connection(e)) => {
+                    error!("mqtt error {}", e);
                     if let Some(ref probe) = daemon_ctx.mqtt_probe {
-                        probe.record_error(format!("{:?}", e));
+                        probe.record_error(e);
                     }
                     break;
                 }
             };
 
             match event {
-                Event::Incoming(Incoming::ConnAck(_)) => {
+                MqttEvent::ConnAck => {
                     if !connected {
                         connected = true;
                         if let Some(fp) = attempt_tls_fp {
@@ -469,67 +500,137 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
                         probe.set_connected();
                     }
                 }
-                Event::Incoming(Incoming::SubAck(suback)) => {
+                MqttEvent::Subscribed(pkid) => {
+                    if let Some(topic) = pending_subscriptions.pop_front() {
+                        subscriptions.insert(pkid, topic);
+                    }
+                }
+                MqttEvent::SubAck { pkid, reasons } => {
+                    let topic = subscriptions.remove(&pkid).unwrap_or_default();
+                    let rejection = reasons.iter().find_map(|reason| match reason {
+                        SubAckReason::Rejected(code) => Some(code.as_str()),
+                        SubAckReason::Granted(_) => None,
+                    });
+                    if let Some(code) = rejection {
+                        error!("MQTT subscription to {} rejected: {}", topic, code);
+                    } else if let Some(SubAckReason::Granted(granted)) = reasons.first()
+                        && (*granted as u8) < (qos as u8)
+                    {
+                        warn!(
+                            "MQTT subscription to {} granted with QoS {:?} instead of {:?}",
+                            topic, granted, qos
+                        );
+                    }
                     if let Some(ref probe) = daemon_ctx.mqtt_probe {
-                        let has_failure = suback
-                            .return_codes
-                            .iter()
-                            .any(|rc| matches!(rc, SubscribeReasonCode::Failure));
-                        if has_failure {
-                            let err =
-                                format!("Subscription rejected: {:?}", suback.return_codes);
-                            error!("MQTT {}", err);
-                            probe.record_error(err);
-                        } else {
-                            probe.record_suback_success();
+                        match rejection {
+                            Some(code) => probe.record_suback_rejected(&topic, code),
+                            None => probe.record_suback_success(),
                         }
                     }
                 }
-                Event::Incoming(Incoming::Publish(message)) => {
+                MqttEvent::Publish(message) => {
                     recon_attempts = 0;
 
-                    let payload = str::from_utf8(&message.payload);
-                    if payload.is_err() {
-                        error!("Failed to decode mqtt payload {:?}", payload);
-                        acknowledge_mqtt_publish(&client, &message);
+                    if let Some(ref mut sparkplug) = sparkplug
+                        && sparkplug::is_sparkplug_topic(&message.topic)
+                    {
+                        let should_retry =
+                            process_sparkplug_message(&daemon_ctx, &db, sparkplug, &message);
+                        if !settle_mqtt_publish(&client, &message, should_retry) {
+                            break;
+                        }
                         continue;
                     }
-                    let payload = payload.expect("payload from utf8");
+
+                    let decoded;
+                    let payload = match find_decoder(&daemon_ctx.config, "mqtt", &message.topic) {
+                        Some(decoder) => match tokio::runtime::Handle::current()
+                            .block_on(decoder.decode(message.payload()))
+                        {
+                            Ok(json) => {
+                                decoded = json.to_string();
+                                decoded.as_str()
+                            }
+                            Err(e) => {
+                                error!("Failed to decode mqtt payload of {}: {}", message.topic, e);
+                                if matches!(e, DecodeError::Unavailable(_))
+                                    && message.qos != QoS::AtMostOnce
+                                {
+                                    warn!(
+                                        "MQTT message from topic {} cannot be decoded yet, reconnecting without acknowledgement",
+                                        message.topic
+                                    );
+                                    let _ = client.disconnect();
+                                    break;
+                                }
+                                acknowledge_mqtt_publish(&client, &message);
+                                continue;
+                            }
+                        },
+                        None => {
+                            let payload = str::from_utf8(message.payload());
+                            if payload.is_err() {
+                                error!("Failed to decode mqtt payload {:?}", payload);
+                                acknowledge_mqtt_publish(&client, &message);
+                                continue;
+                            }
+                            payload.expect("payload from utf8")
+                        }
+                    };
+
+                    // v5 properties are available to mappings and filters as `_mqtt`
+                    let with_properties;
+                    let payload = match message.metadata() {
+                        Some(metadata) => {
+                            with_properties = super::with_metadata(
+                                payload,
+                                mqtt_client::METADATA_FIELD,
+                                &metadata,
+                            );
+                            with_properties.as_str()
+                        }
+                        None => payload,
+                    };
 
                     info!("Received mqtt message {}", message.topic);
+                    if !daemon_ctx.config.device_watches.is_empty()
+                        && let Err(e) = device_watch::observe(
+                            &daemon_ctx.config,
+                            &db,
+                            "mqtt",
+                            payload,
+                            &message.topic,
+                        )
+                    {
+                        error!("Failed to track device last seen {}", e);
+                    }
                     let should_retry = match classify_configured_message(
                         &message.topic,
-                        event_topic.as_deref(),
-                        heartbeat_topic.as_deref(),
-                        policy_topic.as_deref(),
+                        &event_topics,
+                        &heartbeat_topics,
+                        &policy_topics,
                     ) {
-                        MessageType::Heartbeat => process_heartbeat_message(&daemon_ctx, payload),
+                        MessageType::Heartbeat => {
+                            process_heartbeat_message(&daemon_ctx, payload, &message.topic)
+                        }
                         MessageType::Event => {
                             process_event_message(&daemon_ctx, &db, payload, &message.topic)
                         }
                         MessageType::Policy => {
                             process_policy_message(&daemon_ctx, &db, payload, &message.topic)
                         }
-                        MessageType::Ignored => false,
-                    };
-
-                    if should_retry {
-                        if message.qos == QoS::AtMostOnce {
+                        MessageType::Ignored => {
                             warn!(
-                                "MQTT message from topic {} failed but cannot be retried with QoS 0",
+                                "Ignoring MQTT message on topic {} that matches no configured topic",
                                 message.topic
                             );
-                            continue;
+                            false
                         }
-                        warn!(
-                            "MQTT message from topic {} failed, reconnecting without acknowledgement",
-                            message.topic
-                        );
-                        let _ = client.disconnect();
+                    };
+
+                    if !settle_mqtt_publish(&client, &message, should_retry) {
                         break;
                     }
-
-                    acknowledge_mqtt_publish(&client, &message);
                 }
                 _ => continue,
             }
@@ -554,23 +655,41 @@ pub fn run_mqtt_job(daemon_ctx: Arc<DaemonContext>) -> () {
     }
 }
 
-fn acknowledge_mqtt_publish(client: &Client, message: &Publish) {
+/// Acknowledges the message unless it failed and can be redelivered, false if the connection has to be dropped.
+fn settle_mqtt_publish(client: &MqttClient, message: &MqttPublish, should_retry: bool) -> bool {
+    if should_retry {
+        if message.qos == QoS::AtMostOnce {
+            warn!(
+                "MQTT message from topic {} failed but cannot be retried with QoS 0",
+                message.topic
+            );
+            return true;
+        }
+        warn!(
+            "MQTT message from topic {} failed, reconnecting without acknowledgement",
+            message.topic
+        );
+        let _ = client.disconnect();
+        return false;
+    }
+    acknowledge_mqtt_publish(client, message);
+    true
+}
+
+fn acknowledge_mqtt_publish(client: &MqttClient, message: &MqttPublish) {
     if let Err(e) = client.ack(message) {
         error!("Failed to acknowledge MQTT message {:?}", e);
     }
 }
 
-fn process_heartbeat_message(daemon_ctx: &Arc<DaemonContext>, payload: &str) -> bool {
-    let parsed = crate::models::heartbeat::HeartbeatJson::parse_heartbeat_json(payload);
-    if let Some(heartbeat) = parsed {
-        let ok = tokio::runtime::Handle::current().block_on(hbt::ping_heartbeat(
-            &daemon_ctx.ilert_client,
-            heartbeat.integrationKey.as_str(),
-        ));
+fn process_heartbeat_message(daemon_ctx: &Arc<DaemonContext>, payload: &str, topic: &str) -> bool {
+    if let Some(key) = heartbeat::resolve_heartbeat_key(&daemon_ctx.config, payload, topic) {
+        let ok = tokio::runtime::Handle::current()
+            .block_on(hbt::ping_heartbeat(&daemon_ctx.ilert_client, key.as_str()));
         if ok {
             info!(
                 "Heartbeat {} pinged, triggered by mqtt message",
-                heartbeat.integrationKey.as_str()
+                key.as_str()
             );
         }
         return !ok;
@@ -578,6 +697,65 @@ fn process_heartbeat_message(daemon_ctx: &Arc<DaemonContext>, payload: &str) ->
     false
 }
 
+fn process_sparkplug_message(
+    daemon_ctx: &Arc<DaemonContext>,
+    db: &ILDatabase,
+    sparkplug: &mut SparkplugState,
+    message: &MqttPublish,
+) -> bool {
+    let config = &daemon_ctx.config;
+    match sparkplug.handle(config, &message.topic, message.payload()) {
+        Ok(SparkplugMessage::Lifecycle(events)) => {
+            for event in events.iter() {
+                match queue::create_event(config, db, event) {
+                    Ok(_) => info!("{} queued ({})", event.summary, event.event_type),
+                    Err(QueueError::Suppressed(reason)) => {
+                        info!("{} not queued, {}", event.summary, reason)
+                    }
+                    Err(QueueError::Full) => warn!(
+                        "{} not queued ({}), queue is full",
+                        event.summary, event.event_type
+                    ),
+                    Err(QueueError::Db(e)) => {
+                        error!("Failed to queue Sparkplug event: {}", e);
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+        Ok(SparkplugMessage::Data(_)) if !has_sparkplug_data_rules(config, &message.topic) => {
+            debug!(
+                "Ignoring Sparkplug data on {} without threshold, filter or eventType mapping",
+                message.topic
+            );
+            false
+        }
+        Ok(SparkplugMessage::Data(mut json)) => {
+            if let Some(metadata) = message.metadata() {
+                json[mqtt_client::METADATA_FIELD] = metadata;
+            }
+            process_event_message(daemon_ctx, db, &json.to_string(), &message.topic)
+        }
+        Ok(SparkplugMessage::Ignored) => false,
+        Err(e) => {
+            error!(
+                "Failed to decode Sparkplug payload of {}: {}",
+                message.topic, e
+            );
+            false
+        }
+    }
+}
+
+/// Telemetry only becomes events through rules, otherwise every metric update would open or refresh an alert.
+fn has_sparkplug_data_rules(config: &ILConfig, topic: &str) -> bool {
+    threshold::has_rules_for(config, topic)
+        || (config.filter_key.is_some() && config.filter_val.is_some())
+        || !config.filter_vars.is_empty()
+        || config.map_key_etype.is_some()
+}
+
 fn process_policy_message(
     daemon_ctx: &Arc<DaemonContext>,
     db: &ILDatabase,
@@ -585,11 +763,13 @@ fn process_policy_message(
     topic: &str,
 ) -> bool {
     if daemon_ctx.config.mqtt_buffer {
-        match db.create_mqtt_queue_item(topic, payload) {
+        match queue::create_mqtt_item(&daemon_ctx.config, db, topic, payload) {
             Ok(id) => {
                 info!("Policy message queued for retry processing: {}", id);
                 false
             }
+            // rejected by the queue limit, retrying would not help
+            Err(QueueError::Full) => false,
             Err(e) => {
                 error!("Failed to queue policy message: {}", e);
                 true
@@ -611,24 +791,34 @@ fn process_event_message(
     topic: &str,
 ) -> bool {
     if daemon_ctx.config.mqtt_buffer {
-        match db.create_mqtt_queue_item(topic, payload) {
+        match queue::create_mqtt_item(&daemon_ctx.config, db, topic, payload) {
             Ok(id) => {
                 info!("Event message queued for retry processing: {}", id);
                 false
             }
+            // rejected by the queue limit, retrying would not help
+            Err(QueueError::Full) => false,
             Err(e) => {
                 error!("Failed to queue event message: {}", e);
                 true
             }
         }
+    } else if dedup::is_enabled(&daemon_ctx.config)
+        || threshold::has_rules_for(&daemon_ctx.config, topic)
+    {
+        // deduplication, flap suppression and threshold state work on the sqlite queue
+        enqueue_event(&daemon_ctx.config, db, payload, topic) == EnqueueResult::DbError
     } else {
         if let Some(event) = prepare_mqtt_event(&daemon_ctx.config, payload, topic) {
             let event_api_path = build_event_api_path(&event.integrationKey);
             let db_event = EventQueueItemJson::to_db(event, Some(event_api_path));
-            tokio::runtime::Handle::current().block_on(crate::poll::send_queued_event(
-                &daemon_ctx.ilert_client,
-                &db_event,
-            ))
+            let should_retry = tokio::runtime::Handle::current().block_on(
+                crate::poll::send_queued_event(&daemon_ctx.ilert_client, &db_event),
+            );
+            if !should_retry {
+                track_auto_resolve(&daemon_ctx.config, db, &db_event, payload, topic);
+            }
+            should_retry
         } else {
             false
         }
@@ -639,6 +829,7 @@ fn process_event_message(
 pub enum EnqueueResult {
     Inserted,
     Filtered,
+    Rejected,
     DbError,
 }
 
@@ -648,25 +839,86 @@ pub fn enqueue_event(
     payload: &str,
     topic: &str,
 ) -> EnqueueResult {
+    if threshold::has_rules_for(config, topic) {
+        return enqueue_threshold_events(config, db, payload, topic);
+    }
+
     let event = match prepare_mqtt_event(config, payload, topic) {
         Some(e) => e,
         None => return EnqueueResult::Filtered,
     };
     let event_api_path = build_event_api_path(&event.integrationKey);
     let db_event = EventQueueItemJson::to_db(event, Some(event_api_path));
-    match db.create_il_event(&db_event) {
+    enqueue_db_event(config, db, &db_event, payload, topic)
+}
+
+fn enqueue_threshold_events(
+    config: &ILConfig,
+    db: &ILDatabase,
+    payload: &str,
+    topic: &str,
+) -> EnqueueResult {
+    let events = match threshold::evaluate(config, db, payload, topic) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to evaluate thresholds {:?}.", e);
+            return EnqueueResult::DbError;
+        }
+    };
+
+    let mut result = EnqueueResult::Filtered;
+    for mut transition in events {
+        let db_event = &mut transition.event;
+        db_event.event_api_path = Some(build_event_api_path(&db_event.integration_key));
+        match enqueue_db_event(config, db, db_event, payload, topic) {
+            EnqueueResult::DbError => return EnqueueResult::DbError,
+            EnqueueResult::Rejected => {
+                // the rule state stays unchanged, the next message retries the event
+                warn!(
+                    "{} not queued ({}), queue is full",
+                    db_event.summary, db_event.event_type
+                );
+                continue;
+            }
+            EnqueueResult::Inserted => result = EnqueueResult::Inserted,
+            EnqueueResult::Filtered => {}
+        }
+        if let Err(e) = transition.record(db) {
+            error!("Failed to record threshold state {:?}.", e);
+            return EnqueueResult::DbError;
+        }
+    }
+    result
+}
+
+fn enqueue_db_event(
+    config: &ILConfig,
+    db: &ILDatabase,
+    db_event: &EventQueueItem,
+    payload: &str,
+    topic: &str,
+) -> EnqueueResult {
+    match queue::create_event(config, db, db_event) {
         Ok(Some(val)) => {
             let event_id = val.id.clone().unwrap_or("".to_string());
             info!(
                 "Event {} successfully created and added to queue.",
                 event_id
             );
+            track_auto_resolve(config, db, db_event, payload, topic);
             EnqueueResult::Inserted
         }
         Ok(None) => {
             error!("Failed to create event, result is empty");
             EnqueueResult::DbError
         }
+        Err(QueueError::Full) => EnqueueResult::Rejected,
+        Err(QueueError::Suppressed(reason)) => {
+            info!("Event from topic {} not queued, {}", topic, reason);
+            // a duplicate ALERT still shows the alert is not silent
+            track_auto_resolve(config, db, db_event, payload, topic);
+            EnqueueResult::Filtered
+        }
         Err(e) => {
             error!("Failed to create event {:?}.", e);
             EnqueueResult::DbError
@@ -674,6 +926,20 @@ pub fn enqueue_event(
     }
 }
 
+fn track_auto_resolve(
+    config: &ILConfig,
+    db: &ILDatabase,
+    event: &EventQueueItem,
+    payload: &str,
+    topic: &str,
+) {
+    if auto_resolve::is_enabled(config)
+        && let Err(e) = auto_resolve::track_event(config, db, event, payload, topic)
+    {
+        error!("Failed to track alert state for auto resolve {}", e);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -737,7 +1003,7 @@ mod tests {
 
     #[test]
     fn classify_wildcard_does_not_match_heartbeat_topic() {
-        // heartbeat topic is checked first, so a non-heartbeat message with wildcard event topic is Event
+        // a wildcard event topic only matches the topics it covers, anything else is ignored
         assert_eq!(
             classify_message(
                 "some/random/topic",
@@ -745,7 +1011,29 @@ mod tests {
                 "ilert/heartbeats",
                 None
             ),
-            MessageType::Event
+            MessageType::Ignored
+        );
+    }
+
+    #[test]
+    fn classify_multiple_topics_per_kind() {
+        let events = vec!["plant/+/alarm".to_string(), "ilert/events".to_string()];
+        let heartbeats = vec!["plant/+/heartbeat".to_string(), "ilert/hb".to_string()];
+        let policies = vec!["ilert/policies".to_string()];
+        let classify = |topic| classify_configured_message(topic, &events, &heartbeats, &policies);
+        assert_eq!(classify("plant/a/alarm"), MessageType::Event);
+        assert_eq!(classify("ilert/events"), MessageType::Event);
+        assert_eq!(classify("plant/a/heartbeat"), MessageType::Heartbeat);
+        assert_eq!(classify("ilert/hb"), MessageType::Heartbeat);
+        assert_eq!(classify("ilert/policies"), MessageType::Policy);
+
+        assert_eq!(classify("other"), MessageType::Ignored);
+        assert_eq!(classify("plant/a/status"), MessageType::Ignored);
+
+        let events = vec!["ilert/events".to_string()];
+        assert_eq!(
+            classify_configured_message("other", &events, &heartbeats, &policies),
+            MessageType::Ignored
         );
     }
 
@@ -894,6 +1182,59 @@ mod tests {
         assert!(cd.get("topic").is_none());
     }
 
+    #[test]
+    fn sparkplug_data_needs_rules() {
+        let topic = "spBv1.0/plant1/DDATA/edge1/boiler";
+        let mut config = ILConfig::new();
+        config.event_key = Some("il1api1".to_string());
+        config.map_key_summary = Some("metrics.Status".to_string());
+        assert!(!has_sparkplug_data_rules(&config, topic));
+
+        config.map_key_etype = Some("metrics.State".to_string());
+        assert!(has_sparkplug_data_rules(&config, topic));
+
+        let mut config = ILConfig::new();
+        config.filter_key = Some("metrics.Alarm".to_string());
+        config.filter_val = Some("true".to_string());
+        assert!(has_sparkplug_data_rules(&config, topic));
+
+        let mut config = ILConfig::new();
+        config.thresholds = vec![
+            crate::consumers::threshold::ThresholdRule::parse(
+                "name=boiler_temp,topic=spBv1.0/+/DDATA/#,path=metrics.Temperature,above=80",
+            )
+            .unwrap(),
+        ];
+        assert!(has_sparkplug_data_rules(&config, topic));
+        assert!(!has_sparkplug_data_rules(
+            &config,
+            "spBv1.0/plant1/NDATA/edge1"
+        ));
+    }
+
+    #[test]
+    fn prepare_event_passes_v5_properties() {
+        let mut config = ILConfig::new();
+        config.mqtt_v5 = true;
+        config.map_key_alert_key = Some("_mqtt.userProperties.device".to_string());
+        let payload = r#"{"apiKey": "k1", "eventType": "ALERT", "summary": "test",
+            "_mqtt": {"topic": "plant/alarms", "contentType": "application/json",
+            "correlationData": "req-42", "userProperties": {"device": "plc-7"}}}"#;
+        let event = prepare_mqtt_event(&config, payload, "plant/alarms").unwrap();
+        assert_eq!(event.alertKey.as_deref(), Some("plc-7"));
+        let cd = event.customDetails.unwrap();
+        assert_eq!(cd["contentType"], "application/json");
+        assert_eq!(cd["userProperties"]["device"], "plc-7");
+        assert_eq!(cd["correlationData"], "req-42");
+
+        config.forward_message_payload = true;
+        let event = prepare_mqtt_event(&config, payload, "plant/alarms").unwrap();
+        let cd = event.customDetails.unwrap();
+        assert!(cd.get("_mqtt").is_none());
+        assert_eq!(cd["summary"], "test");
+        assert_eq!(cd["correlationData"], "req-42");
+    }
+
     #[test]
     fn prepare_event_returns_none_for_invalid_json() {
         let config = ILConfig::new();
@@ -963,6 +1304,26 @@ mod tests {
         assert_eq!(cd["topic"], "factory/alarms");
     }
 
+    #[test]
+    fn prepare_event_with_topic_variables() {
+        let mut config = ILConfig::new();
+        config.event_key = Some("static-key".to_string());
+        config.topic_pattern =
+            Some(topic_vars::TopicPattern::parse("plant/{site}/sensor/{device}").unwrap());
+        config.alert_key_template = Some("{site}-{device}".to_string());
+        config.filter_vars = vec![("site".to_string(), "berlin".to_string())];
+
+        let payload = r#"{"summary": "too hot"}"#;
+        let event = prepare_mqtt_event(&config, payload, "plant/berlin/sensor/t1").unwrap();
+        assert_eq!(event.alertKey.as_ref().unwrap(), "berlin-t1");
+        let cd = event.customDetails.unwrap();
+        assert_eq!(cd["topicVars"]["site"], "berlin");
+        assert_eq!(cd["topicVars"]["device"], "t1");
+
+        assert!(prepare_mqtt_event(&config, payload, "plant/paris/sensor/t1").is_none());
+        assert!(prepare_mqtt_event(&config, payload, "plant/berlin/actor/t1").is_none());
+    }
+
     // --- TlsMaterial ---
 
     #[test]
@@ -0,0 +1,339 @@
+use rumqttc::v5;
+use rumqttc::{Client, Connection, MqttOptions, Outgoing, Publish, QoS, Transport};
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+
+use crate::config::ILConfig;
+
+/// Reserved payload field with the MQTT v5 properties of a message.
+pub const METADATA_FIELD: &str = "_mqtt";
+
+/**
+    MQTT client of the configured protocol version, v3.1.1 by default and v5 with `--mqtt_version 5`,
+    so the consumer loop handles both the same way.
+*/
+pub enum MqttClient {
+    V3(Client),
+    V5(v5::Client),
+}
+
+pub enum MqttConnection {
+    V3(Box<Connection>),
+    V5(Box<v5::Connection>),
+}
+
+pub enum MqttEvent {
+    ConnAck,
+    /// packet id of a subscription that was sent to the broker
+    Subscribed(u16),
+    SubAck {
+        pkid: u16,
+        reasons: Vec<SubAckReason>,
+    },
+    Publish(Box<MqttPublish>),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubAckReason {
+    Granted(QoS),
+    /// name of the v5 reason code, `Failure` for v3.1.1
+    Rejected(String),
+}
+
+pub enum RecvError {
+    Timeout,
+    Disconnected,
+    Connection(String),
+}
+
+pub struct MqttPublish {
+    pub topic: String,
+    pub qos: QoS,
+    packet: PublishPacket,
+}
+
+enum PublishPacket {
+    V3(Publish),
+    V5(v5::mqttbytes::v5::Publish),
+}
+
+impl MqttClient {
+    /// Persistent session with manual acknowledgements, messages that are not acknowledged are redelivered.
+    pub fn new(
+        config: &ILConfig,
+        name: &str,
+        host: &str,
+        port: u16,
+        transport: Option<Transport>,
+    ) -> (MqttClient, MqttConnection) {
+        let credentials = config.mqtt_username.as_ref().map(|username| {
+            let password = config
+                .mqtt_password
+                .clone()
+                .expect("mqtt_username is set, expecting mqtt_password to be set as well");
+            (username.clone(), password)
+        });
+
+        if config.mqtt_v5 {
+            let mut options = v5::MqttOptions::new(name, host, port);
+            options
+                .set_keep_alive(Duration::from_secs(5))
+                .set_pending_throttle(Duration::from_secs(1))
+                .set_clean_start(false)
+                // without an interval v5 ends the session on disconnect, u32::MAX means it never expires
+                .set_session_expiry_interval(Some(config.mqtt_session_expiry.unwrap_or(u32::MAX)))
+                .set_manual_acks(true);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            let (client, connection) = v5::Client::new(options, 10);
+            (
+                MqttClient::V5(client),
+                MqttConnection::V5(Box::new(connection)),
+            )
+        } else {
+            let mut options = MqttOptions::new(name, host, port);
+            options
+                .set_keep_alive(Duration::from_secs(5))
+                .set_pending_throttle(Duration::from_secs(1))
+                .set_clean_session(false)
+                .set_manual_acks(true);
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            let (client, connection) = Client::new(options, 10);
+            (
+                MqttClient::V3(client),
+                MqttConnection::V3(Box::new(connection)),
+            )
+        }
+    }
+
+    pub fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), String> {
+        match self {
+            MqttClient::V3(client) => client.subscribe(topic, qos).map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client
+                .subscribe(topic, v5_qos(qos))
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn ack(&self, message: &MqttPublish) -> Result<(), String> {
+        match (self, &message.packet) {
+            (MqttClient::V3(client), PublishPacket::V3(publish)) => {
+                client.ack(publish).map_err(|e| e.to_string())
+            }
+            (MqttClient::V5(client), PublishPacket::V5(publish)) => {
+                client.ack(publish).map_err(|e| e.to_string())
+            }
+            _ => Err("message was received with another protocol version".to_string()),
+        }
+    }
+
+    pub fn disconnect(&self) -> Result<(), String> {
+        match self {
+            MqttClient::V3(client) => client.disconnect().map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client.disconnect().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl MqttConnection {
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<MqttEvent, RecvError> {
+        match self {
+            MqttConnection::V3(connection) => match connection.recv_timeout(timeout) {
+                Ok(Ok(event)) => Ok(v3_event(event)),
+                Ok(Err(e)) => Err(RecvError::Connection(format!("{:?}", e))),
+                Err(rumqttc::RecvTimeoutError::Timeout) => Err(RecvError::Timeout),
+                Err(rumqttc::RecvTimeoutError::Disconnected) => Err(RecvError::Disconnected),
+            },
+            MqttConnection::V5(connection) => match connection.recv_timeout(timeout) {
+                Ok(Ok(event)) => Ok(v5_event(event)),
+                Ok(Err(e)) => Err(RecvError::Connection(format!("{:?}", e))),
+                Err(v5::RecvTimeoutError::Timeout) => Err(RecvError::Timeout),
+                Err(v5::RecvTimeoutError::Disconnected) => Err(RecvError::Disconnected),
+            },
+        }
+    }
+}
+
+impl MqttPublish {
+    pub fn payload(&self) -> &[u8] {
+        match self.packet {
+            PublishPacket::V3(ref publish) => &publish.payload,
+            PublishPacket::V5(ref publish) => &publish.payload,
+        }
+    }
+
+    /// Content type, user properties, response topic and correlation data of a v5 message.
+    pub fn metadata(&self) -> Option<Value> {
+        let PublishPacket::V5(ref publish) = self.packet else {
+            return None;
+        };
+        let properties = publish.properties.as_ref()?;
+        let mut user_properties = Map::new();
+        for (key, value) in properties.user_properties.iter() {
+            user_properties.insert(key.clone(), Value::String(value.clone()));
+        }
+        Some(json!({
+            "topic": self.topic,
+            "contentType": properties.content_type,
+            "responseTopic": properties.response_topic,
+            "correlationData": properties.correlation_data.as_deref().map(correlation_data),
+            "userProperties": user_properties,
+        }))
+    }
+}
+
+/// Correlation data as text, hex encoded if it is binary.
+fn correlation_data(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => data.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn v3_event(event: rumqttc::Event) -> MqttEvent {
+    use rumqttc::{Event, Incoming, SubscribeReasonCode};
+    match event {
+        Event::Incoming(Incoming::ConnAck(_)) => MqttEvent::ConnAck,
+        Event::Incoming(Incoming::SubAck(suback)) => MqttEvent::SubAck {
+            pkid: suback.pkid,
+            reasons: suback
+                .return_codes
+                .iter()
+                .map(|code| match code {
+                    SubscribeReasonCode::Success(qos) => SubAckReason::Granted(*qos),
+                    SubscribeReasonCode::Failure => SubAckReason::Rejected("Failure".to_string()),
+                })
+                .collect(),
+        },
+        Event::Incoming(Incoming::Publish(publish)) => MqttEvent::Publish(Box::new(MqttPublish {
+            topic: publish.topic.clone(),
+            qos: publish.qos,
+            packet: PublishPacket::V3(publish),
+        })),
+        Event::Outgoing(Outgoing::Subscribe(pkid)) => MqttEvent::Subscribed(pkid),
+        _ => MqttEvent::Other,
+    }
+}
+
+fn v5_event(event: v5::Event) -> MqttEvent {
+    use v5::mqttbytes::v5::{Packet, SubscribeReasonCode};
+    match event {
+        v5::Event::Incoming(Packet::ConnAck(_)) => MqttEvent::ConnAck,
+        v5::Event::Incoming(Packet::SubAck(suback)) => MqttEvent::SubAck {
+            pkid: suback.pkid,
+            reasons: suback
+                .return_codes
+                .iter()
+                .map(|code| match code {
+                    SubscribeReasonCode::Success(qos) => SubAckReason::Granted(match qos {
+                        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+                        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+                        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+                    }),
+                    other => SubAckReason::Rejected(format!("{:?}", other)),
+                })
+                .collect(),
+        },
+        v5::Event::Incoming(Packet::Publish(publish)) => {
+            MqttEvent::Publish(Box::new(MqttPublish {
+                topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                qos: match publish.qos {
+                    v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+                    v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+                    v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+                },
+                packet: PublishPacket::V5(publish),
+            }))
+        }
+        v5::Event::Outgoing(Outgoing::Subscribe(pkid)) => MqttEvent::Subscribed(pkid),
+        _ => MqttEvent::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+
+    fn v5_publish(properties: Option<PublishProperties>) -> MqttPublish {
+        let mut publish = v5::mqttbytes::v5::Publish::new(
+            "plant/1/alarm",
+            v5::mqttbytes::QoS::AtLeastOnce,
+            r#"{"summary": "hot"}"#,
+            None,
+        );
+        publish.properties = properties;
+        match v5_event(v5::Event::Incoming(v5::mqttbytes::v5::Packet::Publish(
+            publish,
+        ))) {
+            MqttEvent::Publish(message) => *message,
+            _ => panic!("expected a publish"),
+        }
+    }
+
+    #[test]
+    fn v5_properties_become_metadata() {
+        let message = v5_publish(Some(PublishProperties {
+            content_type: Some("application/json".to_string()),
+            correlation_data: Some(vec![0x01, 0xab].into()),
+            user_properties: vec![("source".to_string(), "plc-7".to_string())],
+            ..Default::default()
+        }));
+        assert_eq!(message.topic, "plant/1/alarm");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert_eq!(message.payload(), br#"{"summary": "hot"}"#);
+        assert_eq!(
+            message.metadata().unwrap(),
+            json!({
+                "topic": "plant/1/alarm",
+                "contentType": "application/json",
+                "responseTopic": null,
+                "correlationData": "01ab",
+                "userProperties": {"source": "plc-7"},
+            })
+        );
+        assert!(v5_publish(None).metadata().is_none());
+    }
+
+    #[test]
+    fn suback_reason_codes_are_named() {
+        use v5::mqttbytes::v5::{Packet, SubAck, SubscribeReasonCode};
+        let event = v5_event(v5::Event::Incoming(Packet::SubAck(SubAck {
+            pkid: 3,
+            return_codes: vec![
+                SubscribeReasonCode::Success(v5::mqttbytes::QoS::AtMostOnce),
+                SubscribeReasonCode::NotAuthorized,
+            ],
+            properties: None,
+        })));
+        let MqttEvent::SubAck { pkid, reasons } = event else {
+            panic!("expected a suback");
+        };
+        assert_eq!(pkid, 3);
+        assert_eq!(
+            reasons,
+            vec![
+                SubAckReason::Granted(QoS::AtMostOnce),
+                SubAckReason::Rejected("NotAuthorized".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::config::{ILConfig, Spec};
+use crate::consumers::mqtt::topic_filter_matches;
+use crate::json_util::get_nested_value;
+use crate::models::event::EventQueueItemJson;
+
+#[derive(Debug, Clone)]
+pub enum RouteTopic {
+    /// MQTT topic filter with `+` and `#` wildcards, an exact topic name for Kafka
+    Name(String),
+    Regex(Regex),
+}
+
+impl RouteTopic {
+    /// Takes the optional 'topic' or 'topic_regex' option of a definition.
+    pub fn take(spec: &mut Spec) -> Result<Option<RouteTopic>, String> {
+        match (spec.take("topic"), spec.take("topic_regex")) {
+            (Some(_), Some(_)) => {
+                Err("only one of 'topic' or 'topic_regex' can be set".to_string())
+            }
+            (Some(name), None) => Ok(Some(RouteTopic::Name(name))),
+            (None, Some(pattern)) => Ok(Some(RouteTopic::Regex(
+                Regex::new(&pattern).map_err(|e| format!("invalid 'topic_regex': {}", e))?,
+            ))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn matches(&self, consumer_type: &str, topic: &str) -> bool {
+        match self {
+            RouteTopic::Name(name) if consumer_type == "mqtt" => topic_filter_matches(name, topic),
+            RouteTopic::Name(name) => name == topic,
+            RouteTopic::Regex(regex) => regex.is_match(topic),
+        }
+    }
+}
+
+/**
+    Routes events to an integration key by topic and/or payload field value, e.g.
+    `topic=plant/+/alarm,key=il1api...`, `topic_regex=^alerts-.*,key=il1api...` or
+    `field=customer.id,value=42,key=il1api...`. All given conditions have to match.
+*/
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub topic: Option<RouteTopic>,
+    pub field: Option<(String, String)>,
+    pub key: String,
+}
+
+impl Route {
+    pub fn parse(definition: &str) -> Result<Route, String> {
+        let mut spec = Spec::parse(definition)?;
+        let key = spec
+            .take("key")
+            .filter(|k| !k.is_empty())
+            .ok_or("'key' is required")?;
+
+        let topic = RouteTopic::take(&mut spec)?;
+
+        let field = match (spec.take("field"), spec.take("value")) {
+            (Some(field), Some(value)) if !field.is_empty() => Some((field, value)),
+            (None, None) => None,
+            _ => return Err("'field' and 'value' have to be set together".to_string()),
+        };
+        spec.finish()?;
+
+        if topic.is_none() && field.is_none() {
+            return Err(
+                "at least one of 'topic', 'topic_regex' or 'field' is required".to_string(),
+            );
+        }
+        Ok(Route { topic, field, key })
+    }
+
+    pub fn matches(&self, consumer_type: &str, topic: &str, payload: Option<&Value>) -> bool {
+        if let Some(ref route_topic) = self.topic
+            && !route_topic.matches(consumer_type, topic)
+        {
+            return false;
+        }
+        match self.field {
+            Some((ref path, ref expected)) => payload
+                .and_then(|json| get_nested_value(json, path))
+                .is_some_and(|val| value_matches(val, expected)),
+            None => true,
+        }
+    }
+}
+
+/// Compares a payload value with a configured string, numbers and booleans are parsed first.
+pub(crate) fn value_matches(val: &Value, expected: &str) -> bool {
+    match val {
+        Value::String(s) => s == expected,
+        Value::Number(n) => n.as_f64().is_some_and(|n| expected.parse() == Ok(n)),
+        Value::Bool(b) => expected.parse() == Ok(*b),
+        _ => false,
+    }
+}
+
+/// Integration key of the first matching route, None if no route matches.
+pub fn find_route_key<'a>(
+    config: &'a ILConfig,
+    consumer_type: &str,
+    payload: &str,
+    topic: &str,
+) -> Option<&'a str> {
+    if config.routes.is_empty() {
+        return None;
+    }
+    let json = if config.routes.iter().any(|r| r.field.is_some()) {
+        serde_json::from_str::<Value>(payload).ok()
+    } else {
+        None
+    };
+    config
+        .routes
+        .iter()
+        .find(|route| route.matches(consumer_type, topic, json.as_ref()))
+        .map(|route| route.key.as_str())
+}
+
+/// Overwrites the integration key of the event if a route matches.
+pub fn apply_routes(
+    config: &ILConfig,
+    consumer_type: &str,
+    event: &mut EventQueueItemJson,
+    payload: &str,
+    topic: &str,
+) {
+    if let Some(key) = find_route_key(config, consumer_type, payload, topic) {
+        event.integrationKey = key.to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(routes: &[&str]) -> ILConfig {
+        let mut config = ILConfig::new();
+        config.routes = routes.iter().map(|r| Route::parse(r).unwrap()).collect();
+        config
+    }
+
+    #[test]
+    fn parse_routes() {
+        assert!(Route::parse("topic=a/+,key=k1").is_ok());
+        assert!(Route::parse("topic_regex=^alerts-.*,key=k1").is_ok());
+        assert!(Route::parse("field=site,value=berlin,key=k1").is_ok());
+        assert!(Route::parse("topic=a").is_err());
+        assert!(Route::parse("key=k1").is_err());
+        assert!(Route::parse("topic=a,topic_regex=b,key=k1").is_err());
+        assert!(Route::parse("topic_regex=(,key=k1").is_err());
+        assert!(Route::parse("field=site,key=k1").is_err());
+    }
+
+    #[test]
+    fn topic_routes_follow_consumer_semantics() {
+        let config = config_with(&[
+            "topic=plant/+/alarm,key=k1",
+            "topic_regex=^alerts-.*,key=k2",
+        ]);
+        assert_eq!(
+            find_route_key(&config, "mqtt", "{}", "plant/a/alarm"),
+            Some("k1")
+        );
+        // no wildcards for kafka topic names
+        assert_eq!(
+            find_route_key(&config, "kafka", "{}", "plant/a/alarm"),
+            None
+        );
+        assert_eq!(
+            find_route_key(&config, "kafka", "{}", "alerts-db"),
+            Some("k2")
+        );
+        assert_eq!(find_route_key(&config, "kafka", "{}", "metrics"), None);
+    }
+
+    #[test]
+    fn field_routes_look_up_payload_values() {
+        let config = config_with(&[
+            "topic=alerts,field=customer.id,value=42,key=k1",
+            "field=site,value=berlin,key=k2",
+        ]);
+        let payload = r#"{"customer": {"id": 42}, "site": "berlin"}"#;
+        assert_eq!(
+            find_route_key(&config, "kafka", payload, "alerts"),
+            Some("k1")
+        );
+        assert_eq!(
+            find_route_key(&config, "kafka", payload, "other"),
+            Some("k2")
+        );
+        assert_eq!(
+            find_route_key(&config, "kafka", r#"{"site": "paris"}"#, "alerts"),
+            None
+        );
+        assert_eq!(find_route_key(&config, "kafka", "not json", "alerts"), None);
+    }
+}
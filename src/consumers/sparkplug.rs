@@ -0,0 +1,618 @@
+use ilert::ilert_builders::ILertEventType;
+use log::{debug, info, warn};
+use prost::Message;
+use serde_json::{Map, Value, json};
+use std::collections::{HashMap, HashSet};
+
+use crate::config::ILConfig;
+use crate::consumers::build_event_api_path;
+use crate::consumers::routing::find_route_key;
+use crate::models::event_db::EventQueueItem;
+
+pub const SPARKPLUG_NAMESPACE: &str = "spBv1.0";
+
+/// Sparkplug B payload, only the fields needed to read metric values.
+#[derive(Clone, PartialEq, Message)]
+pub struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(bool, optional, tag = "5")]
+    pub is_historical: Option<bool>,
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    #[prost(oneof = "MetricValue", tags = "10, 11, 12, 13, 14, 15, 16")]
+    pub value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(float, tag = "12")]
+    Float(f32),
+    #[prost(double, tag = "13")]
+    Double(f64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+    #[prost(bytes = "vec", tag = "16")]
+    Bytes(Vec<u8>),
+}
+
+/// `spBv1.0/{group}/{message_type}/{edge_node}[/{device}]`
+#[derive(Debug, PartialEq)]
+pub struct SparkplugTopic<'a> {
+    pub group: &'a str,
+    pub message_type: &'a str,
+    pub edge_node: &'a str,
+    pub device: Option<&'a str>,
+}
+
+impl<'a> SparkplugTopic<'a> {
+    pub fn parse(topic: &'a str) -> Option<SparkplugTopic<'a>> {
+        let mut levels = topic.split('/');
+        if levels.next()? != SPARKPLUG_NAMESPACE {
+            return None;
+        }
+        let group = levels.next()?;
+        let message_type = levels.next()?;
+        let edge_node = levels.next()?;
+        let device = levels.next();
+        if levels.next().is_some() {
+            return None;
+        }
+        Some(SparkplugTopic {
+            group,
+            message_type,
+            edge_node,
+            device,
+        })
+    }
+
+    fn alert_key(&self) -> String {
+        match self.device {
+            Some(device) => format!("sparkplug-{}-{}-{}", self.group, self.edge_node, device),
+            None => format!("sparkplug-{}-{}", self.group, self.edge_node),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.device {
+            Some(device) => format!("device {}/{}/{}", self.group, self.edge_node, device),
+            None => format!("edge node {}/{}", self.group, self.edge_node),
+        }
+    }
+}
+
+pub fn is_sparkplug_topic(topic: &str) -> bool {
+    topic
+        .strip_prefix(SPARKPLUG_NAMESPACE)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[derive(Debug)]
+pub enum SparkplugMessage {
+    /// birth (RESOLVE) or death (ALERT) of an edge node or device, a node death also alerts its devices
+    Lifecycle(Vec<EventQueueItem>),
+    /// metrics of a data message, handled like a JSON event payload
+    Data(Value),
+    Ignored,
+}
+
+/**
+    Metric aliases, birth/death sequence numbers and born devices per edge node, learned from NBIRTH and DBIRTH.
+    Data messages may only carry aliases, an NDEATH is stale if its bdSeq differs from the last NBIRTH.
+    Devices do not send a DDEATH when their edge node dies, so the NDEATH alerts every device still born.
+*/
+#[derive(Debug, Default)]
+pub struct SparkplugState {
+    aliases: HashMap<(String, String), HashMap<u64, String>>,
+    bd_seq: HashMap<(String, String), u64>,
+    devices: HashMap<(String, String), HashSet<String>>,
+}
+
+impl SparkplugState {
+    pub fn handle(
+        &mut self,
+        config: &ILConfig,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<SparkplugMessage, String> {
+        let Some(sp_topic) = SparkplugTopic::parse(topic) else {
+            debug!("Ignoring Sparkplug message on {}", topic);
+            return Ok(SparkplugMessage::Ignored);
+        };
+        let node = (sp_topic.group.to_string(), sp_topic.edge_node.to_string());
+        let decode = || Payload::decode(payload).map_err(|e| e.to_string());
+
+        match sp_topic.message_type {
+            "NBIRTH" => {
+                let payload = decode()?;
+                let aliases = self.aliases.entry(node.clone()).or_default();
+                aliases.clear();
+                learn_aliases(aliases, &payload);
+                // devices of a new session are born again after the NBIRTH
+                self.devices.remove(&node);
+                if let Some(bd_seq) = bd_seq(&payload) {
+                    self.bd_seq.insert(node, bd_seq);
+                }
+                Ok(lifecycle_message(
+                    lifecycle_event(config, topic, &sp_topic, &payload, false)
+                        .into_iter()
+                        .collect(),
+                ))
+            }
+            "DBIRTH" => {
+                let payload = decode()?;
+                learn_aliases(self.aliases.entry(node.clone()).or_default(), &payload);
+                if let Some(device) = sp_topic.device {
+                    self.devices
+                        .entry(node)
+                        .or_default()
+                        .insert(device.to_string());
+                }
+                Ok(lifecycle_message(
+                    lifecycle_event(config, topic, &sp_topic, &payload, false)
+                        .into_iter()
+                        .collect(),
+                ))
+            }
+            "NDEATH" => {
+                let payload = decode()?;
+                if let (Some(current), Some(death)) = (self.bd_seq.get(&node), bd_seq(&payload))
+                    && *current != death
+                {
+                    info!(
+                        "Ignoring stale NDEATH of {} (bdSeq {}, current {})",
+                        sp_topic.describe(),
+                        death,
+                        current
+                    );
+                    return Ok(SparkplugMessage::Ignored);
+                }
+                self.aliases.remove(&node);
+                self.bd_seq.remove(&node);
+                let mut devices: Vec<String> = self
+                    .devices
+                    .remove(&node)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                devices.sort();
+                let mut events: Vec<EventQueueItem> =
+                    lifecycle_event(config, topic, &sp_topic, &payload, true)
+                        .into_iter()
+                        .collect();
+                for device in devices.iter() {
+                    let device_topic = SparkplugTopic {
+                        device: Some(device),
+                        ..sp_topic
+                    };
+                    events.extend(lifecycle_event(
+                        config,
+                        topic,
+                        &device_topic,
+                        &payload,
+                        true,
+                    ));
+                }
+                Ok(lifecycle_message(events))
+            }
+            "DDEATH" => {
+                let payload = decode()?;
+                if let (Some(devices), Some(device)) =
+                    (self.devices.get_mut(&node), sp_topic.device)
+                {
+                    devices.remove(device);
+                }
+                Ok(lifecycle_message(
+                    lifecycle_event(config, topic, &sp_topic, &payload, true)
+                        .into_iter()
+                        .collect(),
+                ))
+            }
+            "NDATA" | "DDATA" => {
+                let payload = decode()?;
+                let aliases = self.aliases.get(&node);
+                Ok(SparkplugMessage::Data(payload_json(
+                    &sp_topic, &payload, aliases,
+                )))
+            }
+            _ => Ok(SparkplugMessage::Ignored),
+        }
+    }
+}
+
+fn learn_aliases(aliases: &mut HashMap<u64, String>, payload: &Payload) {
+    for metric in payload.metrics.iter() {
+        if let (Some(alias), Some(name)) = (metric.alias, metric.name.as_ref()) {
+            aliases.insert(alias, name.clone());
+        }
+    }
+}
+
+fn bd_seq(payload: &Payload) -> Option<u64> {
+    payload
+        .metrics
+        .iter()
+        .find(|m| m.name.as_deref() == Some("bdSeq"))
+        .and_then(|m| match m.value {
+            Some(MetricValue::Long(v)) => Some(v),
+            Some(MetricValue::Int(v)) => Some(v as u64),
+            _ => None,
+        })
+}
+
+/// Metric value by its Sparkplug datatype, signed integers are stored as unsigned.
+fn metric_value(metric: &Metric) -> Value {
+    if metric.is_null == Some(true) {
+        return Value::Null;
+    }
+    match (metric.datatype, metric.value.as_ref()) {
+        (Some(1), Some(MetricValue::Int(v))) => json!(*v as i8),
+        (Some(2), Some(MetricValue::Int(v))) => json!(*v as i16),
+        (Some(3), Some(MetricValue::Int(v))) => json!(*v as i32),
+        (Some(4), Some(MetricValue::Long(v))) => json!(*v as i64),
+        (_, Some(MetricValue::Int(v))) => json!(v),
+        (_, Some(MetricValue::Long(v))) => json!(v),
+        (_, Some(MetricValue::Float(v))) => json!(v),
+        (_, Some(MetricValue::Double(v))) => json!(v),
+        (_, Some(MetricValue::Boolean(v))) => json!(v),
+        (_, Some(MetricValue::String(v))) => json!(v),
+        // bytes, files, datasets and templates are not exposed
+        _ => Value::Null,
+    }
+}
+
+fn payload_json(
+    sp_topic: &SparkplugTopic,
+    payload: &Payload,
+    aliases: Option<&HashMap<u64, String>>,
+) -> Value {
+    let mut metrics = Map::new();
+    for metric in payload.metrics.iter() {
+        if metric.is_historical == Some(true) {
+            continue;
+        }
+        let name = metric.name.clone().or_else(|| {
+            metric
+                .alias
+                .and_then(|alias| aliases.and_then(|a| a.get(&alias)).cloned())
+        });
+        match name {
+            Some(name) => {
+                metrics.insert(name, metric_value(metric));
+            }
+            None => debug!(
+                "Skipping Sparkplug metric with unknown alias {:?} of {}",
+                metric.alias,
+                sp_topic.describe()
+            ),
+        }
+    }
+    json!({
+        "group": sp_topic.group,
+        "messageType": sp_topic.message_type,
+        "edgeNode": sp_topic.edge_node,
+        "device": sp_topic.device,
+        "timestamp": payload.timestamp,
+        "seq": payload.seq,
+        "metrics": metrics,
+    })
+}
+
+fn lifecycle_message(events: Vec<EventQueueItem>) -> SparkplugMessage {
+    if events.is_empty() {
+        SparkplugMessage::Ignored
+    } else {
+        SparkplugMessage::Lifecycle(events)
+    }
+}
+
+fn lifecycle_event(
+    config: &ILConfig,
+    topic: &str,
+    sp_topic: &SparkplugTopic,
+    payload: &Payload,
+    dead: bool,
+) -> Option<EventQueueItem> {
+    let details = payload_json(sp_topic, payload, None);
+    let Some(integration_key) =
+        find_route_key(config, "mqtt", &details.to_string(), topic).or(config.event_key.as_deref())
+    else {
+        warn!(
+            "No integration key for Sparkplug {} of {}, skipping",
+            sp_topic.message_type,
+            sp_topic.describe()
+        );
+        return None;
+    };
+
+    let (event_type, summary) = if dead {
+        (
+            ILertEventType::ALERT,
+            format!("Sparkplug {} is offline", sp_topic.describe()),
+        )
+    } else {
+        (
+            ILertEventType::RESOLVE,
+            format!("Sparkplug {} is online", sp_topic.describe()),
+        )
+    };
+    let mut event = EventQueueItem::new_with_required(
+        integration_key,
+        event_type.as_str(),
+        &summary,
+        Some(sp_topic.alert_key()),
+    );
+    event.event_api_path = Some(build_event_api_path("mqtt", integration_key));
+    event.custom_details = Some(details.to_string());
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: Option<&str>, alias: Option<u64>, datatype: u32, value: MetricValue) -> Metric {
+        Metric {
+            name: name.map(|n| n.to_string()),
+            alias,
+            datatype: Some(datatype),
+            value: Some(value),
+            ..Default::default()
+        }
+    }
+
+    fn encode(metrics: Vec<Metric>) -> Vec<u8> {
+        Payload {
+            timestamp: Some(1_700_000_000_000),
+            metrics,
+            seq: Some(0),
+        }
+        .encode_to_vec()
+    }
+
+    fn config() -> ILConfig {
+        let mut config = ILConfig::new();
+        config.event_key = Some("il1api1".to_string());
+        config
+    }
+
+    fn lifecycle(message: SparkplugMessage) -> EventQueueItem {
+        let mut events = lifecycle_events(message);
+        assert_eq!(events.len(), 1, "expected a single lifecycle event");
+        events.remove(0)
+    }
+
+    fn lifecycle_events(message: SparkplugMessage) -> Vec<EventQueueItem> {
+        match message {
+            SparkplugMessage::Lifecycle(events) => events,
+            other => panic!("expected lifecycle events, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_topics() {
+        assert_eq!(
+            SparkplugTopic::parse("spBv1.0/plant1/DDATA/edge1/pump1"),
+            Some(SparkplugTopic {
+                group: "plant1",
+                message_type: "DDATA",
+                edge_node: "edge1",
+                device: Some("pump1"),
+            })
+        );
+        assert!(SparkplugTopic::parse("spBv1.0/plant1/NDEATH/edge1").is_some());
+        assert!(SparkplugTopic::parse("spBv1.0/STATE/host1").is_none());
+        assert!(SparkplugTopic::parse("spBv1.0/plant1/DDATA/edge1/pump1/x").is_none());
+        assert!(is_sparkplug_topic("spBv1.0/STATE/host1"));
+        assert!(!is_sparkplug_topic("spBv1.0x/plant1"));
+    }
+
+    #[test]
+    fn births_resolve_and_deaths_alert() {
+        let config = config();
+        let mut state = SparkplugState::default();
+
+        let birth = lifecycle(
+            state
+                .handle(
+                    &config,
+                    "spBv1.0/plant1/NBIRTH/edge1",
+                    &encode(vec![metric(Some("bdSeq"), None, 8, MetricValue::Long(3))]),
+                )
+                .unwrap(),
+        );
+        assert_eq!(birth.event_type, "RESOLVE");
+        assert_eq!(birth.alert_key.as_deref(), Some("sparkplug-plant1-edge1"));
+        assert_eq!(birth.integration_key, "il1api1");
+
+        let device_death = lifecycle(
+            state
+                .handle(
+                    &config,
+                    "spBv1.0/plant1/DDEATH/edge1/pump1",
+                    &encode(vec![]),
+                )
+                .unwrap(),
+        );
+        assert_eq!(device_death.event_type, "ALERT");
+        assert_eq!(
+            device_death.alert_key.as_deref(),
+            Some("sparkplug-plant1-edge1-pump1")
+        );
+        assert_eq!(
+            device_death.summary,
+            "Sparkplug device plant1/edge1/pump1 is offline"
+        );
+
+        // a death of an earlier session must not open an alert
+        let stale = state
+            .handle(
+                &config,
+                "spBv1.0/plant1/NDEATH/edge1",
+                &encode(vec![metric(Some("bdSeq"), None, 8, MetricValue::Long(2))]),
+            )
+            .unwrap();
+        assert!(matches!(stale, SparkplugMessage::Ignored));
+
+        let death = lifecycle(
+            state
+                .handle(
+                    &config,
+                    "spBv1.0/plant1/NDEATH/edge1",
+                    &encode(vec![metric(Some("bdSeq"), None, 8, MetricValue::Long(3))]),
+                )
+                .unwrap(),
+        );
+        assert_eq!(death.event_type, "ALERT");
+        assert_eq!(death.summary, "Sparkplug edge node plant1/edge1 is offline");
+    }
+
+    #[test]
+    fn node_death_alerts_born_devices() {
+        let config = config();
+        let mut state = SparkplugState::default();
+        let born = encode(vec![metric(Some("bdSeq"), None, 8, MetricValue::Long(1))]);
+        state
+            .handle(&config, "spBv1.0/plant1/NBIRTH/edge1", &born)
+            .unwrap();
+        for device in ["pump1", "pump2", "valve1"] {
+            state
+                .handle(
+                    &config,
+                    &format!("spBv1.0/plant1/DBIRTH/edge1/{}", device),
+                    &encode(vec![]),
+                )
+                .unwrap();
+        }
+        state
+            .handle(
+                &config,
+                "spBv1.0/plant1/DDEATH/edge1/valve1",
+                &encode(vec![]),
+            )
+            .unwrap();
+
+        let events = lifecycle_events(
+            state
+                .handle(&config, "spBv1.0/plant1/NDEATH/edge1", &born)
+                .unwrap(),
+        );
+        let keys: Vec<_> = events.iter().map(|e| e.alert_key.as_deref()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                Some("sparkplug-plant1-edge1"),
+                Some("sparkplug-plant1-edge1-pump1"),
+                Some("sparkplug-plant1-edge1-pump2"),
+            ]
+        );
+        assert!(events.iter().all(|e| e.event_type == "ALERT"));
+        assert_eq!(
+            events[1].summary,
+            "Sparkplug device plant1/edge1/pump1 is offline"
+        );
+
+        // a new session forgets the devices until they are born again
+        state
+            .handle(&config, "spBv1.0/plant1/NBIRTH/edge1", &born)
+            .unwrap();
+        state
+            .handle(
+                &config,
+                "spBv1.0/plant1/DBIRTH/edge1/pump2",
+                &encode(vec![]),
+            )
+            .unwrap();
+        let events = lifecycle_events(
+            state
+                .handle(&config, "spBv1.0/plant1/NDEATH/edge1", &born)
+                .unwrap(),
+        );
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].alert_key.as_deref(),
+            Some("sparkplug-plant1-edge1-pump2")
+        );
+    }
+
+    #[test]
+    fn data_metrics_are_resolved_by_alias() {
+        let config = config();
+        let mut state = SparkplugState::default();
+        state
+            .handle(
+                &config,
+                "spBv1.0/plant1/DBIRTH/edge1/pump1",
+                &encode(vec![
+                    metric(Some("Temperature"), Some(1), 10, MetricValue::Double(20.0)),
+                    metric(Some("Offset"), Some(2), 3, MetricValue::Int(0)),
+                ]),
+            )
+            .unwrap();
+
+        let mut historical = metric(None, Some(1), 10, MetricValue::Double(99.0));
+        historical.is_historical = Some(true);
+        let data = state
+            .handle(
+                &config,
+                "spBv1.0/plant1/DDATA/edge1/pump1",
+                &encode(vec![
+                    metric(None, Some(1), 10, MetricValue::Double(81.5)),
+                    metric(None, Some(2), 3, MetricValue::Int(-4i32 as u32)),
+                    metric(Some("Running"), None, 11, MetricValue::Boolean(true)),
+                    metric(None, Some(9), 10, MetricValue::Double(1.0)),
+                    historical,
+                ]),
+            )
+            .unwrap();
+        let SparkplugMessage::Data(json) = data else {
+            panic!("expected data, got {:?}", data);
+        };
+        assert_eq!(json["device"], "pump1");
+        assert_eq!(json["messageType"], "DDATA");
+        assert_eq!(
+            json["metrics"],
+            json!({"Temperature": 81.5, "Offset": -4, "Running": true})
+        );
+    }
+
+    #[test]
+    fn invalid_payloads_and_missing_keys() {
+        let mut state = SparkplugState::default();
+        assert!(
+            state
+                .handle(&config(), "spBv1.0/plant1/NDATA/edge1", &[0x12, 0xff])
+                .is_err()
+        );
+        let without_key = state
+            .handle(
+                &ILConfig::new(),
+                "spBv1.0/plant1/NDEATH/edge1",
+                &encode(vec![]),
+            )
+            .unwrap();
+        assert!(matches!(without_key, SparkplugMessage::Ignored));
+    }
+}
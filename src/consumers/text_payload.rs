@@ -0,0 +1,185 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// Fields of the named capture groups, groups that did not participate are left out.
+pub fn parse_regex(regex: &Regex, payload: &str) -> Result<Value, String> {
+    let captures = regex
+        .captures(payload.trim())
+        .ok_or("payload does not match the pattern")?;
+    let mut object = Map::new();
+    for name in regex.capture_names().flatten() {
+        if let Some(value) = captures.name(name) {
+            object.insert(name.to_string(), Value::String(value.as_str().to_string()));
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+/**
+    Fields of the first CSV record, named by the configured columns or by a header line
+    in front of it. Columns without a name are skipped, dots in names create nested objects.
+*/
+pub fn parse_csv(
+    columns: Option<&[String]>,
+    delimiter: u8,
+    payload: &str,
+) -> Result<Value, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(columns.is_none())
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(payload.as_bytes());
+    let header: Vec<String> = match columns {
+        Some(columns) => columns.to_vec(),
+        None => reader
+            .headers()
+            .map_err(|e| format!("invalid csv header: {}", e))?
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+    };
+    let record = reader
+        .records()
+        .next()
+        .ok_or("no csv record")?
+        .map_err(|e| format!("invalid csv record: {}", e))?;
+
+    let mut object = Map::new();
+    for (name, value) in header.iter().zip(record.iter()) {
+        if !name.is_empty() {
+            insert_field(&mut object, name, Value::String(value.to_string()));
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+/**
+    Fields of a logfmt line like `level=error msg="disk full" host=db1 critical`,
+    keys without a value are set to true and dots in keys create nested objects.
+*/
+pub fn parse_logfmt(payload: &str) -> Result<Value, String> {
+    let mut object = Map::new();
+    let mut chars = payload.trim().chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            if c == '"' {
+                return Err(format!("unexpected quote in key '{}'", key));
+            }
+            key.push(c);
+        }
+        if key.is_empty() {
+            if chars.peek().is_some() {
+                return Err("missing key before '='".to_string());
+            }
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            insert_field(&mut object, &key, Value::Bool(true));
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some(c) => value.push(c),
+                        None => return Err(format!("unterminated value of '{}'", key)),
+                    },
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated value of '{}'", key)),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        insert_field(&mut object, &key, Value::String(value));
+    }
+
+    if object.is_empty() {
+        return Err("no key=value pairs".to_string());
+    }
+    Ok(Value::Object(object))
+}
+
+fn insert_field(object: &mut Map<String, Value>, name: &str, value: Value) {
+    match name.split_once('.') {
+        Some((parent, rest)) if !parent.is_empty() && !rest.is_empty() => {
+            let child = object
+                .entry(parent.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                insert_field(child, rest, value);
+            }
+        }
+        _ => {
+            object.insert(name.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn regex_named_groups_become_fields() {
+        let regex = Regex::new(
+            r"^(?P<eventType>\w+) (?P<alertKey>\w+) (?P<value>[\d.]+)(?: (?P<unit>\w+))?$",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_regex(&regex, "ALARM high_temp 87.2\n").unwrap(),
+            json!({"eventType": "ALARM", "alertKey": "high_temp", "value": "87.2"})
+        );
+        assert!(parse_regex(&regex, "hello").is_err());
+    }
+
+    #[test]
+    fn csv_records_are_mapped_by_columns_or_header() {
+        let columns = vec![
+            "eventType".to_string(),
+            String::new(),
+            "device.id".to_string(),
+        ];
+        assert_eq!(
+            parse_csv(Some(&columns), b',', "ALERT, ignored,\"s,1\"").unwrap(),
+            json!({"eventType": "ALERT", "device": {"id": "s,1"}})
+        );
+        assert_eq!(
+            parse_csv(None, b';', "eventType;summary\nRESOLVE;back to normal\n").unwrap(),
+            json!({"eventType": "RESOLVE", "summary": "back to normal"})
+        );
+        assert!(parse_csv(None, b',', "eventType,summary").is_err());
+    }
+
+    #[test]
+    fn logfmt_pairs_become_fields() {
+        assert_eq!(
+            parse_logfmt(r#"level=error msg="disk \"/\" full" host.name=db1 critical empty="#)
+                .unwrap(),
+            json!({
+                "level": "error",
+                "msg": "disk \"/\" full",
+                "host": {"name": "db1"},
+                "critical": true,
+                "empty": "",
+            })
+        );
+        assert!(parse_logfmt("").is_err());
+        assert!(parse_logfmt("=value").is_err());
+        assert!(parse_logfmt(r#"msg="unterminated"#).is_err());
+    }
+}
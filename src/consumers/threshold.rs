@@ -0,0 +1,495 @@
+use log::{debug, info, warn};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+use crate::config::{ILConfig, Spec};
+use crate::consumers::mqtt::topic_filter_matches;
+use crate::db::ILDatabase;
+use crate::json_util::get_nested_value;
+use crate::models::event_db::EventQueueItem;
+use ilert::ilert_builders::ILertEventType;
+
+const STATE_PENDING: &str = "pending";
+const STATE_ALERTING: &str = "alerting";
+
+/**
+    Compares a numeric payload value of telemetry messages against thresholds,
+    e.g. `name=boiler_temp,topic=plant/+/temp,path=value,above=80,hysteresis=5,for=60,key_path=device.id`.
+    Messages on a matching topic are evaluated by the rule instead of being parsed as events.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdRule {
+    pub name: String,
+    pub topic: String,
+    pub path: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    /// distance the value has to move back into range before the alert resolves
+    pub hysteresis: f64,
+    /// how long the value has to stay out of range before the alert opens
+    pub min_duration: Duration,
+    pub integration_key: Option<String>,
+    pub key_path: Option<String>,
+    pub key_level: Option<usize>,
+}
+
+fn take_f64(spec: &mut Spec, key: &str) -> Result<Option<f64>, String> {
+    match spec.take(key) {
+        Some(val) => match val.parse::<f64>() {
+            Ok(num) if num.is_finite() => Ok(Some(num)),
+            _ => Err(format!("'{}' must be a number, got '{}'", key, val)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl ThresholdRule {
+    pub fn parse(definition: &str) -> Result<ThresholdRule, String> {
+        let mut spec = Spec::parse(definition)?;
+        let name = spec
+            .take("name")
+            .filter(|n| !n.is_empty())
+            .ok_or("'name' is required")?;
+        let topic = spec
+            .take("topic")
+            .filter(|t| !t.is_empty())
+            .ok_or("'topic' is required")?;
+        let path = spec
+            .take("path")
+            .filter(|p| !p.is_empty())
+            .ok_or("'path' is required")?;
+        let above = take_f64(&mut spec, "above")?;
+        let below = take_f64(&mut spec, "below")?;
+        let hysteresis = take_f64(&mut spec, "hysteresis")?.unwrap_or(0.0);
+        let min_duration = Duration::from_secs(spec.take_u64("for")?.unwrap_or(0));
+        let integration_key = spec.take("integration_key");
+        let key_path = spec.take("key_path");
+        let key_level = spec.take_u64("key_level")?.map(|level| level as usize);
+        spec.finish()?;
+
+        if above.is_none() && below.is_none() {
+            return Err("at least one of 'above' or 'below' is required".to_string());
+        }
+        if hysteresis < 0.0 {
+            return Err("'hysteresis' must not be negative".to_string());
+        }
+        if let (Some(above), Some(below)) = (above, below)
+            && below >= above
+        {
+            return Err("'below' must be lower than 'above'".to_string());
+        }
+        // the value has to clear both bands at once to resolve the alert
+        if let (Some(above), Some(below)) = (above, below)
+            && above - hysteresis < below + hysteresis
+        {
+            return Err(format!(
+                "'hysteresis' must be at most {}, half the distance between 'below' and 'above'",
+                (above - below) / 2.0
+            ));
+        }
+        if key_path.is_some() && key_level.is_some() {
+            return Err("only one of 'key_path' or 'key_level' can be set".to_string());
+        }
+
+        Ok(ThresholdRule {
+            name,
+            topic,
+            path,
+            above,
+            below,
+            hysteresis,
+            min_duration,
+            integration_key,
+            key_path,
+            key_level,
+        })
+    }
+
+    pub fn matches_topic(&self, topic: &str) -> bool {
+        topic_filter_matches(&self.topic, topic)
+    }
+
+    pub fn is_breached(&self, value: f64) -> bool {
+        self.above.is_some_and(|above| value > above)
+            || self.below.is_some_and(|below| value < below)
+    }
+
+    pub fn is_cleared(&self, value: f64) -> bool {
+        self.above
+            .is_none_or(|above| value <= above - self.hysteresis)
+            && self
+                .below
+                .is_none_or(|below| value >= below + self.hysteresis)
+    }
+
+    /// Per-device alert key from a payload field, a topic level or the full topic.
+    pub fn alert_key(&self, json: &Value, topic: &str) -> Option<String> {
+        let device = if let Some(ref key_path) = self.key_path {
+            match get_nested_value(json, key_path)? {
+                Value::String(s) => s.to_string(),
+                Value::Number(n) => n.to_string(),
+                _ => return None,
+            }
+        } else if let Some(level) = self.key_level {
+            topic.split('/').nth(level)?.to_string()
+        } else {
+            topic.to_string()
+        };
+        Some(format!("{}-{}", self.name, device))
+    }
+
+    fn describe_breach(&self, value: f64) -> String {
+        match (self.above, self.below) {
+            (Some(above), _) if value > above => format!("above {}", above),
+            (_, Some(below)) if value < below => format!("below {}", below),
+            _ => "out of range".to_string(),
+        }
+    }
+}
+
+pub fn has_rules_for(config: &ILConfig, topic: &str) -> bool {
+    config
+        .thresholds
+        .iter()
+        .any(|rule| rule.matches_topic(topic))
+}
+
+fn numeric_value(val: &Value) -> Option<f64> {
+    val.as_f64()
+        .or_else(|| val.as_str().and_then(|s| s.trim().parse::<f64>().ok()))
+}
+
+/// ALERT or RESOLVE of a rule, its state only changes once the event was queued.
+#[derive(Debug)]
+pub struct ThresholdTransition {
+    pub event: EventQueueItem,
+    rule: String,
+    alert_key: String,
+}
+
+impl ThresholdTransition {
+    /// Marks the rule alerting after its ALERT and clears it after its RESOLVE.
+    pub fn record(&self, db: &ILDatabase) -> Result<usize, rusqlite::Error> {
+        if self.event.event_type == ILertEventType::ALERT.as_str() {
+            db.set_threshold_state(&self.rule, &self.alert_key, STATE_ALERTING)
+        } else {
+            db.delete_threshold_state(&self.rule, &self.alert_key)
+        }
+    }
+}
+
+fn build_threshold_event(
+    rule: &ThresholdRule,
+    integration_key: &str,
+    alert_key: &str,
+    event_type: ILertEventType,
+    value: f64,
+    topic: &str,
+) -> EventQueueItem {
+    let summary = if matches!(event_type, ILertEventType::ALERT) {
+        format!(
+            "{} is {}, {}",
+            rule.name,
+            value,
+            rule.describe_breach(value)
+        )
+    } else {
+        format!("{} is back in range at {}", rule.name, value)
+    };
+    let mut event = EventQueueItem::new_with_required(
+        integration_key,
+        event_type.as_str(),
+        &summary,
+        Some(alert_key.to_string()),
+    );
+    event.custom_details = Some(
+        json!({
+            "rule": rule.name,
+            "topic": topic,
+            "value": value,
+            "above": rule.above,
+            "below": rule.below,
+        })
+        .to_string(),
+    );
+    event
+}
+
+/**
+    Evaluates all rules matching the topic against the payload and returns the transitions
+    to queue. State is kept in SQLite per rule and alert key: a breach first becomes
+    pending and opens the alert once it lasted `for` seconds, measured on the next
+    message, a cleared value resolves an open alert. Callers record a transition after
+    its event was queued, so a rejected event is retried with the next message.
+*/
+pub fn evaluate(
+    config: &ILConfig,
+    db: &ILDatabase,
+    payload: &str,
+    topic: &str,
+) -> Result<Vec<ThresholdTransition>, rusqlite::Error> {
+    let json: Value = match serde_json::from_str(payload) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Invalid telemetry payload json on topic {}: {}", topic, e);
+            return Ok(Vec::new());
+        }
+    };
+
+    let mut events = Vec::new();
+    for rule in config.thresholds.iter().filter(|r| r.matches_topic(topic)) {
+        let Some(value) = get_nested_value(&json, &rule.path).and_then(numeric_value) else {
+            debug!(
+                "Threshold {} skipped, no numeric value at '{}' on topic {}",
+                rule.name, rule.path, topic
+            );
+            continue;
+        };
+        let Some(integration_key) = rule
+            .integration_key
+            .as_deref()
+            .or(config.event_key.as_deref())
+        else {
+            warn!("Threshold {} has no integration key, skipping", rule.name);
+            continue;
+        };
+        let Some(alert_key) = rule.alert_key(&json, topic) else {
+            warn!(
+                "Threshold {} skipped, no alert key in message on topic {}",
+                rule.name, topic
+            );
+            continue;
+        };
+
+        let state = db.get_threshold_state(&rule.name, &alert_key)?;
+        let state = state.as_ref().map(|(s, age)| (s.as_str(), *age));
+        match state {
+            Some((STATE_ALERTING, _)) => {
+                if rule.is_cleared(value) {
+                    info!(
+                        "Threshold {} cleared for {} at {}",
+                        rule.name, alert_key, value
+                    );
+                    events.push(ThresholdTransition {
+                        event: build_threshold_event(
+                            rule,
+                            integration_key,
+                            &alert_key,
+                            ILertEventType::RESOLVE,
+                            value,
+                            topic,
+                        ),
+                        rule: rule.name.clone(),
+                        alert_key,
+                    });
+                }
+            }
+            _ if !rule.is_breached(value) => {
+                if state.is_some() {
+                    db.delete_threshold_state(&rule.name, &alert_key)?;
+                }
+            }
+            Some((STATE_PENDING, age)) if age < rule.min_duration.as_secs() => {}
+            None if !rule.min_duration.is_zero() => {
+                db.set_threshold_state(&rule.name, &alert_key, STATE_PENDING)?;
+            }
+            _ => {
+                info!(
+                    "Threshold {} crossed for {} at {}",
+                    rule.name, alert_key, value
+                );
+                events.push(ThresholdTransition {
+                    event: build_threshold_event(
+                        rule,
+                        integration_key,
+                        &alert_key,
+                        ILertEventType::ALERT,
+                        value,
+                        topic,
+                    ),
+                    rule: rule.name.clone(),
+                    alert_key,
+                });
+            }
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn config_with(rule: &str) -> ILConfig {
+        let mut config = ILConfig::new();
+        config.event_key = Some("il1api1".to_string());
+        config.thresholds = vec![ThresholdRule::parse(rule).unwrap()];
+        config
+    }
+
+    /// Evaluates and records every transition, as if all events were queued.
+    fn evaluate_queued(
+        config: &ILConfig,
+        db: &ILDatabase,
+        payload: &str,
+        topic: &str,
+    ) -> Result<Vec<EventQueueItem>, rusqlite::Error> {
+        let mut events = Vec::new();
+        for transition in evaluate(config, db, payload, topic)? {
+            transition.record(db)?;
+            events.push(transition.event);
+        }
+        Ok(events)
+    }
+
+    fn types(events: &[EventQueueItem]) -> Vec<&str> {
+        events.iter().map(|e| e.event_type.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_rule() {
+        let rule = ThresholdRule::parse(
+            "name=temp,topic=plant/+/temp,path=data.value,above=80,hysteresis=2.5,for=60,key_level=1",
+        )
+        .unwrap();
+        assert_eq!(rule.above, Some(80.0));
+        assert_eq!(rule.below, None);
+        assert_eq!(rule.hysteresis, 2.5);
+        assert_eq!(rule.min_duration, Duration::from_secs(60));
+        assert_eq!(rule.key_level, Some(1));
+
+        assert!(ThresholdRule::parse("name=t,topic=a,path=v").is_err());
+        assert!(ThresholdRule::parse("name=t,topic=a,path=v,above=hot").is_err());
+        assert!(ThresholdRule::parse("name=t,topic=a,path=v,above=1,below=5").is_err());
+        assert_eq!(
+            ThresholdRule::parse("name=t,topic=a,path=v,above=20,below=10,hysteresis=6"),
+            Err(
+                "'hysteresis' must be at most 5, half the distance between 'below' and 'above'"
+                    .to_string()
+            )
+        );
+        assert!(
+            ThresholdRule::parse("name=t,topic=a,path=v,above=20,below=10,hysteresis=5").is_ok()
+        );
+        assert!(
+            ThresholdRule::parse("name=t,topic=a,path=v,above=1,key_path=id,key_level=1").is_err()
+        );
+    }
+
+    #[test]
+    fn hysteresis_keeps_alert_open_until_cleared() {
+        let rule =
+            ThresholdRule::parse("name=t,topic=a,path=v,above=80,below=10,hysteresis=5").unwrap();
+        assert!(rule.is_breached(81.0));
+        assert!(rule.is_breached(9.0));
+        assert!(!rule.is_breached(80.0));
+        assert!(!rule.is_cleared(76.0));
+        assert!(rule.is_cleared(75.0));
+        assert!(!rule.is_cleared(14.0));
+    }
+
+    #[test]
+    fn alert_key_from_payload_or_topic() {
+        let json = json!({"device": {"id": 7}});
+        let by_path =
+            ThresholdRule::parse("name=t,topic=#,path=v,above=1,key_path=device.id").unwrap();
+        assert_eq!(by_path.alert_key(&json, "plant/a/temp").unwrap(), "t-7");
+        let by_level = ThresholdRule::parse("name=t,topic=#,path=v,above=1,key_level=1").unwrap();
+        assert_eq!(by_level.alert_key(&json, "plant/a/temp").unwrap(), "t-a");
+        assert!(by_level.alert_key(&json, "plant").is_none());
+        let by_topic = ThresholdRule::parse("name=t,topic=#,path=v,above=1").unwrap();
+        assert_eq!(
+            by_topic.alert_key(&json, "plant/a/temp").unwrap(),
+            "t-plant/a/temp"
+        );
+    }
+
+    #[test]
+    fn crossing_opens_and_clearing_resolves() {
+        let (db, _f) = temp_db();
+        let config = config_with(
+            "name=temp,topic=plant/+/temp,path=value,above=80,hysteresis=5,key_level=1",
+        );
+        let topic = "plant/boiler/temp";
+
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":70}"#, topic)
+                .unwrap()
+                .is_empty()
+        );
+        let events = evaluate_queued(&config, &db, r#"{"value":85}"#, topic).unwrap();
+        assert_eq!(types(&events), vec!["ALERT"]);
+        assert_eq!(events[0].alert_key.as_deref(), Some("temp-boiler"));
+        assert_eq!(events[0].integration_key, "il1api1");
+        // still breached or inside the hysteresis band
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":90}"#, topic)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":"78"}"#, topic)
+                .unwrap()
+                .is_empty()
+        );
+        let events = evaluate_queued(&config, &db, r#"{"value":74}"#, topic).unwrap();
+        assert_eq!(types(&events), vec!["RESOLVE"]);
+        // other topics and payloads without the value are ignored
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":99}"#, "office/temp")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            evaluate_queued(&config, &db, r#"{"other":99}"#, topic)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn min_duration_delays_alert() {
+        let (db, _f) = temp_db();
+        let config = config_with("name=temp,topic=t,path=value,above=80,for=60");
+
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":85}"#, "t")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":86}"#, "t")
+                .unwrap()
+                .is_empty()
+        );
+        // back in range resets the pending breach
+        assert!(
+            evaluate_queued(&config, &db, r#"{"value":50}"#, "t")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(db.get_threshold_state("temp", "temp-t").unwrap().is_none());
+    }
+
+    #[test]
+    fn unrecorded_transition_is_retried() {
+        let (db, _f) = temp_db();
+        let config = config_with("name=temp,topic=t,path=value,above=80");
+
+        // the ALERT was not queued, so the next breach raises it again
+        let rejected = evaluate(&config, &db, r#"{"value":85}"#, "t").unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert!(db.get_threshold_state("temp", "temp-t").unwrap().is_none());
+        let events = evaluate_queued(&config, &db, r#"{"value":86}"#, "t").unwrap();
+        assert_eq!(types(&events), vec!["ALERT"]);
+
+        // the same for a RESOLVE, the alert stays open until it is queued
+        let rejected = evaluate(&config, &db, r#"{"value":50}"#, "t").unwrap();
+        assert_eq!(rejected[0].event.event_type, "RESOLVE");
+        assert!(db.get_threshold_state("temp", "temp-t").unwrap().is_some());
+        let events = evaluate_queued(&config, &db, r#"{"value":50}"#, "t").unwrap();
+        assert_eq!(types(&events), vec!["RESOLVE"]);
+        assert!(db.get_threshold_state("temp", "temp-t").unwrap().is_none());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+
+use crate::config::{ILConfig, Spec};
+use crate::models::event::EventQueueItemJson;
+
+pub type TopicVars = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternLevel {
+    Literal(String),
+    Var(String),
+    Any,
+    Rest,
+}
+
+/**
+    Captures MQTT topic levels as named variables, e.g. `plant/{site}/sensor/{device}`.
+    Besides `{name}` a level can be `+` (any level, not captured) and the last level
+    can be `#` (any remaining levels).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicPattern {
+    levels: Vec<PatternLevel>,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<TopicPattern, String> {
+        let parts: Vec<&str> = pattern.split('/').collect();
+        let mut levels = Vec::new();
+        for (idx, part) in parts.iter().enumerate() {
+            let level = match *part {
+                "+" => PatternLevel::Any,
+                "#" if idx == parts.len() - 1 => PatternLevel::Rest,
+                "#" => return Err("'#' is only allowed as the last level".to_string()),
+                _ if part.starts_with('{') && part.ends_with('}') => {
+                    let name = &part[1..part.len() - 1];
+                    if name.is_empty() || name == "topic" || name == "summary" {
+                        return Err(format!("invalid variable name '{}'", name));
+                    }
+                    if levels.contains(&PatternLevel::Var(name.to_string())) {
+                        return Err(format!("duplicate variable '{}'", name));
+                    }
+                    PatternLevel::Var(name.to_string())
+                }
+                _ if part.contains(['{', '}', '+', '#']) => {
+                    return Err(format!("invalid topic level '{}'", part));
+                }
+                _ => PatternLevel::Literal(part.to_string()),
+            };
+            levels.push(level);
+        }
+        if !levels.iter().any(|l| matches!(l, PatternLevel::Var(_))) {
+            return Err("at least one '{variable}' level is required".to_string());
+        }
+        Ok(TopicPattern { levels })
+    }
+
+    /// Variables of the topic, None if the topic does not match the pattern.
+    pub fn capture(&self, topic: &str) -> Option<TopicVars> {
+        let topic_parts: Vec<&str> = topic.split('/').collect();
+        let mut vars = TopicVars::new();
+        for (idx, level) in self.levels.iter().enumerate() {
+            if *level == PatternLevel::Rest {
+                return Some(vars);
+            }
+            let part = topic_parts.get(idx)?;
+            match level {
+                PatternLevel::Literal(lit) if lit != part => return None,
+                PatternLevel::Var(name) => {
+                    vars.insert(name.clone(), part.to_string());
+                }
+                _ => {}
+            }
+        }
+        (self.levels.len() == topic_parts.len()).then_some(vars)
+    }
+}
+
+/// Integration key for events whose topic variable has a certain value, e.g. `var=site,value=berlin,key=il1api...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicVarKey {
+    pub var: String,
+    pub value: String,
+    pub key: String,
+}
+
+impl TopicVarKey {
+    pub fn parse(definition: &str) -> Result<TopicVarKey, String> {
+        let mut spec = Spec::parse(definition)?;
+        let var = spec
+            .take("var")
+            .filter(|v| !v.is_empty())
+            .ok_or("'var' is required")?;
+        let value = spec.take("value").ok_or("'value' is required")?;
+        let key = spec
+            .take("key")
+            .filter(|k| !k.is_empty())
+            .ok_or("'key' is required")?;
+        spec.finish()?;
+        Ok(TopicVarKey { var, value, key })
+    }
+}
+
+/// Parses a `--filter_var` definition like `site=berlin`.
+pub fn parse_filter_var(definition: &str) -> Result<(String, String), String> {
+    match definition.split_once('=') {
+        Some((var, value)) if !var.trim().is_empty() => {
+            Ok((var.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected 'variable=value', got '{}'", definition)),
+    }
+}
+
+/// Replaces `{name}` placeholders, None if a placeholder has no value.
+pub fn render_template(template: &str, vars: &TopicVars) -> Option<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(vars.get(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Some(rendered)
+}
+
+/// Variables of the message topic, empty if no pattern is configured or the topic does not match.
+pub fn capture_topic_vars(config: &ILConfig, topic: &str) -> TopicVars {
+    config
+        .topic_pattern
+        .as_ref()
+        .and_then(|pattern| pattern.capture(topic))
+        .unwrap_or_default()
+}
+
+pub fn matches_filter_vars(config: &ILConfig, vars: &TopicVars) -> bool {
+    config
+        .filter_vars
+        .iter()
+        .all(|(var, value)| vars.get(var) == Some(value))
+}
+
+/// Applies integration key selection and the alertKey and summary templates to the event.
+pub fn apply_topic_vars(
+    config: &ILConfig,
+    event: &mut EventQueueItemJson,
+    vars: &TopicVars,
+    topic: &str,
+) {
+    if let Some(var_key) = config
+        .topic_var_keys
+        .iter()
+        .find(|k| vars.get(&k.var) == Some(&k.value))
+    {
+        event.integrationKey = var_key.key.clone();
+    }
+
+    let mut template_vars = vars.clone();
+    template_vars.insert("topic".to_string(), topic.to_string());
+    template_vars.insert("summary".to_string(), event.summary.clone());
+
+    if let Some(ref template) = config.alert_key_template
+        && let Some(alert_key) = render_template(template, &template_vars)
+    {
+        event.alertKey = Some(alert_key);
+    }
+
+    if let Some(ref template) = config.summary_template
+        && let Some(summary) = render_template(template, &template_vars)
+    {
+        event.summary = summary;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> TopicVars {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn pattern_captures_named_levels() {
+        let pattern = TopicPattern::parse("plant/{site}/sensor/{device}").unwrap();
+        assert_eq!(
+            pattern.capture("plant/berlin/sensor/t1"),
+            Some(vars(&[("site", "berlin"), ("device", "t1")]))
+        );
+        assert_eq!(pattern.capture("plant/berlin/actor/t1"), None);
+        assert_eq!(pattern.capture("plant/berlin/sensor"), None);
+        assert_eq!(pattern.capture("plant/berlin/sensor/t1/temp"), None);
+
+        let pattern = TopicPattern::parse("plant/{site}/+/#").unwrap();
+        assert_eq!(
+            pattern.capture("plant/berlin/sensor/t1/temp"),
+            Some(vars(&[("site", "berlin")]))
+        );
+    }
+
+    #[test]
+    fn pattern_rejects_invalid_definitions() {
+        assert!(TopicPattern::parse("plant/+/sensor").is_err());
+        assert!(TopicPattern::parse("plant/#/{device}").is_err());
+        assert!(TopicPattern::parse("plant/{a}/{a}").is_err());
+        assert!(TopicPattern::parse("plant/{}/x").is_err());
+        assert!(TopicPattern::parse("plant/{topic}").is_err());
+        assert!(TopicPattern::parse("plant/x{a}").is_err());
+    }
+
+    #[test]
+    fn render_template_requires_all_vars() {
+        let vars = vars(&[("site", "berlin"), ("device", "t1")]);
+        assert_eq!(
+            render_template("{site}-{device}", &vars).unwrap(),
+            "berlin-t1"
+        );
+        assert_eq!(render_template("static", &vars).unwrap(), "static");
+        assert_eq!(render_template("{site}-{line}", &vars), None);
+        assert_eq!(render_template("{site", &vars), None);
+    }
+
+    #[test]
+    fn topic_vars_route_and_rename_event() {
+        let mut config = ILConfig::new();
+        config.topic_var_keys =
+            vec![TopicVarKey::parse("var=site,value=berlin,key=il1api2").unwrap()];
+        config.alert_key_template = Some("{site}-{device}".to_string());
+        config.summary_template = Some("{device} in {site}: {summary}".to_string());
+
+        let mut event = EventQueueItemJson::from_transition(
+            serde_json::from_str(r#"{"apiKey":"il1api1","summary":"too hot"}"#).unwrap(),
+        );
+        apply_topic_vars(
+            &config,
+            &mut event,
+            &vars(&[("site", "berlin"), ("device", "t1")]),
+            "plant/berlin/sensor/t1",
+        );
+        assert_eq!(event.integrationKey, "il1api2");
+        assert_eq!(event.alertKey.unwrap(), "berlin-t1");
+        assert_eq!(event.summary, "t1 in berlin: too hot");
+    }
+
+    #[test]
+    fn filter_vars_require_matching_values() {
+        let mut config = ILConfig::new();
+        assert!(matches_filter_vars(&config, &TopicVars::new()));
+        config.filter_vars = vec![parse_filter_var("site=berlin").unwrap()];
+        assert!(matches_filter_vars(&config, &vars(&[("site", "berlin")])));
+        assert!(!matches_filter_vars(&config, &vars(&[("site", "paris")])));
+        assert!(!matches_filter_vars(&config, &TopicVars::new()));
+        assert!(parse_filter_var("berlin").is_err());
+    }
+}
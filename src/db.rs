@@ -4,6 +4,9 @@ use rusqlite::types::ToSql;
 use rusqlite::{Connection, Row};
 use uuid::Uuid;
 
+use crate::models::alert_state::AlertStateItem;
+use crate::models::coalesce_audit::CoalesceAuditItem;
+use crate::models::device_state::DeviceStateItem;
 use crate::models::event_db::EventQueueItem;
 use crate::models::mqtt_queue::MqttQueueItem;
 use ilert::ilert_builders::ILertEventType;
@@ -14,6 +17,24 @@ const DB_MIGRATION_V2: &str = "mig_2";
 const DB_MIGRATION_V3: &str = "mig_3";
 const DB_MIGRATION_V4: &str = "mig_4";
 const DB_MIGRATION_V5: &str = "mig_5";
+const DB_MIGRATION_V6: &str = "mig_6";
+const DB_MIGRATION_V7: &str = "mig_7";
+const DB_MIGRATION_V8: &str = "mig_8";
+const DB_MIGRATION_V9: &str = "mig_9";
+const DB_MIGRATION_V10: &str = "mig_10";
+
+// approximate payload size of a queued row in bytes
+const EVENT_ITEM_SIZE_SQL: &str =
+    "LENGTH(CAST(integration_key AS BLOB)) + LENGTH(CAST(event_type AS BLOB))
+    + LENGTH(CAST(summary AS BLOB)) + IFNULL(LENGTH(CAST(alert_key AS BLOB)), 0)
+    + IFNULL(LENGTH(CAST(details AS BLOB)), 0) + IFNULL(LENGTH(CAST(priority AS BLOB)), 0)
+    + IFNULL(LENGTH(CAST(images AS BLOB)), 0) + IFNULL(LENGTH(CAST(links AS BLOB)), 0)
+    + IFNULL(LENGTH(CAST(custom_details AS BLOB)), 0)";
+const MQTT_QUEUE_ITEM_SIZE_SQL: &str =
+    "LENGTH(CAST(topic AS BLOB)) + LENGTH(CAST(payload AS BLOB))";
+const OLDEST_FIRST_SQL: &str = "inserted_at ASC, rowid ASC";
+// LOW before events without priority before HIGH
+const PRIORITY_RANK_SQL: &str = "CASE priority WHEN 'LOW' THEN 0 WHEN 'HIGH' THEN 2 ELSE 1 END";
 
 #[derive(Debug)]
 struct ILAgentItem {
@@ -149,6 +170,117 @@ impl ILDatabase {
             info!("Database migrated to {}", DB_MIGRATION_V5);
         }
 
+        let mig_6 = self.get_il_value(DB_MIGRATION_V6);
+        if mig_6.is_none() {
+            self.conn
+                .execute(
+                    "ALTER TABLE event_items ADD COLUMN not_before DATETIME NULL",
+                    [],
+                )
+                .expect("Database migration failed (v6, 1)");
+
+            self.conn
+                .execute(
+                    "CREATE TABLE event_dedup (
+                      integration_key    TEXT NOT NULL,
+                      alert_key          TEXT NOT NULL,
+                      event_type         TEXT NOT NULL,
+                      last_seen          DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+                      PRIMARY KEY (integration_key, alert_key)
+                  )",
+                    [],
+                )
+                .expect("Database migration failed (v6, 2)");
+
+            self.set_il_val(DB_MIGRATION_V6, DB_MIGRATION_VAL)
+                .expect("Database migration failed (v6, set)");
+            info!("Database migrated to {}", DB_MIGRATION_V6);
+        }
+
+        let mig_7 = self.get_il_value(DB_MIGRATION_V7);
+        if mig_7.is_none() {
+            self.conn
+                .execute(
+                    "CREATE TABLE event_coalesce_audit (
+                      integration_key    TEXT NOT NULL,
+                      alert_key          TEXT NOT NULL,
+                      kept_event_ids     TEXT NOT NULL,
+                      dropped_events     TEXT NOT NULL,
+                      coalesced_at       DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+                  )",
+                    [],
+                )
+                .expect("Database migration failed (v7)");
+
+            self.set_il_val(DB_MIGRATION_V7, DB_MIGRATION_VAL)
+                .expect("Database migration failed (v7, set)");
+            info!("Database migrated to {}", DB_MIGRATION_V7);
+        }
+
+        let mig_8 = self.get_il_value(DB_MIGRATION_V8);
+        if mig_8.is_none() {
+            self.conn
+                .execute(
+                    "CREATE TABLE alert_state (
+                      integration_key    TEXT NOT NULL,
+                      alert_key          TEXT NOT NULL,
+                      summary            TEXT NOT NULL,
+                      event_api_path     TEXT NULL,
+                      resolve_after      DATETIME NOT NULL,
+                      last_alert_at      DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+                      PRIMARY KEY (integration_key, alert_key)
+                  )",
+                    [],
+                )
+                .expect("Database migration failed (v8)");
+
+            self.set_il_val(DB_MIGRATION_V8, DB_MIGRATION_VAL)
+                .expect("Database migration failed (v8, set)");
+            info!("Database migrated to {}", DB_MIGRATION_V8);
+        }
+
+        let mig_9 = self.get_il_value(DB_MIGRATION_V9);
+        if mig_9.is_none() {
+            self.conn
+                .execute(
+                    "CREATE TABLE threshold_state (
+                      rule_name          TEXT NOT NULL,
+                      alert_key          TEXT NOT NULL,
+                      state              TEXT NOT NULL,
+                      since              DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+                      PRIMARY KEY (rule_name, alert_key)
+                  )",
+                    [],
+                )
+                .expect("Database migration failed (v9)");
+
+            self.set_il_val(DB_MIGRATION_V9, DB_MIGRATION_VAL)
+                .expect("Database migration failed (v9, set)");
+            info!("Database migrated to {}", DB_MIGRATION_V9);
+        }
+
+        let mig_10 = self.get_il_value(DB_MIGRATION_V10);
+        if mig_10.is_none() {
+            self.conn
+                .execute(
+                    "CREATE TABLE device_state (
+                      watch_name         TEXT NOT NULL,
+                      device             TEXT NOT NULL,
+                      event_api_path     TEXT NULL,
+                      silent_after       DATETIME NOT NULL,
+                      silent             INTEGER NOT NULL DEFAULT 0,
+                      last_seen          DATETIME DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+                      PRIMARY KEY (watch_name, device)
+                  )",
+                    [],
+                )
+                .expect("Database migration failed (v10)");
+
+            self.set_il_val(DB_MIGRATION_V10, DB_MIGRATION_VAL)
+                .expect("Database migration failed (v10, set)");
+            info!("Database migrated to {}", DB_MIGRATION_V10);
+        }
+
         /*
         Run simple db migrations, if needed, like this:
 
@@ -280,8 +412,13 @@ impl ILDatabase {
     }
 
     pub fn get_il_events(&self, limit: i32) -> Result<Vec<EventQueueItem>, rusqlite::Error> {
-        let mut stmt = self.conn.prepare("SELECT id, integration_key, event_type, alert_key, summary, created_at,
-         priority, images, links, custom_details, details, event_api_path FROM event_items ORDER BY inserted_at ASC LIMIT ?1")?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, integration_key, event_type, alert_key, summary, created_at,
+         priority, images, links, custom_details, details, event_api_path FROM event_items
+         WHERE not_before IS NULL OR not_before <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+         ORDER BY {} LIMIT ?1",
+            OLDEST_FIRST_SQL
+        ))?;
         let query_result =
             stmt.query_map(&[&limit], |row| ILDatabase::convert_db_row_to_event(row));
 
@@ -335,11 +472,407 @@ impl ILDatabase {
         }
     }
 
+    /// Runs `f` in a transaction that is rolled back if it fails.
+    pub fn in_transaction<T, E: From<rusqlite::Error>>(
+        &self,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f()?;
+        tx.commit()?;
+        Ok(result)
+    }
+
     pub fn delete_il_event(&self, id: &str) -> Result<usize, rusqlite::Error> {
         self.conn
             .execute("DELETE FROM event_items WHERE id = ?1", &[&id])
     }
 
+    /// Moves an event behind all queued events, the new rowid also orders it after events of the same millisecond.
+    pub fn requeue_il_event(&self, id: &str) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE event_items SET inserted_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'),
+                rowid = (SELECT MAX(rowid) + 1 FROM event_items)
+                WHERE id = ?1",
+            [id],
+        )
+    }
+
+    /// Deliverable events and comments of every alert that has more than one event (without comments)
+    /// queued, grouped by integration key and alert key, oldest first.
+    pub fn get_il_events_to_coalesce(&self) -> Result<Vec<EventQueueItem>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, integration_key, event_type, alert_key, summary, created_at,
+         priority, images, links, custom_details, details, event_api_path FROM event_items
+         WHERE alert_key IS NOT NULL AND alert_key != ''
+            AND (not_before IS NULL OR not_before <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            AND (integration_key, alert_key) IN (SELECT integration_key, alert_key FROM event_items
+                WHERE event_type != 'COMMENT'
+                    AND (not_before IS NULL OR not_before <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+                GROUP BY integration_key, alert_key HAVING COUNT(*) > 1)
+         ORDER BY integration_key, alert_key, inserted_at ASC, rowid ASC",
+        )?;
+        let items = stmt.query_map([], ILDatabase::convert_db_row_to_event)?;
+        items.collect()
+    }
+
+    pub fn create_il_coalesce_audit(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+        kept_event_ids: &str,
+        dropped_events: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO event_coalesce_audit (integration_key, alert_key, kept_event_ids, dropped_events)
+                VALUES (?1, ?2, ?3, ?4)",
+            [integration_key, alert_key, kept_event_ids, dropped_events],
+        )
+    }
+
+    pub fn get_il_coalesce_audit(
+        &self,
+        limit: i32,
+    ) -> Result<Vec<CoalesceAuditItem>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT integration_key, alert_key, kept_event_ids, dropped_events, coalesced_at
+                FROM event_coalesce_audit ORDER BY coalesced_at DESC, rowid DESC LIMIT ?1",
+        )?;
+        let items = stmt.query_map([limit], |row| {
+            Ok(CoalesceAuditItem {
+                integration_key: row.get(0)?,
+                alert_key: row.get(1)?,
+                kept_event_ids: row.get(2)?,
+                dropped_events: row.get(3)?,
+                coalesced_at: row.get(4).unwrap_or(None),
+            })
+        })?;
+        items.collect()
+    }
+
+    pub fn delete_expired_il_coalesce_audit(
+        &self,
+        max_age_days: u32,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM event_coalesce_audit WHERE coalesced_at < STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?1)",
+            [format!("-{} days", max_age_days)],
+        )
+    }
+
+    /// Records an open alert that is resolved automatically after `timeout_secs` without a new ALERT.
+    pub fn upsert_alert_state(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+        summary: &str,
+        event_api_path: Option<&str>,
+        timeout_secs: u64,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO alert_state (integration_key, alert_key, summary, event_api_path, resolve_after)
+                VALUES (?1, ?2, ?3, ?4, STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?5))
+                ON CONFLICT(integration_key, alert_key) DO UPDATE
+                SET summary = ?3, event_api_path = ?4,
+                    resolve_after = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?5),
+                    last_alert_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')",
+            [
+                &integration_key as &dyn ToSql,
+                &alert_key,
+                &summary,
+                &event_api_path,
+                &format!("+{} seconds", timeout_secs),
+            ],
+        )
+    }
+
+    pub fn delete_alert_state(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM alert_state WHERE integration_key = ?1 AND alert_key = ?2",
+            [integration_key, alert_key],
+        )
+    }
+
+    /// Open alerts whose timeout passed without a new ALERT, oldest first.
+    pub fn get_expired_alert_states(
+        &self,
+        limit: i32,
+    ) -> Result<Vec<AlertStateItem>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT integration_key, alert_key, summary, event_api_path, last_alert_at FROM alert_state
+                WHERE resolve_after <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+                ORDER BY resolve_after ASC LIMIT ?1",
+        )?;
+        let items = stmt.query_map([limit], |row| {
+            Ok(AlertStateItem {
+                integration_key: row.get(0)?,
+                alert_key: row.get(1)?,
+                summary: row.get(2)?,
+                event_api_path: row.get(3)?,
+                last_alert_at: row.get(4).unwrap_or(None),
+            })
+        })?;
+        items.collect()
+    }
+
+    /// State of a threshold rule for one alert key and the seconds since it was entered.
+    pub fn get_threshold_state(
+        &self,
+        rule_name: &str,
+        alert_key: &str,
+    ) -> Result<Option<(String, u64)>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT state, (JULIANDAY('NOW') - JULIANDAY(since)) * 86400.0 FROM threshold_state
+                WHERE rule_name = ?1 AND alert_key = ?2",
+        )?;
+        let mut rows = stmt.query([rule_name, alert_key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get::<_, f64>(1)?.max(0.0) as u64))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_threshold_state(
+        &self,
+        rule_name: &str,
+        alert_key: &str,
+        state: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO threshold_state (rule_name, alert_key, state) VALUES (?1, ?2, ?3)
+                ON CONFLICT(rule_name, alert_key) DO UPDATE
+                SET state = ?3, since = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')",
+            [rule_name, alert_key, state],
+        )
+    }
+
+    pub fn delete_threshold_state(
+        &self,
+        rule_name: &str,
+        alert_key: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM threshold_state WHERE rule_name = ?1 AND alert_key = ?2",
+            [rule_name, alert_key],
+        )
+    }
+
+    /// Records a message of the device, returns true if the device is marked as silent.
+    /// The flag stays set until `clear_device_silent`, once the RESOLVE of the device was queued.
+    pub fn touch_device(
+        &self,
+        watch_name: &str,
+        device: &str,
+        event_api_path: Option<&str>,
+        interval_secs: u64,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT silent FROM device_state WHERE watch_name = ?1 AND device = ?2")?;
+        let mut rows = stmt.query([watch_name, device])?;
+        let was_silent = match rows.next()? {
+            Some(row) => row.get::<_, i64>(0)? != 0,
+            None => false,
+        };
+        self.conn.execute(
+            "INSERT INTO device_state (watch_name, device, event_api_path, silent_after)
+                VALUES (?1, ?2, ?3, STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?4))
+                ON CONFLICT(watch_name, device) DO UPDATE
+                SET event_api_path = ?3,
+                    silent_after = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?4),
+                    last_seen = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')",
+            [
+                &watch_name as &dyn ToSql,
+                &device,
+                &event_api_path,
+                &format!("+{} seconds", interval_secs),
+            ],
+        )?;
+        Ok(was_silent)
+    }
+
+    /// Devices that exceeded their interval and were not yet marked as silent.
+    pub fn get_newly_silent_devices(
+        &self,
+        limit: i32,
+    ) -> Result<Vec<DeviceStateItem>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT watch_name, device, event_api_path, last_seen FROM device_state
+                WHERE silent = 0 AND silent_after <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+                ORDER BY silent_after ASC LIMIT ?1",
+        )?;
+        let items = stmt.query_map([limit], |row| {
+            Ok(DeviceStateItem {
+                watch_name: row.get(0)?,
+                device: row.get(1)?,
+                event_api_path: row.get(2)?,
+                last_seen: row.get(3).unwrap_or(None),
+            })
+        })?;
+        items.collect()
+    }
+
+    pub fn set_device_silent(
+        &self,
+        watch_name: &str,
+        device: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE device_state SET silent = 1 WHERE watch_name = ?1 AND device = ?2",
+            [watch_name, device],
+        )
+    }
+
+    pub fn clear_device_silent(
+        &self,
+        watch_name: &str,
+        device: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE device_state SET silent = 0 WHERE watch_name = ?1 AND device = ?2",
+            [watch_name, device],
+        )
+    }
+
+    pub fn delete_device_state(
+        &self,
+        watch_name: &str,
+        device: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM device_state WHERE watch_name = ?1 AND device = ?2",
+            [watch_name, device],
+        )
+    }
+
+    /// Keeps the event in the queue without delivering it for the next `secs` seconds.
+    pub fn hold_il_event(&self, id: &str, secs: u64) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE event_items SET not_before = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?2) WHERE id = ?1",
+            [id, format!("+{} seconds", secs).as_str()],
+        )
+    }
+
+    /// Removes held (not yet delivered) events of the given alert and type.
+    pub fn delete_held_il_events(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+        event_type: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM event_items WHERE integration_key = ?1 AND alert_key = ?2
+                AND event_type = ?3 AND not_before IS NOT NULL",
+            [integration_key, alert_key, event_type],
+        )
+    }
+
+    /// Last accepted event type of an alert and its age in seconds.
+    pub fn get_il_event_dedup(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+    ) -> Result<Option<(String, u64)>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT event_type, (JULIANDAY('NOW') - JULIANDAY(last_seen)) * 86400.0 FROM event_dedup
+                WHERE integration_key = ?1 AND alert_key = ?2",
+        )?;
+        let mut rows = stmt.query([integration_key, alert_key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get::<_, f64>(1)?.max(0.0) as u64))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_il_event_dedup(
+        &self,
+        integration_key: &str,
+        alert_key: &str,
+        event_type: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO event_dedup (integration_key, alert_key, event_type) VALUES (?1, ?2, ?3)
+                ON CONFLICT(integration_key, alert_key) DO UPDATE
+                SET event_type = ?3, last_seen = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')",
+            [integration_key, alert_key, event_type],
+        )
+    }
+
+    pub fn delete_expired_il_event_dedup(
+        &self,
+        max_age_secs: u64,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM event_dedup WHERE last_seen < STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?1)",
+            [format!("-{} seconds", max_age_secs)],
+        )
+    }
+
+    /// Age in seconds of the oldest queued event, None if the queue is empty.
+    pub fn get_oldest_il_event_age_secs(&self) -> Result<Option<u64>, rusqlite::Error> {
+        let age: Option<f64> = self.conn.query_row(
+            "SELECT (JULIANDAY('NOW') - JULIANDAY(MIN(inserted_at))) * 86400.0 FROM event_items",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(age.map(|secs| secs.max(0.0) as u64))
+    }
+
+    pub fn count_il_events(&self) -> Result<u64, rusqlite::Error> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM event_items", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|count| count as u64)
+    }
+
+    /// Number of queued events and their approximate size in bytes.
+    pub fn get_il_events_usage(&self) -> Result<(u64, u64), rusqlite::Error> {
+        self.get_queue_usage("event_items", EVENT_ITEM_SIZE_SQL)
+    }
+
+    /**
+        Ids of the events to drop so that at least `min_items` and `min_bytes` are freed, None if that is not possible.
+        With `below_priority` only events of a lower priority rank are dropped, lowest priority and oldest first.
+    */
+    pub fn select_il_events_to_evict(
+        &self,
+        below_priority: Option<u8>,
+        min_items: u64,
+        min_bytes: u64,
+    ) -> Result<Option<Vec<String>>, rusqlite::Error> {
+        match below_priority {
+            Some(rank) => self.select_queue_items_to_evict(
+                "event_items",
+                EVENT_ITEM_SIZE_SQL,
+                &format!("{} < {}", PRIORITY_RANK_SQL, rank),
+                &format!("{} ASC, {}", PRIORITY_RANK_SQL, OLDEST_FIRST_SQL),
+                min_items,
+                min_bytes,
+            ),
+            None => self.select_queue_items_to_evict(
+                "event_items",
+                EVENT_ITEM_SIZE_SQL,
+                "1",
+                OLDEST_FIRST_SQL,
+                min_items,
+                min_bytes,
+            ),
+        }
+    }
+
+    pub fn delete_il_events(&self, ids: &[String]) -> Result<usize, rusqlite::Error> {
+        let mut deleted = 0;
+        for id in ids {
+            deleted += self.delete_il_event(id)?;
+        }
+        Ok(deleted)
+    }
+
     pub fn create_mqtt_queue_item(
         &self,
         topic: &str,
@@ -373,4 +906,115 @@ impl ILDatabase {
         self.conn
             .execute("DELETE FROM mqtt_queue WHERE id = ?1", &[&id])
     }
+
+    /// Age in seconds of the oldest buffered MQTT message, None if the buffer is empty.
+    pub fn get_oldest_mqtt_queue_item_age_secs(&self) -> Result<Option<u64>, rusqlite::Error> {
+        let age: Option<f64> = self.conn.query_row(
+            "SELECT (JULIANDAY('NOW') - JULIANDAY(MIN(inserted_at))) * 86400.0 FROM mqtt_queue",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(age.map(|secs| secs.max(0.0) as u64))
+    }
+
+    pub fn count_mqtt_queue_items(&self) -> Result<u64, rusqlite::Error> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM mqtt_queue", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|count| count as u64)
+    }
+
+    /// Number of buffered MQTT messages and their approximate size in bytes.
+    pub fn get_mqtt_queue_usage(&self) -> Result<(u64, u64), rusqlite::Error> {
+        self.get_queue_usage("mqtt_queue", MQTT_QUEUE_ITEM_SIZE_SQL)
+    }
+
+    /// Ids of the oldest buffered messages that free at least `min_items` and `min_bytes`, None if that is not possible.
+    pub fn select_mqtt_queue_items_to_evict(
+        &self,
+        min_items: u64,
+        min_bytes: u64,
+    ) -> Result<Option<Vec<String>>, rusqlite::Error> {
+        self.select_queue_items_to_evict(
+            "mqtt_queue",
+            MQTT_QUEUE_ITEM_SIZE_SQL,
+            "1",
+            OLDEST_FIRST_SQL,
+            min_items,
+            min_bytes,
+        )
+    }
+
+    pub fn delete_mqtt_queue_items(&self, ids: &[String]) -> Result<usize, rusqlite::Error> {
+        let mut deleted = 0;
+        for id in ids {
+            deleted += self.delete_mqtt_queue_item(id)?;
+        }
+        Ok(deleted)
+    }
+
+    fn get_queue_usage(&self, table: &str, size_sql: &str) -> Result<(u64, u64), rusqlite::Error> {
+        self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*), IFNULL(SUM({}), 0) FROM {}",
+                size_sql, table
+            ),
+            [],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )
+    }
+
+    fn select_queue_items_to_evict(
+        &self,
+        table: &str,
+        size_sql: &str,
+        filter_sql: &str,
+        order_sql: &str,
+        min_items: u64,
+        min_bytes: u64,
+    ) -> Result<Option<Vec<String>>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, {} FROM {} WHERE {} ORDER BY {}",
+            size_sql, table, filter_sql, order_sql
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        let mut ids = Vec::new();
+        let mut freed_bytes = 0;
+        while (ids.len() as u64) < min_items || freed_bytes < min_bytes {
+            let Some(row) = rows.next()? else {
+                return Ok(None);
+            };
+            ids.push(row.get::<_, String>(0)?);
+            freed_bytes += row.get::<_, i64>(1)? as u64;
+        }
+        Ok(Some(ids))
+    }
+
+    /// Adds to a persistent counter in the ilagent table and returns the new total.
+    pub fn add_il_counter(&self, key: &str, amount: u64) -> Result<u64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO ilagent (key, val, created_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(key) DO UPDATE SET val = CAST(val AS INTEGER) + CAST(?2 AS INTEGER)",
+            [
+                &key as &dyn ToSql,
+                &amount.to_string(),
+                &Utc::now().to_string(),
+            ],
+        )?;
+        Ok(self
+            .get_il_value(key)
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(0))
+    }
+}
+
+/// Prepared database in a temporary file for unit tests, removed when the file is dropped.
+#[cfg(test)]
+pub(crate) fn temp_db() -> (ILDatabase, tempfile::NamedTempFile) {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let db = ILDatabase::new(file.path().to_str().unwrap());
+    db.prepare_database();
+    (db, file)
 }
@@ -0,0 +1,191 @@
+use log::info;
+use std::time::Duration;
+
+use crate::config::ILConfig;
+use crate::db::ILDatabase;
+use crate::models::event_db::EventQueueItem;
+use ilert::ilert_builders::ILertEventType;
+
+#[derive(Debug, PartialEq)]
+pub enum DedupDecision {
+    Accept,
+    /// queue the event, but only deliver it after the given delay
+    Hold(Duration),
+    Suppress(String),
+}
+
+pub fn is_enabled(config: &ILConfig) -> bool {
+    config.dedup_window.is_some() || config.resolve_hold.is_some()
+}
+
+/**
+    Decides what to do with an event before it is queued, based on state in SQLite.
+    With `--resolve_hold` a RESOLVE is held back, a new ALERT for the same alert cancels
+    the held RESOLVE and is dropped itself, as the alert never resolved in ilert.
+    With `--dedup_window` an event is dropped if the last queued event of the same
+    `integrationKey` + `alertKey` had the same `eventType` and is younger than the window.
+    Events without an alert key are always accepted. Accepted events are only remembered
+    by `record_event` once they were queued.
+*/
+pub fn check_event(
+    config: &ILConfig,
+    db: &ILDatabase,
+    event: &EventQueueItem,
+) -> Result<DedupDecision, rusqlite::Error> {
+    let Some(alert_key) = event.alert_key.as_deref().filter(|k| !k.is_empty()) else {
+        return Ok(DedupDecision::Accept);
+    };
+    let integration_key = event.integration_key.as_str();
+    let alert = ILertEventType::ALERT.as_str();
+    let resolve = ILertEventType::RESOLVE.as_str();
+
+    if config.resolve_hold.is_some() && event.event_type == alert {
+        let cancelled = db.delete_held_il_events(integration_key, alert_key, resolve)?;
+        if cancelled > 0 {
+            db.set_il_event_dedup(integration_key, alert_key, alert)?;
+            let total = db.add_il_counter("flap_suppressed", 1)?;
+            info!(
+                "Alert {} is flapping, cancelled held RESOLVE ({} suppressed in total)",
+                alert_key, total
+            );
+            return Ok(DedupDecision::Suppress(
+                "alert is flapping, held RESOLVE was cancelled".to_string(),
+            ));
+        }
+    }
+
+    if let Some(window) = config.dedup_window {
+        db.delete_expired_il_event_dedup(window.as_secs())?;
+        if let Some((last_type, age)) = db.get_il_event_dedup(integration_key, alert_key)?
+            && last_type == event.event_type
+            && age < window.as_secs()
+        {
+            let total = db.add_il_counter("dedup_suppressed", 1)?;
+            info!(
+                "Dropping duplicate {} for alert {} seen {}s ago ({} suppressed in total)",
+                event.event_type, alert_key, age, total
+            );
+            return Ok(DedupDecision::Suppress(format!(
+                "duplicate {} within {}s",
+                event.event_type,
+                window.as_secs()
+            )));
+        }
+    }
+
+    match config.resolve_hold {
+        Some(hold) if event.event_type == resolve => Ok(DedupDecision::Hold(hold)),
+        _ => Ok(DedupDecision::Accept),
+    }
+}
+
+/// Remembers the event type of a queued event for the deduplication of the next ones.
+pub fn record_event(db: &ILDatabase, event: &EventQueueItem) -> Result<(), rusqlite::Error> {
+    if let Some(alert_key) = event.alert_key.as_deref().filter(|k| !k.is_empty()) {
+        db.set_il_event_dedup(
+            event.integration_key.as_str(),
+            alert_key,
+            event.event_type.as_str(),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    /// Decides like `queue::create_event`, which records every event that is not suppressed.
+    fn check_and_record(
+        config: &ILConfig,
+        db: &ILDatabase,
+        event: &EventQueueItem,
+    ) -> DedupDecision {
+        let decision = check_event(config, db, event).unwrap();
+        if !matches!(decision, DedupDecision::Suppress(_)) {
+            record_event(db, event).unwrap();
+        }
+        decision
+    }
+
+    fn event(event_type: &str, alert_key: Option<&str>) -> EventQueueItem {
+        EventQueueItem::new_with_required(
+            "k1",
+            event_type,
+            "sensor",
+            alert_key.map(|k| k.to_string()),
+        )
+    }
+
+    #[test]
+    fn disabled_accepts_everything() {
+        let (db, _f) = temp_db();
+        let config = ILConfig::new();
+        assert!(!is_enabled(&config));
+        for _ in 0..3 {
+            assert_eq!(
+                check_and_record(&config, &db, &event("ALERT", Some("a1"))),
+                DedupDecision::Accept
+            );
+        }
+    }
+
+    #[test]
+    fn dedup_drops_repeated_event_type() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.dedup_window = Some(Duration::from_secs(60));
+
+        let alert = event("ALERT", Some("a1"));
+        assert_eq!(
+            check_and_record(&config, &db, &alert),
+            DedupDecision::Accept
+        );
+        assert!(matches!(
+            check_and_record(&config, &db, &alert),
+            DedupDecision::Suppress(_)
+        ));
+        // other alerts and events without alert key are independent
+        assert_eq!(
+            check_and_record(&config, &db, &event("ALERT", Some("a2"))),
+            DedupDecision::Accept
+        );
+        assert_eq!(
+            check_and_record(&config, &db, &event("ALERT", None)),
+            DedupDecision::Accept
+        );
+        // a state change is never a duplicate
+        assert_eq!(
+            check_and_record(&config, &db, &event("RESOLVE", Some("a1"))),
+            DedupDecision::Accept
+        );
+        assert_eq!(
+            check_and_record(&config, &db, &alert),
+            DedupDecision::Accept
+        );
+    }
+
+    #[test]
+    fn resolve_is_held_and_cancelled_by_new_alert() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.resolve_hold = Some(Duration::from_secs(30));
+
+        let resolve = event("RESOLVE", Some("a1"));
+        assert_eq!(
+            check_and_record(&config, &db, &resolve),
+            DedupDecision::Hold(Duration::from_secs(30))
+        );
+        let queued = db.create_il_event(&resolve).unwrap().unwrap();
+        db.hold_il_event(queued.id.as_deref().unwrap(), 30).unwrap();
+        assert!(db.get_il_events(10).unwrap().is_empty());
+
+        assert!(matches!(
+            check_and_record(&config, &db, &event("ALERT", Some("a1"))),
+            DedupDecision::Suppress(_)
+        ));
+        assert_eq!(db.count_il_events().unwrap(), 0);
+        assert_eq!(db.get_il_value("flap_suppressed"), Some("1".to_string()));
+    }
+}
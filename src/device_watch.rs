@@ -0,0 +1,377 @@
+use log::{error, info, warn};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::DaemonContext;
+use crate::config::{ILConfig, Spec};
+use crate::consumers::build_event_api_path;
+use crate::consumers::mqtt::topic_filter_matches;
+use crate::db::ILDatabase;
+use crate::json_util::get_nested_value;
+use crate::models::device_state::DeviceStateItem;
+use crate::models::event_db::EventQueueItem;
+use crate::queue::{self, QueueError};
+use ilert::ilert_builders::ILertEventType;
+
+const DEVICE_WATCH_BATCH_SIZE: i32 = 50;
+
+/**
+    Watches devices publishing on matching topics for silence,
+    e.g. `name=sensors,topic=plant/+/status,interval=300,key_level=1`.
+    The device is taken from `key_path`, `key_level` or the full topic; a device that published nothing
+    within `interval` seconds opens an ALERT with the alert key `{name}-{device}`, its next message resolves it.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceWatch {
+    pub name: String,
+    pub topic: String,
+    pub interval: Duration,
+    pub integration_key: Option<String>,
+    pub key_path: Option<String>,
+    pub key_level: Option<usize>,
+}
+
+impl DeviceWatch {
+    pub fn parse(definition: &str) -> Result<DeviceWatch, String> {
+        let mut spec = Spec::parse(definition)?;
+        let name = spec
+            .take("name")
+            .filter(|n| !n.is_empty())
+            .ok_or("'name' is required")?;
+        let topic = spec
+            .take("topic")
+            .filter(|t| !t.is_empty())
+            .ok_or("'topic' is required")?;
+        let interval = spec.take_u64("interval")?.ok_or("'interval' is required")?;
+        if interval == 0 {
+            return Err("'interval' must be greater than 0".to_string());
+        }
+        let integration_key = spec.take("integration_key").filter(|k| !k.is_empty());
+        let key_path = spec.take("key_path");
+        let key_level = spec.take_u64("key_level")?.map(|level| level as usize);
+        spec.finish()?;
+
+        if key_path.is_some() && key_level.is_some() {
+            return Err("only one of 'key_path' or 'key_level' can be set".to_string());
+        }
+        Ok(DeviceWatch {
+            name,
+            topic,
+            interval: Duration::from_secs(interval),
+            integration_key,
+            key_path,
+            key_level,
+        })
+    }
+
+    pub fn matches_topic(&self, topic: &str) -> bool {
+        topic_filter_matches(&self.topic, topic)
+    }
+
+    pub fn device(&self, payload: &str, topic: &str) -> Option<String> {
+        if let Some(ref key_path) = self.key_path {
+            let json = serde_json::from_str::<Value>(payload).ok()?;
+            match get_nested_value(&json, key_path)? {
+                Value::String(s) => Some(s.to_string()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            }
+        } else if let Some(level) = self.key_level {
+            topic.split('/').nth(level).map(|l| l.to_string())
+        } else {
+            Some(topic.to_string())
+        }
+    }
+
+    pub fn alert_key(&self, device: &str) -> String {
+        format!("{}-{}", self.name, device)
+    }
+
+    fn integration_key<'a>(&'a self, config: &'a ILConfig) -> Option<&'a str> {
+        self.integration_key
+            .as_deref()
+            .or(config.event_key.as_deref())
+    }
+}
+
+fn build_device_event(
+    watch: &DeviceWatch,
+    integration_key: &str,
+    device: &str,
+    event_type: ILertEventType,
+    event_api_path: Option<String>,
+    last_seen: Option<&str>,
+) -> EventQueueItem {
+    let summary = if matches!(event_type, ILertEventType::ALERT) {
+        format!(
+            "Device {} silent for more than {}s ({})",
+            device,
+            watch.interval.as_secs(),
+            watch.name
+        )
+    } else {
+        format!("Device {} reported again ({})", device, watch.name)
+    };
+    let mut event = EventQueueItem::new_with_required(
+        integration_key,
+        event_type.as_str(),
+        &summary,
+        Some(watch.alert_key(device)),
+    );
+    event.event_api_path = event_api_path;
+    event.custom_details = Some(
+        json!({
+            "watch": watch.name,
+            "device": device,
+            "interval": watch.interval.as_secs(),
+            "lastSeen": last_seen,
+        })
+        .to_string(),
+    );
+    event
+}
+
+/// Records the message for every matching watch, queues a RESOLVE for devices that were silent.
+/// A device stays silent until its RESOLVE is queued, so a rejected RESOLVE is retried with the next message.
+pub fn observe(
+    config: &ILConfig,
+    db: &ILDatabase,
+    consumer_type: &str,
+    payload: &str,
+    topic: &str,
+) -> Result<(), rusqlite::Error> {
+    for watch in config
+        .device_watches
+        .iter()
+        .filter(|w| w.matches_topic(topic))
+    {
+        let Some(device) = watch.device(payload, topic) else {
+            warn!(
+                "Device watch {} skipped, no device in message on topic {}",
+                watch.name, topic
+            );
+            continue;
+        };
+        let Some(integration_key) = watch.integration_key(config) else {
+            warn!(
+                "Device watch {} has no integration key, skipping",
+                watch.name
+            );
+            continue;
+        };
+        let event_api_path = build_event_api_path(consumer_type, integration_key);
+        let was_silent = db.touch_device(
+            &watch.name,
+            &device,
+            Some(event_api_path.as_str()),
+            watch.interval.as_secs(),
+        )?;
+        if was_silent {
+            info!("Device {} of watch {} reported again", device, watch.name);
+            let event = build_device_event(
+                watch,
+                integration_key,
+                &device,
+                ILertEventType::RESOLVE,
+                Some(event_api_path),
+                None,
+            );
+            match queue::create_event(config, db, &event) {
+                Ok(_) | Err(QueueError::Suppressed(_)) => {}
+                Err(QueueError::Full) => {
+                    warn!(
+                        "{} not queued ({}), queue is full",
+                        event.summary, event.event_type
+                    );
+                    continue;
+                }
+                Err(QueueError::Db(e)) => return Err(e),
+            }
+            db.clear_device_silent(&watch.name, &device)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+    Queues an ALERT for every device that became silent, returns the number of alerted devices.
+    A device is only marked as silent once its ALERT is queued, so its return resolves an existing alert.
+*/
+pub fn alert_silent_devices(config: &ILConfig, db: &ILDatabase) -> Result<usize, rusqlite::Error> {
+    let states = db.get_newly_silent_devices(DEVICE_WATCH_BATCH_SIZE)?;
+    let mut alerted = 0;
+    for state in states.iter() {
+        let Some(event) = build_silence_event(config, state) else {
+            // nothing can be alerted, the device is tracked again from its next message
+            db.delete_device_state(&state.watch_name, &state.device)?;
+            continue;
+        };
+        match queue::create_event(config, db, &event) {
+            Ok(_) | Err(QueueError::Suppressed(_)) => {
+                info!(
+                    "Device {} of watch {} is silent since {}, queued ALERT",
+                    state.device,
+                    state.watch_name,
+                    state.last_seen.as_deref().unwrap_or_default()
+                );
+                alerted += 1;
+            }
+            Err(QueueError::Db(e)) => return Err(e),
+            // try again on the next run
+            Err(QueueError::Full) => continue,
+        }
+        db.set_device_silent(&state.watch_name, &state.device)?;
+    }
+    Ok(alerted)
+}
+
+fn build_silence_event(config: &ILConfig, state: &DeviceStateItem) -> Option<EventQueueItem> {
+    let Some(watch) = config
+        .device_watches
+        .iter()
+        .find(|w| w.name == state.watch_name)
+    else {
+        warn!(
+            "Device watch {} is no longer configured, not alerting device {}",
+            state.watch_name, state.device
+        );
+        return None;
+    };
+    let integration_key = watch.integration_key(config)?;
+    Some(build_device_event(
+        watch,
+        integration_key,
+        &state.device,
+        ILertEventType::ALERT,
+        state.event_api_path.clone(),
+        state.last_seen.as_deref(),
+    ))
+}
+
+pub async fn run_device_watch_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    while daemon_ctx.running.load(Ordering::Relaxed) {
+        {
+            let db = daemon_ctx.db.lock().await;
+            if let Err(e) = alert_silent_devices(&daemon_ctx.config, &db) {
+                error!("Failed to check silent devices {}", e);
+            }
+        }
+
+        // short sleeps so shutdown is not delayed
+        for _ in 0..4 {
+            if !daemon_ctx.running.load(Ordering::Relaxed) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn config_with(watch: &str) -> ILConfig {
+        let mut config = ILConfig::new();
+        config.event_key = Some("il1api1".to_string());
+        config.device_watches = vec![DeviceWatch::parse(watch).unwrap()];
+        config
+    }
+
+    #[test]
+    fn parse_watch() {
+        let watch =
+            DeviceWatch::parse("name=s,topic=plant/+/status,interval=300,key_level=1").unwrap();
+        assert_eq!(watch.interval, Duration::from_secs(300));
+        assert_eq!(watch.key_level, Some(1));
+        assert!(DeviceWatch::parse("name=s,topic=a").is_err());
+        assert!(DeviceWatch::parse("name=s,topic=a,interval=0").is_err());
+        assert!(DeviceWatch::parse("name=s,topic=a,interval=1,key_path=id,key_level=1").is_err());
+    }
+
+    #[test]
+    fn device_from_path_level_or_topic() {
+        let by_path = DeviceWatch::parse("name=s,topic=#,interval=1,key_path=device.id").unwrap();
+        assert_eq!(
+            by_path.device(r#"{"device":{"id":7}}"#, "a/b").as_deref(),
+            Some("7")
+        );
+        assert_eq!(by_path.device("alive", "a/b"), None);
+        let by_level = DeviceWatch::parse("name=s,topic=#,interval=1,key_level=1").unwrap();
+        assert_eq!(by_level.device("alive", "a/b").as_deref(), Some("b"));
+        let by_topic = DeviceWatch::parse("name=s,topic=#,interval=1").unwrap();
+        assert_eq!(by_topic.device("alive", "a/b").as_deref(), Some("a/b"));
+    }
+
+    #[test]
+    fn silent_device_alerts_once_and_resolves_on_return() {
+        let (db, _f) = temp_db();
+        let config = config_with("name=s,topic=plant/+/status,interval=60,key_level=1");
+
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        observe(&config, &db, "mqtt", "alive", "other/d2/status").unwrap();
+        assert_eq!(alert_silent_devices(&config, &db).unwrap(), 0);
+
+        // simulate the interval passing
+        db.touch_device("s", "d1", Some("/v1/events/mqtt/il1api1"), 0)
+            .unwrap();
+        assert_eq!(alert_silent_devices(&config, &db).unwrap(), 1);
+        assert_eq!(alert_silent_devices(&config, &db).unwrap(), 0);
+
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        let queued = db.get_il_events(10).unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].event_type, "ALERT");
+        assert_eq!(queued[0].alert_key.as_deref(), Some("s-d1"));
+        assert_eq!(queued[1].event_type, "RESOLVE");
+        assert_eq!(
+            queued[1].event_api_path.as_deref(),
+            Some("/v1/events/mqtt/il1api1")
+        );
+    }
+
+    #[test]
+    fn device_stays_silent_until_resolve_is_queued() {
+        let (db, _f) = temp_db();
+        let mut config = config_with("name=s,topic=plant/+/status,interval=60,key_level=1");
+        config.event_queue_limit.max_items = Some(1);
+        config.event_queue_limit.overflow = crate::queue::QueueOverflow::RejectNew;
+
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        db.touch_device("s", "d1", Some("/v1/events/mqtt/il1api1"), 0)
+            .unwrap();
+        assert_eq!(alert_silent_devices(&config, &db).unwrap(), 1);
+
+        // the queue is full with the ALERT, so the RESOLVE is rejected
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        let queued = db.get_il_events(10).unwrap();
+        assert_eq!(queued.len(), 1);
+
+        db.delete_il_event(queued[0].id.as_deref().unwrap())
+            .unwrap();
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        let queued = db.get_il_events(10).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].event_type, "RESOLVE");
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        assert_eq!(db.get_il_events(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn device_without_alert_is_not_resolved() {
+        let (db, _f) = temp_db();
+        let config = config_with("name=s,topic=plant/+/status,interval=60,key_level=1");
+
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        db.touch_device("s", "d1", Some("/v1/events/mqtt/il1api1"), 0)
+            .unwrap();
+        // the watch was removed, so no ALERT can be queued for the silent device
+        assert_eq!(alert_silent_devices(&ILConfig::new(), &db).unwrap(), 0);
+
+        observe(&config, &db, "mqtt", "alive", "plant/d1/status").unwrap();
+        assert!(db.get_il_events(10).unwrap().is_empty());
+    }
+}
@@ -1,35 +1,207 @@
-use log::error;
+use log::{error, info, warn};
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
 
 use crate::DaemonContext;
+use crate::config::{ILConfig, Spec};
 use ilert::ilert::ILert;
 use ilert::ilert_builders::{HeartbeatApiResource, PingApiResource};
 
-pub async fn run_hbt_job(daemon_ctx: Arc<DaemonContext>) -> () {
-    let mut last_run = Instant::now();
+const DEFAULT_HEARTBEAT_INTERVAL_SEC: u64 = 30;
+const HEARTBEAT_PING_TIMEOUT_SEC: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeartbeatCondition {
+    MqttReady,
+    KafkaReady,
+    PipelineReady,
+}
+
+impl HeartbeatCondition {
+    pub fn parse(val: &str) -> Result<HeartbeatCondition, String> {
+        match val {
+            "mqtt_ready" => Ok(HeartbeatCondition::MqttReady),
+            "kafka_ready" => Ok(HeartbeatCondition::KafkaReady),
+            "pipeline_ready" => Ok(HeartbeatCondition::PipelineReady),
+            _ => Err(format!(
+                "unsupported condition '{}', expected 'mqtt_ready', 'kafka_ready' or 'pipeline_ready'",
+                val
+            )),
+        }
+    }
+}
+
+/**
+    A heartbeat pinged by the daemon, either a plain integration key (every 30s)
+    or a definition like `key=il1hbt...,interval=60,jitter=5,condition=pipeline_ready,max_queue_age=300`.
+    With a condition or max_queue_age the heartbeat reflects pipeline health instead of process liveness.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartbeatDefinition {
+    pub integration_key: String,
+    pub interval: Duration,
+    pub jitter: Duration,
+    pub condition: Option<HeartbeatCondition>,
+    pub max_queue_age: Option<Duration>,
+}
+
+impl HeartbeatDefinition {
+    pub fn new(integration_key: &str) -> HeartbeatDefinition {
+        HeartbeatDefinition {
+            integration_key: integration_key.to_string(),
+            interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SEC),
+            jitter: Duration::ZERO,
+            condition: None,
+            max_queue_age: None,
+        }
+    }
+
+    pub fn parse(definition: &str) -> Result<HeartbeatDefinition, String> {
+        if !definition.contains('=') {
+            return Ok(HeartbeatDefinition::new(definition.trim()));
+        }
+
+        let mut spec = Spec::parse(definition)?;
+        let integration_key = spec
+            .take("key")
+            .filter(|k| !k.is_empty())
+            .ok_or("'key' is required")?;
+        let mut heartbeat = HeartbeatDefinition::new(&integration_key);
+
+        if let Some(interval) = spec.take_u64("interval")? {
+            if interval == 0 {
+                return Err("'interval' must be greater than 0".to_string());
+            }
+            heartbeat.interval = Duration::from_secs(interval);
+        }
+        if let Some(jitter) = spec.take_u64("jitter")? {
+            heartbeat.jitter = Duration::from_secs(jitter);
+        }
+        if let Some(condition) = spec.take("condition") {
+            heartbeat.condition = Some(HeartbeatCondition::parse(&condition)?);
+        }
+        if let Some(max_queue_age) = spec.take_u64("max_queue_age")? {
+            heartbeat.max_queue_age = Some(Duration::from_secs(max_queue_age));
+        }
+
+        spec.finish()?;
+        Ok(heartbeat)
+    }
+
+    /// Interval until the next ping, including a random share of the configured jitter.
+    pub fn next_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        let jitter_ms = rand::random_range(0..=self.jitter.as_millis() as u64);
+        self.interval + Duration::from_millis(jitter_ms)
+    }
+}
+
+pub fn validate_heartbeats(config: &ILConfig) {
+    for heartbeat in config.heartbeats.iter() {
+        match heartbeat.condition {
+            Some(HeartbeatCondition::MqttReady) if config.mqtt_host.is_none() => {
+                panic!("Heartbeat condition 'mqtt_ready' requires --mqtt_host to be configured")
+            }
+            Some(HeartbeatCondition::KafkaReady) if config.kafka_brokers.is_none() => panic!(
+                "Heartbeat condition 'kafka_ready' requires --kafka_brokers to be configured"
+            ),
+            _ => {}
+        }
+    }
+}
 
-    let integration_key = daemon_ctx
-        .config
-        .clone()
-        .heartbeat_key
-        .expect("Failed to access heartbeat integration key");
-    let integration_key = integration_key.as_str();
+pub fn is_condition_met(daemon_ctx: &DaemonContext, condition: &HeartbeatCondition) -> bool {
+    let mqtt_ready = daemon_ctx.mqtt_probe.as_ref().map(|probe| probe.is_ready());
+    let kafka_ready = daemon_ctx
+        .kafka_probe
+        .as_ref()
+        .map(|probe| probe.is_ready());
+    match condition {
+        HeartbeatCondition::MqttReady => mqtt_ready.unwrap_or(false),
+        HeartbeatCondition::KafkaReady => kafka_ready.unwrap_or(false),
+        // every configured consumer must be ready
+        HeartbeatCondition::PipelineReady => {
+            mqtt_ready.unwrap_or(true) && kafka_ready.unwrap_or(true)
+        }
+    }
+}
+
+/// Returns the reason why a heartbeat must not be pinged right now, if any.
+pub async fn get_unhealthy_reason(
+    daemon_ctx: &DaemonContext,
+    heartbeat: &HeartbeatDefinition,
+) -> Option<String> {
+    if let Some(ref condition) = heartbeat.condition
+        && !is_condition_met(daemon_ctx, condition)
+    {
+        return Some(format!("condition {:?} is not met", condition));
+    }
+
+    if let Some(max_queue_age) = heartbeat.max_queue_age {
+        match daemon_ctx.db.lock().await.get_oldest_il_event_age_secs() {
+            Ok(Some(age)) if age > max_queue_age.as_secs() => {
+                return Some(format!(
+                    "oldest queued event is {}s old (max {}s)",
+                    age,
+                    max_queue_age.as_secs()
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => return Some(format!("failed to read event queue age {}", e)),
+        }
+    }
+
+    None
+}
+
+pub async fn run_hbt_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    let heartbeats = daemon_ctx.config.heartbeats.clone();
 
-    // kick off call
-    ping_heartbeat(&daemon_ctx.ilert_client, integration_key).await;
+    // kick off all heartbeats right away
+    let mut next_runs: Vec<Instant> = heartbeats.iter().map(|_| Instant::now()).collect();
 
     while daemon_ctx.running.load(Ordering::Relaxed) {
-        tokio::time::sleep(Duration::from_millis(300)).await;
+        // due heartbeats are pinged concurrently, so a slow endpoint does not delay the others
+        let mut pings = JoinSet::new();
+        for (heartbeat, next_run) in heartbeats.iter().zip(next_runs.iter_mut()) {
+            if Instant::now() < *next_run {
+                continue;
+            }
+            *next_run = Instant::now() + heartbeat.next_delay();
+
+            if let Some(reason) = get_unhealthy_reason(&daemon_ctx, heartbeat).await {
+                warn!(
+                    "Skipping heartbeat {}, {}",
+                    heartbeat.integration_key, reason
+                );
+                continue;
+            }
 
-        if last_run.elapsed().as_millis() < 30000 {
-            continue;
-        } else {
-            last_run = Instant::now();
+            let daemon_ctx = daemon_ctx.clone();
+            let integration_key = heartbeat.integration_key.clone();
+            let timeout = heartbeat
+                .interval
+                .min(Duration::from_secs(HEARTBEAT_PING_TIMEOUT_SEC));
+            pings.spawn(async move {
+                let ping = ping_heartbeat(&daemon_ctx.ilert_client, &integration_key);
+                match tokio::time::timeout(timeout, ping).await {
+                    Ok(true) => info!("Heartbeat {} pinged", integration_key),
+                    Ok(false) => {}
+                    Err(_) => error!(
+                        "Heartbeat {} ping timed out after {}s",
+                        integration_key,
+                        timeout.as_secs()
+                    ),
+                }
+            });
         }
+        pings.join_all().await;
 
-        ping_heartbeat(&daemon_ctx.ilert_client, integration_key).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
     }
 }
 
@@ -63,3 +235,54 @@ pub async fn ping_heartbeat(ilert_client: &ILert, integration_key: &str) -> bool
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plain_key_uses_defaults() {
+        let heartbeat = HeartbeatDefinition::parse("il1hbt123").unwrap();
+        assert_eq!(heartbeat, HeartbeatDefinition::new("il1hbt123"));
+        assert_eq!(heartbeat.interval, Duration::from_secs(30));
+        assert_eq!(heartbeat.next_delay(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn parse_full_definition() {
+        let heartbeat =
+            HeartbeatDefinition::parse("key=il1hbt123,interval=60,jitter=5,condition=mqtt_ready")
+                .unwrap();
+        assert_eq!(heartbeat.integration_key, "il1hbt123");
+        assert_eq!(heartbeat.interval, Duration::from_secs(60));
+        assert_eq!(heartbeat.jitter, Duration::from_secs(5));
+        assert_eq!(heartbeat.condition, Some(HeartbeatCondition::MqttReady));
+        assert!(heartbeat.max_queue_age.is_none());
+    }
+
+    #[test]
+    fn parse_pipeline_definition() {
+        let heartbeat =
+            HeartbeatDefinition::parse("key=k,condition=pipeline_ready,max_queue_age=300").unwrap();
+        assert_eq!(heartbeat.condition, Some(HeartbeatCondition::PipelineReady));
+        assert_eq!(heartbeat.max_queue_age, Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_definitions() {
+        assert!(HeartbeatDefinition::parse("interval=60").is_err());
+        assert!(HeartbeatDefinition::parse("key=k,interval=0").is_err());
+        assert!(HeartbeatDefinition::parse("key=k,condition=always").is_err());
+        assert!(HeartbeatDefinition::parse("key=k,foo=bar").is_err());
+    }
+
+    #[test]
+    fn next_delay_stays_within_jitter() {
+        let heartbeat = HeartbeatDefinition::parse("key=k,interval=10,jitter=2").unwrap();
+        for _ in 0..50 {
+            let delay = heartbeat.next_delay();
+            assert!(delay >= Duration::from_secs(10));
+            assert!(delay <= Duration::from_secs(12));
+        }
+    }
+}
@@ -11,7 +11,8 @@ use ilert::ilert_builders::{ILertEventType, ILertPriority};
 
 use crate::db::ILDatabase;
 use crate::models::event::EventQueueItemJson;
-use crate::{CALLER_AGENT, DaemonContext, hbt};
+use crate::queue::{self, QueueError};
+use crate::{CALLER_AGENT, DaemonContext, KafkaProbeState, hbt};
 
 pub struct WebContextContainer {
     pub db: ILDatabase,
@@ -24,6 +25,11 @@ async fn get_index(_req: HttpRequest) -> impl Responder {
         .body(format!("ilagent/{}", env!("CARGO_PKG_VERSION")))
 }
 
+/**
+    204 once every configured consumer is ready, otherwise 503 with the diagnostics of the first
+    consumer that is not. With a Kafka consumer a ready agent answers 200 with its diagnostics,
+    so assigned partitions, committed offsets and lag are visible in normal operation.
+*/
 async fn get_ready(
     daemon_ctx: Option<web::Data<Arc<DaemonContext>>>,
     _req: HttpRequest,
@@ -42,34 +48,97 @@ async fn get_ready(
             let error = probe.last_error().unwrap_or_default();
             let connected = probe.connected.load(Ordering::Relaxed);
             let subs_ready = probe.subscriptions_ready.load(Ordering::Relaxed);
+            let rejected: Vec<serde_json::Value> = probe
+                .rejected_subscriptions()
+                .iter()
+                .map(|(topic, reason)| json!({"topic": topic, "reason": reason}))
+                .collect();
             return HttpResponse::ServiceUnavailable().json(json!({
                 "component": "mqtt",
                 "connected": connected,
                 "subscriptions_ready": subs_ready,
+                "rejected_subscriptions": rejected,
                 "error": error,
             }));
         }
     }
 
     if let Some(ref probe) = ctx.kafka_probe {
+        let diagnostics = kafka_diagnostics(probe);
         if !probe.is_ready() {
-            let error = probe.last_error().unwrap_or_default();
-            let consumer_started = probe.consumer_started.load(Ordering::Relaxed);
-            let subscribed = probe.subscribed.load(Ordering::Relaxed);
-            let worker_exited = probe.worker_exited.load(Ordering::Relaxed);
-            return HttpResponse::ServiceUnavailable().json(json!({
-                "component": "kafka",
-                "consumer_started": consumer_started,
-                "subscribed": subscribed,
-                "worker_exited": worker_exited,
-                "error": error,
-            }));
+            return HttpResponse::ServiceUnavailable().json(diagnostics);
         }
+        return HttpResponse::Ok().json(diagnostics);
     }
 
     HttpResponse::NoContent().finish()
 }
 
+fn kafka_diagnostics(probe: &KafkaProbeState) -> serde_json::Value {
+    let partitions: Vec<serde_json::Value> = probe
+        .partitions()
+        .iter()
+        .map(|p| {
+            json!({
+                "topic": p.topic,
+                "partition": p.partition,
+                "committed_offset": p.committed_offset,
+                "lag": p.lag,
+            })
+        })
+        .collect();
+    json!({
+        "component": "kafka",
+        "consumer_started": probe.consumer_started.load(Ordering::Relaxed),
+        "subscribed": probe.subscribed.load(Ordering::Relaxed),
+        "worker_exited": probe.worker_exited.load(Ordering::Relaxed),
+        "delivery_failing": probe.delivery_failing.load(Ordering::Relaxed),
+        "lagging": probe.lagging.load(Ordering::Relaxed),
+        "max_lag": probe.max_lag(),
+        "partitions": partitions,
+        "error": probe.last_error().unwrap_or_default(),
+    })
+}
+
+/// Consumer gauges in the Prometheus text format.
+async fn get_metrics(
+    daemon_ctx: Option<web::Data<Arc<DaemonContext>>>,
+    _req: HttpRequest,
+) -> impl Responder {
+    let mut body = String::new();
+    if let Some(probe) = daemon_ctx.as_ref().and_then(|ctx| ctx.kafka_probe.as_ref()) {
+        let partitions = probe.partitions();
+        body.push_str("# TYPE ilagent_kafka_ready gauge\n");
+        body.push_str(&format!("ilagent_kafka_ready {}\n", probe.is_ready() as u8));
+        body.push_str("# TYPE ilagent_kafka_assigned_partitions gauge\n");
+        body.push_str(&format!(
+            "ilagent_kafka_assigned_partitions {}\n",
+            partitions.len()
+        ));
+        body.push_str("# TYPE ilagent_kafka_committed_offset gauge\n");
+        for p in partitions.iter() {
+            if let Some(offset) = p.committed_offset {
+                body.push_str(&format!(
+                    "ilagent_kafka_committed_offset{{topic=\"{}\",partition=\"{}\"}} {}\n",
+                    p.topic, p.partition, offset
+                ));
+            }
+        }
+        body.push_str("# TYPE ilagent_kafka_consumer_lag gauge\n");
+        for p in partitions.iter() {
+            if let Some(lag) = p.lag {
+                body.push_str(&format!(
+                    "ilagent_kafka_consumer_lag{{topic=\"{}\",partition=\"{}\"}} {}\n",
+                    p.topic, p.partition, lag
+                ));
+            }
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
 async fn get_health(
     daemon_ctx: Option<web::Data<Arc<DaemonContext>>>,
     _req: HttpRequest,
@@ -106,6 +175,7 @@ async fn get_heartbeat(
 async fn post_event(
     _req: HttpRequest,
     container: web::Data<Mutex<WebContextContainer>>,
+    daemon_ctx: Option<web::Data<Arc<DaemonContext>>>,
     event: web::Json<EventQueueItemJson>,
 ) -> impl Responder {
     let container = container.lock().await;
@@ -125,7 +195,11 @@ async fn post_event(
             .json(json!({ "error": "Unsupported value for field 'priority'." }));
     }
 
-    let insert_result = container.db.create_il_event(&event);
+    // the queue limit only applies when running as part of the daemon
+    let insert_result = match daemon_ctx {
+        Some(ctx) => queue::create_event(&ctx.config, &container.db, &event),
+        None => container.db.create_il_event(&event).map_err(QueueError::Db),
+    };
 
     match insert_result {
         Ok(res) => match res {
@@ -143,7 +217,14 @@ async fn post_event(
                     .json(json!({ "error":  "Failed to create event." }))
             }
         },
-        Err(e) => {
+        Err(QueueError::Full) => {
+            HttpResponse::ServiceUnavailable().json(json!({ "error": "Event queue is full." }))
+        }
+        Err(QueueError::Suppressed(reason)) => {
+            info!("Event not queued, {}", reason);
+            HttpResponse::Accepted().json(json!({ "status": "suppressed", "reason": reason }))
+        }
+        Err(QueueError::Db(e)) => {
             error!("Failed to create event {:?}.", e);
             HttpResponse::InternalServerError()
                 .json(json!({ "error":  "Internal error occurred." }))
@@ -160,6 +241,8 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
 
     cfg.service(web::resource("/health").route(web::get().to(get_health)));
 
+    cfg.service(web::resource("/metrics").route(web::get().to(get_metrics)));
+
     cfg.service(
         web::resource("/api/events").route(web::post().to(post_event)), // POST
     );
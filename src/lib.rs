@@ -4,15 +4,22 @@ use tokio::sync::Mutex;
 
 pub const CALLER_AGENT: &str = concat!("ilagent/", env!("CARGO_PKG_VERSION"));
 
+pub mod auto_resolve;
+pub mod checks;
+pub mod coalesce;
 pub mod config;
 pub mod consumers;
 pub mod db;
+pub mod dedup;
+pub mod device_watch;
 pub mod hbt;
 pub mod http_server;
 pub mod json_util;
 pub mod models;
 pub mod poll;
+pub mod queue;
 pub mod version_check;
+pub mod watchdog;
 
 pub struct MqttProbeState {
     pub connected: AtomicBool,
@@ -21,6 +28,8 @@ pub struct MqttProbeState {
     pub received_subscriptions: AtomicU32,
     pub last_error: std::sync::Mutex<Option<String>>,
     pub reconnect_attempts: AtomicU32,
+    /// topics and reason codes of subscriptions the broker refused since the last connect
+    pub rejected_subscriptions: std::sync::Mutex<Vec<(String, String)>>,
 }
 
 impl MqttProbeState {
@@ -32,6 +41,7 @@ impl MqttProbeState {
             received_subscriptions: AtomicU32::new(0),
             last_error: std::sync::Mutex::new(None),
             reconnect_attempts: AtomicU32::new(0),
+            rejected_subscriptions: std::sync::Mutex::new(Vec::new()),
         }
     }
 
@@ -47,6 +57,9 @@ impl MqttProbeState {
         self.connected.store(false, Ordering::Relaxed);
         self.subscriptions_ready.store(false, Ordering::Relaxed);
         self.received_subscriptions.store(0, Ordering::Relaxed);
+        if let Ok(mut rejected) = self.rejected_subscriptions.lock() {
+            rejected.clear();
+        }
     }
 
     pub fn record_suback_success(&self) {
@@ -58,6 +71,10 @@ impl MqttProbeState {
 
     pub fn record_error(&self, error: String) {
         self.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+        self.set_last_error(error);
+    }
+
+    fn set_last_error(&self, error: String) {
         if let Ok(mut last_error) = self.last_error.lock() {
             *last_error = Some(error);
         }
@@ -66,13 +83,42 @@ impl MqttProbeState {
     pub fn last_error(&self) -> Option<String> {
         self.last_error.lock().ok().and_then(|e| e.clone())
     }
+
+    pub fn record_suback_rejected(&self, topic: &str, reason: &str) {
+        if let Ok(mut rejected) = self.rejected_subscriptions.lock() {
+            rejected.push((topic.to_string(), reason.to_string()));
+        }
+        // a rejected subscription is no failed connection attempt
+        self.set_last_error(format!("Subscription to {} rejected: {}", topic, reason));
+    }
+
+    pub fn rejected_subscriptions(&self) -> Vec<(String, String)> {
+        self.rejected_subscriptions
+            .lock()
+            .map(|r| r.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Assigned partition with the committed offset and lag of the last consumer statistics, None while unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaPartitionState {
+    pub topic: String,
+    pub partition: i32,
+    pub committed_offset: Option<i64>,
+    pub lag: Option<i64>,
 }
 
 pub struct KafkaProbeState {
     pub consumer_started: AtomicBool,
     pub subscribed: AtomicBool,
     pub worker_exited: AtomicBool,
+    /// set while a message is retried because ilert delivery failed
+    pub delivery_failing: AtomicBool,
+    /// set while a partition lags behind more than `--kafka_max_lag` messages
+    pub lagging: AtomicBool,
     pub last_error: std::sync::Mutex<Option<String>>,
+    pub partitions: std::sync::Mutex<Vec<KafkaPartitionState>>,
 }
 
 impl KafkaProbeState {
@@ -81,7 +127,10 @@ impl KafkaProbeState {
             consumer_started: AtomicBool::new(false),
             subscribed: AtomicBool::new(false),
             worker_exited: AtomicBool::new(false),
+            delivery_failing: AtomicBool::new(false),
+            lagging: AtomicBool::new(false),
             last_error: std::sync::Mutex::new(None),
+            partitions: std::sync::Mutex::new(Vec::new()),
         }
     }
 
@@ -89,6 +138,63 @@ impl KafkaProbeState {
         self.consumer_started.load(Ordering::Relaxed)
             && self.subscribed.load(Ordering::Relaxed)
             && !self.worker_exited.load(Ordering::Relaxed)
+            && !self.delivery_failing.load(Ordering::Relaxed)
+            && !self.lagging.load(Ordering::Relaxed)
+    }
+
+    pub fn assign_partitions(&self, assigned: &[(String, i32)]) {
+        if let Ok(mut partitions) = self.partitions.lock() {
+            for (topic, partition) in assigned.iter() {
+                if !partitions
+                    .iter()
+                    .any(|p| p.topic == *topic && p.partition == *partition)
+                {
+                    partitions.push(KafkaPartitionState {
+                        topic: topic.clone(),
+                        partition: *partition,
+                        committed_offset: None,
+                        lag: None,
+                    });
+                }
+            }
+            partitions.sort_by(|a, b| (&a.topic, a.partition).cmp(&(&b.topic, b.partition)));
+        }
+    }
+
+    pub fn revoke_partitions(&self, revoked: &[(String, i32)]) {
+        if let Ok(mut partitions) = self.partitions.lock() {
+            partitions.retain(|p| !revoked.contains(&(p.topic.clone(), p.partition)));
+        }
+    }
+
+    /// Updates an assigned partition, statistics of unassigned partitions are ignored.
+    pub fn update_partition(
+        &self,
+        topic: &str,
+        partition: i32,
+        committed_offset: Option<i64>,
+        lag: Option<i64>,
+    ) {
+        if let Ok(mut partitions) = self.partitions.lock()
+            && let Some(state) = partitions
+                .iter_mut()
+                .find(|p| p.topic == topic && p.partition == partition)
+        {
+            state.committed_offset = committed_offset;
+            state.lag = lag;
+        }
+    }
+
+    pub fn partitions(&self) -> Vec<KafkaPartitionState> {
+        self.partitions
+            .lock()
+            .map(|partitions| partitions.clone())
+            .unwrap_or_default()
+    }
+
+    /// Highest known lag of all assigned partitions.
+    pub fn max_lag(&self) -> Option<i64> {
+        self.partitions().iter().filter_map(|p| p.lag).max()
     }
 
     pub fn record_error(&self, error: String) {
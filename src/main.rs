@@ -9,12 +9,28 @@ use std::panic;
 use std::process;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::sync::Mutex;
 
+use ilagent::auto_resolve::AutoResolveRule;
+use ilagent::checks::CheckDefinition;
+use ilagent::coalesce::CoalesceMode;
 use ilagent::config::ILConfig;
+use ilagent::consumers::decoder::PayloadDecoder;
+use ilagent::consumers::heartbeat::HeartbeatMapping;
+use ilagent::consumers::routing::Route;
+use ilagent::consumers::threshold::ThresholdRule;
+use ilagent::consumers::topic_vars::{self, TopicPattern, TopicVarKey};
 use ilagent::db::ILDatabase;
+use ilagent::device_watch::DeviceWatch;
+use ilagent::hbt::HeartbeatDefinition;
 use ilagent::models::event_db::EventQueueItem;
-use ilagent::{DaemonContext, KafkaProbeState, MqttProbeState, consumers, hbt, http_server, poll, version_check};
+use ilagent::queue::QueueOverflow;
+use ilagent::watchdog::WatchdogDefinition;
+use ilagent::{
+    DaemonContext, KafkaProbeState, MqttProbeState, auto_resolve, checks, consumers, dedup,
+    device_watch, hbt, http_server, poll, queue, version_check, watchdog,
+};
 
 fn strip_bearer_prefix(key: String) -> String {
     if let Some(stripped) = key.strip_prefix("Bearer ") {
@@ -34,12 +50,14 @@ pub fn consumer_args() -> Vec<Arg> {
             .short('e')
             .long("event_topic")
             .value_name("EVENT_TOPIC")
-            .help("Consumer topic to listen to for events"),
+            .action(ArgAction::Append)
+            .help("Consumer topic to listen to for events, can be repeated (Kafka also accepts regex subscriptions like '^alerts-.*')"),
         Arg::new("heartbeat_topic")
             .short('r')
             .long("heartbeat_topic")
             .value_name("HEARTBEAT_TOPIC")
-            .help("Consumer topic to listen to for heartbeats"),
+            .action(ArgAction::Append)
+            .help("Consumer topic to listen to for heartbeats, can be repeated (Kafka also accepts regex subscriptions)"),
         Arg::new("event_key")
             .long("event_key")
             .value_name("EVENT_KEY")
@@ -48,10 +66,14 @@ pub fn consumer_args() -> Vec<Arg> {
             .long("map_key_etype")
             .value_name("MAP_KEY_ETYPE")
             .help("Overwrites JSON payload key for eventType"),
+        Arg::new("map_key_integration_key")
+            .long("map_key_integration_key")
+            .value_name("MAP_KEY_INTEGRATION_KEY")
+            .help("Takes the integration key from the given JSON payload key, e.g. '_kafka.headers.integrationKey'"),
         Arg::new("map_key_alert_key")
             .long("map_key_alert_key")
             .value_name("MAP_KEY_ALERT_KEY")
-            .help("Overwrites JSON payload key for alertKey"),
+            .help("Overwrites JSON payload key for alertKey, e.g. '_kafka.key' for the Kafka message key"),
         Arg::new("map_key_summary")
             .long("map_key_summary")
             .value_name("MAP_KEY_SUMMARY")
@@ -71,7 +93,7 @@ pub fn consumer_args() -> Vec<Arg> {
         Arg::new("filter_key")
             .long("filter_key")
             .value_name("FILTER_KEY")
-            .help("Requires the given key in JSON payload"),
+            .help("Requires the given key in JSON payload, nested keys like '_kafka.headers.source' are looked up if there is no top level key of that name"),
         Arg::new("filter_val")
             .long("filter_val")
             .value_name("FILTER_VAL")
@@ -83,7 +105,8 @@ pub fn consumer_args() -> Vec<Arg> {
         Arg::new("policy_topic")
             .long("policy_topic")
             .value_name("POLICY_TOPIC")
-            .help("Consumer topic to listen to for escalation policy updates"),
+            .action(ArgAction::Append)
+            .help("Consumer topic to listen to for escalation policy updates, can be repeated (Kafka also accepts regex subscriptions)"),
         Arg::new("policy_routing_keys")
             .long("policy_routing_keys")
             .value_name("POLICY_ROUTING_KEYS")
@@ -104,6 +127,44 @@ pub fn consumer_args() -> Vec<Arg> {
             .long("max_retries")
             .value_name("MAX_RETRIES")
             .help("Maximum number of retries for failed queue items (0 = unlimited, default: 100)"),
+        Arg::new("auto_resolve")
+            .long("auto_resolve")
+            .value_name("AUTO_RESOLVE")
+            .help("Queues a RESOLVE when no new ALERT arrived for an alert key within this many seconds"),
+        Arg::new("auto_resolve_rule")
+            .long("auto_resolve_rule")
+            .value_name("AUTO_RESOLVE_RULE")
+            .action(ArgAction::Append)
+            .help("Overrides the auto resolve timeout for a topic, can be repeated, first match wins (e.g. 'topic=plant/+/alarm,timeout=300')"),
+        Arg::new("map_key_auto_resolve")
+            .long("map_key_auto_resolve")
+            .value_name("MAP_KEY_AUTO_RESOLVE")
+            .help("JSON path for a per-message auto resolve timeout in seconds, takes precedence over rules"),
+        Arg::new("route")
+            .long("route")
+            .value_name("ROUTE")
+            .action(ArgAction::Append)
+            .help("Routes events to an integration key by topic and/or payload field, can be repeated, first match wins (e.g. 'topic=plant/+/alarm,key=il1api...', 'topic_regex=^alerts-.*,key=il1api...' or 'field=customer.id,value=42,key=il1api...'); takes precedence over --event_key"),
+        Arg::new("heartbeat_map")
+            .long("heartbeat_map")
+            .value_name("HEARTBEAT_MAP")
+            .action(ArgAction::Append)
+            .help("Maps heartbeat messages without 'integrationKey' to a heartbeat key, can be repeated, first matching topic wins (e.g. 'topic=devices/+/alive,key=il1hbt...', 'topic=hb/+,key_level=1' or 'key_path=device.hbt,field=status,value=ok')"),
+        Arg::new("device_watch")
+            .long("device_watch")
+            .value_name("DEVICE_WATCH")
+            .action(ArgAction::Append)
+            .help("Tracks when devices on matching topics were last seen and alerts once they are silent longer than the interval, can be repeated (e.g. 'name=sensors,topic=plant/+/status,interval=300,key_level=1'; also key_path, integration_key — defaults to --event_key)"),
+        Arg::new("decoder")
+            .long("decoder")
+            .value_name("DECODER")
+            .action(ArgAction::Append)
+            .help("Decodes binary payloads of matching topics into JSON before mapping, can be repeated, first match wins (e.g. 'topic=orders,format=avro,schema=/etc/order.avsc', 'topic=orders,format=avro,registry=http://localhost:8081' for Confluent framed messages or 'topic=sensors/+/data,format=protobuf,descriptor=/etc/sensors.desc,message=acme.Reading'); text payloads can be parsed with 'format=regex,pattern=...' (named groups), 'format=csv,columns=eventType|alertKey' (or a header line), 'format=logfmt' and 'format=msgpack'"),
+        Arg::new("threshold")
+            .long("threshold")
+            .value_name("THRESHOLD")
+            .action(ArgAction::Append)
+            .help("Evaluates numeric telemetry on matching topics instead of parsing events, can be repeated (e.g. 'name=boiler_temp,topic=plant/+/temp,path=value,above=80,hysteresis=5,for=60,key_level=1'; also below, key_path, integration_key — defaults to --event_key)"),
     ]
 }
 
@@ -119,7 +180,46 @@ pub fn build_cli() -> Command {
             .short('b')
             .long("heartbeat")
             .value_name("HEARTBEAT")
-            .help("Sets the API key of the heartbeat (pings regularly)"))
+            .action(ArgAction::Append)
+            .help("Sets the API key of a heartbeat that is pinged every 30s, can be repeated; also accepts a definition like 'key=il1hbt...,interval=60,jitter=5,condition=pipeline_ready,max_queue_age=300' (conditions: mqtt_ready, kafka_ready, pipeline_ready; max_queue_age skips pings while queued events are older)"))
+        .arg(Arg::new("check")
+            .long("check")
+            .value_name("CHECK")
+            .action(ArgAction::Append)
+            .help("Runs a local check on a schedule, can be repeated (e.g. 'name=db,tcp=localhost:5432,interval=30,heartbeat=il1hbt...,integration_key=il1api...'); supports command, tcp, http and file (with max_age) checks"))
+        .arg(Arg::new("queue_max_items")
+            .long("queue_max_items")
+            .value_name("QUEUE_MAX_ITEMS")
+            .help("Hard cap for the number of items in each local queue table (events and MQTT buffer, default: unlimited)"))
+        .arg(Arg::new("queue_max_bytes")
+            .long("queue_max_bytes")
+            .value_name("QUEUE_MAX_BYTES")
+            .help("Hard cap for the payload size in bytes of each local queue table (events and MQTT buffer, default: unlimited)"))
+        .arg(Arg::new("queue_overflow")
+            .long("queue_overflow")
+            .value_name("QUEUE_OVERFLOW")
+            .help("What to do when a queue limit is reached: 'drop_oldest' (default), 'drop_lowest_priority' or 'reject_new' (HTTP answers 503)"))
+        .arg(Arg::new("queue_limit")
+            .long("queue_limit")
+            .value_name("QUEUE_LIMIT")
+            .action(ArgAction::Append)
+            .help("Overrides the limits of a single queue table, can be repeated (e.g. 'queue=mqtt,max_items=5000,max_bytes=10000000,overflow=reject_new'; queues: events, mqtt)"))
+        .arg(Arg::new("dedup_window")
+            .long("dedup_window")
+            .value_name("DEDUP_WINDOW")
+            .help("Drops queued events that repeat the last event type of the same integration key and alert key within this many seconds"))
+        .arg(Arg::new("resolve_hold")
+            .long("resolve_hold")
+            .value_name("RESOLVE_HOLD")
+            .help("Holds queued RESOLVE events for this many seconds, a new ALERT for the same alert key cancels both (flap suppression)"))
+        .arg(Arg::new("coalesce_events")
+            .long("coalesce_events")
+            .value_name("COALESCE_EVENTS")
+            .help("Collapses a backlog of queued events per alert key into its net state before delivery: 'keep_last' or 'drop_resolved' (also drops backlogs that start with ALERT and end with RESOLVE)"))
+        .arg(Arg::new("queue_watchdog")
+            .long("queue_watchdog")
+            .value_name("QUEUE_WATCHDOG")
+            .help("Raises an alert through its own integration key when the local queues back up (e.g. 'integration_key=il1api...,max_age=900,max_events=1000,max_mqtt_queue=5000,interval=60')"))
         // mqtt
         .arg(Arg::new("mqtt_host")
             .short('m')
@@ -172,6 +272,42 @@ pub fn build_cli() -> Command {
             .long("mqtt_shared_group")
             .value_name("MQTT_SHARED_GROUP")
             .help("MQTT v5 shared subscription group name (topics are prefixed with $share/<group>/ for load balancing)"))
+        .arg(Arg::new("mqtt_version")
+            .long("mqtt_version")
+            .value_name("MQTT_VERSION")
+            .help("Sets the MQTT protocol version: 3 (3.1.1) or 5, v5 passes content type, user properties and correlation data of messages to mappings as '_mqtt' (default: 3)"))
+        .arg(Arg::new("mqtt_session_expiry")
+            .long("mqtt_session_expiry")
+            .value_name("SECONDS")
+            .help("MQTT v5 session expiry interval, unacknowledged messages are redelivered after reconnects within it (default: never, the session is kept until the broker drops it)"))
+        .arg(Arg::new("sparkplug")
+            .long("sparkplug")
+            .value_name("GROUP")
+            .num_args(0..=1)
+            .default_missing_value("+")
+            .help("Consumes Eclipse Sparkplug B messages of all or the given group: NDEATH/DDEATH open an ALERT per edge node/device (an NDEATH also for the devices of the node), NBIRTH/DBIRTH resolve it, NDATA/DDATA metrics are passed to thresholds, filters and an eventType mapping as JSON (e.g. 'metrics.Temperature') and ignored without them; needs --event_key or --route"))
+        .arg(Arg::new("topic_pattern")
+            .long("topic_pattern")
+            .value_name("TOPIC_PATTERN")
+            .help("Captures MQTT topic levels as variables for templates, key selection and filters (e.g. 'plant/{site}/sensor/{device}', '+' and a trailing '#' are allowed)"))
+        .arg(Arg::new("alert_key_template")
+            .long("alert_key_template")
+            .value_name("ALERT_KEY_TEMPLATE")
+            .help("Builds the MQTT event alertKey from topic variables, {topic} and {summary} (e.g. '{site}-{device}')"))
+        .arg(Arg::new("summary_template")
+            .long("summary_template")
+            .value_name("SUMMARY_TEMPLATE")
+            .help("Builds the MQTT event summary from topic variables, {topic} and {summary} (e.g. '{device} in {site}: {summary}')"))
+        .arg(Arg::new("topic_var_key")
+            .long("topic_var_key")
+            .value_name("TOPIC_VAR_KEY")
+            .action(ArgAction::Append)
+            .help("Selects the integration key by topic variable, can be repeated, first match wins (e.g. 'var=site,value=berlin,key=il1api...')"))
+        .arg(Arg::new("filter_var")
+            .long("filter_var")
+            .value_name("FILTER_VAR")
+            .action(ArgAction::Append)
+            .help("Requires a topic variable to have the given value, can be repeated (e.g. 'site=berlin')"))
         // kafka
         .arg(Arg::new("kafka_brokers")
             .long("kafka_brokers")
@@ -179,7 +315,70 @@ pub fn build_cli() -> Command {
         .arg(Arg::new("kafka_group_id")
             .long("kafka_group_id")
             .help("Kafka consumer group id")
-            .default_value("ilagent"));
+            .default_value("ilagent"))
+        .arg(Arg::new("kafka_security_protocol")
+            .long("kafka_security_protocol")
+            .value_name("KAFKA_SECURITY_PROTOCOL")
+            .value_parser(["plaintext", "ssl", "sasl_plaintext", "sasl_ssl"])
+            .help("Kafka security.protocol (default: plaintext)"))
+        .arg(Arg::new("kafka_sasl_mechanism")
+            .long("kafka_sasl_mechanism")
+            .value_name("KAFKA_SASL_MECHANISM")
+            .value_parser(["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512", "OAUTHBEARER"])
+            .help("Kafka SASL mechanism, requires a sasl_* security protocol (OAUTHBEARER settings like sasl.oauthbearer.method can be set with --kafka_property)"))
+        .arg(Arg::new("kafka_sasl_username")
+            .long("kafka_sasl_username")
+            .value_name("KAFKA_SASL_USERNAME")
+            .help("Kafka SASL username for PLAIN and SCRAM"))
+        .arg(Arg::new("kafka_sasl_password")
+            .long("kafka_sasl_password")
+            .value_name("KAFKA_SASL_PASSWORD")
+            .help("Kafka SASL password for PLAIN and SCRAM (requires kafka_sasl_username)"))
+        .arg(Arg::new("kafka_ca")
+            .long("kafka_ca")
+            .value_name("KAFKA_CA")
+            .help("CA certificate file path for Kafka TLS (PEM format)"))
+        .arg(Arg::new("kafka_client_cert")
+            .long("kafka_client_cert")
+            .value_name("KAFKA_CLIENT_CERT")
+            .help("Client certificate file path for Kafka mTLS (PEM format)"))
+        .arg(Arg::new("kafka_client_key")
+            .long("kafka_client_key")
+            .value_name("KAFKA_CLIENT_KEY")
+            .help("Client private key file path for Kafka mTLS (PEM format)"))
+        .arg(Arg::new("kafka_property")
+            .long("kafka_property")
+            .value_name("KAFKA_PROPERTY")
+            .action(ArgAction::Append)
+            .help("Passes a librdkafka property to the consumer, can be repeated, applied after all other Kafka options (e.g. 'fetch.min.bytes=1024')"))
+        .arg(Arg::new("kafka_retry_topic")
+            .long("kafka_retry_topic")
+            .value_name("KAFKA_RETRY_TOPIC")
+            .help("Moves messages that still fail ilert delivery after --kafka_retry_attempts to this topic and commits them, without it they are retried with backoff until delivered"))
+        .arg(Arg::new("kafka_retry_attempts")
+            .long("kafka_retry_attempts")
+            .value_name("KAFKA_RETRY_ATTEMPTS")
+            .help("Delivery attempts before a message is moved to --kafka_retry_topic (default: 5)"))
+        .arg(Arg::new("kafka_dlq_topic")
+            .long("kafka_dlq_topic")
+            .value_name("KAFKA_DLQ_TOPIC")
+            .help("Moves messages that are unparseable, invalid or rejected by ilert to this topic with failure headers, without it they are dropped"))
+        .arg(Arg::new("kafka_commit_interval")
+            .long("kafka_commit_interval")
+            .value_name("KAFKA_COMMIT_INTERVAL")
+            .help("Milliseconds between batched offset commits of processed Kafka messages (default: 1000)"))
+        .arg(Arg::new("kafka_buffer")
+            .long("kafka_buffer")
+            .action(ArgAction::SetTrue)
+            .help("Queue Kafka events in SQLite and commit their offsets once written, delivery and retries are left to the poll job"))
+        .arg(Arg::new("kafka_max_lag")
+            .long("kafka_max_lag")
+            .value_name("KAFKA_MAX_LAG")
+            .help("Reports the agent as not ready on /ready while a Kafka partition lags behind more than this many messages (the lag of every partition is on /ready and /metrics)"))
+        .arg(Arg::new("kafka_properties_file")
+            .long("kafka_properties_file")
+            .value_name("KAFKA_PROPERTIES_FILE")
+            .help("Reads librdkafka properties from a 'key=value' file ('#' comments), --kafka_property entries take precedence"));
 
     for arg in consumer_args() {
         daemon_cmd = daemon_cmd.arg(arg);
@@ -329,8 +528,92 @@ pub fn build_daemon_config(matches: &ArgMatches, global_matches: &ArgMatches) ->
         .unwrap_or(default_port.as_str());
     config.set_port_from_str(port);
 
-    if let Some(heartbeat_key) = matches.get_one::<String>("heartbeat") {
-        config.heartbeat_key = Some(heartbeat_key.to_string());
+    if let Some(heartbeats) = matches.get_many::<String>("heartbeat") {
+        for heartbeat in heartbeats {
+            let definition = HeartbeatDefinition::parse(heartbeat)
+                .unwrap_or_else(|e| panic!("Invalid --heartbeat '{}': {}", heartbeat, e));
+            config.heartbeats.push(definition);
+        }
+    }
+
+    if let Some(checks) = matches.get_many::<String>("check") {
+        for check in checks {
+            let definition = CheckDefinition::parse(check)
+                .unwrap_or_else(|e| panic!("Invalid --check '{}': {}", check, e));
+            info!("Local check '{}' has been configured", definition.name);
+            config.checks.push(definition);
+        }
+    }
+
+    if let Some(queue_max_items) = matches.get_one::<String>("queue_max_items") {
+        let queue_max_items = queue_max_items
+            .parse::<u64>()
+            .expect("Failed to parse queue_max_items as integer");
+        if queue_max_items == 0 {
+            panic!("--queue_max_items must be greater than 0");
+        }
+        config.event_queue_limit.max_items = Some(queue_max_items);
+        config.mqtt_queue_limit.max_items = Some(queue_max_items);
+        info!("Queue item limit has been configured: {}", queue_max_items);
+    }
+
+    if let Some(queue_max_bytes) = matches.get_one::<String>("queue_max_bytes") {
+        let queue_max_bytes = queue_max_bytes
+            .parse::<u64>()
+            .expect("Failed to parse queue_max_bytes as integer");
+        if queue_max_bytes == 0 {
+            panic!("--queue_max_bytes must be greater than 0");
+        }
+        config.event_queue_limit.max_bytes = Some(queue_max_bytes);
+        config.mqtt_queue_limit.max_bytes = Some(queue_max_bytes);
+        info!("Queue byte limit has been configured: {}", queue_max_bytes);
+    }
+
+    if let Some(queue_overflow) = matches.get_one::<String>("queue_overflow") {
+        let queue_overflow = QueueOverflow::parse(queue_overflow)
+            .unwrap_or_else(|e| panic!("Invalid --queue_overflow: {}", e));
+        config.event_queue_limit.overflow = queue_overflow;
+        config.mqtt_queue_limit.overflow = queue_overflow;
+    }
+
+    if let Some(queue_limits) = matches.get_many::<String>("queue_limit") {
+        for queue_limit in queue_limits {
+            queue::apply_queue_limit(&mut config, queue_limit)
+                .unwrap_or_else(|e| panic!("Invalid --queue_limit '{}': {}", queue_limit, e));
+        }
+    }
+
+    if let Some(dedup_window) = matches.get_one::<String>("dedup_window") {
+        let dedup_window = dedup_window
+            .parse::<u64>()
+            .expect("Failed to parse dedup_window as integer");
+        config.dedup_window = Some(Duration::from_secs(dedup_window));
+        info!(
+            "Event deduplication window has been configured: {}s",
+            dedup_window
+        );
+    }
+
+    if let Some(resolve_hold) = matches.get_one::<String>("resolve_hold") {
+        let resolve_hold = resolve_hold
+            .parse::<u64>()
+            .expect("Failed to parse resolve_hold as integer");
+        config.resolve_hold = Some(Duration::from_secs(resolve_hold));
+        info!("RESOLVE events will be held for {}s", resolve_hold);
+    }
+
+    if let Some(coalesce_events) = matches.get_one::<String>("coalesce_events") {
+        let mode = CoalesceMode::parse(coalesce_events)
+            .unwrap_or_else(|e| panic!("Invalid --coalesce_events: {}", e));
+        config.coalesce_events = Some(mode);
+        info!("Queued events will be coalesced using {}", coalesce_events);
+    }
+
+    if let Some(queue_watchdog) = matches.get_one::<String>("queue_watchdog") {
+        let definition = WatchdogDefinition::parse(queue_watchdog)
+            .unwrap_or_else(|e| panic!("Invalid --queue_watchdog '{}': {}", queue_watchdog, e));
+        info!("Queue watchdog has been configured");
+        config.queue_watchdog = Some(definition);
     }
 
     if let Some(mqtt_host) = matches.get_one::<String>("mqtt_host") {
@@ -347,13 +630,8 @@ pub fn build_daemon_config(matches: &ArgMatches, global_matches: &ArgMatches) ->
         config.set_mqtt_port_from_str(mqtt_port_str);
         config.mqtt_name = Some(mqtt_name.to_string());
 
-        if let Some(event_topic) = matches.get_one::<String>("event_topic") {
-            config.event_topic = Some(event_topic.to_string());
-        }
-
-        if let Some(heartbeat_topic) = matches.get_one::<String>("heartbeat_topic") {
-            config.heartbeat_topic = Some(heartbeat_topic.to_string());
-        }
+        config.event_topics = get_topics(matches, "event_topic");
+        config.heartbeat_topics = get_topics(matches, "heartbeat_topic");
 
         config.mqtt_tls = matches.get_flag("mqtt_tls");
         config.mqtt_ca_path = matches.get_one::<String>("mqtt_ca").map(|s| s.to_string());
@@ -385,7 +663,97 @@ pub fn build_daemon_config(matches: &ArgMatches, global_matches: &ArgMatches) ->
             info!("MQTT shared subscription group: '{}'", shared_group);
         }
 
+        if let Some(version) = matches.get_one::<String>("mqtt_version") {
+            config.mqtt_v5 = match version.as_str() {
+                "3" | "3.1.1" => false,
+                "5" => true,
+                _ => panic!("Invalid --mqtt_version '{}': expected 3 or 5", version),
+            };
+            info!("MQTT protocol version set to {}", version);
+        }
+
+        if let Some(expiry) = matches.get_one::<String>("mqtt_session_expiry") {
+            if !config.mqtt_v5 {
+                panic!("Invalid --mqtt_session_expiry: requires --mqtt_version 5");
+            }
+            let seconds = expiry
+                .parse::<u32>()
+                .unwrap_or_else(|e| panic!("Invalid --mqtt_session_expiry '{}': {}", expiry, e));
+            config.mqtt_session_expiry = Some(seconds);
+            info!("MQTT session expiry set to {} seconds", seconds);
+        }
+
+        if let Some(group) = matches.get_one::<String>("sparkplug") {
+            if group.is_empty()
+                || group.contains(['/', '#'])
+                || (group.contains('+') && group != "+")
+            {
+                panic!(
+                    "Invalid --sparkplug '{}': expected a group id or '+'",
+                    group
+                );
+            }
+            config.sparkplug_topic = Some(format!(
+                "{}/{}/#",
+                consumers::sparkplug::SPARKPLUG_NAMESPACE,
+                group
+            ));
+            info!("Sparkplug B mode enabled for group '{}'", group);
+        }
+
+        if let Some(topic_pattern) = matches.get_one::<String>("topic_pattern") {
+            let pattern = TopicPattern::parse(topic_pattern)
+                .unwrap_or_else(|e| panic!("Invalid --topic_pattern '{}': {}", topic_pattern, e));
+            config.topic_pattern = Some(pattern);
+            info!(
+                "MQTT topic pattern has been configured: '{}'",
+                topic_pattern
+            );
+        }
+
+        if let Some(template) = matches.get_one::<String>("alert_key_template") {
+            config.alert_key_template = Some(template.to_string());
+            info!("MQTT alertKey template has been configured: '{}'", template);
+        }
+
+        if let Some(template) = matches.get_one::<String>("summary_template") {
+            config.summary_template = Some(template.to_string());
+            info!("MQTT summary template has been configured: '{}'", template);
+        }
+
+        if let Some(var_keys) = matches.get_many::<String>("topic_var_key") {
+            config.topic_var_keys = var_keys
+                .map(|def| {
+                    TopicVarKey::parse(def)
+                        .unwrap_or_else(|e| panic!("Invalid --topic_var_key '{}': {}", def, e))
+                })
+                .collect();
+        }
+
+        if let Some(filter_vars) = matches.get_many::<String>("filter_var") {
+            config.filter_vars = filter_vars
+                .map(|def| {
+                    topic_vars::parse_filter_var(def)
+                        .unwrap_or_else(|e| panic!("Invalid --filter_var '{}': {}", def, e))
+                })
+                .collect();
+        }
+
+        if config.topic_pattern.is_none()
+            && (!config.topic_var_keys.is_empty() || !config.filter_vars.is_empty())
+        {
+            panic!("--topic_var_key and --filter_var require --topic_pattern");
+        }
+
         config = parse_consumer_arguments(matches, config);
+        if config.sparkplug_topic.is_some()
+            && config.event_key.is_none()
+            && config.routes.is_empty()
+        {
+            panic!(
+                "Invalid --sparkplug: --event_key or --route is required for birth and death events"
+            );
+        }
         consumers::mqtt::validate_mqtt_config(&config);
     }
 
@@ -397,27 +765,69 @@ pub fn build_daemon_config(matches: &ArgMatches, global_matches: &ArgMatches) ->
 
         config.kafka_brokers = Some(kafka_brokers.to_string());
         config.kafka_group_id = Some(kafka_group_id.to_string());
+        config = parse_kafka_security_arguments(matches, config);
 
-        if let Some(topic) = matches.get_one::<String>("event_topic") {
-            config.event_topic = Some(topic.to_string());
+        if let Some(topic) = matches.get_one::<String>("kafka_retry_topic") {
+            config.kafka_retry_topic = Some(topic.to_string());
+            info!("Kafka retry topic has been configured: {}", topic);
+        }
+        if let Some(attempts) = matches.get_one::<String>("kafka_retry_attempts") {
+            config.kafka_retry_attempts = match attempts.parse::<u32>() {
+                Ok(attempts) if attempts > 0 => attempts,
+                _ => panic!(
+                    "Invalid --kafka_retry_attempts '{}': expected a number greater than 0",
+                    attempts
+                ),
+            };
+        }
+        if let Some(topic) = matches.get_one::<String>("kafka_dlq_topic") {
+            config.kafka_dlq_topic = Some(topic.to_string());
+            info!("Kafka dead-letter topic has been configured: {}", topic);
+        }
+        if let Some(interval) = matches.get_one::<String>("kafka_commit_interval") {
+            config.kafka_commit_interval = match interval.parse::<u64>() {
+                Ok(interval) if interval > 0 => Duration::from_millis(interval),
+                _ => panic!(
+                    "Invalid --kafka_commit_interval '{}': expected milliseconds greater than 0",
+                    interval
+                ),
+            };
         }
 
-        if let Some(topic) = matches.get_one::<String>("heartbeat_topic") {
-            config.heartbeat_topic = Some(topic.to_string());
+        config.kafka_buffer = matches.get_flag("kafka_buffer");
+        if config.kafka_buffer {
+            info!("Kafka buffering enabled — events will be queued in SQLite before delivery");
         }
+        if let Some(max_lag) = matches.get_one::<String>("kafka_max_lag") {
+            config.kafka_max_lag = match max_lag.parse::<u64>() {
+                Ok(max_lag) => Some(max_lag),
+                Err(_) => panic!(
+                    "Invalid --kafka_max_lag '{}': expected a number of messages",
+                    max_lag
+                ),
+            };
+        }
+
+        config.event_topics = get_topics(matches, "event_topic");
+        config.heartbeat_topics = get_topics(matches, "heartbeat_topic");
 
         config = parse_consumer_arguments(matches, config);
 
-        if config.event_topic.is_none()
-            && config.heartbeat_topic.is_none()
-            && config.policy_topic.is_none()
+        if config.event_topics.is_empty()
+            && config.heartbeat_topics.is_empty()
+            && config.policy_topics.is_empty()
         {
             panic!(
                 "At least one Kafka topic must be configured: --event_topic, --heartbeat_topic, or --policy_topic"
             );
         }
+        if let Err(e) = consumers::kafka::validate_kafka_topics(&config) {
+            panic!("Invalid Kafka topic regex {}", e);
+        }
     }
 
+    hbt::validate_heartbeats(&config);
+
     if let Some(file) = global_matches.get_one::<String>("file") {
         config.db_file = file.to_string();
     } else if let Some(file) = matches.get_one::<String>("file") {
@@ -427,6 +837,52 @@ pub fn build_daemon_config(matches: &ArgMatches, global_matches: &ArgMatches) ->
     config
 }
 
+fn parse_kafka_security_arguments(matches: &ArgMatches, mut config: ILConfig) -> ILConfig {
+    let get = |name: &str| matches.get_one::<String>(name).map(|s| s.to_string());
+    config.kafka_security_protocol = get("kafka_security_protocol");
+    config.kafka_sasl_mechanism = get("kafka_sasl_mechanism");
+    config.kafka_sasl_username = get("kafka_sasl_username");
+    config.kafka_sasl_password = get("kafka_sasl_password");
+    config.kafka_ca_path = get("kafka_ca");
+    config.kafka_client_cert_path = get("kafka_client_cert");
+    config.kafka_client_key_path = get("kafka_client_key");
+
+    if let Some(file) = matches.get_one::<String>("kafka_properties_file") {
+        let content = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("Invalid --kafka_properties_file '{}': {}", file, e));
+        config.kafka_properties = consumers::kafka::parse_kafka_properties(&content)
+            .unwrap_or_else(|e| panic!("Invalid --kafka_properties_file '{}': {}", file, e));
+    }
+    if let Some(properties) = matches.get_many::<String>("kafka_property") {
+        for property in properties {
+            let parsed = consumers::kafka::parse_kafka_properties(property)
+                .unwrap_or_else(|e| panic!("Invalid --kafka_property '{}': {}", property, e));
+            config.kafka_properties.extend(parsed);
+        }
+    }
+
+    if let Err(e) = consumers::kafka::validate_kafka_security(&config) {
+        panic!("Invalid Kafka security options: {}", e);
+    }
+    if let Some(ref protocol) = config.kafka_security_protocol {
+        info!("Kafka security protocol has been configured: {}", protocol);
+    }
+    if !config.kafka_properties.is_empty() {
+        info!(
+            "{} Kafka properties have been configured",
+            config.kafka_properties.len()
+        );
+    }
+    config
+}
+
+fn get_topics(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .get_many::<String>(name)
+        .map(|topics| topics.map(|t| t.to_string()).collect())
+        .unwrap_or_default()
+}
+
 pub fn parse_consumer_arguments(matches: &ArgMatches, mut config: ILConfig) -> ILConfig {
     if let Some(username) = matches.get_one::<String>("mqtt_username") {
         config.mqtt_username = Some(username.to_string());
@@ -469,6 +925,14 @@ pub fn parse_consumer_arguments(matches: &ArgMatches, mut config: ILConfig) -> I
         );
     }
 
+    if let Some(map_key_integration_key) = matches.get_one::<String>("map_key_integration_key") {
+        config.map_key_integration_key = Some(map_key_integration_key.to_string());
+        info!(
+            "Overwrite for payload key 'integrationKey' has been configured: '{:?}'",
+            config.map_key_integration_key
+        );
+    }
+
     if let Some(map_key_alert_key) = matches.get_one::<String>("map_key_alert_key") {
         config.map_key_alert_key = Some(map_key_alert_key.to_string());
         info!(
@@ -514,9 +978,12 @@ pub fn parse_consumer_arguments(matches: &ArgMatches, mut config: ILConfig) -> I
         info!("API key has been configured from ILERT_API_KEY environment variable");
     }
 
-    if let Some(policy_topic) = matches.get_one::<String>("policy_topic") {
-        config.policy_topic = Some(policy_topic.to_string());
-        info!("Policy topic has been configured: '{}'", policy_topic);
+    config.policy_topics = get_topics(matches, "policy_topic");
+    if !config.policy_topics.is_empty() {
+        info!(
+            "Policy topics have been configured: '{}'",
+            config.policy_topics.join("', '")
+        );
     }
 
     if let Some(policy_routing_keys) = matches.get_one::<String>("policy_routing_keys") {
@@ -555,6 +1022,119 @@ pub fn parse_consumer_arguments(matches: &ArgMatches, mut config: ILConfig) -> I
         }
     }
 
+    if let Some(auto_resolve) = matches.get_one::<String>("auto_resolve") {
+        let auto_resolve = auto_resolve
+            .parse::<u64>()
+            .expect("Failed to parse auto_resolve as integer");
+        if auto_resolve == 0 {
+            panic!("Invalid --auto_resolve '0': must be greater than 0");
+        }
+        config.auto_resolve = Some(Duration::from_secs(auto_resolve));
+        info!("Silent alerts will be resolved after {}s", auto_resolve);
+    }
+
+    if let Some(rules) = matches.get_many::<String>("auto_resolve_rule") {
+        config.auto_resolve_rules = rules
+            .map(|rule| {
+                AutoResolveRule::parse(rule)
+                    .unwrap_or_else(|e| panic!("Invalid --auto_resolve_rule '{}': {}", rule, e))
+            })
+            .collect();
+        info!(
+            "{} auto resolve rules have been configured",
+            config.auto_resolve_rules.len()
+        );
+    }
+
+    if let Some(map_key_auto_resolve) = matches.get_one::<String>("map_key_auto_resolve") {
+        config.map_key_auto_resolve = Some(map_key_auto_resolve.to_string());
+        info!(
+            "Auto resolve timeout field path has been configured: '{}'",
+            map_key_auto_resolve
+        );
+    }
+
+    if let Some(routes) = matches.get_many::<String>("route") {
+        config.routes = routes
+            .map(|route| {
+                Route::parse(route).unwrap_or_else(|e| panic!("Invalid --route '{}': {}", route, e))
+            })
+            .collect();
+        info!("{} event routes have been configured", config.routes.len());
+    }
+
+    if let Some(mappings) = matches.get_many::<String>("heartbeat_map") {
+        config.heartbeat_mappings = mappings
+            .map(|mapping| {
+                HeartbeatMapping::parse(mapping)
+                    .unwrap_or_else(|e| panic!("Invalid --heartbeat_map '{}': {}", mapping, e))
+            })
+            .collect();
+        info!(
+            "{} heartbeat mappings have been configured",
+            config.heartbeat_mappings.len()
+        );
+    }
+
+    if let Some(thresholds) = matches.get_many::<String>("threshold") {
+        config.thresholds = thresholds
+            .map(|rule| {
+                ThresholdRule::parse(rule)
+                    .unwrap_or_else(|e| panic!("Invalid --threshold '{}': {}", rule, e))
+            })
+            .collect();
+        if let Some(rule) = config
+            .thresholds
+            .iter()
+            .find(|r| r.integration_key.is_none() && config.event_key.is_none())
+        {
+            panic!(
+                "Invalid --threshold '{}': 'integration_key' or --event_key is required",
+                rule.name
+            );
+        }
+        info!(
+            "{} threshold rules have been configured",
+            config.thresholds.len()
+        );
+    }
+
+    if let Some(watches) = matches.get_many::<String>("device_watch") {
+        config.device_watches = watches
+            .map(|watch| {
+                DeviceWatch::parse(watch)
+                    .unwrap_or_else(|e| panic!("Invalid --device_watch '{}': {}", watch, e))
+            })
+            .collect();
+        if let Some(watch) = config
+            .device_watches
+            .iter()
+            .find(|w| w.integration_key.is_none() && config.event_key.is_none())
+        {
+            panic!(
+                "Invalid --device_watch '{}': 'integration_key' or --event_key is required",
+                watch.name
+            );
+        }
+        info!(
+            "{} device watches have been configured",
+            config.device_watches.len()
+        );
+    }
+
+    if let Some(decoders) = matches.get_many::<String>("decoder") {
+        config.decoders = decoders
+            .map(|decoder| {
+                PayloadDecoder::parse(decoder)
+                    .unwrap_or_else(|e| panic!("Invalid --decoder '{}': {}", decoder, e))
+            })
+            .collect();
+        info!(
+            "{} payload decoders have been configured",
+            config.decoders.len()
+        );
+    }
+
     config
 }
 
@@ -562,6 +1142,10 @@ pub fn parse_consumer_arguments(matches: &ArgMatches, mut config: ILConfig) -> I
     If port is provided starts a http server with proxy functionality /api/events and /api/heartbeats
     Where events are queued in a local SQLite table to ensure delivery
     If provided, pings a heartbeat api key regularly
+    If provided, runs local checks that ping heartbeats and queue ALERT/RESOLVE events
+    If provided, watches the local queues and alerts through its own integration key when they back up
+    If provided, tracks alerts from consumers and queues a RESOLVE once they go silent
+    If provided, tracks devices publishing to consumer topics and alerts once they go silent
     If provided, connects to MQTT or Kafka broker and proxies events (through queue) and heartbeats
     If http server or mqtt client is started will also spawn a poll thread to poll the db
     Kafka will use the consumer offset to ensure at least once delivery, no db polling needed
@@ -615,8 +1199,16 @@ async fn run_daemon(config: &ILConfig) {
     })
     .expect("Error setting Ctrl-C handler");
 
-    // poll is only needed if mqtt or web server are running
-    let is_poll_needed = config.start_http || config.mqtt_buffer;
+    // poll is only needed if mqtt (buffered, deduplicated or sparkplug), buffered kafka, web server, local checks, auto resolve, thresholds or device watches are running
+    let is_poll_needed = config.start_http
+        || config.mqtt_buffer
+        || config.kafka_buffer
+        || (config.mqtt_host.is_some() && dedup::is_enabled(config))
+        || !config.checks.is_empty()
+        || auto_resolve::is_enabled(config)
+        || !config.thresholds.is_empty()
+        || !config.device_watches.is_empty()
+        || config.sparkplug_topic.is_some();
     let mut poll_job = None;
     if is_poll_needed {
         info!("Starting poll job..");
@@ -627,7 +1219,7 @@ async fn run_daemon(config: &ILConfig) {
     }
 
     let mut hbt_job = None;
-    if config.heartbeat_key.is_some() {
+    if !config.heartbeats.is_empty() {
         info!("Running regular heartbeats..");
         let cloned_ctx = daemon_ctx.clone();
         hbt_job = Some(tokio::spawn(async move {
@@ -635,6 +1227,42 @@ async fn run_daemon(config: &ILConfig) {
         }));
     }
 
+    let mut check_job = None;
+    if !config.checks.is_empty() {
+        info!("Running local checks..");
+        let cloned_ctx = daemon_ctx.clone();
+        check_job = Some(tokio::spawn(async move {
+            checks::run_check_job(cloned_ctx).await;
+        }));
+    }
+
+    let mut watchdog_job = None;
+    if config.queue_watchdog.is_some() {
+        info!("Running queue watchdog..");
+        let cloned_ctx = daemon_ctx.clone();
+        watchdog_job = Some(tokio::spawn(async move {
+            watchdog::run_watchdog_job(cloned_ctx).await;
+        }));
+    }
+
+    let mut auto_resolve_job = None;
+    if auto_resolve::is_enabled(config) {
+        info!("Running auto resolve job..");
+        let cloned_ctx = daemon_ctx.clone();
+        auto_resolve_job = Some(tokio::spawn(async move {
+            auto_resolve::run_auto_resolve_job(cloned_ctx).await;
+        }));
+    }
+
+    let mut device_watch_job = None;
+    if !config.device_watches.is_empty() {
+        info!("Running device watch job..");
+        let cloned_ctx = daemon_ctx.clone();
+        device_watch_job = Some(tokio::spawn(async move {
+            device_watch::run_device_watch_job(cloned_ctx).await;
+        }));
+    }
+
     let mut mqtt_job = None;
     let mut mqtt_poll_job = None;
     if config.mqtt_host.is_some() {
@@ -708,6 +1336,26 @@ async fn run_daemon(config: &ILConfig) {
         debug!("hbt ended");
     }
 
+    if let Some(handle) = check_job {
+        handle.await.expect("Failed to join check thread");
+        debug!("checks ended");
+    }
+
+    if let Some(handle) = watchdog_job {
+        handle.await.expect("Failed to join watchdog thread");
+        debug!("watchdog ended");
+    }
+
+    if let Some(handle) = auto_resolve_job {
+        handle.await.expect("Failed to join auto resolve thread");
+        debug!("auto resolve ended");
+    }
+
+    if let Some(handle) = device_watch_job {
+        handle.await.expect("Failed to join device watch thread");
+        debug!("device watch ended");
+    }
+
     if let Some(handle) = mqtt_poll_job {
         handle.await.expect("Failed to join mqtt poll thread");
         debug!("mqtt poll ended");
@@ -822,7 +1470,7 @@ mod tests {
         let config = build_daemon_config(sub, &m);
         assert!(!config.start_http);
         assert_eq!(config.http_port, 8977);
-        assert!(config.heartbeat_key.is_none());
+        assert!(config.heartbeats.is_empty());
         assert!(config.mqtt_host.is_none());
         assert!(config.kafka_brokers.is_none());
         assert_eq!(config.db_file, "./ilagent.db3");
@@ -850,187 +1498,687 @@ mod tests {
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert_eq!(config.heartbeat_key.unwrap(), "il1hbt123");
+        assert_eq!(config.heartbeats.len(), 1);
+        assert_eq!(config.heartbeats[0].integration_key, "il1hbt123");
+        assert_eq!(config.heartbeats[0].interval.as_secs(), 30);
     }
 
-    // --- build_daemon_config: MQTT ---
-
     #[test]
-    #[should_panic(expected = "At least one MQTT topic must be configured")]
-    fn daemon_config_mqtt_requires_topic() {
+    fn daemon_config_with_multiple_heartbeats() {
         let m = build_cli()
-            .try_get_matches_from(vec!["ilagent", "daemon", "-m", "broker.local"])
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-b",
+                "il1hbt123",
+                "--heartbeat",
+                "key=il1hbt456,interval=120,jitter=10",
+            ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
-        build_daemon_config(sub, &m);
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.heartbeats.len(), 2);
+        assert_eq!(config.heartbeats[1].integration_key, "il1hbt456");
+        assert_eq!(config.heartbeats[1].interval.as_secs(), 120);
+        assert_eq!(config.heartbeats[1].jitter.as_secs(), 10);
     }
 
     #[test]
-    #[should_panic(expected = "QoS 0 has no broker acknowledgement")]
-    fn daemon_config_mqtt_non_buffered_rejects_qos_zero() {
+    #[should_panic(expected = "requires --mqtt_host")]
+    fn daemon_config_heartbeat_condition_requires_consumer() {
         let m = build_cli()
             .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
-                "-m",
-                "broker.local",
-                "-e",
-                "ilert/events",
+                "-b",
+                "key=il1hbt123,condition=mqtt_ready",
             ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         build_daemon_config(sub, &m);
     }
 
+    // --- build_daemon_config: checks ---
+
     #[test]
-    fn daemon_config_mqtt_defaults_with_event_topic() {
+    fn daemon_config_with_checks() {
         let m = build_cli()
             .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
-                "-m",
-                "broker.local",
-                "-e",
-                "ilert/events",
-                "--mqtt_qos",
-                "1",
+                "--check",
+                "name=db,tcp=localhost:5432,heartbeat=il1hbt1",
+                "--check",
+                "name=api,http=http://localhost:8080/health,integration_key=il1api1,interval=15",
             ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert_eq!(config.mqtt_host.unwrap(), "broker.local");
-        assert_eq!(config.mqtt_port.unwrap(), 1883);
-        assert_eq!(config.mqtt_name.unwrap(), "ilagent");
-        assert_eq!(config.event_topic.unwrap(), "ilert/events");
-        assert!(config.heartbeat_topic.is_none());
-        assert_eq!(config.mqtt_qos, 1);
-        assert!(!config.mqtt_tls);
+        assert_eq!(config.checks.len(), 2);
+        assert_eq!(config.checks[0].name, "db");
+        assert_eq!(config.checks[1].interval.as_secs(), 15);
     }
 
     #[test]
-    fn daemon_config_mqtt_buffer_allows_qos_zero() {
+    #[should_panic(expected = "Invalid --check")]
+    fn daemon_config_rejects_invalid_check() {
+        let m = build_cli()
+            .try_get_matches_from(vec!["ilagent", "daemon", "--check", "name=db"])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_queue_limit_and_watchdog() {
         let m = build_cli()
             .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
-                "-m",
-                "broker.local",
-                "-e",
-                "ilert/events",
-                "--mqtt_buffer",
+                "--queue_max_items",
+                "500",
+                "--queue_overflow",
+                "reject_new",
+                "--queue_watchdog",
+                "integration_key=il1api1,max_age=900",
             ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert!(config.mqtt_buffer);
-        assert_eq!(config.mqtt_qos, 0);
+        assert_eq!(config.event_queue_limit.max_items, Some(500));
+        assert_eq!(config.mqtt_queue_limit.max_items, Some(500));
+        assert_eq!(config.event_queue_limit.overflow, QueueOverflow::RejectNew);
+        let watchdog = config.queue_watchdog.unwrap();
+        assert_eq!(watchdog.integration_key, "il1api1");
+        assert_eq!(watchdog.max_age.unwrap().as_secs(), 900);
     }
 
     #[test]
-    fn daemon_config_mqtt_custom() {
+    fn daemon_config_with_per_queue_limits() {
         let m = build_cli()
             .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
-                "-m",
-                "broker.local",
-                "-q",
-                "8883",
-                "-n",
-                "myagent",
-                "--mqtt_tls",
-                "--mqtt_username",
-                "user1",
-                "--mqtt_password",
-                "pass1",
-                "-e",
-                "custom/events",
-                "-r",
-                "custom/heartbeats",
-                "--mqtt_qos",
-                "1",
+                "--queue_max_bytes",
+                "1000000",
+                "--queue_limit",
+                "queue=events,max_items=100,overflow=drop_lowest_priority",
             ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert_eq!(config.mqtt_port.unwrap(), 8883);
-        assert_eq!(config.mqtt_name.unwrap(), "myagent");
-        assert!(config.mqtt_tls);
-        assert_eq!(config.mqtt_username.unwrap(), "user1");
-        assert_eq!(config.mqtt_password.unwrap(), "pass1");
-        assert_eq!(config.event_topic.unwrap(), "custom/events");
-        assert_eq!(config.heartbeat_topic.unwrap(), "custom/heartbeats");
+        assert_eq!(config.event_queue_limit.max_items, Some(100));
+        assert_eq!(config.event_queue_limit.max_bytes, Some(1000000));
+        assert_eq!(
+            config.event_queue_limit.overflow,
+            QueueOverflow::DropLowestPriority
+        );
+        assert_eq!(config.mqtt_queue_limit.max_items, None);
+        assert_eq!(config.mqtt_queue_limit.max_bytes, Some(1000000));
+        assert_eq!(config.mqtt_queue_limit.overflow, QueueOverflow::DropOldest);
     }
 
     #[test]
-    fn daemon_config_mqtt_tls_certs() {
+    fn daemon_config_with_dedup_and_resolve_hold() {
         let m = build_cli()
             .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
-                "-m",
-                "broker.local",
-                "--mqtt_tls",
-                "-e",
-                "ilert/events",
-                "--mqtt_qos",
-                "1",
-                "--mqtt_ca",
-                "/certs/ca.pem",
-                "--mqtt_client_cert",
-                "/certs/client.pem",
-                "--mqtt_client_key",
-                "/certs/client.key",
+                "--dedup_window",
+                "120",
+                "--resolve_hold",
+                "30",
             ])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert!(config.mqtt_tls);
-        assert_eq!(config.mqtt_ca_path.unwrap(), "/certs/ca.pem");
-        assert_eq!(config.mqtt_client_cert_path.unwrap(), "/certs/client.pem");
-        assert_eq!(config.mqtt_client_key_path.unwrap(), "/certs/client.key");
+        assert_eq!(config.dedup_window, Some(Duration::from_secs(120)));
+        assert_eq!(config.resolve_hold, Some(Duration::from_secs(30)));
     }
 
-    // --- build_daemon_config: Kafka ---
-
     #[test]
-    fn daemon_config_kafka_does_not_force_http() {
+    fn daemon_config_with_coalesce_events() {
         let m = build_cli()
-            .try_get_matches_from(vec![
-                "ilagent",
-                "daemon",
-                "--kafka_brokers",
-                "localhost:9092",
-                "-e",
-                "kafka/events",
-            ])
+            .try_get_matches_from(vec!["ilagent", "daemon", "--coalesce_events", "keep_last"])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
-        assert_eq!(config.kafka_brokers.unwrap(), "localhost:9092");
-        assert_eq!(config.kafka_group_id.unwrap(), "ilagent");
-        assert!(!config.start_http, "kafka should not force http on");
+        assert_eq!(config.coalesce_events, Some(CoalesceMode::KeepLast));
     }
 
     #[test]
-    #[should_panic(expected = "At least one Kafka topic must be configured")]
-    fn daemon_config_kafka_requires_topic() {
+    #[should_panic(expected = "Invalid --coalesce_events")]
+    fn daemon_config_rejects_invalid_coalesce_events() {
         let m = build_cli()
-            .try_get_matches_from(vec![
-                "ilagent",
-                "daemon",
-                "--kafka_brokers",
-                "localhost:9092",
-            ])
+            .try_get_matches_from(vec!["ilagent", "daemon", "--coalesce_events", "merge"])
             .unwrap();
         let sub = m.subcommand_matches("daemon").unwrap();
         build_daemon_config(sub, &m);
     }
 
     #[test]
-    fn daemon_config_kafka_custom_group() {
+    #[should_panic(expected = "Invalid --queue_overflow")]
+    fn daemon_config_rejects_invalid_queue_overflow() {
         let m = build_cli()
-            .try_get_matches_from(vec![
+            .try_get_matches_from(vec!["ilagent", "daemon", "--queue_overflow", "block"])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    // --- build_daemon_config: MQTT ---
+
+    #[test]
+    #[should_panic(expected = "At least one MQTT topic must be configured")]
+    fn daemon_config_mqtt_requires_topic() {
+        let m = build_cli()
+            .try_get_matches_from(vec!["ilagent", "daemon", "-m", "broker.local"])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_sparkplug() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_qos",
+                "1",
+                "--sparkplug",
+                "--event_key",
+                "il1api1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.sparkplug_topic.as_deref(), Some("spBv1.0/+/#"));
+
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_qos",
+                "1",
+                "--sparkplug",
+                "plant1",
+                "--route",
+                "topic=spBv1.0/plant1/#,key=il1api2",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.sparkplug_topic.as_deref(), Some("spBv1.0/plant1/#"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --sparkplug: --event_key or --route is required")]
+    fn daemon_config_mqtt_sparkplug_requires_integration_key() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_qos",
+                "1",
+                "--sparkplug",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_v5() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_qos",
+                "1",
+                "-e",
+                "ilert/events",
+                "--event_key",
+                "il1api1",
+                "--mqtt_version",
+                "5",
+                "--mqtt_session_expiry",
+                "3600",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert!(config.mqtt_v5);
+        assert_eq!(config.mqtt_session_expiry, Some(3600));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --mqtt_session_expiry: requires --mqtt_version 5")]
+    fn daemon_config_mqtt_session_expiry_requires_v5() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_qos",
+                "1",
+                "-e",
+                "ilert/events",
+                "--mqtt_session_expiry",
+                "3600",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    #[should_panic(expected = "QoS 0 has no broker acknowledgement")]
+    fn daemon_config_mqtt_non_buffered_rejects_qos_zero() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "ilert/events",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_defaults_with_event_topic() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "ilert/events",
+                "--mqtt_qos",
+                "1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.mqtt_host.unwrap(), "broker.local");
+        assert_eq!(config.mqtt_port.unwrap(), 1883);
+        assert_eq!(config.mqtt_name.unwrap(), "ilagent");
+        assert_eq!(config.event_topics, vec!["ilert/events"]);
+        assert!(config.heartbeat_topics.is_empty());
+        assert_eq!(config.mqtt_qos, 1);
+        assert!(!config.mqtt_tls);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_buffer_allows_qos_zero() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "ilert/events",
+                "--mqtt_buffer",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert!(config.mqtt_buffer);
+        assert_eq!(config.mqtt_qos, 0);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_custom() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-q",
+                "8883",
+                "-n",
+                "myagent",
+                "--mqtt_tls",
+                "--mqtt_username",
+                "user1",
+                "--mqtt_password",
+                "pass1",
+                "-e",
+                "custom/events",
+                "-r",
+                "custom/heartbeats",
+                "--mqtt_qos",
+                "1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.mqtt_port.unwrap(), 8883);
+        assert_eq!(config.mqtt_name.unwrap(), "myagent");
+        assert!(config.mqtt_tls);
+        assert_eq!(config.mqtt_username.unwrap(), "user1");
+        assert_eq!(config.mqtt_password.unwrap(), "pass1");
+        assert_eq!(config.event_topics, vec!["custom/events"]);
+        assert_eq!(config.heartbeat_topics, vec!["custom/heartbeats"]);
+    }
+
+    #[test]
+    fn daemon_config_mqtt_tls_certs() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--mqtt_tls",
+                "-e",
+                "ilert/events",
+                "--mqtt_qos",
+                "1",
+                "--mqtt_ca",
+                "/certs/ca.pem",
+                "--mqtt_client_cert",
+                "/certs/client.pem",
+                "--mqtt_client_key",
+                "/certs/client.key",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert!(config.mqtt_tls);
+        assert_eq!(config.mqtt_ca_path.unwrap(), "/certs/ca.pem");
+        assert_eq!(config.mqtt_client_cert_path.unwrap(), "/certs/client.pem");
+        assert_eq!(config.mqtt_client_key_path.unwrap(), "/certs/client.key");
+    }
+
+    // --- build_daemon_config: Kafka ---
+
+    #[test]
+    fn daemon_config_kafka_does_not_force_http() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.kafka_brokers.unwrap(), "localhost:9092");
+        assert_eq!(config.kafka_group_id.unwrap(), "ilagent");
+        assert!(!config.start_http, "kafka should not force http on");
+    }
+
+    #[test]
+    #[should_panic(expected = "At least one Kafka topic must be configured")]
+    fn daemon_config_kafka_requires_topic() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_kafka_multiple_topics() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "-e",
+                "^alerts-.*",
+                "--heartbeat_topic",
+                "hb-1",
+                "--heartbeat_topic",
+                "hb-2",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.event_topics, vec!["kafka/events", "^alerts-.*"]);
+        assert_eq!(config.heartbeat_topics, vec!["hb-1", "hb-2"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid Kafka topic regex")]
+    fn daemon_config_kafka_rejects_invalid_topic_regex() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "^alerts-(",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_kafka_sasl_ssl() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            b"# tuning\nfetch.min.bytes=1024\nsession.timeout.ms=10000\n",
+        )
+        .unwrap();
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "broker:9093",
+                "-e",
+                "kafka/events",
+                "--kafka_security_protocol",
+                "sasl_ssl",
+                "--kafka_sasl_mechanism",
+                "SCRAM-SHA-256",
+                "--kafka_sasl_username",
+                "user1",
+                "--kafka_sasl_password",
+                "pass1",
+                "--kafka_ca",
+                "/certs/ca.pem",
+                "--kafka_properties_file",
+                file.path().to_str().unwrap(),
+                "--kafka_property",
+                "session.timeout.ms=15000",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.kafka_security_protocol.unwrap(), "sasl_ssl");
+        assert_eq!(config.kafka_sasl_mechanism.unwrap(), "SCRAM-SHA-256");
+        assert_eq!(config.kafka_sasl_username.unwrap(), "user1");
+        assert_eq!(config.kafka_sasl_password.unwrap(), "pass1");
+        assert_eq!(config.kafka_ca_path.unwrap(), "/certs/ca.pem");
+        assert_eq!(
+            config.kafka_properties,
+            vec![
+                ("fetch.min.bytes".to_string(), "1024".to_string()),
+                ("session.timeout.ms".to_string(), "10000".to_string()),
+                ("session.timeout.ms".to_string(), "15000".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid Kafka security options")]
+    fn daemon_config_kafka_sasl_requires_sasl_protocol() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "broker:9093",
+                "-e",
+                "kafka/events",
+                "--kafka_sasl_mechanism",
+                "PLAIN",
+                "--kafka_sasl_username",
+                "user1",
+                "--kafka_sasl_password",
+                "pass1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_kafka_retry_topic() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_retry_topic",
+                "ilert-events-retry",
+                "--kafka_retry_attempts",
+                "3",
+                "--kafka_dlq_topic",
+                "ilert-events-dlq",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.kafka_retry_topic.unwrap(), "ilert-events-retry");
+        assert_eq!(config.kafka_retry_attempts, 3);
+        assert_eq!(config.kafka_dlq_topic.unwrap(), "ilert-events-dlq");
+    }
+
+    #[test]
+    fn daemon_config_kafka_buffer() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_buffer",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert!(config.kafka_buffer);
+    }
+
+    #[test]
+    fn daemon_config_kafka_max_lag() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_max_lag",
+                "10000",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.kafka_max_lag, Some(10000));
+    }
+
+    #[test]
+    fn daemon_config_kafka_commit_interval() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_commit_interval",
+                "250",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.kafka_commit_interval, Duration::from_millis(250));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --kafka_commit_interval '0'")]
+    fn daemon_config_kafka_rejects_zero_commit_interval() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_commit_interval",
+                "0",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --kafka_retry_attempts '0'")]
+    fn daemon_config_kafka_rejects_zero_retry_attempts() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "kafka/events",
+                "--kafka_retry_attempts",
+                "0",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_kafka_custom_group() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
                 "ilagent",
                 "daemon",
                 "--kafka_brokers",
@@ -1044,7 +2192,7 @@ mod tests {
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
         assert_eq!(config.kafka_group_id.unwrap(), "mygroup");
-        assert_eq!(config.event_topic.unwrap(), "kafka/events");
+        assert_eq!(config.event_topics, vec!["kafka/events"]);
     }
 
     // --- build_daemon_config: consumer mappings ---
@@ -1094,6 +2242,361 @@ mod tests {
         assert_eq!(config.filter_val.unwrap(), "ALARM");
     }
 
+    #[test]
+    fn daemon_config_auto_resolve_via_mqtt() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--auto_resolve",
+                "600",
+                "--auto_resolve_rule",
+                "topic=plant/+/alarm,timeout=60",
+                "--auto_resolve_rule",
+                "topic=plant/#,timeout=300",
+                "--map_key_auto_resolve",
+                "meta.resolveAfter",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.auto_resolve, Some(Duration::from_secs(600)));
+        assert_eq!(config.auto_resolve_rules.len(), 2);
+        assert_eq!(config.auto_resolve_rules[0].topic, "plant/+/alarm");
+        assert_eq!(config.auto_resolve_rules[1].timeout.as_secs(), 300);
+        assert_eq!(config.map_key_auto_resolve.unwrap(), "meta.resolveAfter");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --auto_resolve '0': must be greater than 0")]
+    fn daemon_config_rejects_zero_auto_resolve() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--auto_resolve",
+                "0",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --auto_resolve_rule")]
+    fn daemon_config_rejects_auto_resolve_rule_without_timeout() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--auto_resolve_rule",
+                "topic=plant/#",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_heartbeat_mappings() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--heartbeat_topic",
+                "devices/+/alive",
+                "--mqtt_qos",
+                "1",
+                "--heartbeat_map",
+                "topic=devices/+/alive,key_level=1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.heartbeat_mappings.len(), 1);
+        assert_eq!(
+            config.heartbeat_mappings[0].topic.as_deref(),
+            Some("devices/+/alive")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --heartbeat_map")]
+    fn daemon_config_rejects_heartbeat_mapping_without_key() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "--heartbeat_topic",
+                "devices/+/alive",
+                "--mqtt_qos",
+                "1",
+                "--heartbeat_map",
+                "topic=devices/+/alive",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_device_watches() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--device_watch",
+                "name=sensors,topic=plant/+/status,interval=300,key_level=1,integration_key=il1api1",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.device_watches.len(), 1);
+        assert_eq!(config.device_watches[0].interval, Duration::from_secs(300));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Invalid --device_watch 'sensors': 'integration_key' or --event_key is required"
+    )]
+    fn daemon_config_device_watch_requires_integration_key() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--device_watch",
+                "name=sensors,topic=plant/+/status,interval=300",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_threshold_rules() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--event_key",
+                "il1api1",
+                "--threshold",
+                "name=temp,topic=plant/+/temp,path=value,above=80,hysteresis=5,for=60,key_level=1",
+                "--threshold",
+                "name=pressure,topic=plant/+/pressure,path=bar,below=1.5,key_path=device.id",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.thresholds.len(), 2);
+        assert_eq!(config.thresholds[0].above, Some(80.0));
+        assert_eq!(config.thresholds[0].min_duration, Duration::from_secs(60));
+        assert_eq!(config.thresholds[1].below, Some(1.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "'integration_key' or --event_key is required")]
+    fn daemon_config_rejects_threshold_without_integration_key() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--threshold",
+                "name=temp,topic=plant/+/temp,path=value,above=80",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_topic_variables() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/+/sensor/#",
+                "--mqtt_qos",
+                "1",
+                "--topic_pattern",
+                "plant/{site}/sensor/{device}",
+                "--alert_key_template",
+                "{site}-{device}",
+                "--summary_template",
+                "{device}: {summary}",
+                "--topic_var_key",
+                "var=site,value=berlin,key=il1api2",
+                "--filter_var",
+                "site=berlin",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        let vars = config
+            .topic_pattern
+            .as_ref()
+            .unwrap()
+            .capture("plant/berlin/sensor/t1")
+            .unwrap();
+        assert_eq!(vars["device"], "t1");
+        assert_eq!(config.alert_key_template.unwrap(), "{site}-{device}");
+        assert_eq!(config.summary_template.unwrap(), "{device}: {summary}");
+        assert_eq!(config.topic_var_keys[0].key, "il1api2");
+        assert_eq!(
+            config.filter_vars,
+            vec![("site".to_string(), "berlin".to_string())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "require --topic_pattern")]
+    fn daemon_config_rejects_filter_var_without_pattern() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "-m",
+                "broker.local",
+                "-e",
+                "plant/#",
+                "--mqtt_qos",
+                "1",
+                "--filter_var",
+                "site=berlin",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    fn daemon_config_with_routes_via_kafka() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "alerts",
+                "--route",
+                "topic_regex=^alerts-.*,key=il1api1",
+                "--route",
+                "field=customer.id,value=42,key=il1api2",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.routes.len(), 2);
+        assert_eq!(config.routes[1].key, "il1api2");
+    }
+
+    #[test]
+    fn daemon_config_with_decoders() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "orders",
+                "--decoder",
+                "topic=orders,format=avro,registry=http://localhost:8081",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        let config = build_daemon_config(sub, &m);
+        assert_eq!(config.decoders.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --decoder 'topic=orders,format=avro'")]
+    fn daemon_config_rejects_decoder_without_schema() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "orders",
+                "--decoder",
+                "topic=orders,format=avro",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid --route")]
+    fn daemon_config_rejects_route_without_key() {
+        let m = build_cli()
+            .try_get_matches_from(vec![
+                "ilagent",
+                "daemon",
+                "--kafka_brokers",
+                "localhost:9092",
+                "-e",
+                "alerts",
+                "--route",
+                "topic=alerts",
+            ])
+            .unwrap();
+        let sub = m.subcommand_matches("daemon").unwrap();
+        build_daemon_config(sub, &m);
+    }
+
     // --- build_daemon_config: db file ---
 
     #[test]
@@ -1130,7 +2633,7 @@ mod tests {
         let config = build_daemon_config(sub, &m);
         assert!(config.start_http);
         assert_eq!(config.http_port, 3000);
-        assert_eq!(config.heartbeat_key.unwrap(), "il1hbt999");
+        assert_eq!(config.heartbeats[0].integration_key, "il1hbt999");
         assert_eq!(config.mqtt_host.unwrap(), "mqtt.example.com");
     }
 
@@ -1165,7 +2668,7 @@ mod tests {
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
         assert_eq!(config.api_key.as_deref().unwrap(), "il1api-test-token");
-        assert_eq!(config.policy_topic.unwrap(), "ilert/policies");
+        assert_eq!(config.policy_topics, vec!["ilert/policies"]);
         assert_eq!(config.policy_routing_keys.unwrap(), "location,slot");
         assert_eq!(config.filter_key.unwrap(), "eventType");
         assert_eq!(config.filter_val.unwrap(), "active");
@@ -1186,7 +2689,7 @@ mod tests {
         let sub = m.subcommand_matches("daemon").unwrap();
         let config = build_daemon_config(sub, &m);
         assert_eq!(config.api_key.as_deref().unwrap(), "il1api-test-token");
-        assert_eq!(config.policy_topic.unwrap(), "policy-updates");
+        assert_eq!(config.policy_topics, vec!["policy-updates"]);
         assert_eq!(config.policy_routing_keys.unwrap(), "location");
 
         unsafe {
@@ -1291,4 +2794,3 @@ async fn run_heartbeat(matches: &ArgMatches) {
         info!("Heartbeat ping successful");
     }
 }
-
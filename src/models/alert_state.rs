@@ -0,0 +1,8 @@
+#[derive(Debug, Clone)]
+pub struct AlertStateItem {
+    pub integration_key: String,
+    pub alert_key: String,
+    pub summary: String,
+    pub event_api_path: Option<String>,
+    pub last_alert_at: Option<String>,
+}
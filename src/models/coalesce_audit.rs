@@ -0,0 +1,8 @@
+#[derive(Debug, Clone)]
+pub struct CoalesceAuditItem {
+    pub integration_key: String,
+    pub alert_key: String,
+    pub kept_event_ids: String,
+    pub dropped_events: String,
+    pub coalesced_at: Option<String>,
+}
@@ -0,0 +1,7 @@
+#[derive(Debug, Clone)]
+pub struct DeviceStateItem {
+    pub watch_name: String,
+    pub device: String,
+    pub event_api_path: Option<String>,
+    pub last_seen: Option<String>,
+}
@@ -171,7 +171,9 @@ impl EventQueueItemJson {
 
         // event filter check
         if let Some(ref filter_key) = config.filter_key {
-            let val_opt = json.get(filter_key);
+            let val_opt = json
+                .get(filter_key)
+                .or_else(|| get_nested_value(&json, filter_key));
 
             if val_opt.is_none() {
                 debug!("Dropping event because filter key is missing");
@@ -210,6 +212,18 @@ impl EventQueueItemJson {
 
         // mappings
 
+        if let Some(ref map_key_integration_key) = config.map_key_integration_key
+            && let Some(val) = get_nested_value(&json, map_key_integration_key)
+        {
+            match val.as_str() {
+                Some(s) if !s.is_empty() => parsed.integrationKey = Some(s.to_string()),
+                _ => warn!(
+                    "map_key_integration_key '{}' matched a non-string value: {:?}",
+                    map_key_integration_key, val
+                ),
+            }
+        }
+
         if let Some(ref map_key_alert_key) = config.map_key_alert_key {
             if let Some(val) = get_nested_value(&json, map_key_alert_key) {
                 match val.as_str() {
@@ -105,6 +105,20 @@ mod tests {
         assert_eq!(result.unwrap().alertKey.unwrap(), "CODE-42");
     }
 
+    #[test]
+    fn parse_event_maps_nested_integration_key() {
+        let mut config = default_config();
+        config.event_key = Some("static-key".to_string());
+        config.map_key_integration_key = Some("_kafka.headers.integrationKey".to_string());
+        let payload = r#"{"apiKey": "k1", "summary": "test", "_kafka": {"headers": {"integrationKey": "k2"}}}"#;
+        let result = EventQueueItemJson::parse_event_json(&config, payload, "ilert/events");
+        assert_eq!(result.unwrap().integrationKey, "k2");
+
+        let payload = r#"{"apiKey": "k1", "summary": "test"}"#;
+        let result = EventQueueItemJson::parse_event_json(&config, payload, "ilert/events");
+        assert_eq!(result.unwrap().integrationKey, "static-key");
+    }
+
     #[test]
     fn parse_event_maps_custom_event_type_key() {
         let mut config = default_config();
@@ -262,6 +276,22 @@ mod tests {
         assert!(result.is_some());
     }
 
+    #[test]
+    fn parse_event_filter_nested_key_value() {
+        let mut config = default_config();
+        config.filter_key = Some("_kafka.headers.source".to_string());
+        config.filter_val = Some("plant".to_string());
+        let payload =
+            r#"{"apiKey": "k1", "summary": "test", "_kafka": {"headers": {"source": "plant"}}}"#;
+        let result = EventQueueItemJson::parse_event_json(&config, payload, "ilert/events");
+        assert!(result.is_some());
+
+        let payload =
+            r#"{"apiKey": "k1", "summary": "test", "_kafka": {"headers": {"source": "office"}}}"#;
+        let result = EventQueueItemJson::parse_event_json(&config, payload, "ilert/events");
+        assert!(result.is_none());
+    }
+
     #[test]
     fn parse_event_filter_key_and_value_mismatch_drops() {
         let mut config = default_config();
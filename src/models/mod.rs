@@ -1,3 +1,6 @@
+pub mod alert_state;
+pub mod coalesce_audit;
+pub mod device_state;
 pub mod event;
 pub mod event_db;
 pub mod heartbeat;
@@ -4,11 +4,13 @@ use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 
-use crate::DaemonContext;
-use crate::consumers::mqtt::{EnqueueResult, MessageType, classify_configured_message};
+use crate::consumers::mqtt::{
+    EnqueueResult, MessageType, classify_configured_message, topic_filter_matches,
+};
 use crate::models::event::EventQueueItemJson;
 use crate::models::event_db::EventQueueItem;
 use crate::models::mqtt_queue::MqttQueueItem;
+use crate::{DaemonContext, coalesce};
 use ilert::ilert::ILert;
 use ilert::ilert_builders::{EventApiResource, ILertEventType, ILertPriority};
 
@@ -30,6 +32,12 @@ pub async fn run_poll_job(daemon_ctx: Arc<DaemonContext>) -> () {
             last_run = Instant::now();
         }
 
+        if let Some(mode) = daemon_ctx.config.coalesce_events
+            && let Err(e) = coalesce::coalesce_queue(&*daemon_ctx.db.lock().await, mode)
+        {
+            error!("Failed to coalesce queued events {}", e);
+        }
+
         let items_result = daemon_ctx
             .db
             .lock()
@@ -120,7 +128,29 @@ async fn process_queued_events(
     had_failures
 }
 
+/// Result of delivering an event to ilert.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryOutcome {
+    Delivered,
+    Retry,
+    /// rejected by ilert (with the HTTP status) or not deliverable at all
+    Rejected(Option<u16>),
+}
+
+impl DeliveryOutcome {
+    pub fn should_retry(&self) -> bool {
+        matches!(self, DeliveryOutcome::Retry)
+    }
+}
+
+/// Returns true if the event should be retried.
 pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) -> bool {
+    deliver_queued_event(ilert_client, event)
+        .await
+        .should_retry()
+}
+
+pub async fn deliver_queued_event(ilert_client: &ILert, event: &EventQueueItem) -> DeliveryOutcome {
     let parsed_event = EventQueueItemJson::from_db(event.clone());
 
     let event_id = event.id.clone().unwrap_or("no_id".to_string());
@@ -132,7 +162,7 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
                 "Failed to parse event {} with type {}",
                 event_id, event.event_type
             );
-            return false; // broken event type, drop this event
+            return DeliveryOutcome::Rejected(None); // broken event type, drop this event
         }
     };
 
@@ -146,7 +176,7 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
                         "Failed to parse event {} with priority {}",
                         event_id, prio_str
                     );
-                    return false; // broken event priority, drop this event
+                    return DeliveryOutcome::Rejected(None); // broken event priority, drop this event
                 }
             }
         }
@@ -184,7 +214,7 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
         Ok(res) => res,
         _ => {
             error!("Network error during event post {}", event_id);
-            return true; // network error, retry
+            return DeliveryOutcome::Retry; // network error, retry
         }
     };
 
@@ -196,12 +226,12 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
             "Event id: {}, correlation-id: {:?} successfully delivered",
             event_id, correlation_id
         );
-        return false; // default happy case, no retry
+        return DeliveryOutcome::Delivered; // default happy case, no retry
     }
 
     if status == 429 {
         warn!("Event {} failed too many requests", event_id);
-        return true; // too many requests, retry
+        return DeliveryOutcome::Retry; // too many requests, retry
     }
 
     if status == 404 {
@@ -209,12 +239,12 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
             "Event {} failed with bad URL {}, potentially due to bad api key value",
             event_id, response.url
         );
-        return false; // no point in retrying
+        return DeliveryOutcome::Rejected(Some(status)); // no point in retrying
     }
 
     if status > 499 {
         warn!("Event {} failed server side exception", event_id);
-        return true; // 500 exceptions, retry
+        return DeliveryOutcome::Retry; // 500 exceptions, retry
     }
 
     warn!("Event {} failed bad request rejection {}", event_id, status);
@@ -222,7 +252,7 @@ pub async fn send_queued_event(ilert_client: &ILert, event: &EventQueueItem) ->
         "Response body: {}",
         response.body_raw.unwrap_or("No body provided".to_string())
     );
-    false // any other status code e.g. 400, no retry
+    DeliveryOutcome::Rejected(Some(status)) // any other status code e.g. 400, no retry
 }
 
 const MQTT_POLL_MIN_MS: u64 = 500;
@@ -282,19 +312,25 @@ async fn process_mqtt_queue(
     items: Vec<MqttQueueItem>,
     retry_counts: &mut HashMap<String, u32>,
 ) -> bool {
-    let policy_topic = daemon_ctx.config.policy_topic.clone();
     let max_retries = daemon_ctx.config.max_retries;
     let mut had_failures = false;
 
     for item in items.iter() {
         let item_id = item.id.clone().unwrap_or_default();
 
-        let should_retry = match classify_configured_message(
-            &item.topic,
-            daemon_ctx.config.event_topic.as_deref(),
-            daemon_ctx.config.heartbeat_topic.as_deref(),
-            policy_topic.as_deref(),
-        ) {
+        // decoded Sparkplug data is buffered as JSON under its spBv1.0 topic
+        let message_type = match daemon_ctx.config.sparkplug_topic.as_deref() {
+            Some(sparkplug_topic) if topic_filter_matches(sparkplug_topic, &item.topic) => {
+                MessageType::Event
+            }
+            _ => classify_configured_message(
+                &item.topic,
+                &daemon_ctx.config.event_topics,
+                &daemon_ctx.config.heartbeat_topics,
+                &daemon_ctx.config.policy_topics,
+            ),
+        };
+        let should_retry = match message_type {
             MessageType::Policy => {
                 crate::consumers::policy::handle_policy_update(
                     &daemon_ctx.ilert_client,
@@ -313,6 +349,7 @@ async fn process_mqtt_queue(
                 ) {
                     EnqueueResult::Inserted => false,
                     EnqueueResult::Filtered => false,
+                    EnqueueResult::Rejected => false,
                     EnqueueResult::DbError => true,
                 }
             }
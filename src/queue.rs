@@ -0,0 +1,549 @@
+use log::{info, warn};
+use std::fmt;
+
+use crate::config::{ILConfig, Spec};
+use crate::db::ILDatabase;
+use crate::dedup::{self, DedupDecision};
+use crate::models::event_db::EventQueueItem;
+
+/// What happens when a queue table reached its item or byte limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueOverflow {
+    DropOldest,
+    DropLowestPriority,
+    RejectNew,
+}
+
+impl QueueOverflow {
+    pub fn parse(val: &str) -> Result<QueueOverflow, String> {
+        match val {
+            "drop_oldest" => Ok(QueueOverflow::DropOldest),
+            "drop_lowest_priority" => Ok(QueueOverflow::DropLowestPriority),
+            "reject_new" => Ok(QueueOverflow::RejectNew),
+            _ => Err(format!(
+                "unsupported overflow mode '{}', expected 'drop_oldest', 'drop_lowest_priority' or 'reject_new'",
+                val
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueOverflow::DropOldest => "drop_oldest",
+            QueueOverflow::DropLowestPriority => "drop_lowest_priority",
+            QueueOverflow::RejectNew => "reject_new",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueTable {
+    Events,
+    Mqtt,
+}
+
+impl QueueTable {
+    pub fn parse(val: &str) -> Result<QueueTable, String> {
+        match val {
+            "events" => Ok(QueueTable::Events),
+            "mqtt" => Ok(QueueTable::Mqtt),
+            _ => Err(format!(
+                "unsupported queue '{}', expected 'events' or 'mqtt'",
+                val
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueTable::Events => "events",
+            QueueTable::Mqtt => "mqtt",
+        }
+    }
+}
+
+/// Size limits of a single queue table, unlimited by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueLimit {
+    pub max_items: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub overflow: QueueOverflow,
+}
+
+impl QueueLimit {
+    pub fn new() -> QueueLimit {
+        QueueLimit {
+            max_items: None,
+            max_bytes: None,
+            overflow: QueueOverflow::DropOldest,
+        }
+    }
+}
+
+impl Default for QueueLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+    Applies a per queue limit like `queue=mqtt,max_items=5000,max_bytes=10000000,overflow=reject_new`
+    on top of the limits that are already configured.
+*/
+pub fn apply_queue_limit(config: &mut ILConfig, definition: &str) -> Result<(), String> {
+    let mut spec = Spec::parse(definition)?;
+    let table = QueueTable::parse(&spec.take("queue").ok_or("'queue' is required")?)?;
+    let limit = match table {
+        QueueTable::Events => &mut config.event_queue_limit,
+        QueueTable::Mqtt => &mut config.mqtt_queue_limit,
+    };
+
+    if let Some(max_items) = spec.take_u64("max_items")? {
+        if max_items == 0 {
+            return Err("'max_items' must be greater than 0".to_string());
+        }
+        limit.max_items = Some(max_items);
+    }
+    if let Some(max_bytes) = spec.take_u64("max_bytes")? {
+        if max_bytes == 0 {
+            return Err("'max_bytes' must be greater than 0".to_string());
+        }
+        limit.max_bytes = Some(max_bytes);
+    }
+    if let Some(overflow) = spec.take("overflow") {
+        limit.overflow = QueueOverflow::parse(&overflow)?;
+    }
+    spec.finish()
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    Full,
+    /// dropped on purpose by deduplication or flap suppression
+    Suppressed(String),
+    Db(rusqlite::Error),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "queue is full"),
+            QueueError::Suppressed(reason) => write!(f, "event suppressed, {}", reason),
+            QueueError::Db(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for QueueError {
+    fn from(e: rusqlite::Error) -> Self {
+        QueueError::Db(e)
+    }
+}
+
+/// Adds an event to `event_items`, honoring deduplication, flap suppression and the configured limits.
+pub fn create_event(
+    config: &ILConfig,
+    db: &ILDatabase,
+    item: &EventQueueItem,
+) -> Result<Option<EventQueueItem>, QueueError> {
+    let hold = match dedup::check_event(config, db, item)? {
+        DedupDecision::Accept => None,
+        DedupDecision::Hold(hold) => Some(hold),
+        DedupDecision::Suppress(reason) => return Err(QueueError::Suppressed(reason)),
+    };
+
+    // evicted items are only dropped once the event is queued, which is when it counts for deduplication
+    let created = db.in_transaction(|| {
+        if !make_room(
+            db,
+            QueueTable::Events,
+            &config.event_queue_limit,
+            event_size(item),
+            priority_rank(item.priority.as_deref()),
+        )? {
+            return Ok(None);
+        }
+        let created = db
+            .create_il_event(item)?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        dedup::record_event(db, item)?;
+        if let Some(hold) = hold
+            && let Some(id) = created.id.as_deref()
+        {
+            db.hold_il_event(id, hold.as_secs())?;
+        }
+        Ok(Some(created))
+    });
+
+    match created {
+        Ok(Some(created)) => {
+            if let Some(hold) = hold {
+                info!(
+                    "Holding {} for alert {} for {}s",
+                    item.event_type,
+                    item.alert_key.as_deref().unwrap_or_default(),
+                    hold.as_secs()
+                );
+            }
+            Ok(Some(created))
+        }
+        Ok(None) => Err(QueueError::Full),
+        // the event could not be read back, its insert and evictions were rolled back
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds a raw message to the `mqtt_queue` buffer, honoring the configured limits.
+pub fn create_mqtt_item(
+    config: &ILConfig,
+    db: &ILDatabase,
+    topic: &str,
+    payload: &str,
+) -> Result<String, QueueError> {
+    let id = db.in_transaction(|| {
+        if !make_room(
+            db,
+            QueueTable::Mqtt,
+            &config.mqtt_queue_limit,
+            (topic.len() + payload.len()) as u64,
+            0,
+        )? {
+            return Ok(None);
+        }
+        db.create_mqtt_queue_item(topic, payload).map(Some)
+    })?;
+    id.ok_or(QueueError::Full)
+}
+
+/// Counter key in the ilagent table, e.g. `queue_events_rejected`.
+pub fn counter_key(table: QueueTable, action: &str) -> String {
+    format!("queue_{}_{}", table.as_str(), action)
+}
+
+// must match EVENT_ITEM_SIZE_SQL in db.rs
+fn event_size(item: &EventQueueItem) -> u64 {
+    let optional = [
+        &item.alert_key,
+        &item.details,
+        &item.priority,
+        &item.images,
+        &item.links,
+        &item.custom_details,
+    ];
+    let size = item.integration_key.len()
+        + item.event_type.len()
+        + item.summary.len()
+        + optional
+            .iter()
+            .map(|val| val.as_ref().map_or(0, |v| v.len()))
+            .sum::<usize>();
+    size as u64
+}
+
+// must match PRIORITY_RANK_SQL in db.rs
+fn priority_rank(priority: Option<&str>) -> u8 {
+    match priority {
+        Some("LOW") => 0,
+        Some("HIGH") => 2,
+        _ => 1,
+    }
+}
+
+/// Evicts items for a new one if the limit requires it, returns false if the new item is rejected.
+fn make_room(
+    db: &ILDatabase,
+    table: QueueTable,
+    limit: &QueueLimit,
+    item_size: u64,
+    item_priority: u8,
+) -> Result<bool, rusqlite::Error> {
+    if limit.max_items.is_none() && limit.max_bytes.is_none() {
+        return Ok(true);
+    }
+
+    if let Some(max_bytes) = limit.max_bytes
+        && item_size > max_bytes
+    {
+        let total = db.add_il_counter(&counter_key(table, "rejected"), 1)?;
+        warn!(
+            "Queue {} rejected an item of {} bytes, it exceeds the limit of {} bytes on its own ({} rejected in total)",
+            table.as_str(),
+            item_size,
+            max_bytes,
+            total
+        );
+        return Ok(false);
+    }
+
+    let (items, bytes) = match table {
+        QueueTable::Events => db.get_il_events_usage()?,
+        QueueTable::Mqtt => db.get_mqtt_queue_usage()?,
+    };
+    let excess_items = limit
+        .max_items
+        .map_or(0, |max| (items + 1).saturating_sub(max));
+    let excess_bytes = limit
+        .max_bytes
+        .map_or(0, |max| (bytes + item_size).saturating_sub(max));
+    if excess_items == 0 && excess_bytes == 0 {
+        return Ok(true);
+    }
+
+    if limit.overflow == QueueOverflow::RejectNew {
+        let total = db.add_il_counter(&counter_key(table, "rejected"), 1)?;
+        warn!(
+            "Queue {} is full ({} items, {} bytes), rejected new item ({} rejected in total)",
+            table.as_str(),
+            items,
+            bytes,
+            total
+        );
+        return Ok(false);
+    }
+
+    // the mqtt buffer holds raw payloads without priority, so it always drops the oldest
+    let ids = match table {
+        QueueTable::Events => db.select_il_events_to_evict(
+            (limit.overflow == QueueOverflow::DropLowestPriority).then_some(item_priority),
+            excess_items,
+            excess_bytes,
+        )?,
+        QueueTable::Mqtt => db.select_mqtt_queue_items_to_evict(excess_items, excess_bytes)?,
+    };
+    // only events of a lower priority than the new one make room for it
+    let Some(ids) = ids else {
+        let total = db.add_il_counter(&counter_key(table, "rejected"), 1)?;
+        warn!(
+            "Queue {} is full ({} items, {} bytes) without items of a lower priority, rejected new item ({} rejected in total)",
+            table.as_str(),
+            items,
+            bytes,
+            total
+        );
+        return Ok(false);
+    };
+    let dropped = match table {
+        QueueTable::Events => db.delete_il_events(&ids)?,
+        QueueTable::Mqtt => db.delete_mqtt_queue_items(&ids)?,
+    } as u64;
+    let total = db.add_il_counter(&counter_key(table, "dropped"), dropped)?;
+    warn!(
+        "Queue {} is full ({} items, {} bytes), dropped {} item(s) using {} ({} dropped in total)",
+        table.as_str(),
+        items,
+        bytes,
+        dropped,
+        limit.overflow.as_str(),
+        total
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp_db;
+
+    fn event(summary: &str) -> EventQueueItem {
+        EventQueueItem::new_with_required("k1", "ALERT", summary, None)
+    }
+
+    fn queued_summaries(db: &ILDatabase) -> Vec<String> {
+        let mut summaries: Vec<String> = db
+            .get_il_events(100)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.summary)
+            .collect();
+        summaries.sort();
+        summaries
+    }
+
+    #[test]
+    fn parse_overflow_modes() {
+        assert_eq!(
+            QueueOverflow::parse("drop_oldest").unwrap(),
+            QueueOverflow::DropOldest
+        );
+        assert_eq!(
+            QueueOverflow::parse("drop_lowest_priority").unwrap(),
+            QueueOverflow::DropLowestPriority
+        );
+        assert_eq!(
+            QueueOverflow::parse("reject_new").unwrap(),
+            QueueOverflow::RejectNew
+        );
+        assert!(QueueOverflow::parse("block").is_err());
+    }
+
+    #[test]
+    fn apply_queue_limit_overrides_single_table() {
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(100);
+        config.mqtt_queue_limit.max_items = Some(100);
+        apply_queue_limit(
+            &mut config,
+            "queue=mqtt,max_items=5,max_bytes=1000,overflow=reject_new",
+        )
+        .unwrap();
+        assert_eq!(config.event_queue_limit.max_items, Some(100));
+        assert_eq!(config.mqtt_queue_limit.max_items, Some(5));
+        assert_eq!(config.mqtt_queue_limit.max_bytes, Some(1000));
+        assert_eq!(config.mqtt_queue_limit.overflow, QueueOverflow::RejectNew);
+
+        assert!(apply_queue_limit(&mut config, "max_items=5").is_err());
+        assert!(apply_queue_limit(&mut config, "queue=kafka,max_items=5").is_err());
+        assert!(apply_queue_limit(&mut config, "queue=events,max_items=0").is_err());
+    }
+
+    #[test]
+    fn unlimited_queue_accepts_everything() {
+        let (db, _f) = temp_db();
+        let config = ILConfig::new();
+        for i in 0..5 {
+            create_event(&config, &db, &event(&format!("e{}", i))).unwrap();
+        }
+        assert_eq!(db.count_il_events().unwrap(), 5);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest_items() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(2);
+        for i in 0..4 {
+            create_event(&config, &db, &event(&format!("e{}", i))).unwrap();
+        }
+        assert_eq!(queued_summaries(&db), vec!["e2", "e3"]);
+        assert_eq!(
+            db.get_il_value(&counter_key(QueueTable::Events, "dropped")),
+            Some("2".to_string())
+        );
+    }
+
+    #[test]
+    fn drop_lowest_priority_keeps_high_priority_items() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(2);
+        config.event_queue_limit.overflow = QueueOverflow::DropLowestPriority;
+
+        let mut high = event("high");
+        high.priority = Some("HIGH".to_string());
+        let mut low = event("low");
+        low.priority = Some("LOW".to_string());
+        create_event(&config, &db, &high).unwrap();
+        create_event(&config, &db, &low).unwrap();
+        create_event(&config, &db, &event("unset")).unwrap();
+        assert_eq!(queued_summaries(&db), vec!["high", "unset"]);
+
+        // an item never drops another one of the same or a higher priority
+        let rejected = create_event(&config, &db, &event("newest"));
+        assert!(matches!(rejected, Err(QueueError::Full)));
+        assert_eq!(queued_summaries(&db), vec!["high", "unset"]);
+    }
+
+    #[test]
+    fn drop_lowest_priority_rejects_low_item_into_high_queue() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(2);
+        config.event_queue_limit.overflow = QueueOverflow::DropLowestPriority;
+
+        let mut high = event("high");
+        high.priority = Some("HIGH".to_string());
+        create_event(&config, &db, &high).unwrap();
+        create_event(&config, &db, &high).unwrap();
+
+        let mut low = event("low");
+        low.priority = Some("LOW".to_string());
+        let rejected = create_event(&config, &db, &low);
+        assert!(matches!(rejected, Err(QueueError::Full)));
+        assert_eq!(queued_summaries(&db), vec!["high", "high"]);
+        assert_eq!(
+            db.get_il_value(&counter_key(QueueTable::Events, "rejected")),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn rejected_event_is_no_duplicate() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.dedup_window = Some(std::time::Duration::from_secs(60));
+        config.event_queue_limit.max_items = Some(1);
+        config.event_queue_limit.overflow = QueueOverflow::RejectNew;
+
+        let queued = create_event(&config, &db, &event("other"))
+            .unwrap()
+            .unwrap();
+        let alert = EventQueueItem::new_with_required("k1", "ALERT", "hot", Some("a1".to_string()));
+        assert!(matches!(
+            create_event(&config, &db, &alert),
+            Err(QueueError::Full)
+        ));
+
+        db.delete_il_event(queued.id.as_deref().unwrap()).unwrap();
+        assert!(create_event(&config, &db, &alert).unwrap().is_some());
+        assert!(matches!(
+            create_event(&config, &db, &alert),
+            Err(QueueError::Suppressed(_))
+        ));
+    }
+
+    #[test]
+    fn failed_insert_keeps_evicted_events() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.event_queue_limit.max_items = Some(2);
+        create_event(&config, &db, &event("e0")).unwrap();
+        let queued = create_event(&config, &db, &event("e1")).unwrap().unwrap();
+
+        // the id is already queued, so the insert fails after e0 was evicted for it
+        let mut duplicate = event("e2");
+        duplicate.id = queued.id;
+        assert!(matches!(
+            create_event(&config, &db, &duplicate),
+            Err(QueueError::Db(_))
+        ));
+        assert_eq!(queued_summaries(&db), vec!["e0", "e1"]);
+        assert_eq!(
+            db.get_il_value(&counter_key(QueueTable::Events, "dropped")),
+            None
+        );
+    }
+
+    #[test]
+    fn byte_limit_drops_until_item_fits() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.mqtt_queue_limit.max_bytes = Some(30);
+        create_mqtt_item(&config, &db, "t", "0123456789").unwrap();
+        create_mqtt_item(&config, &db, "t", "0123456789").unwrap();
+        assert_eq!(db.get_mqtt_queue_usage().unwrap(), (2, 22));
+
+        create_mqtt_item(&config, &db, "t", "0123456789").unwrap();
+        assert_eq!(db.get_mqtt_queue_usage().unwrap(), (2, 22));
+
+        // a single item larger than the limit can never fit
+        let rejected = create_mqtt_item(&config, &db, "t", &"x".repeat(40));
+        assert!(matches!(rejected, Err(QueueError::Full)));
+    }
+
+    #[test]
+    fn reject_new_keeps_existing_items() {
+        let (db, _f) = temp_db();
+        let mut config = ILConfig::new();
+        config.mqtt_queue_limit.max_items = Some(1);
+        config.mqtt_queue_limit.overflow = QueueOverflow::RejectNew;
+        create_mqtt_item(&config, &db, "t", "first").unwrap();
+        let rejected = create_mqtt_item(&config, &db, "t", "second");
+        assert!(matches!(rejected, Err(QueueError::Full)));
+        assert_eq!(db.count_mqtt_queue_items().unwrap(), 1);
+        assert_eq!(
+            db.get_il_value(&counter_key(QueueTable::Mqtt, "rejected")),
+            Some("1".to_string())
+        );
+    }
+}
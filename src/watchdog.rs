@@ -0,0 +1,260 @@
+use log::{error, info, warn};
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::config::Spec;
+use crate::models::event_db::EventQueueItem;
+use crate::{DaemonContext, poll};
+use ilert::ilert_builders::ILertEventType;
+
+const DEFAULT_WATCHDOG_INTERVAL_SEC: u64 = 60;
+pub const WATCHDOG_ALERT_KEY: &str = "ilagent-queue-watchdog";
+
+/**
+    Self-monitoring of the local queues, e.g.
+    `integration_key=il1api...,max_age=900,max_events=1000,max_mqtt_queue=5000`.
+    Crossing a threshold sends an ALERT through its own integration key, draining sends a RESOLVE.
+    Both are sent directly instead of through the queue being watched.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchdogDefinition {
+    pub integration_key: String,
+    pub interval: Duration,
+    pub max_age: Option<Duration>,
+    pub max_events: Option<u64>,
+    pub max_mqtt_queue: Option<u64>,
+}
+
+impl WatchdogDefinition {
+    pub fn parse(definition: &str) -> Result<WatchdogDefinition, String> {
+        let mut spec = Spec::parse(definition)?;
+        let integration_key = spec
+            .take("integration_key")
+            .filter(|k| !k.is_empty())
+            .ok_or("'integration_key' is required")?;
+        let interval = spec
+            .take_u64("interval")?
+            .unwrap_or(DEFAULT_WATCHDOG_INTERVAL_SEC);
+        if interval == 0 {
+            return Err("'interval' must be greater than 0".to_string());
+        }
+
+        let watchdog = WatchdogDefinition {
+            integration_key,
+            interval: Duration::from_secs(interval),
+            max_age: spec.take_u64("max_age")?.map(Duration::from_secs),
+            max_events: spec.take_u64("max_events")?,
+            max_mqtt_queue: spec.take_u64("max_mqtt_queue")?,
+        };
+        spec.finish()?;
+
+        if watchdog.max_age.is_none()
+            && watchdog.max_events.is_none()
+            && watchdog.max_mqtt_queue.is_none()
+        {
+            return Err(
+                "at least one of 'max_age', 'max_events' or 'max_mqtt_queue' is required"
+                    .to_string(),
+            );
+        }
+        Ok(watchdog)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueueStats {
+    pub event_count: u64,
+    pub oldest_event_age: Option<u64>,
+    pub mqtt_queue_count: u64,
+    pub oldest_mqtt_queue_age: Option<u64>,
+}
+
+/// Returns every threshold of the watchdog that the given stats exceed.
+pub fn get_breaches(watchdog: &WatchdogDefinition, stats: &QueueStats) -> Vec<String> {
+    let mut breaches = Vec::new();
+    if let Some(max_age) = watchdog.max_age {
+        let max_age = max_age.as_secs();
+        if let Some(age) = stats.oldest_event_age
+            && age > max_age
+        {
+            breaches.push(format!("oldest event is {}s old (max {}s)", age, max_age));
+        }
+        if let Some(age) = stats.oldest_mqtt_queue_age
+            && age > max_age
+        {
+            breaches.push(format!(
+                "oldest buffered mqtt message is {}s old (max {}s)",
+                age, max_age
+            ));
+        }
+    }
+    if let Some(max_events) = watchdog.max_events
+        && stats.event_count > max_events
+    {
+        breaches.push(format!(
+            "{} events queued (max {})",
+            stats.event_count, max_events
+        ));
+    }
+    if let Some(max_mqtt_queue) = watchdog.max_mqtt_queue
+        && stats.mqtt_queue_count > max_mqtt_queue
+    {
+        breaches.push(format!(
+            "{} mqtt messages buffered (max {})",
+            stats.mqtt_queue_count, max_mqtt_queue
+        ));
+    }
+    breaches
+}
+
+pub fn build_watchdog_event(
+    watchdog: &WatchdogDefinition,
+    event_type: ILertEventType,
+    stats: &QueueStats,
+    breaches: &[String],
+) -> EventQueueItem {
+    let summary = match event_type {
+        ILertEventType::RESOLVE => "ilagent queue drained".to_string(),
+        _ => format!("ilagent queue is backing up: {}", breaches.join(", ")),
+    };
+    let mut event = EventQueueItem::new_with_required(
+        watchdog.integration_key.as_str(),
+        event_type.as_str(),
+        summary.as_str(),
+        Some(WATCHDOG_ALERT_KEY.to_string()),
+    );
+    event.custom_details = Some(
+        json!({
+            "eventCount": stats.event_count,
+            "oldestEventAgeSec": stats.oldest_event_age,
+            "mqttQueueCount": stats.mqtt_queue_count,
+            "oldestMqttQueueAgeSec": stats.oldest_mqtt_queue_age,
+        })
+        .to_string(),
+    );
+    event
+}
+
+async fn read_queue_stats(daemon_ctx: &DaemonContext) -> Result<QueueStats, rusqlite::Error> {
+    let db = daemon_ctx.db.lock().await;
+    Ok(QueueStats {
+        event_count: db.count_il_events()?,
+        oldest_event_age: db.get_oldest_il_event_age_secs()?,
+        mqtt_queue_count: db.count_mqtt_queue_items()?,
+        oldest_mqtt_queue_age: db.get_oldest_mqtt_queue_item_age_secs()?,
+    })
+}
+
+pub async fn run_watchdog_job(daemon_ctx: Arc<DaemonContext>) -> () {
+    let Some(watchdog) = daemon_ctx.config.queue_watchdog.clone() else {
+        return;
+    };
+    info!(
+        "Running queue watchdog every {}s",
+        watchdog.interval.as_secs()
+    );
+
+    let mut alerting = false;
+    let mut last_run: Option<Instant> = None;
+    while daemon_ctx.running.load(Ordering::Relaxed) {
+        if let Some(last) = last_run
+            && last.elapsed() < watchdog.interval
+        {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            continue;
+        }
+        last_run = Some(Instant::now());
+
+        let stats = match read_queue_stats(&daemon_ctx).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                error!("Queue watchdog failed to read queue stats {:?}", e);
+                continue;
+            }
+        };
+
+        let breaches = get_breaches(&watchdog, &stats);
+        let event_type = match (alerting, breaches.is_empty()) {
+            (false, false) => ILertEventType::ALERT,
+            (true, true) => ILertEventType::RESOLVE,
+            _ => continue,
+        };
+
+        let event = build_watchdog_event(&watchdog, event_type, &stats, &breaches);
+        // on failure the state stays as is, so the next run tries again
+        if poll::send_queued_event(&daemon_ctx.ilert_client, &event).await {
+            warn!("Queue watchdog failed to send {}", event.event_type);
+            continue;
+        }
+        alerting = !breaches.is_empty();
+        if alerting {
+            warn!("Queue watchdog alerted: {}", breaches.join(", "));
+        } else {
+            info!("Queue watchdog resolved, queues drained");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog() -> WatchdogDefinition {
+        WatchdogDefinition::parse("integration_key=k,max_age=60,max_events=10,max_mqtt_queue=20")
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_definition() {
+        let watchdog = watchdog();
+        assert_eq!(watchdog.integration_key, "k");
+        assert_eq!(watchdog.interval, Duration::from_secs(60));
+        assert_eq!(watchdog.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(watchdog.max_events, Some(10));
+        assert_eq!(watchdog.max_mqtt_queue, Some(20));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_definitions() {
+        assert!(WatchdogDefinition::parse("max_age=60").is_err());
+        assert!(WatchdogDefinition::parse("integration_key=k").is_err());
+        assert!(WatchdogDefinition::parse("integration_key=k,max_age=60,interval=0").is_err());
+        assert!(WatchdogDefinition::parse("integration_key=k,max_age=60,foo=1").is_err());
+    }
+
+    #[test]
+    fn breaches_only_for_exceeded_thresholds() {
+        let watchdog = watchdog();
+        let healthy = QueueStats {
+            event_count: 10,
+            oldest_event_age: Some(60),
+            mqtt_queue_count: 0,
+            oldest_mqtt_queue_age: None,
+        };
+        assert!(get_breaches(&watchdog, &healthy).is_empty());
+
+        let stuck = QueueStats {
+            event_count: 11,
+            oldest_event_age: Some(61),
+            mqtt_queue_count: 21,
+            oldest_mqtt_queue_age: Some(5),
+        };
+        assert_eq!(get_breaches(&watchdog, &stuck).len(), 3);
+    }
+
+    #[test]
+    fn build_event_uses_own_key_and_alert_key() {
+        let event = build_watchdog_event(
+            &watchdog(),
+            ILertEventType::ALERT,
+            &QueueStats::default(),
+            &["11 events queued (max 10)".to_string()],
+        );
+        assert_eq!(event.integration_key, "k");
+        assert_eq!(event.event_type, "ALERT");
+        assert_eq!(event.alert_key.as_deref(), Some(WATCHDOG_ALERT_KEY));
+        assert!(event.summary.contains("11 events queued"));
+    }
+}
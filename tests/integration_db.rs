@@ -1,3 +1,4 @@
+use ilagent::coalesce::{CoalesceMode, coalesce_queue};
 use ilagent::db::ILDatabase;
 use ilagent::models::event_db::EventQueueItem;
 use tempfile::NamedTempFile;
@@ -140,6 +141,17 @@ fn delete_nonexistent_event_returns_zero() {
     assert_eq!(deleted, 0);
 }
 
+#[test]
+fn oldest_event_age_reflects_queue() {
+    let (db, _f) = temp_db();
+    assert_eq!(db.get_oldest_il_event_age_secs().unwrap(), None);
+
+    let event = EventQueueItem::new_with_required("k1", "ALERT", "test", None);
+    db.create_il_event(&event).unwrap();
+    let age = db.get_oldest_il_event_age_secs().unwrap();
+    assert!(age.is_some_and(|a| a < 5));
+}
+
 #[test]
 fn insert_event_with_all_optional_fields() {
     let (db, _f) = temp_db();
@@ -184,3 +196,132 @@ fn get_missing_value_returns_none() {
     let (db, _f) = temp_db();
     assert!(db.get_il_value("nonexistent").is_none());
 }
+
+#[test]
+fn add_il_counter_accumulates() {
+    let (db, _f) = temp_db();
+    assert_eq!(db.add_il_counter("queue_events_dropped", 2).unwrap(), 2);
+    assert_eq!(db.add_il_counter("queue_events_dropped", 3).unwrap(), 5);
+    assert_eq!(
+        db.get_il_value("queue_events_dropped"),
+        Some("5".to_string())
+    );
+}
+
+// --- coalescing ---
+
+#[test]
+fn coalesce_queue_keeps_net_state_and_audits() {
+    let (db, _f) = temp_db();
+    for event_type in ["ALERT", "RESOLVE", "ALERT", "RESOLVE"] {
+        let event =
+            EventQueueItem::new_with_required("k1", event_type, "flappy", Some("a1".to_string()));
+        db.create_il_event(&event).unwrap();
+    }
+    let single = EventQueueItem::new_with_required("k1", "ALERT", "other", Some("a2".to_string()));
+    db.create_il_event(&single).unwrap();
+    let comment =
+        EventQueueItem::new_with_required("k1", "COMMENT", "note", Some("a2".to_string()));
+    db.create_il_event(&comment).unwrap();
+
+    let dropped = coalesce_queue(&db, CoalesceMode::KeepLast).unwrap();
+    assert_eq!(dropped, 3);
+
+    let mut remaining: Vec<(String, String)> = db
+        .get_il_events(10)
+        .unwrap()
+        .into_iter()
+        .map(|e| (e.alert_key.unwrap(), e.event_type))
+        .collect();
+    remaining.sort();
+    assert_eq!(
+        remaining,
+        vec![
+            ("a1".to_string(), "RESOLVE".to_string()),
+            ("a2".to_string(), "ALERT".to_string()),
+            ("a2".to_string(), "COMMENT".to_string()),
+        ]
+    );
+
+    let audit = db.get_il_coalesce_audit(10).unwrap();
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].alert_key, "a1");
+    assert_eq!(audit[0].dropped_events.matches("ALERT:").count(), 2);
+    assert_eq!(audit[0].dropped_events.matches("RESOLVE:").count(), 1);
+
+    // nothing left to coalesce
+    assert_eq!(coalesce_queue(&db, CoalesceMode::KeepLast).unwrap(), 0);
+}
+
+// --- alert state ---
+
+#[test]
+fn alert_state_expires_and_refreshes() {
+    let (db, _f) = temp_db();
+    db.upsert_alert_state("k1", "a1", "too hot", Some("/events/mqtt/k1"), 0)
+        .unwrap();
+    db.upsert_alert_state("k1", "a2", "too cold", None, 600)
+        .unwrap();
+
+    let expired = db.get_expired_alert_states(10).unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].alert_key, "a1");
+    assert_eq!(
+        expired[0].event_api_path.as_deref(),
+        Some("/events/mqtt/k1")
+    );
+    assert!(expired[0].last_alert_at.is_some());
+
+    // a new ALERT pushes the timeout out again
+    db.upsert_alert_state("k1", "a1", "still too hot", None, 600)
+        .unwrap();
+    assert!(db.get_expired_alert_states(10).unwrap().is_empty());
+
+    assert_eq!(db.delete_alert_state("k1", "a2").unwrap(), 1);
+    assert_eq!(db.delete_alert_state("k1", "a2").unwrap(), 0);
+}
+
+// --- threshold state ---
+
+#[test]
+fn threshold_state_resets_since_on_update() {
+    let (db, _f) = temp_db();
+    assert!(db.get_threshold_state("temp", "temp-a").unwrap().is_none());
+
+    db.set_threshold_state("temp", "temp-a", "pending").unwrap();
+    let (state, age) = db.get_threshold_state("temp", "temp-a").unwrap().unwrap();
+    assert_eq!(state, "pending");
+    assert!(age < 5);
+
+    db.set_threshold_state("temp", "temp-a", "alerting")
+        .unwrap();
+    let (state, _) = db.get_threshold_state("temp", "temp-a").unwrap().unwrap();
+    assert_eq!(state, "alerting");
+
+    assert_eq!(db.delete_threshold_state("temp", "temp-a").unwrap(), 1);
+    assert!(db.get_threshold_state("temp", "temp-a").unwrap().is_none());
+}
+
+// --- device state ---
+
+#[test]
+fn device_state_is_reported_silent_once() {
+    let (db, _f) = temp_db();
+    assert!(!db.touch_device("sensors", "d1", None, 300).unwrap());
+    assert!(!db.touch_device("sensors", "d2", None, 0).unwrap());
+
+    let silent = db.get_newly_silent_devices(10).unwrap();
+    assert_eq!(silent.len(), 1);
+    assert_eq!(silent[0].device, "d2");
+    assert!(silent[0].last_seen.is_some());
+
+    db.set_device_silent("sensors", "d2").unwrap();
+    assert!(db.get_newly_silent_devices(10).unwrap().is_empty());
+
+    // a message reports the silent device until its flag is cleared
+    assert!(db.touch_device("sensors", "d2", None, 300).unwrap());
+    assert!(db.touch_device("sensors", "d2", None, 300).unwrap());
+    assert!(db.get_newly_silent_devices(10).unwrap().is_empty());
+    db.clear_device_silent("sensors", "d2").unwrap();
+    assert!(!db.touch_device("sensors", "d2", None, 300).unwrap());
+}
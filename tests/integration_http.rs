@@ -9,6 +9,7 @@ use tokio::sync::Mutex;
 use ilagent::config::ILConfig;
 use ilagent::db::ILDatabase;
 use ilagent::http_server::{WebContextContainer, config_app};
+use ilagent::queue::QueueOverflow;
 use ilagent::{DaemonContext, KafkaProbeState, MqttProbeState};
 
 fn test_container() -> (web::Data<Mutex<WebContextContainer>>, NamedTempFile) {
@@ -284,6 +285,18 @@ fn test_daemon_ctx_full(
     web::Data<Mutex<WebContextContainer>>,
     web::Data<Arc<DaemonContext>>,
     NamedTempFile,
+) {
+    test_daemon_ctx_with_config(mqtt_probe, kafka_probe, ILConfig::new())
+}
+
+fn test_daemon_ctx_with_config(
+    mqtt_probe: Option<MqttProbeState>,
+    kafka_probe: Option<KafkaProbeState>,
+    mut config: ILConfig,
+) -> (
+    web::Data<Mutex<WebContextContainer>>,
+    web::Data<Arc<DaemonContext>>,
+    NamedTempFile,
 ) {
     let file = NamedTempFile::new().unwrap();
     let db_path = file.path().to_str().unwrap();
@@ -295,7 +308,6 @@ fn test_daemon_ctx_full(
         ilert_client: ILert::new().unwrap(),
     }));
 
-    let mut config = ILConfig::new();
     config.db_file = db_path.to_string();
 
     let daemon_ctx = Arc::new(DaemonContext {
@@ -414,6 +426,39 @@ async fn ready_returns_503_mqtt_partial_suback() {
     assert_eq!(body["subscriptions_ready"], false);
 }
 
+#[actix_rt::test]
+async fn ready_returns_503_mqtt_subscription_rejected() {
+    let probe = MqttProbeState::new(2);
+    probe.set_connected();
+    probe.record_suback_success();
+    probe.record_suback_rejected("$share/ilagent/ilert/events", "NotAuthorized");
+    assert_eq!(probe.reconnect_attempts.load(Ordering::Relaxed), 0);
+    let (container, daemon_data, _f) = test_daemon_ctx(Some(probe));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(container.clone())
+            .app_data(daemon_data.clone())
+            .configure(config_app),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/ready").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["subscriptions_ready"], false);
+    assert_eq!(
+        body["rejected_subscriptions"],
+        serde_json::json!([{"topic": "$share/ilagent/ilert/events", "reason": "NotAuthorized"}])
+    );
+    assert_eq!(
+        body["error"],
+        "Subscription to $share/ilagent/ilert/events rejected: NotAuthorized"
+    );
+}
+
 #[actix_rt::test]
 async fn ready_returns_204_mqtt_fully_ready() {
     let probe = MqttProbeState::new(2);
@@ -552,10 +597,12 @@ async fn ready_returns_503_kafka_started_not_subscribed() {
 }
 
 #[actix_rt::test]
-async fn ready_returns_204_kafka_subscribed_and_running() {
+async fn ready_returns_200_kafka_subscribed_and_running_with_partitions() {
     let probe = KafkaProbeState::new();
     probe.consumer_started.store(true, Ordering::Relaxed);
     probe.subscribed.store(true, Ordering::Relaxed);
+    probe.assign_partitions(&[("events".to_string(), 0)]);
+    probe.update_partition("events", 0, Some(40), Some(3));
     let (container, daemon_data, _f) = test_daemon_ctx_full(None, Some(probe));
 
     let app = test::init_service(
@@ -568,7 +615,17 @@ async fn ready_returns_204_kafka_subscribed_and_running() {
 
     let req = test::TestRequest::get().uri("/ready").to_request();
     let resp = test::call_service(&app, req).await;
-    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.status(), 200);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["component"], "kafka");
+    assert_eq!(body["subscribed"], true);
+    assert_eq!(body["lagging"], false);
+    assert_eq!(body["max_lag"], 3);
+    assert_eq!(
+        body["partitions"],
+        json!([{"topic": "events", "partition": 0, "committed_offset": 40, "lag": 3}])
+    );
 }
 
 #[actix_rt::test]
@@ -597,3 +654,130 @@ async fn ready_returns_503_kafka_worker_exited() {
     assert_eq!(body["worker_exited"], true);
     assert_eq!(body["error"], "broker connection lost");
 }
+
+#[actix_rt::test]
+async fn ready_returns_503_kafka_delivery_failing() {
+    let probe = KafkaProbeState::new();
+    probe.consumer_started.store(true, Ordering::Relaxed);
+    probe.subscribed.store(true, Ordering::Relaxed);
+    probe.delivery_failing.store(true, Ordering::Relaxed);
+    probe.record_error("Failed to deliver Kafka message events/0@42 (attempt 3)".to_string());
+    let (container, daemon_data, _f) = test_daemon_ctx_full(None, Some(probe));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(container.clone())
+            .app_data(daemon_data.clone())
+            .configure(config_app),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/ready").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["component"], "kafka");
+    assert_eq!(body["worker_exited"], false);
+    assert_eq!(body["delivery_failing"], true);
+}
+
+#[actix_rt::test]
+async fn ready_returns_503_kafka_lagging_with_partitions() {
+    let probe = KafkaProbeState::new();
+    probe.consumer_started.store(true, Ordering::Relaxed);
+    probe.subscribed.store(true, Ordering::Relaxed);
+    probe.assign_partitions(&[("events".to_string(), 1), ("events".to_string(), 0)]);
+    probe.update_partition("events", 0, Some(40), Some(1500));
+    probe.update_partition("other", 0, Some(1), Some(1));
+    probe.lagging.store(true, Ordering::Relaxed);
+    let (container, daemon_data, _f) = test_daemon_ctx_full(None, Some(probe));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(container.clone())
+            .app_data(daemon_data.clone())
+            .configure(config_app),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/ready").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["lagging"], true);
+    assert_eq!(body["max_lag"], 1500);
+    assert_eq!(body["partitions"].as_array().unwrap().len(), 2);
+    assert_eq!(body["partitions"][0]["partition"], 0);
+    assert_eq!(body["partitions"][0]["committed_offset"], 40);
+    assert_eq!(body["partitions"][1]["lag"], serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn metrics_report_kafka_assignment_and_lag() {
+    let probe = KafkaProbeState::new();
+    probe.consumer_started.store(true, Ordering::Relaxed);
+    probe.subscribed.store(true, Ordering::Relaxed);
+    probe.assign_partitions(&[("events".to_string(), 0), ("events".to_string(), 1)]);
+    probe.update_partition("events", 0, Some(40), Some(3));
+    probe.revoke_partitions(&[("events".to_string(), 1)]);
+    let (container, daemon_data, _f) = test_daemon_ctx_full(None, Some(probe));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(container.clone())
+            .app_data(daemon_data.clone())
+            .configure(config_app),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert!(body.contains("ilagent_kafka_ready 1\n"));
+    assert!(body.contains("ilagent_kafka_assigned_partitions 1\n"));
+    assert!(body.contains("ilagent_kafka_committed_offset{topic=\"events\",partition=\"0\"} 40\n"));
+    assert!(body.contains("ilagent_kafka_consumer_lag{topic=\"events\",partition=\"0\"} 3\n"));
+}
+
+// --- queue limit ---
+
+#[actix_rt::test]
+async fn post_event_returns_503_when_queue_is_full() {
+    let mut config = ILConfig::new();
+    config.event_queue_limit.max_items = Some(1);
+    config.event_queue_limit.overflow = QueueOverflow::RejectNew;
+    let (container, daemon_data, _f) = test_daemon_ctx_with_config(None, None, config);
+    let app = test::init_service(
+        App::new()
+            .app_data(container.clone())
+            .app_data(daemon_data.clone())
+            .configure(config_app),
+    )
+    .await;
+
+    let payload = json!({
+        "apiKey": "il1api123",
+        "eventType": "ALERT",
+        "summary": "Server down"
+    });
+
+    let req = test::TestRequest::post()
+        .uri("/api/events")
+        .set_json(&payload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let req = test::TestRequest::post()
+        .uri("/api/events")
+        .set_json(&payload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+
+    let c = container.lock().await;
+    assert_eq!(c.db.get_il_events(10).unwrap().len(), 1);
+}
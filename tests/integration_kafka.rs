@@ -91,8 +91,8 @@ fn kafka_daemon_ctx(
     let mut config = ILConfig::new();
     config.kafka_brokers = Some(broker.to_string());
     config.kafka_group_id = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert-events".to_string());
-    config.heartbeat_topic = Some("ilert-heartbeats".to_string());
+    config.event_topics = vec!["ilert-events".to_string()];
+    config.heartbeat_topics = vec!["ilert-heartbeats".to_string()];
     config.db_file = db_path.to_string();
 
     let db = ILDatabase::new(db_path);
@@ -406,7 +406,7 @@ async fn kafka_event_with_config_mappings() {
     let mut config = ILConfig::new();
     config.kafka_brokers = Some(broker.clone());
     config.kafka_group_id = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert-events".to_string());
+    config.event_topics = vec!["ilert-events".to_string()];
     config.db_file = db_path.clone();
     config.event_key = Some("static-api-key".to_string());
     config.map_key_summary = Some("msg".to_string());
@@ -485,7 +485,7 @@ async fn kafka_event_forward_payload() {
     let mut config = ILConfig::new();
     config.kafka_brokers = Some(broker.clone());
     config.kafka_group_id = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert-events".to_string());
+    config.event_topics = vec!["ilert-events".to_string()];
     config.db_file = db_path.clone();
     config.event_key = Some("fwd-key".to_string());
     config.map_key_etype = Some("eventType".to_string());
@@ -682,9 +682,9 @@ async fn kafka_policy_delivered() {
     let mut config = ILConfig::new();
     config.kafka_brokers = Some(broker.clone());
     config.kafka_group_id = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert-events".to_string());
-    config.heartbeat_topic = Some("ilert-heartbeats".to_string());
-    config.policy_topic = Some("ilert-policies".to_string());
+    config.event_topics = vec!["ilert-events".to_string()];
+    config.heartbeat_topics = vec!["ilert-heartbeats".to_string()];
+    config.policy_topics = vec!["ilert-policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.db_file = db_path.clone();
 
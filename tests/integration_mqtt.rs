@@ -42,8 +42,8 @@ fn mqtt_daemon_ctx(
     config.mqtt_host = Some(mqtt_host.to_string());
     config.mqtt_port = Some(mqtt_port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
     config.mqtt_qos = 1;
     config.db_file = db_path.to_string();
 
@@ -471,8 +471,8 @@ async fn mqtt_event_e2e_with_poll() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
 
@@ -574,9 +574,9 @@ async fn mqtt_policy_buffer_drain() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
-    config.policy_topic = Some("ilert/policies".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
+    config.policy_topics = vec!["ilert/policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.filter_key = Some("eventType".to_string());
     config.filter_val = Some("active".to_string());
@@ -664,9 +664,9 @@ async fn mqtt_policy_buffer_retry_on_failure() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
-    config.policy_topic = Some("ilert/policies".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
+    config.policy_topics = vec!["ilert/policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
@@ -741,9 +741,9 @@ async fn mqtt_policy_buffer_filtered_dropped() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
-    config.policy_topic = Some("ilert/policies".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
+    config.policy_topics = vec!["ilert/policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.filter_key = Some("eventType".to_string());
     config.filter_val = Some("active".to_string());
@@ -825,9 +825,9 @@ async fn mqtt_policy_buffer_max_retries_drops_message() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
-    config.policy_topic = Some("ilert/policies".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
+    config.policy_topics = vec!["ilert/policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
@@ -906,9 +906,9 @@ async fn mqtt_policy_buffer_unlimited_retries_keeps_message() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
-    config.policy_topic = Some("ilert/policies".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
+    config.policy_topics = vec!["ilert/policies".to_string()];
     config.policy_routing_keys = Some("location".to_string());
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
@@ -979,8 +979,8 @@ async fn mqtt_event_forward_payload_with_nested_mapping() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
     config.db_file = db_path.clone();
     config.mqtt_buffer = true;
     config.event_key = Some("il1api-test-key".to_string());
@@ -1089,8 +1089,8 @@ async fn mqtt_event_with_config_mappings() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(port);
     config.mqtt_name = Some(format!("ilagent-test-{}", uuid::Uuid::new_v4()));
-    config.event_topic = Some("ilert/events".to_string());
-    config.heartbeat_topic = Some("ilert/heartbeats".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
+    config.heartbeat_topics = vec!["ilert/heartbeats".to_string()];
     config.db_file = db_path.clone();
     config.mqtt_buffer = true;
     config.event_key = Some("static-api-key".to_string());
@@ -1162,7 +1162,7 @@ async fn mqtt_buffered_event_moves_from_mqtt_queue_to_event_items() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(1883);
     config.mqtt_name = Some("test".to_string());
-    config.event_topic = Some("ilert/events".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
 
@@ -1226,7 +1226,7 @@ async fn mqtt_buffered_event_db_failure_keeps_mqtt_queue_item() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(1883);
     config.mqtt_name = Some("test".to_string());
-    config.event_topic = Some("ilert/events".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
 
@@ -1272,7 +1272,7 @@ async fn mqtt_buffered_event_invalid_payload_dropped_from_mqtt_queue() {
     config.mqtt_host = Some("127.0.0.1".to_string());
     config.mqtt_port = Some(1883);
     config.mqtt_name = Some("test".to_string());
-    config.event_topic = Some("ilert/events".to_string());
+    config.event_topics = vec!["ilert/events".to_string()];
     config.mqtt_buffer = true;
     config.db_file = db_path.clone();
 
@@ -4,10 +4,15 @@ use std::time::Duration;
 
 use ilagent::DaemonContext;
 use ilagent::config::ILConfig;
+use ilagent::consumers::sparkplug::{
+    Metric, MetricValue, Payload, SparkplugMessage, SparkplugState,
+};
+use ilagent::consumers::threshold::ThresholdRule;
 use ilagent::db::ILDatabase;
 use ilagent::models::event_db::EventQueueItem;
-use ilagent::poll::{run_poll_job, send_queued_event};
+use ilagent::poll::{run_mqtt_poll_job, run_poll_job, send_queued_event};
 use ilert::ilert::ILert;
+use prost::Message;
 use tempfile::NamedTempFile;
 use tokio::sync::Mutex;
 use wiremock::matchers::{method, path_regex};
@@ -415,3 +420,73 @@ async fn event_poll_unlimited_retries_keeps_event() {
         "event should remain in queue with unlimited retries"
     );
 }
+
+// --- mqtt poll: buffered Sparkplug data is evaluated by threshold rules ---
+
+#[tokio::test]
+async fn mqtt_poll_evaluates_buffered_sparkplug_data() {
+    let tmp = NamedTempFile::new().unwrap();
+    let db_path = tmp.path().to_str().unwrap().to_string();
+
+    let mut config = ILConfig::new();
+    config.db_file = db_path.clone();
+    config.mqtt_buffer = true;
+    config.event_key = Some("il1apikey123".to_string());
+    config.sparkplug_topic = Some("spBv1.0/+/#".to_string());
+    config.thresholds = vec![
+        ThresholdRule::parse(
+            "name=temp,topic=spBv1.0/+/DDATA/#,path=metrics.Temperature,above=80,key_path=device",
+        )
+        .unwrap(),
+    ];
+
+    let topic = "spBv1.0/plant1/DDATA/edge1/pump1";
+    let payload = Payload {
+        timestamp: Some(1_700_000_000_000),
+        metrics: vec![Metric {
+            name: Some("Temperature".to_string()),
+            datatype: Some(10),
+            value: Some(MetricValue::Double(85.5)),
+            ..Default::default()
+        }],
+        seq: Some(1),
+    };
+    let SparkplugMessage::Data(json) = SparkplugState::default()
+        .handle(&config, topic, &payload.encode_to_vec())
+        .unwrap()
+    else {
+        panic!("expected Sparkplug data");
+    };
+
+    let db = ILDatabase::new(&db_path);
+    db.prepare_database();
+    db.create_mqtt_queue_item(topic, &json.to_string()).unwrap();
+
+    let ilert_client =
+        ILert::new_with_opts(Some("http://127.0.0.1:1"), None, Some(5), None).unwrap();
+    let daemon_ctx = Arc::new(DaemonContext {
+        config,
+        db: Mutex::new(db),
+        ilert_client,
+        running: AtomicBool::new(true),
+        mqtt_probe: None,
+        kafka_probe: None,
+    });
+
+    let poll_ctx = daemon_ctx.clone();
+    let _poller = tokio::spawn(async move {
+        run_mqtt_poll_job(poll_ctx).await;
+    });
+
+    // the first mqtt poll runs after ~10s
+    tokio::time::sleep(Duration::from_secs(12)).await;
+
+    daemon_ctx.running.store(false, Ordering::Relaxed);
+
+    let db = ILDatabase::new(&db_path);
+    assert!(db.get_mqtt_queue_items(10).unwrap().is_empty());
+    let events = db.get_il_events(10).unwrap();
+    assert_eq!(events.len(), 1, "the threshold should open an alert");
+    assert_eq!(events[0].event_type, "ALERT");
+    assert_eq!(events[0].alert_key.as_deref(), Some("temp-pump1"));
+}